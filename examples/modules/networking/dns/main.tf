@@ -0,0 +1,15 @@
+terraform {
+  required_providers {
+    null = {
+      source  = "hashicorp/null"
+      version = "~> 3.0"
+    }
+  }
+}
+
+# This resource does nothing
+resource "null_resource" "dummy" {}
+
+output "zone_id" {
+  value = "Z111"
+}
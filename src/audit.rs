@@ -0,0 +1,87 @@
+use anyhow::{Context, Result};
+use serde::Serialize;
+use serde_yaml::Value;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+
+/// One audited terraform change
+#[derive(Debug, Serialize)]
+pub struct AuditEntry {
+    /// Seconds since the unix epoch
+    pub timestamp: u64,
+    pub module_id: String,
+    pub action: String,
+    /// Injected variables, sensitive values redacted
+    pub variables: HashMap<String, Value>,
+    /// Outputs before the change, `None` when the module had no readable state
+    pub outputs_before: Option<HashMap<String, Value>>,
+    pub outputs_after: Option<HashMap<String, Value>>,
+    pub error: Option<String>,
+}
+
+impl AuditEntry {
+    pub fn new(module_id: &str, action: &str, variables: HashMap<String, Value>) -> Self {
+        Self {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs()),
+            module_id: module_id.to_string(),
+            action: action.to_string(),
+            variables,
+            outputs_before: None,
+            outputs_after: None,
+            error: None,
+        }
+    }
+}
+
+/// Append-only JSONL audit log
+#[derive(Debug)]
+pub struct AuditLog {
+    path: PathBuf,
+    // Serializes writers so concurrent modules never interleave lines
+    lock: Mutex<()>,
+}
+
+impl AuditLog {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            lock: Mutex::new(()),
+        }
+    }
+
+    pub async fn append(&self, entry: &AuditEntry) -> Result<()> {
+        let mut line = serde_json::to_string(entry).context("Failed to serialize audit entry")?;
+        line.push('\n');
+        let _guard = self.lock.lock().await;
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await
+            .with_context(|| format!("Failed to open audit log {:?}", self.path))?;
+        file.write_all(line.as_bytes())
+            .await
+            .with_context(|| format!("Failed to write audit log {:?}", self.path))?;
+        Ok(())
+    }
+}
+
+/// Replace the value of every output terraform marked `sensitive` by "***"
+pub fn redact_outputs(outputs: HashMap<String, Value>) -> HashMap<String, Value> {
+    outputs
+        .into_iter()
+        .map(|(k, mut v)| {
+            if let Value::Mapping(m) = &mut v
+                && m.get("sensitive").and_then(Value::as_bool) == Some(true)
+            {
+                m.insert(Value::from("value"), Value::from("***"));
+            }
+            (k, v)
+        })
+        .collect()
+}
@@ -0,0 +1,109 @@
+use crate::graph::ModuleGraph;
+use crate::parser::ModuleNode;
+use colored::*;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+/// Structural delta between two resolved infrastructure graphs
+#[derive(Debug, Default)]
+pub struct GraphDiff {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    /// Changed modules with one line per difference
+    pub changed: BTreeMap<String, Vec<String>>,
+}
+
+impl GraphDiff {
+    pub fn new(base: ModuleGraph, head: ModuleGraph) -> Self {
+        let base = base.modules();
+        let head = head.modules();
+        let mut diff = GraphDiff::default();
+        let ids: BTreeSet<&String> = base.keys().chain(head.keys()).collect();
+        for id in ids {
+            match (base.get(id), head.get(id)) {
+                (None, Some(_)) => diff.added.push(id.clone()),
+                (Some(_), None) => diff.removed.push(id.clone()),
+                (Some(old), Some(new)) => {
+                    let changes = module_changes(old, new);
+                    if !changes.is_empty() {
+                        diff.changed.insert(id.clone(), changes);
+                    }
+                }
+                (None, None) => {}
+            }
+        }
+        diff
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+/// Differences in source, dependency edges and inputs of one module
+fn module_changes(old: &ModuleNode, new: &ModuleNode) -> Vec<String> {
+    let mut changes = Vec::new();
+    if old.source != new.source {
+        changes.push(format!("source: {} -> {}", old.source, new.source));
+    }
+
+    let old_deps: BTreeSet<&str> = old.dependencies.iter().map(|d| d.id.as_str()).collect();
+    let new_deps: BTreeSet<&str> = new.dependencies.iter().map(|d| d.id.as_str()).collect();
+    for dep in new_deps.difference(&old_deps) {
+        changes.push(format!("+ depends on {}", dep));
+    }
+    for dep in old_deps.difference(&new_deps) {
+        changes.push(format!("- depends on {}", dep));
+    }
+    let old_order: BTreeSet<&str> = old.order_after.iter().map(|d| d.id.as_str()).collect();
+    let new_order: BTreeSet<&str> = new.order_after.iter().map(|d| d.id.as_str()).collect();
+    for dep in new_order.difference(&old_order) {
+        changes.push(format!("+ runs after {}", dep));
+    }
+    for dep in old_order.difference(&new_order) {
+        changes.push(format!("- runs after {}", dep));
+    }
+
+    let keys: BTreeSet<&String> = old.inputs.keys().chain(new.inputs.keys()).collect();
+    for key in keys {
+        match (old.inputs.get(key), new.inputs.get(key)) {
+            (None, Some(v)) => changes.push(format!("+ input {}: {}", key, v)),
+            (Some(v), None) => changes.push(format!("- input {}: {}", key, v)),
+            (Some(a), Some(b)) if a != b => {
+                changes.push(format!("~ input {}: {} -> {}", key, a, b))
+            }
+            _ => {}
+        }
+    }
+    changes
+}
+
+impl fmt::Display for GraphDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_empty() {
+            return writeln!(f, "No structural changes");
+        }
+        if !self.added.is_empty() {
+            writeln!(f, "{}", "Added modules:".green().bold())?;
+            for id in &self.added {
+                writeln!(f, "  + {}", id)?;
+            }
+        }
+        if !self.removed.is_empty() {
+            writeln!(f, "{}", "Removed modules:".red().bold())?;
+            for id in &self.removed {
+                writeln!(f, "  - {}", id)?;
+            }
+        }
+        if !self.changed.is_empty() {
+            writeln!(f, "{}", "Changed modules:".yellow().bold())?;
+            for (id, changes) in &self.changed {
+                writeln!(f, "  {}", id)?;
+                for change in changes {
+                    writeln!(f, "    {}", change)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,17 +1,71 @@
 use crate::parser::Dependency;
-use crate::parser::{InfraFile, InfraNode, ModuleNode, ScopeNode};
+use crate::parser::{EachInstance, InfraFile, InfraNode, ModuleNode, ScopeNode, is_each_ref};
 use anyhow::{Result, anyhow};
 use petgraph::Direction;
 use petgraph::graph::{DiGraph, NodeIndex};
+use serde::Serialize;
 use serde_yaml::Value;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 
 #[derive(Debug, Clone)]
 pub struct Scope {
     pub name: String,
     pub variables: HashMap<String, Value>,
+    pub before: Option<String>,
+    pub after: Option<String>,
 }
 
+/// Prefix of references to the module holding a role, as in `@role:database.endpoint`
+pub const ROLE_PREFIX: &str = "@role:";
+
+/// Version of the `export-graph` JSON format, bumped on breaking changes
+pub const GRAPH_SCHEMA_VERSION: u32 = 1;
+
+/// Stable, versioned representation of the module graph
+#[derive(Debug, Serialize)]
+pub struct GraphExport {
+    pub schema_version: u32,
+    /// Sorted by id
+    pub nodes: Vec<GraphExportNode>,
+    /// Sorted by (from, to)
+    pub edges: Vec<GraphExportEdge>,
+    /// Sorted by id
+    pub scopes: Vec<GraphExportScope>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct GraphExportNode {
+    pub id: String,
+    pub source: String,
+    /// Ids of the enclosing scopes, outermost first
+    pub scopes: Vec<String>,
+    /// Sorted
+    pub tags: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct GraphExportScope {
+    pub id: String,
+    /// Scope type, e.g. "account"
+    pub scope: String,
+    pub variables: BTreeMap<String, Value>,
+}
+
+/// `from` must be applied before `to`
+#[derive(Debug, Serialize)]
+pub struct GraphExportEdge {
+    pub from: String,
+    pub to: String,
+    /// Dependency as written in the YAML (the source name)
+    pub dependency: String,
+    /// From `order_after`: orders the runs, no output is read
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub order_only: bool,
+}
+
+/// A scheduled module and the dependencies of the previous layer it waits for
+pub type LayerReason = (String, Vec<String>);
+
 #[derive(Debug, Clone)]
 pub struct ModuleGraph {
     mod_dependency_graph: DiGraph<String, ()>,
@@ -27,32 +81,83 @@ impl ModuleGraph {
 
         // Collect all modules and scopes
         for node in infra.nodes.values() {
-            collect_modules(node, &mut modules, &mut scopes);
+            collect_modules(node, &mut modules, &mut scopes)?;
         }
 
-        // Add nodes
+        // Add nodes, in id order so node indices and the first error reported are stable
+        let mut ids: Vec<&String> = modules.keys().collect();
+        ids.sort();
         let mut node_indices = HashMap::new();
-        for id in modules.keys() {
-            let idx = mod_dependency_graph.add_node(id.clone());
-            node_indices.insert(id.clone(), idx);
+        for id in &ids {
+            let idx = mod_dependency_graph.add_node((*id).clone());
+            node_indices.insert((*id).clone(), idx);
         }
 
         let mut final_modules = HashMap::new();
-        for (id, module) in &modules {
+        for id in ids {
+            let module = &modules[id];
             let mut enriched_deps = Vec::new();
-            for dependency in &module.dependencies {
+            let mut enriched_order = Vec::new();
+            let entries = (module.dependencies.iter().map(|d| (d, false)))
+                .chain(module.order_after.iter().map(|d| (d, true)));
+            for (dependency, order_only) in entries {
+                if let Some(when) = &dependency.when
+                    && !condition_holds(module, when, &scopes)?
+                {
+                    continue;
+                }
                 let dep_id = resolve_dependency_id(module, &dependency.name, &modules)?;
-                enriched_deps.push(Dependency {
+                let enriched = Dependency {
                     id: dep_id.clone(),
                     name: dependency.name.clone(),
-                });
-                if let (Some(&from), Some(&to)) = (node_indices.get(&dep_id), node_indices.get(id))
-                {
-                    mod_dependency_graph.add_edge(from, to, ());
+                    when: dependency.when.clone(),
+                };
+                match order_only {
+                    true => enriched_order.push(enriched),
+                    false => enriched_deps.push(enriched),
                 }
+                // A dropped edge would silently corrupt the execution order
+                let (Some(&from), Some(&to)) = (node_indices.get(&dep_id), node_indices.get(id))
+                else {
+                    return Err(anyhow!(
+                        "dependency '{}' of module '{}' resolved to '{}' which has no node in the graph",
+                        dependency.name,
+                        id,
+                        dep_id
+                    ));
+                };
+                mod_dependency_graph.update_edge(from, to, ());
+            }
+            // `@role:<role>.<output>` references depend on the module holding the role
+            let mut role_refs: Vec<&str> = module
+                .inputs
+                .values()
+                .flat_map(|input| input.ref_paths())
+                .filter_map(|path| path.split('.').next()?.strip_prefix(ROLE_PREFIX))
+                .collect();
+            role_refs.sort();
+            role_refs.dedup();
+            for role in role_refs {
+                let dep_id = resolve_role(module, role, &modules)?;
+                enriched_deps.push(Dependency {
+                    id: dep_id.clone(),
+                    name: format!("{}{}", ROLE_PREFIX, role),
+                    when: None,
+                });
+                let (Some(&from), Some(&to)) = (node_indices.get(&dep_id), node_indices.get(id))
+                else {
+                    return Err(anyhow!(
+                        "role '{}' of module '{}' resolved to '{}' which has no node in the graph",
+                        role,
+                        id,
+                        dep_id
+                    ));
+                };
+                mod_dependency_graph.update_edge(from, to, ());
             }
             let mut module = module.clone();
             module.dependencies = enriched_deps;
+            module.order_after = enriched_order;
             final_modules.insert(id.clone(), module);
         }
 
@@ -67,6 +172,8 @@ impl ModuleGraph {
                         Scope {
                             name: s.scope,
                             variables: s.variables,
+                            before: s.before,
+                            after: s.after,
                         },
                     )
                 })
@@ -74,74 +181,71 @@ impl ModuleGraph {
         })
     }
 
-    pub fn execution_layers(&self, target_module_id: &str) -> Result<(Vec<Vec<String>>, String)> {
-        dbg!(&self.mod_dependency_graph);
-        // Find NodeIndex for target module
-        let target_idx = self
-            .mod_dependency_graph
-            .node_indices()
-            .find(|&i| self.mod_dependency_graph[i] == target_module_id)
-            .ok_or_else(|| anyhow!("Target module not found: {}", target_module_id))?;
+    /// Layers of the union of the targets and all their dependencies, targets included
+    pub fn closure_layers(&self, targets: &[String]) -> Result<Vec<Vec<String>>> {
+        // Find NodeIndex for target modules
+        let target_indices = targets
+            .iter()
+            .map(|target| self.node_index(target))
+            .collect::<Result<Vec<_>>>()?;
 
-        // Collect all dependencies (ancestors) of the target module
-        let mut relevant = HashSet::new();
-        let mut stack = vec![target_idx];
-        while let Some(idx) = stack.pop() {
-            if relevant.insert(idx) {
-                for dep in self
-                    .mod_dependency_graph
-                    .neighbors_directed(idx, Direction::Incoming)
-                {
-                    stack.push(dep);
-                }
-            }
-        }
+        let relevant = self.dependency_closure(&target_indices);
 
         // Topologically sort the relevant subgraph
         let mut sorted: Vec<NodeIndex> = Vec::new();
-        let mut temp_mark = HashSet::new();
+        // Modules on the current DFS path, to report a cycle as the path that closes it
+        let mut temp_mark: Vec<NodeIndex> = Vec::new();
         let mut perm_mark = HashSet::new();
 
         fn visit(
             idx: NodeIndex,
             graph: &DiGraph<String, ()>,
             relevant: &HashSet<NodeIndex>,
-            temp_mark: &mut HashSet<NodeIndex>,
+            temp_mark: &mut Vec<NodeIndex>,
             perm_mark: &mut HashSet<NodeIndex>,
             sorted: &mut Vec<NodeIndex>,
         ) -> Result<()> {
             if perm_mark.contains(&idx) {
                 return Ok(());
             }
-            if temp_mark.contains(&idx) {
-                return Err(anyhow!("Cycle detected in dependency graph"));
+            if let Some(start) = temp_mark.iter().position(|&i| i == idx) {
+                let cycle: Vec<&str> = temp_mark[start..]
+                    .iter()
+                    .chain([&idx])
+                    .map(|&i| graph[i].as_str())
+                    .collect();
+                return Err(anyhow!(
+                    "Cycle detected in dependency graph: {} (each module depends on the next)",
+                    cycle.join(" -> ")
+                ));
             }
-            temp_mark.insert(idx);
+            temp_mark.push(idx);
             for dep in graph.neighbors_directed(idx, Direction::Incoming) {
                 if relevant.contains(&dep) {
                     visit(dep, graph, relevant, temp_mark, perm_mark, sorted)?;
                 }
             }
-            temp_mark.remove(&idx);
+            temp_mark.pop();
             perm_mark.insert(idx);
             sorted.push(idx);
             Ok(())
         }
 
-        visit(
-            target_idx,
-            &self.mod_dependency_graph,
-            &relevant,
-            &mut temp_mark,
-            &mut perm_mark,
-            &mut sorted,
-        )?;
+        for &target_idx in &target_indices {
+            visit(
+                target_idx,
+                &self.mod_dependency_graph,
+                &relevant,
+                &mut temp_mark,
+                &mut perm_mark,
+                &mut sorted,
+            )?;
+        }
 
-        // Build layers (excluding the target from layers, return it separately)
+        // Build layers
         let mut layers: Vec<Vec<String>> = Vec::new();
         let mut assigned: HashSet<NodeIndex> = HashSet::new();
         let mut remaining: HashSet<NodeIndex> = sorted.iter().cloned().collect();
-        remaining.remove(&target_idx); // Exclude target from layers
 
         while !remaining.is_empty() {
             let mut layer = Vec::new();
@@ -160,6 +264,8 @@ impl ModuleGraph {
                     next_remaining.insert(idx);
                 }
             }
+            // Modules of a layer run in id order, so runs are reproducible
+            layer.sort();
 
             if layer.is_empty() {
                 return Err(anyhow!(
@@ -168,24 +274,336 @@ impl ModuleGraph {
             }
 
             for id in &layer {
-                let idx = self
-                    .mod_dependency_graph
-                    .node_indices()
-                    .find(|&i| &self.mod_dependency_graph[i] == id)
-                    .unwrap();
-                assigned.insert(idx);
+                assigned.insert(self.node_index(id)?);
             }
 
             layers.push(layer);
             remaining = next_remaining;
         }
 
-        Ok((layers, target_module_id.to_string()))
+        self.check_layers(&layers)?;
+        Ok(layers)
+    }
+
+    /// For every module of `layers`, the dependencies that forced it into its layer:
+    /// those assigned to the previous layer (none for the first layer)
+    pub fn explain_layers(&self, layers: &[Vec<String>]) -> Result<Vec<Vec<LayerReason>>> {
+        let mut explained = Vec::new();
+        let mut previous: HashSet<&str> = HashSet::new();
+        for (pos, layer) in layers.iter().enumerate() {
+            let mut entries = Vec::new();
+            for id in layer {
+                let module = self.scheduled_module(id, pos)?;
+                let mut forced_by: Vec<String> = module
+                    .dependencies
+                    .iter()
+                    .chain(&module.order_after)
+                    .filter(|dep| previous.contains(dep.id.as_str()))
+                    .map(|dep| dep.id.clone())
+                    .collect();
+                forced_by.sort();
+                entries.push((id.clone(), forced_by));
+            }
+            explained.push(entries);
+            previous = layer.iter().map(String::as_str).collect();
+        }
+        Ok(explained)
+    }
+
+    /// Module of the layer at `pos` (0-based), an error naming both when it is missing
+    fn scheduled_module(&self, id: &str, pos: usize) -> Result<&ModuleNode> {
+        self.modules.get(id).ok_or_else(|| {
+            anyhow!(
+                "Module '{}' is scheduled in layer {} but not in the graph",
+                id,
+                pos + 1
+            )
+        })
+    }
+
+    /// Ids of the modules outside the closure of the entry targets: nothing the entries
+    /// run depends on them, so they are possibly dead configuration; sorted
+    pub fn orphans(&self, entries: &[String]) -> Result<Vec<String>> {
+        let entry_indices = entries
+            .iter()
+            .map(|entry| self.node_index(entry))
+            .collect::<Result<Vec<_>>>()?;
+        let reachable = self.dependency_closure(&entry_indices);
+        let mut orphans: Vec<String> = self
+            .mod_dependency_graph
+            .node_indices()
+            .filter(|idx| !reachable.contains(idx))
+            .map(|idx| self.mod_dependency_graph[idx].clone())
+            .collect();
+        orphans.sort();
+        Ok(orphans)
+    }
+
+    /// The targets and all their dependencies (ancestors)
+    fn dependency_closure(&self, targets: &[NodeIndex]) -> HashSet<NodeIndex> {
+        let mut closure = HashSet::new();
+        let mut stack = targets.to_vec();
+        while let Some(idx) = stack.pop() {
+            if closure.insert(idx) {
+                stack.extend(
+                    self.mod_dependency_graph
+                        .neighbors_directed(idx, Direction::Incoming),
+                );
+            }
+        }
+        closure
+    }
+
+    fn node_index(&self, module_id: &str) -> Result<NodeIndex> {
+        self.mod_dependency_graph
+            .node_indices()
+            .find(|&i| self.mod_dependency_graph[i] == module_id)
+            .ok_or_else(|| anyhow!("Target module not found: {}", module_id))
+    }
+
+    /// Verify every dependency of a scheduled module is produced by an earlier layer,
+    /// so its outputs are available when the module runs
+    fn check_layers(&self, layers: &[Vec<String>]) -> Result<()> {
+        let mut produced: HashSet<&str> = HashSet::new();
+        for (pos, layer) in layers.iter().enumerate() {
+            for id in layer {
+                let module = self.scheduled_module(id, pos)?;
+                if let Some(dep) = module
+                    .dependencies
+                    .iter()
+                    .chain(&module.order_after)
+                    .find(|dep| !produced.contains(dep.id.as_str()))
+                {
+                    return Err(anyhow!(
+                        "dependency '{}' ('{}') of module '{}' is not produced by an earlier layer",
+                        dep.name,
+                        dep.id,
+                        id
+                    ));
+                }
+            }
+            produced.extend(layer.iter().map(String::as_str));
+        }
+        Ok(())
+    }
+
+    /// Stable export of every module and dependency edge
+    pub fn export(&self) -> GraphExport {
+        let mut nodes: Vec<GraphExportNode> = self
+            .modules
+            .values()
+            .map(|m| {
+                let mut scopes: Vec<String> = m.scope_ids.iter().cloned().collect();
+                // Nested scope ids extend their parent's id, so length orders by depth
+                scopes.sort_by_key(|id| (id.len(), id.clone()));
+                let mut tags = m.tags.clone();
+                tags.sort();
+                GraphExportNode {
+                    id: m.id.clone(),
+                    source: m.source.clone(),
+                    scopes,
+                    tags,
+                }
+            })
+            .collect();
+        nodes.sort_by(|a, b| a.id.cmp(&b.id));
+        let mut edges: Vec<GraphExportEdge> = self
+            .modules
+            .values()
+            .flat_map(|m| {
+                let entries = (m.dependencies.iter().map(|dep| (dep, false)))
+                    .chain(m.order_after.iter().map(|dep| (dep, true)));
+                entries.map(|(dep, order_only)| GraphExportEdge {
+                    from: dep.id.clone(),
+                    to: m.id.clone(),
+                    dependency: dep.name.clone(),
+                    order_only,
+                })
+            })
+            .collect();
+        edges.sort_by(|a, b| (&a.from, &a.to).cmp(&(&b.from, &b.to)));
+        let mut scopes: Vec<GraphExportScope> = self
+            .scopes
+            .iter()
+            .map(|(id, s)| GraphExportScope {
+                id: id.clone(),
+                scope: s.name.clone(),
+                variables: s.variables.clone().into_iter().collect(),
+            })
+            .collect();
+        scopes.sort_by(|a, b| a.id.cmp(&b.id));
+        GraphExport {
+            schema_version: GRAPH_SCHEMA_VERSION,
+            nodes,
+            edges,
+            scopes,
+        }
+    }
+
+    /// Graphviz DOT of the graph, modules grouped into a cluster per innermost scope.
+    /// The `highlight` module and every module it depends on are colored.
+    pub fn to_dot(&self, highlight: Option<&str>) -> Result<String> {
+        let mut highlighted = HashSet::new();
+        if let Some(id) = highlight {
+            let mut stack = vec![self.node_index(id)?];
+            while let Some(idx) = stack.pop() {
+                if highlighted.insert(self.mod_dependency_graph[idx].clone()) {
+                    stack.extend(
+                        self.mod_dependency_graph
+                            .neighbors_directed(idx, Direction::Incoming),
+                    );
+                }
+            }
+        }
+        let node = |id: &str| {
+            if highlighted.contains(id) {
+                format!("\"{}\" [style=filled, fillcolor=gold];", id)
+            } else {
+                format!("\"{}\";", id)
+            }
+        };
+
+        let export = self.export();
+        let mut clusters: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+        let mut unscoped = Vec::new();
+        for n in &export.nodes {
+            match n.scopes.last() {
+                Some(scope) => clusters.entry(scope).or_default().push(&n.id),
+                None => unscoped.push(n.id.as_str()),
+            }
+        }
+
+        let mut dot = String::from("digraph tfstacks {\n  rankdir=LR;\n  node [shape=box];\n");
+        for (pos, (scope_id, ids)) in clusters.iter().enumerate() {
+            let label = match self.scopes.get(*scope_id) {
+                Some(scope) => format!("{} ({})", scope_id, scope.name),
+                None => scope_id.to_string(),
+            };
+            dot.push_str(&format!(
+                "  subgraph cluster_{} {{\n    label=\"{}\";\n",
+                pos, label
+            ));
+            for id in ids {
+                dot.push_str(&format!("    {}\n", node(id)));
+            }
+            dot.push_str("  }\n");
+        }
+        for id in unscoped {
+            dot.push_str(&format!("  {}\n", node(id)));
+        }
+        for edge in &export.edges {
+            dot.push_str(&format!("  \"{}\" -> \"{}\";\n", edge.from, edge.to));
+        }
+        dot.push_str("}\n");
+        Ok(dot)
+    }
+
+    /// Indented tree of the scope and module ids, siblings sorted by id. Modules show
+    /// their source and the modules they depend on. With `prefix`, only ids equal to it
+    /// or below it are listed.
+    pub fn to_tree(&self, prefix: Option<&str>) -> String {
+        let listed = |id: &str| {
+            prefix.is_none_or(|p| {
+                id.strip_prefix(p)
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with('.'))
+            })
+        };
+        // Nested scope ids extend their parent's id
+        let parent_scope = |id: &str| {
+            self.scopes
+                .keys()
+                .filter(|s| {
+                    id.strip_prefix(s.as_str())
+                        .is_some_and(|rest| rest.starts_with('.'))
+                })
+                .max_by_key(|s| s.len())
+                .cloned()
+        };
+
+        let export = self.export();
+        let mut entries: Vec<(Option<String>, String, String)> = Vec::new();
+        for (id, scope) in &self.scopes {
+            entries.push((
+                parent_scope(id),
+                id.clone(),
+                format!("{} ({})", id, scope.name),
+            ));
+        }
+        for n in &export.nodes {
+            let deps: Vec<&str> = export
+                .edges
+                .iter()
+                .filter(|e| e.to == n.id)
+                .map(|e| e.from.as_str())
+                .collect();
+            let mut line = format!("{} [{}]", n.id, n.source);
+            if !deps.is_empty() {
+                line.push_str(&format!(" <- {}", deps.join(", ")));
+            }
+            entries.push((n.scopes.last().cloned(), n.id.clone(), line));
+        }
+
+        // An entry whose parent is filtered out is shown at the top level
+        let mut children: BTreeMap<Option<String>, BTreeMap<String, String>> = BTreeMap::new();
+        for (parent, id, line) in entries {
+            if !listed(&id) {
+                continue;
+            }
+            let parent = parent.filter(|p| listed(p));
+            children.entry(parent).or_default().insert(id, line);
+        }
+
+        fn render(
+            parent: Option<String>,
+            depth: usize,
+            children: &BTreeMap<Option<String>, BTreeMap<String, String>>,
+            out: &mut String,
+        ) {
+            for (id, line) in children.get(&parent).into_iter().flatten() {
+                out.push_str(&format!("{}{}\n", "  ".repeat(depth), line));
+                render(Some(id.clone()), depth + 1, children, out);
+            }
+        }
+        let mut out = String::new();
+        render(None, 0, &children, &mut out);
+        out
+    }
+
+    /// Ids of every module carrying `tag`, or of every module when `tag` is None,
+    /// optionally restricted to a scope; sorted
+    pub fn select_modules(&self, tag: Option<&str>, scope_id: Option<&str>) -> Result<Vec<String>> {
+        if let Some(scope_id) = scope_id
+            && !self.scopes.contains_key(scope_id)
+        {
+            return Err(anyhow!("Scope not found: {}", scope_id));
+        }
+        let mut ids: Vec<String> = self
+            .modules
+            .values()
+            .filter(|m| tag.is_none_or(|tag| m.tags.iter().any(|t| t == tag)))
+            .filter(|m| scope_id.is_none_or(|scope_id| m.scope_ids.contains(scope_id)))
+            .map(|m| m.id.clone())
+            .collect();
+        ids.sort();
+        if ids.is_empty()
+            && let (None, Some(scope_id)) = (tag, scope_id)
+        {
+            return Err(anyhow!("Scope '{}' has no modules", scope_id));
+        }
+        if ids.is_empty() {
+            return Err(anyhow!(
+                "No module matches tag '{}' in scope '{}'",
+                tag.unwrap_or("*"),
+                scope_id.unwrap_or("*")
+            ));
+        }
+        Ok(ids)
     }
 
     pub fn modules(self) -> HashMap<String, ModuleNode> {
         self.modules
     }
+    #[allow(dead_code)]
     pub fn scopes(self) -> HashMap<String, Scope> {
         self.scopes
     }
@@ -196,42 +614,661 @@ impl ModuleGraph {
         self.scopes.get(id).cloned()
     }
 }
-fn resolve_dependency_id(
+/// Evaluate a dependency condition, either a literal or a `${<scope>.<variable>[.<key>...]}`
+/// reference to a variable of an enclosing scope. Anything but false, null, 0, "" and
+/// "false" is truthy.
+fn condition_holds(
     module: &ModuleNode,
-    dep_name: &str,
+    when: &str,
+    scopes: &HashMap<String, ScopeNode>,
+) -> Result<bool> {
+    let when = when.trim();
+    let value = match when.strip_prefix("${").and_then(|r| r.strip_suffix('}')) {
+        Some(path) => lookup_scope_variable(module, path.trim(), scopes).ok_or_else(|| {
+            anyhow!(
+                "condition '{}' of module '{}' references an unknown scope variable",
+                when,
+                module.id
+            )
+        })?,
+        None => Value::String(when.to_string()),
+    };
+    Ok(match value {
+        Value::Null => false,
+        Value::Bool(b) => b,
+        Value::Number(n) => n.as_f64() != Some(0.0),
+        Value::String(s) => !(s.is_empty() || s == "false" || s == "0"),
+        _ => true,
+    })
+}
+
+/// Id of the enclosing scope `scope_name` names: the scope with that id (matching the
+/// last segment of the scope id), else the innermost scope of that type. `is_type`
+/// tells whether the scope of an id has that type.
+pub(crate) fn nearest_scope_id<'a>(
+    module: &'a ModuleNode,
+    scope_name: &str,
+    is_type: impl Fn(&str) -> bool,
+) -> Option<&'a String> {
+    // Nested scope ids extend their parent's id, so the longest is the innermost
+    let mut scope_ids: Vec<&String> = module.scope_ids.iter().collect();
+    scope_ids.sort_by(|a, b| b.len().cmp(&a.len()).then(a.cmp(b)));
+    let by_id = scope_ids
+        .iter()
+        .find(|id| id.rsplit('.').next() == Some(scope_name));
+    by_id
+        .or_else(|| scope_ids.iter().find(|id| is_type(id)))
+        .copied()
+}
+
+/// Find `<scope>.<variable>[.<key>...]` in the scopes enclosing a module, the scope
+/// named like `runtime::find_scope_variable` does
+fn lookup_scope_variable(
+    module: &ModuleNode,
+    path: &str,
+    scopes: &HashMap<String, ScopeNode>,
+) -> Option<Value> {
+    let mut keys = path.split('.');
+    let scope_name = keys.next()?;
+    let scope_id = nearest_scope_id(module, scope_name, |id| {
+        scopes.get(id).is_some_and(|s| s.scope == scope_name)
+    })?;
+    let scope = scopes.get(scope_id)?;
+    let mut value = scope.variables.get(keys.next()?)?;
+    for key in keys {
+        value = value.get(key)?;
+    }
+    Some(value.clone())
+}
+
+/// Find the unique module fulfilling `role` in the nearest enclosing scope that has one
+fn resolve_role(
+    module: &ModuleNode,
+    role: &str,
     modules: &HashMap<String, ModuleNode>,
 ) -> Result<String> {
-    // Search in current scope_ids from most specific to least
-    let mut scope_ids: Vec<_> = module.scope_ids.iter().collect();
-    scope_ids.reverse();
+    // Nested scope ids extend their parent's id, so the longest is the nearest
+    let mut scope_ids: Vec<&String> = module.scope_ids.iter().collect();
+    scope_ids.sort_by_key(|id| std::cmp::Reverse(id.len()));
     for scope_id in scope_ids {
-        // Find a module in this scope with matching source
-        for m in modules.values() {
-            if m.source == dep_name && m.scope_ids.contains(scope_id) {
-                return Ok(m.id.clone());
+        let mut holders: Vec<&str> = modules
+            .values()
+            .filter(|m| m.id != module.id && m.role.as_deref() == Some(role))
+            .filter(|m| m.scope_ids.contains(scope_id))
+            .map(|m| m.id.as_str())
+            .collect();
+        match holders.len() {
+            0 => continue,
+            1 => return Ok(holders[0].to_string()),
+            _ => {
+                holders.sort();
+                return Err(anyhow!(
+                    "role '{}' referenced by module '{}' is ambiguous in scope '{}': {}",
+                    role,
+                    module.id,
+                    scope_id,
+                    holders.join(", ")
+                ));
             }
         }
     }
+    Err(anyhow!(
+        "no module with role '{}' found in the scopes of module '{}'",
+        role,
+        module.id
+    ))
+}
+
+/// Id of the module of source `dep_name` in the nearest scope of `module` having one.
+/// Several candidates resolve the same way on every run: the least nested wins, then
+/// the smallest id.
+fn resolve_dependency_id(
+    module: &ModuleNode,
+    dep_name: &str,
+    modules: &HashMap<String, ModuleNode>,
+) -> Result<String> {
+    // Search in current scope_ids from most specific to least; nested scope ids
+    // extend their parent's id, so the longest is the most specific
+    let mut scope_ids: Vec<&String> = module.scope_ids.iter().collect();
+    scope_ids.sort_by_key(|id| std::cmp::Reverse(id.len()));
+    for scope_id in scope_ids {
+        // Find a module in this scope with matching source, the least nested one
+        // first, then by id
+        let candidates: Vec<&ModuleNode> = modules
+            .values()
+            .filter(|m| m.source == dep_name && m.scope_ids.contains(scope_id))
+            .collect();
+        let Some(found) = candidates
+            .iter()
+            .min_by_key(|m| (m.scope_ids.len(), base_id(m)))
+        else {
+            continue;
+        };
+        let Some(set) = &found.each else {
+            return Ok(found.id.clone());
+        };
+        // Instances of expanded modules pair up by key
+        let Some(each) = &module.each else {
+            return Err(anyhow!(
+                "dependency '{}' of module '{}' is expanded with count/for_each, only a module expanded the same way can depend on it",
+                dep_name,
+                module.id
+            ));
+        };
+        return candidates
+            .iter()
+            .find(|m| {
+                m.each
+                    .as_ref()
+                    .is_some_and(|e| e.base_id == set.base_id && e.key == each.key)
+            })
+            .map(|m| m.id.clone())
+            .ok_or_else(|| {
+                anyhow!(
+                    "dependency '{}' of module '{}' has no instance '{}[{}]' to pair with",
+                    dep_name,
+                    module.id,
+                    set.base_id,
+                    key_suffix(&each.key)
+                )
+            });
+    }
     Err(anyhow!(
         "dependency '{}' of module '{}' not found in the infrastructure",
         dep_name,
         module.id
     ))
 }
+
+/// Id of the module an instance was expanded from, the id itself otherwise
+fn base_id(module: &ModuleNode) -> &str {
+    module
+        .each
+        .as_ref()
+        .map_or(module.id.as_str(), |each| each.base_id.as_str())
+}
+
+/// Instance suffix of an expansion key
+fn key_suffix(key: &Value) -> String {
+    match key {
+        Value::String(s) => s.clone(),
+        Value::Number(n) => n.to_string(),
+        other => format!("{:?}", other),
+    }
+}
+
+/// The instances of a module with `count` or `for_each`, `each.*` references of
+/// their inputs replaced, or the module itself
+fn expand_module(module: &ModuleNode) -> Result<Vec<ModuleNode>> {
+    let entries: Vec<(Value, Value)> = if let Some(count) = module.count {
+        (0..count)
+            .map(|i| (Value::from(i), Value::from(i)))
+            .collect()
+    } else if let Some(for_each) = &module.for_each {
+        let entries: Vec<(Value, Value)> = match for_each {
+            Value::Sequence(items) => items.iter().map(|i| (i.clone(), i.clone())).collect(),
+            Value::Mapping(map) => map.iter().map(|(k, v)| (k.clone(), v.clone())).collect(),
+            _ => {
+                return Err(anyhow!(
+                    "'for_each' of module '{}' must be a list or a mapping",
+                    module.id
+                ));
+            }
+        };
+        let mut seen = HashSet::new();
+        for (key, _) in &entries {
+            if !matches!(key, Value::String(_) | Value::Number(_)) {
+                return Err(anyhow!(
+                    "'for_each' keys of module '{}' must be strings or numbers",
+                    module.id
+                ));
+            }
+            if !seen.insert(key_suffix(key)) {
+                return Err(anyhow!(
+                    "'for_each' of module '{}' has duplicate key '{}'",
+                    module.id,
+                    key_suffix(key)
+                ));
+            }
+        }
+        entries
+    } else {
+        if let Some(path) = module
+            .inputs
+            .values()
+            .flat_map(|input| input.ref_paths())
+            .find(|path| is_each_ref(path))
+        {
+            return Err(anyhow!(
+                "module '{}' references '{}' but sets neither count nor for_each",
+                module.id,
+                path
+            ));
+        }
+        return Ok(vec![module.clone()]);
+    };
+
+    let mut instances = Vec::new();
+    for (key, value) in entries {
+        let mut instance = module.clone();
+        instance.id = format!("{}[{}]", module.id, key_suffix(&key));
+        let each = EachInstance {
+            base_id: module.id.clone(),
+            key,
+            value,
+        };
+        for (name, input) in instance.inputs.iter_mut() {
+            input
+                .substitute_each(&each)
+                .map_err(|e| anyhow!("input '{}' of module '{}': {}", name, instance.id, e))?;
+        }
+        instance.each = Some(each);
+        instances.push(instance);
+    }
+    Ok(instances)
+}
+
 fn collect_modules(
     node: &InfraNode,
     modules: &mut HashMap<String, ModuleNode>,
     scopes: &mut HashMap<String, ScopeNode>,
-) {
+) -> Result<()> {
     match node {
         InfraNode::Module(m) => {
-            modules.insert(m.id.clone(), m.clone());
+            for instance in expand_module(m)? {
+                modules.insert(instance.id.clone(), instance);
+            }
         }
         InfraNode::Scope(s) => {
             scopes.insert(s.id.clone(), s.clone());
             for child in s.children.values() {
-                collect_modules(child, modules, scopes);
+                collect_modules(child, modules, scopes)?;
             }
         }
     }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::InputValue;
+    use crate::testing::infra;
+
+    /// `member-1` nests in `org-root`, both of type `account`: the inner one's `nat`
+    /// overrides the outer one's
+    fn conditional(inner_nat: &str) -> ModuleGraph {
+        let yaml = format!(
+            "
+org-root:
+  scope: account
+  variables:
+    nat: true
+  member-1:
+    scope: account
+    variables:
+      nat: {inner_nat}
+    vpc:
+      source: vpc
+    compute:
+      source: compute
+      dependencies:
+        - name: vpc
+          when: \"${{account.nat}}\"
+    webapp:
+      source: webapp
+      dependencies:
+        - name: vpc
+          when: \"${{org-root.nat}}\"
+"
+        );
+        ModuleGraph::new(&infra(&yaml)).unwrap()
+    }
+
+    fn dependency_ids(graph: &ModuleGraph, id: &str) -> Vec<String> {
+        let module = graph.get_module_by_id(id).unwrap();
+        module.dependencies.into_iter().map(|d| d.id).collect()
+    }
+
+    #[test]
+    fn truthy_condition_keeps_the_edge() {
+        let graph = conditional("true");
+        assert_eq!(
+            dependency_ids(&graph, "org-root.member-1.compute"),
+            ["org-root.member-1.vpc"]
+        );
+    }
+
+    #[test]
+    fn falsy_condition_drops_the_edge() {
+        for falsy in ["false", "0", "\"\"", "null"] {
+            let graph = conditional(falsy);
+            assert!(
+                dependency_ids(&graph, "org-root.member-1.compute").is_empty(),
+                "{falsy}"
+            );
+        }
+    }
+
+    #[test]
+    fn innermost_scope_of_the_type_overrides_the_outer_one() {
+        // Whatever the HashSet order of the scope ids, the innermost `account` wins
+        for _ in 0..20 {
+            let graph = conditional("false");
+            assert!(dependency_ids(&graph, "org-root.member-1.compute").is_empty());
+            // Naming the outer scope by id still reads its own variable
+            assert_eq!(
+                dependency_ids(&graph, "org-root.member-1.webapp"),
+                ["org-root.member-1.vpc"]
+            );
+        }
+    }
+
+    #[test]
+    fn unknown_scope_variable_is_an_error() {
+        let yaml = "
+acc:
+  scope: account
+  vpc:
+    source: vpc
+  compute:
+    source: compute
+    dependencies:
+      - name: vpc
+        when: \"${account.missing}\"
+";
+        let err = ModuleGraph::new(&infra(yaml)).unwrap_err();
+        assert!(err.to_string().contains("unknown scope variable"), "{err}");
+    }
+
+    #[test]
+    fn same_source_dependency_resolves_to_the_smallest_id() {
+        let yaml = "
+acc:
+  scope: account
+  main-vpc:
+    source: vpc
+  edge-vpc:
+    source: vpc
+  zz-vpc:
+    source: vpc
+  webapp:
+    source: webapp
+    dependencies: [vpc]
+";
+        // Module maps are hashed: build the graph again and again
+        for _ in 0..20 {
+            let graph = ModuleGraph::new(&infra(yaml)).unwrap();
+            assert_eq!(dependency_ids(&graph, "acc.webapp"), ["acc.edge-vpc"]);
+        }
+    }
+
+    #[test]
+    fn dependency_in_the_closest_scope_wins_over_a_smaller_id() {
+        let yaml = "
+acc:
+  scope: account
+  a-vpc:
+    source: vpc
+  tenant:
+    scope: tenant
+    z-vpc:
+      source: vpc
+    webapp:
+      source: webapp
+      dependencies: [vpc]
+";
+        let graph = ModuleGraph::new(&infra(yaml)).unwrap();
+        assert_eq!(
+            dependency_ids(&graph, "acc.tenant.webapp"),
+            ["acc.tenant.z-vpc"]
+        );
+    }
+
+    #[test]
+    fn cycles_are_reported_as_the_path_closing_them() {
+        let yaml = "
+acc:
+  scope: account
+  vpc:
+    source: vpc
+    dependencies: [webapp]
+  compute:
+    source: compute
+    dependencies: [vpc]
+  webapp:
+    source: webapp
+    dependencies: [compute]
+  network:
+    source: network
+";
+        let graph = ModuleGraph::new(&infra(yaml)).unwrap();
+        let err = graph
+            .closure_layers(&["acc.webapp".to_string()])
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Cycle detected in dependency graph: acc.webapp -> acc.compute -> acc.vpc -> acc.webapp (each module depends on the next)"
+        );
+        // Modules outside the cycle still run
+        assert_eq!(
+            graph.closure_layers(&["acc.network".to_string()]).unwrap(),
+            [["acc.network"]]
+        );
+    }
+
+    const TREE: &str = "
+b-account:
+  scope: account
+  vpc:
+    source: vpc
+  tenant:
+    scope: tenant
+    webapp:
+      source: webapp
+      dependencies: [vpc]
+a-account:
+  scope: account
+  network:
+    source: network
+";
+
+    #[test]
+    fn tree_lists_every_scope_and_module_sorted() {
+        let graph = ModuleGraph::new(&infra(TREE)).unwrap();
+        assert_eq!(
+            graph.to_tree(None),
+            "\
+a-account (account)
+  a-account.network [network]
+b-account (account)
+  b-account.tenant (tenant)
+    b-account.tenant.webapp [webapp] <- b-account.vpc
+  b-account.vpc [vpc]
+"
+        );
+    }
+
+    #[test]
+    fn tree_filtered_by_scope_keeps_what_is_below_it() {
+        let graph = ModuleGraph::new(&infra(TREE)).unwrap();
+        let tree = graph.to_tree(Some("b-account.tenant"));
+        assert!(
+            tree.contains("b-account.tenant.webapp [webapp] <- b-account.vpc"),
+            "{tree}"
+        );
+        assert!(!tree.contains("a-account"), "{tree}");
+        assert!(!tree.contains("b-account.vpc [vpc]"), "{tree}");
+    }
+
+    const WIDE_LAYERS: &str = "
+acc:
+  scope: account
+  vpc:
+    source: vpc
+  network:
+    source: network
+  compute:
+    source: compute
+  webapp:
+    source: webapp
+    dependencies: [vpc, network, compute]
+  dns:
+    source: networking/dns
+    dependencies: [network]
+";
+
+    #[test]
+    fn layers_are_identical_across_builds_and_sorted() {
+        let layers = || {
+            let graph = ModuleGraph::new(&infra(WIDE_LAYERS)).unwrap();
+            graph
+                .closure_layers(&["acc.webapp".to_string(), "acc.dns".to_string()])
+                .unwrap()
+        };
+        let first = layers();
+        assert_eq!(
+            first,
+            [
+                vec!["acc.compute", "acc.network", "acc.vpc"],
+                vec!["acc.dns", "acc.webapp"],
+            ]
+        );
+        for _ in 0..20 {
+            assert_eq!(layers(), first);
+        }
+    }
+
+    #[test]
+    fn count_expands_into_instances_that_pair_by_index() {
+        let yaml = "
+acc:
+  scope: account
+  vpc:
+    source: vpc
+    count: 2
+    inputs:
+      index:
+        from: each.key
+  compute:
+    source: compute
+    count: 2
+    dependencies: [vpc]
+";
+        let graph = ModuleGraph::new(&infra(yaml)).unwrap();
+        let mut ids: Vec<String> = graph.clone().modules().into_keys().collect();
+        ids.sort();
+        assert_eq!(
+            ids,
+            [
+                "acc.compute[0]",
+                "acc.compute[1]",
+                "acc.vpc[0]",
+                "acc.vpc[1]"
+            ]
+        );
+        assert_eq!(dependency_ids(&graph, "acc.compute[1]"), ["acc.vpc[1]"]);
+        let vpc = graph.get_module_by_id("acc.vpc[1]").unwrap();
+        assert!(matches!(&vpc.inputs["index"], InputValue::Default(v) if v.as_u64() == Some(1)));
+    }
+
+    #[test]
+    fn expanded_dependency_of_a_single_module_is_an_error() {
+        let yaml = "
+acc:
+  scope: account
+  vpc:
+    source: vpc
+    for_each: [eu, us]
+  compute:
+    source: compute
+    dependencies: [vpc]
+";
+        let err = ModuleGraph::new(&infra(yaml)).unwrap_err();
+        assert!(
+            format!("{err:#}").contains("only a module expanded the same way can depend on it"),
+            "{err:#}"
+        );
+    }
+
+    #[test]
+    fn layer_module_missing_from_the_graph_is_an_error() {
+        let graph = ModuleGraph::new(&infra(TREE)).unwrap();
+        let layers = vec![
+            vec!["b-account.vpc".to_string()],
+            vec!["b-account.ghost".to_string()],
+        ];
+        let err = graph.explain_layers(&layers).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Module 'b-account.ghost' is scheduled in layer 2 but not in the graph"
+        );
+        let err = graph.check_layers(&layers).unwrap_err();
+        assert!(err.to_string().contains("layer 2"), "{err}");
+    }
+
+    #[test]
+    fn modules_outside_the_entry_closures_are_orphans() {
+        let yaml = "
+acc:
+  scope: account
+  vpc:
+    source: vpc
+  compute:
+    source: compute
+    dependencies: [vpc]
+  network:
+    source: network
+  webapp:
+    source: webapp
+    dependencies: [compute]
+";
+        let graph = ModuleGraph::new(&infra(yaml)).unwrap();
+        assert_eq!(
+            graph.orphans(&["acc.webapp".to_string()]).unwrap(),
+            ["acc.network"]
+        );
+        assert_eq!(
+            graph.orphans(&["acc.compute".to_string()]).unwrap(),
+            ["acc.network", "acc.webapp"]
+        );
+        assert!(graph.orphans(&["acc.ghost".to_string()]).is_err());
+    }
+
+    #[test]
+    fn order_after_orders_the_layers_and_closes_cycles() {
+        let yaml = "
+acc:
+  scope: account
+  vpc:
+    source: vpc
+  webapp:
+    source: webapp
+    order_after: [vpc]
+";
+        let graph = ModuleGraph::new(&infra(yaml)).unwrap();
+        assert_eq!(
+            graph.closure_layers(&["acc.webapp".to_string()]).unwrap(),
+            [["acc.vpc"], ["acc.webapp"]]
+        );
+        let webapp = graph.get_module_by_id("acc.webapp").unwrap();
+        assert!(webapp.dependencies.is_empty());
+        assert_eq!(webapp.order_after[0].id, "acc.vpc");
+
+        let cyclic = "
+acc:
+  scope: account
+  vpc:
+    source: vpc
+    dependencies: [webapp]
+  webapp:
+    source: webapp
+    order_after: [vpc]
+";
+        let graph = ModuleGraph::new(&infra(cyclic)).unwrap();
+        let err = graph
+            .closure_layers(&["acc.webapp".to_string()])
+            .unwrap_err();
+        assert!(err.to_string().contains("Cycle detected"), "{err}");
+    }
 }
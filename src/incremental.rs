@@ -0,0 +1,245 @@
+use crate::manifest::sha256_hex;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_yaml::Value;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// What the last `--incremental` run recorded for a module
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ModuleRecord {
+    /// Digest of the module source and resolved variables it was last applied with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fingerprint: Option<String>,
+    /// Digest of the outputs last read from the module
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub outputs: Option<String>,
+}
+
+/// Records of every module, loaded before a run and saved after it. A module whose
+/// outputs differ from the recorded ones is dirty for the rest of the run, and
+/// forces its dependents to apply again.
+#[derive(Debug)]
+pub struct IncrementalState {
+    path: PathBuf,
+    records: Mutex<BTreeMap<String, ModuleRecord>>,
+    dirty: Mutex<HashSet<String>>,
+}
+
+impl IncrementalState {
+    /// Load the records at `path`, none when the file does not exist yet
+    pub fn load(path: &Path) -> Result<Self> {
+        let records = if path.is_file() {
+            let content = std::fs::read(path)
+                .with_context(|| format!("Failed to read incremental state {:?}", path))?;
+            serde_json::from_slice(&content)
+                .with_context(|| format!("Invalid incremental state {:?}", path))?
+        } else {
+            BTreeMap::new()
+        };
+        Ok(Self {
+            path: path.to_path_buf(),
+            records: Mutex::new(records),
+            dirty: Mutex::new(HashSet::new()),
+        })
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let json = serde_json::to_string_pretty(&*self.records.lock().unwrap())?;
+        std::fs::write(&self.path, json)
+            .with_context(|| format!("Failed to write incremental state {:?}", self.path))
+    }
+
+    /// Whether the module was last applied with this fingerprint
+    pub fn is_unchanged(&self, id: &str, fingerprint: &str) -> bool {
+        self.records
+            .lock()
+            .unwrap()
+            .get(id)
+            .and_then(|r| r.fingerprint.as_deref())
+            == Some(fingerprint)
+    }
+
+    /// The given dependencies whose outputs changed during this run, sorted
+    pub fn dirty_among<'a>(&self, ids: impl IntoIterator<Item = &'a str>) -> Vec<&'a str> {
+        let dirty = self.dirty.lock().unwrap();
+        let mut found: Vec<&str> = ids.into_iter().filter(|id| dirty.contains(*id)).collect();
+        found.sort();
+        found.dedup();
+        found
+    }
+
+    pub fn record_applied(&self, id: &str, fingerprint: String) {
+        self.records
+            .lock()
+            .unwrap()
+            .entry(id.to_string())
+            .or_default()
+            .fingerprint = Some(fingerprint);
+    }
+
+    /// Record freshly read outputs, marking the module dirty when they differ
+    /// from the recorded ones
+    pub fn record_outputs(&self, id: &str, outputs: &HashMap<String, Value>) -> Result<()> {
+        let digest = sha256_hex(&serde_json::to_vec(&sorted(outputs))?);
+        let mut records = self.records.lock().unwrap();
+        let record = records.entry(id.to_string()).or_default();
+        // Outputs seen for the first time have no dependent applied against them yet
+        if record
+            .outputs
+            .as_ref()
+            .is_some_and(|previous| *previous != digest)
+        {
+            self.dirty.lock().unwrap().insert(id.to_string());
+        }
+        record.outputs = Some(digest);
+        Ok(())
+    }
+}
+
+/// Digest of what an apply of the module depends on: its source files and resolved variables
+pub fn fingerprint(
+    source_digest: Option<&str>,
+    variables: &HashMap<String, Value>,
+) -> Result<String> {
+    let payload = serde_json::to_vec(&(source_digest, sorted(variables)))?;
+    Ok(sha256_hex(&payload))
+}
+
+fn sorted(values: &HashMap<String, Value>) -> BTreeMap<&String, &Value> {
+    values.iter().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::{ModuleStatus, RunOptions, Runtime};
+    use crate::scripted::{Command, ScriptedRunner};
+    use crate::terraform::TerraformAction;
+    use crate::testing::{infra, temp_dir};
+    use std::sync::Arc;
+
+    /// compute reads `vpc_id` only, never `revision`
+    const CHAIN: &str = "
+acc:
+  scope: account
+  vpc:
+    source: vpc
+  compute:
+    source: compute
+    dependencies: [vpc]
+    inputs:
+      vpc_id:
+        from: vpc.vpc_id
+";
+
+    /// Apply compute and its dependency, vpc reporting `revision`; the modules applied
+    async fn apply(state: &Path, revision: u64) -> (Vec<String>, Vec<ModuleStatus>) {
+        let outputs = [
+            ("vpc_id", Value::from("vpc-1")),
+            ("revision", Value::from(revision)),
+        ];
+        let runner = Arc::new(
+            ScriptedRunner::builder()
+                .outputs("acc.vpc", outputs)
+                .build(),
+        );
+        let options = RunOptions {
+            with_deps: true,
+            incremental_state: Some(state.to_path_buf()),
+            ..Default::default()
+        };
+        let runtime = Runtime::new(runner.clone(), &infra(CHAIN), options).unwrap();
+        runtime
+            .run_module("acc.compute", TerraformAction::Apply)
+            .await
+            .unwrap();
+        let statuses = runtime.module_runs().into_iter().map(|run| run.status);
+        (runner.finished_on(Command::Apply), statuses.collect())
+    }
+
+    #[tokio::test]
+    async fn changed_dependency_outputs_apply_dependents_again() {
+        let state = temp_dir("incremental").join("state.json");
+        let (applied, _) = apply(&state, 1).await;
+        assert_eq!(applied, ["acc.vpc", "acc.compute"]);
+
+        // Nothing changed: both are skipped
+        let (applied, statuses) = apply(&state, 1).await;
+        assert!(applied.is_empty(), "{applied:?}");
+        assert_eq!(statuses, [ModuleStatus::Unchanged, ModuleStatus::Unchanged]);
+
+        // vpc is still unchanged, but its outputs flipped: compute applies again
+        // although its own variables are the same
+        let (applied, _) = apply(&state, 2).await;
+        assert_eq!(applied, ["acc.compute"]);
+
+        let (applied, _) = apply(&state, 2).await;
+        assert!(applied.is_empty(), "{applied:?}");
+    }
+
+    #[test]
+    fn outputs_seen_first_are_not_dirty() {
+        let state = IncrementalState::load(&temp_dir("incremental-first").join("s")).unwrap();
+        let outputs = HashMap::from([("id".to_string(), Value::from(1))]);
+        state.record_outputs("acc.vpc", &outputs).unwrap();
+        assert!(state.dirty_among(["acc.vpc"]).is_empty());
+        state.record_outputs("acc.vpc", &outputs).unwrap();
+        assert!(state.dirty_among(["acc.vpc"]).is_empty());
+        let changed = HashMap::from([("id".to_string(), Value::from(2))]);
+        state.record_outputs("acc.vpc", &changed).unwrap();
+        assert_eq!(state.dirty_among(["acc.vpc", "acc.network"]), ["acc.vpc"]);
+    }
+
+    #[tokio::test]
+    async fn unchanged_module_is_skipped_while_its_changed_dependent_runs() {
+        let state = temp_dir("incremental-dependent").join("state.json");
+        let apply = |size: &str| {
+            let yaml = format!("{CHAIN}      size: {size}\n");
+            let state = state.clone();
+            async move {
+                let runner = Arc::new(
+                    ScriptedRunner::builder()
+                        .outputs("acc.vpc", [("vpc_id", Value::from("vpc-1"))])
+                        .build(),
+                );
+                let options = RunOptions {
+                    with_deps: true,
+                    incremental_state: Some(state),
+                    ..Default::default()
+                };
+                let runtime = Runtime::new(runner.clone(), &infra(&yaml), options).unwrap();
+                runtime
+                    .run_module("acc.compute", TerraformAction::Apply)
+                    .await
+                    .unwrap();
+                let statuses: Vec<ModuleStatus> = runtime
+                    .module_runs()
+                    .into_iter()
+                    .map(|run| run.status)
+                    .collect();
+                (runner.finished_on(Command::Apply), statuses)
+            }
+        };
+        apply("small").await;
+        let (applied, statuses) = apply("large").await;
+        assert_eq!(applied, ["acc.compute"]);
+        assert_eq!(statuses, [ModuleStatus::Unchanged, ModuleStatus::Succeeded]);
+    }
+
+    #[test]
+    fn fingerprints_follow_sources_and_variables() {
+        let variables = HashMap::from([
+            ("a".to_string(), Value::from(1)),
+            ("b".to_string(), Value::from("x")),
+        ]);
+        let base = fingerprint(Some("src-1"), &variables).unwrap();
+        assert_eq!(fingerprint(Some("src-1"), &variables).unwrap(), base);
+        assert_ne!(fingerprint(Some("src-2"), &variables).unwrap(), base);
+        assert_ne!(fingerprint(None, &variables).unwrap(), base);
+        let mut changed = variables.clone();
+        changed.insert("b".to_string(), Value::from("y"));
+        assert_ne!(fingerprint(Some("src-1"), &changed).unwrap(), base);
+    }
+}
@@ -0,0 +1,141 @@
+use anyhow::{Result, anyhow};
+use serde_yaml::Value;
+
+/// Replace `${VAR}`, `${VAR:-default}` and `$VAR` in every string of `value`,
+/// sequences and mapping values included, with the environment variable. `$$`
+/// is a literal `$`. An unset variable without a default is an error.
+pub fn interpolate_env(value: &mut Value) -> Result<()> {
+    match value {
+        Value::String(s) if s.contains('$') => *s = expand(s)?,
+        Value::Sequence(items) => {
+            for item in items {
+                interpolate_env(item)?;
+            }
+        }
+        Value::Mapping(map) => {
+            for (_, item) in map.iter_mut() {
+                interpolate_env(item)?;
+            }
+        }
+        Value::Tagged(tagged) => interpolate_env(&mut tagged.value)?,
+        _ => {}
+    }
+    Ok(())
+}
+
+fn is_name_start(c: char) -> bool {
+    c.is_ascii_alphabetic() || c == '_'
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+/// Expand the environment variable references of `s`
+pub fn expand(s: &str) -> Result<String> {
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(at) = rest.find('$') {
+        out.push_str(&rest[..at]);
+        let after = &rest[at + 1..];
+        if let Some(after) = after.strip_prefix('$') {
+            out.push('$');
+            rest = after;
+        } else if let Some(braced) = after.strip_prefix('{') {
+            let end = braced
+                .find('}')
+                .ok_or_else(|| anyhow!("Unclosed '${{' in '{}'", s))?;
+            let (name, default) = match braced[..end].split_once(":-") {
+                Some((name, default)) => (name, Some(default)),
+                None => (&braced[..end], None),
+            };
+            if !name.starts_with(is_name_start) || !name.chars().all(is_name_char) {
+                return Err(anyhow!(
+                    "Invalid environment variable name '{}' in '{}'",
+                    name,
+                    s
+                ));
+            }
+            out.push_str(&resolve(name, default)?);
+            rest = &braced[end + 1..];
+        } else if after.starts_with(is_name_start) {
+            let end = after.find(|c| !is_name_char(c)).unwrap_or(after.len());
+            out.push_str(&resolve(&after[..end], None)?);
+            rest = &after[end..];
+        } else {
+            // A `$` not starting a reference is kept
+            out.push('$');
+            rest = after;
+        }
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+fn resolve(name: &str, default: Option<&str>) -> Result<String> {
+    match (std::env::var(name).ok(), default) {
+        (Some(value), _) => Ok(value),
+        (None, Some(default)) => Ok(default.to_string()),
+        (None, None) => Err(anyhow!(
+            "Environment variable '{}' is not set (use '${{{}:-<default>}}' for a default)",
+            name,
+            name
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Set in every environment tests run in
+    fn path() -> String {
+        std::env::var("PATH").expect("PATH")
+    }
+
+    #[test]
+    fn defined_variables_are_replaced() {
+        assert_eq!(expand("${PATH}").unwrap(), path());
+        assert_eq!(expand("$PATH:/opt").unwrap(), format!("{}:/opt", path()));
+        assert_eq!(expand("${PATH:-unused}").unwrap(), path());
+    }
+
+    #[test]
+    fn unset_variables_take_their_default() {
+        assert_eq!(
+            expand("${LIGHTSTACKS_TEST_UNSET:-eu-west-1}").unwrap(),
+            "eu-west-1"
+        );
+        assert_eq!(expand("x${LIGHTSTACKS_TEST_UNSET:-}y").unwrap(), "xy");
+    }
+
+    #[test]
+    fn undefined_variables_are_an_error() {
+        let err = expand("region-$LIGHTSTACKS_TEST_UNSET").unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("'LIGHTSTACKS_TEST_UNSET' is not set"),
+            "{err}"
+        );
+        assert!(expand("${LIGHTSTACKS_TEST_UNSET").is_err());
+        assert!(expand("${1BAD}").is_err());
+    }
+
+    #[test]
+    fn dollars_not_starting_a_reference_are_kept() {
+        assert_eq!(expand("$$5").unwrap(), "$5");
+        assert_eq!(expand("cost: 5$ or $1").unwrap(), "cost: 5$ or $1");
+    }
+
+    #[test]
+    fn only_strings_are_expanded_recursively() {
+        let mut value: Value = serde_yaml::from_str(
+            "{list: ['${LIGHTSTACKS_TEST_UNSET:-a}', 1, true], nested: {key: '$$x'}}",
+        )
+        .unwrap();
+        interpolate_env(&mut value).unwrap();
+        let expected: Value =
+            serde_yaml::from_str("{list: [a, 1, true], nested: {key: '$x'}}").unwrap();
+        assert_eq!(value, expected);
+    }
+}
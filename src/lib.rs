@@ -0,0 +1,88 @@
+//! Orchestrate terraform modules declared in an infra file, layer by layer in
+//! dependency order.
+//!
+//! Load an [`InfraFile`], build a [`Runtime`] over it with any
+//! [`RunTerraformCommand`] (the `terraform` binary with
+//! [`terraform::TerraformRunner`], or a runner of your own implemented with
+//! `async_trait`) and run a [`TerraformAction`] on a module and its dependencies.
+//!
+//! ```no_run
+//! use lightstacks::terraform::TerraformRunner;
+//! use lightstacks::{InfraFile, RunOptions, Runtime, TerraformAction};
+//! use std::{path::Path, sync::Arc};
+//!
+//! # #[tokio::main]
+//! # async fn main() -> anyhow::Result<()> {
+//! let infra = InfraFile::from_path(
+//!     Path::new("deployments/infra_example.yaml"),
+//!     Path::new("modules"),
+//!     None,
+//!     true,
+//! )?;
+//! let runner = TerraformRunner::new("terraform".into(), ".tfstacks".into(), "modules".into());
+//! let runtime = Runtime::new(Arc::new(runner), &infra, RunOptions::default())?;
+//! let report = runtime
+//!     .run_module("account-1.tenant-c.webapp", TerraformAction::Plan)
+//!     .await?;
+//! println!("modules with changes: {:?}", report.changes);
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! To test code built on the library, [`scripted::ScriptedRunner`] runs nothing and
+//! answers as scripted, outputs being the `mocked_outputs` of a module unless
+//! scripted too:
+//!
+//! ```
+//! use lightstacks::scripted::{Command, ScriptedRunner};
+//! use lightstacks::{InfraFile, RunOptions, Runtime, TerraformAction};
+//! use std::{path::Path, sync::Arc};
+//!
+//! # #[tokio::main]
+//! # async fn main() -> anyhow::Result<()> {
+//! # let examples = Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/examples"));
+//! let infra = InfraFile::from_path(
+//!     &examples.join("deployments/infra_example.yaml"),
+//!     &examples.join("modules"),
+//!     None,
+//!     true,
+//! )?;
+//! let runner = Arc::new(
+//!     ScriptedRunner::builder()
+//!         .fail("account-1.compute", Command::Apply, "quota exceeded")
+//!         .build(),
+//! );
+//! let runtime = Runtime::new(runner.clone(), &infra, RunOptions::default())?;
+//! let err = runtime
+//!     .run_module("account-1.compute", TerraformAction::Apply)
+//!     .await
+//!     .unwrap_err();
+//! assert!(format!("{err:#}").contains("quota exceeded"), "{err:#}");
+//! assert_eq!(runner.calls("account-1.compute", Command::Apply), 1);
+//! assert_eq!(runner.calls("account-1.vpc", Command::Apply), 0);
+//! # Ok(())
+//! # }
+//! ```
+pub mod audit;
+pub mod diff;
+pub mod graph;
+pub mod incremental;
+mod interpolate;
+pub mod manifest;
+pub mod parser;
+pub mod policy;
+pub mod progress;
+pub mod resume;
+pub mod runtime;
+pub mod scripted;
+pub mod secrets;
+pub mod terraform;
+#[cfg(test)]
+mod testing;
+pub mod variables;
+pub mod version;
+
+pub use crate::graph::ModuleGraph;
+pub use crate::parser::{InfraFile, ModuleNode};
+pub use crate::runtime::{RunOptions, RunReport, Runtime};
+pub use crate::terraform::{PlanResult, RunTerraformCommand, TerraformAction};
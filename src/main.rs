@@ -1,21 +1,29 @@
 use anyhow::{Context, Result};
-use clap::{Parser, Subcommand};
+use clap::{Parser, Subcommand, ValueEnum};
 use colored::*;
-use parser::InfraFile;
-use std::path::PathBuf;
+use lightstacks::diff::GraphDiff;
+use lightstacks::policy::RuleFile;
+use lightstacks::runtime::{ModuleRun, ModuleStatus, TimingReport};
+use lightstacks::terraform::{
+    ChangeCounts, MockRunner, TerraformRunner, TerraformWarning, env_value, read_backend_template,
+};
+use lightstacks::{
+    InfraFile, ModuleGraph, RunOptions, RunReport, RunTerraformCommand, Runtime, TerraformAction,
+    policy, progress, variables,
+};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::io::IsTerminal;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-mod graph;
-mod parser;
-mod runtime;
-use crate::runtime::Runtime;
-use crate::terraform::{TerraformAction, TerraformRunner};
-mod terraform;
+use std::time::Duration;
 /// tfstacks CLI
 #[derive(Parser, Debug)]
 #[command(name = "tfstacks")]
 #[command(about = "Run Terraform modules with dependency management", long_about = None)]
 struct Cli {
-    /// Path to the infrastructure YAML file
+    /// Path to the infrastructure file, YAML or JSON (`.json`), `-` to read YAML from stdin
     #[arg(
         long,
         env = "TFSTACKS_INFRA_FILE",
@@ -23,9 +31,26 @@ struct Cli {
     )]
     infra_file: PathBuf,
 
+    /// Environment whose `variables_by_env` are merged into scope variables
+    #[arg(long, env = "TFSTACKS_ENVIRONMENT")]
+    environment: Option<String>,
+
+    /// Let a mapping variable or input replace its defaults as a whole, instead of
+    /// merging them key by key
+    #[arg(long)]
+    no_deep_merge: bool,
+
     /// Target module ID (e.g., "account-1.tenant-a.webapp")
     #[arg(long)]
-    module_id: String,
+    module_id: Option<String>,
+
+    /// Target every module carrying this tag
+    #[arg(long, conflicts_with = "module_id")]
+    tag: Option<String>,
+
+    /// Only target modules under this scope (with --tag, or alone for the whole scope)
+    #[arg(long, conflicts_with = "module_id")]
+    filter_scope: Option<String>,
 
     #[arg(
         long,
@@ -40,59 +65,978 @@ struct Cli {
     #[arg(long, env = "TFSTACKS_TF_BIN", default_value = "terraform")]
     bin_path: PathBuf,
 
+    /// First execution layer to run (1-based); earlier layers only have their outputs read
+    #[arg(long)]
+    from_layer: Option<usize>,
+
+    /// Last execution layer to run (1-based); the target module is the final layer
+    #[arg(long)]
+    to_layer: Option<usize>,
+
+    /// Run the action on every dependency too, not only read their outputs
+    #[arg(long)]
+    with_deps: bool,
+
+    /// Apply the target but only plan its dependencies, failing when one of them has
+    /// changes to apply
+    #[arg(long, conflicts_with_all = ["with_deps", "deps_only"])]
+    plan_only_deps: bool,
+
+    /// Let --plan-only-deps apply the target even when a dependency plan has changes
+    #[arg(long, requires = "plan_only_deps")]
+    ignore_dep_drift: bool,
+
+    /// Run every action one at a time (implies --with-deps); modules whose dependencies
+    /// need not run first are initialized concurrently up front
+    #[arg(long)]
+    serial_global_apply: bool,
+
+    /// Append a JSONL audit entry (inputs, outputs before/after) for every apply/destroy
+    #[arg(long, env = "TFSTACKS_AUDIT_LOG")]
+    audit_log: Option<PathBuf>,
+
+    /// Check each module's terraform version constraints (required_version, min_tf_version) before running
+    #[arg(long)]
+    check_versions: bool,
+
+    /// Run the action on every dependency of the target but not on the target itself
+    #[arg(long)]
+    deps_only: bool,
+
+    /// Run only the target, reading the outputs of its dependencies from the cache an
+    /// earlier run left in their module directories instead of running them
+    #[arg(
+        long,
+        requires = "module_id",
+        conflicts_with_all = [
+            "tag",
+            "filter_scope",
+            "with_deps",
+            "serial_global_apply",
+            "deps_only",
+            "from_layer",
+            "to_layer"
+        ]
+    )]
+    only: bool,
+
+    /// List every input of a module that does not resolve instead of failing on the first
+    #[arg(long)]
+    collect_errors: bool,
+
+    /// Print why each module landed in its execution layer before running
+    #[arg(long)]
+    explain_layers: bool,
+
+    /// JSON file of `{ "<module_id>": <terraform output -json> }` whose dependencies are not run again
+    #[arg(long)]
+    load_outputs: Option<PathBuf>,
+
+    /// Run dependencies even when their outputs are preloaded with --load-outputs
+    #[arg(long, requires = "load_outputs")]
+    force: bool,
+
+    /// Read the outputs dependencies cached in `<cache-dir>/<module_id>/outputs.json` by an
+    /// earlier invocation instead of initializing them again
+    #[arg(long, conflicts_with = "dry_run")]
+    use_cached_outputs: bool,
+
+    /// Use the `mocked_outputs` of a dependency whose outputs are empty or cannot be read,
+    /// e.g. when bootstrapping a new environment
+    #[arg(long, conflicts_with = "dry_run")]
+    allow_mock_fallback: bool,
+
+    /// Check injected variables against each module's variable declarations before running
+    #[arg(long)]
+    check_variables: bool,
+
+    /// Like --check-variables, but only warn about injected inputs the module does not declare
+    #[arg(long, conflicts_with = "check_variables")]
+    check_inputs: bool,
+
+    /// Init and `terraform validate` every module the action runs on before running any of them
+    #[arg(long)]
+    validate_modules: bool,
+
+    /// With --validate-modules, also fail on modules `terraform fmt -check -recursive` rejects
+    #[arg(long, requires = "validate_modules")]
+    check_fmt: bool,
+
+    /// Resource address the plan or apply of the target is limited to (terraform `-target`),
+    /// can be repeated; dependencies are never limited
+    #[arg(
+        long = "tf-target",
+        value_name = "ADDRESS",
+        requires = "module_id",
+        conflicts_with_all = ["tag", "filter_scope"]
+    )]
+    tf_targets: Vec<String>,
+
+    /// Terraform variable given to every module that does not set it, can be repeated
+    #[arg(long = "var", value_name = "KEY=VALUE")]
+    vars: Vec<String>,
+
+    /// YAML or JSON mapping of variables given to every module that does not set them,
+    /// can be repeated (later files win, --var wins over files)
+    #[arg(long = "var-file", value_name = "FILE")]
+    var_files: Vec<PathBuf>,
+
+    /// Set a variable of the scope with that id over the infrastructure file, e.g.
+    /// `account-1:region=eu-west-3`, can be repeated
+    #[arg(long = "scope-var", value_name = "SCOPE_ID:KEY=VALUE")]
+    scope_vars: Vec<String>,
+
+    /// Most modules running terraform at once [default: number of CPUs]
+    #[arg(long, env = "TFSTACKS_CONCURRENCY")]
+    concurrency: Option<NonZeroUsize>,
+
+    /// Skip applying modules whose source and variables did not change since their last
+    /// apply, unless the outputs of one of their dependencies changed
+    #[arg(long)]
+    incremental: bool,
+
+    /// Apply again after a failure, skipping the modules the failed apply of the same
+    /// targets completed and reading their outputs instead. Every failed apply records
+    /// them in the cache dir for this, with or without --resume
+    #[arg(long, conflicts_with = "dry_run")]
+    resume: bool,
+
+    /// Let terraform ask on the terminal to approve the apply or destroy of the target
+    #[arg(long)]
+    no_auto_approve: bool,
+
+    /// With --no-auto-approve, ask to approve the apply or destroy of every dependency too
+    #[arg(long, requires = "no_auto_approve")]
+    confirm_deps: bool,
+
+    /// Let every module of a layer finish when one of them fails, instead of cancelling the others
+    #[arg(long)]
+    keep_going: bool,
+
+    /// Retry a failed terraform init, plan or apply up to N times
+    #[arg(long, default_value_t = 0)]
+    retries: u32,
+
+    /// Delay before the first retry in milliseconds, doubled (with jitter) for each next one
+    #[arg(long, value_name = "MS", default_value_t = 1000)]
+    retry_backoff: u64,
+
+    /// Kill any terraform command running longer than this many seconds
+    #[arg(long, value_name = "SECONDS")]
+    timeout: Option<u64>,
+
+    /// Terraform workspace selected (created when missing) after init; module directories
+    /// go to `<cache-dir>/<workspace>/<module_id>`
+    #[arg(long, env = "TFSTACKS_WORKSPACE")]
+    workspace: Option<String>,
+
+    /// Terraform JSON file written to every module dir as `backend_override.tf.json`, with
+    /// `${module_id}` and `${workspace}` replaced in its strings, e.g. to derive state keys
+    #[arg(long, env = "TFSTACKS_BACKEND_TEMPLATE", conflicts_with = "dry_run")]
+    backend_template: Option<PathBuf>,
+
+    /// Let terraform plan, apply and destroy write to the terminal directly, e.g. to answer
+    /// its prompts, instead of prefixing every line with the module id
+    #[arg(long, conflicts_with = "dry_run")]
+    raw_output: bool,
+
+    /// Run terraform with -chdir=<module dir> instead of in the module dir, for wrappers
+    /// (tfenv, asdf shims) that pick the terraform version from their working directory
+    #[arg(long, conflicts_with = "dry_run")]
+    use_chdir: bool,
+
+    /// Copy module sources into the cache dir even when they did not change
+    #[arg(long)]
+    force_copy: bool,
+
+    /// Pass -compact-warnings to terraform plan/apply/destroy
+    #[arg(long)]
+    compact_warnings: bool,
+
+    /// Resources terraform plan, apply and destroy operate on at once in each module
+    /// (terraform `-parallelism`), independent of --concurrency
+    #[arg(long, value_name = "N")]
+    tf_parallelism: Option<NonZeroUsize>,
+
+    /// Run the whole orchestration without terraform; dependency outputs come from `mocked_outputs`
+    #[arg(long, conflicts_with_all = ["audit_log", "incremental"])]
+    dry_run: bool,
+
+    /// Output of plan, apply, destroy and refresh: `json` prints one document with the status of
+    /// every module on stdout, progress and terraform output go to stderr
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    format: OutputFormat,
+
+    /// Print no color codes, like NO_COLOR; colors are also off on a stream that is not a
+    /// terminal, unless CLICOLOR_FORCE is set: stdout, or stderr for errors and for the
+    /// progress of --format json
+    #[arg(long)]
+    no_color: bool,
+
     /// Terraform subcommand
     #[command(subcommand)]
     action: Actions,
 }
 
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum OutputFormat {
+    Text,
+    Json,
+}
+
+/// `--format json` document of a plan, apply, destroy or refresh run
+#[derive(Serialize)]
+struct RunDocument<'a> {
+    action: &'static str,
+    success: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    /// In execution order
+    modules: Vec<ModuleRun>,
+    /// Modules whose plan has changes, plan only
+    #[serde(skip_serializing_if = "Option::is_none")]
+    changes: Option<&'a [String]>,
+    /// Resource actions of the plans with changes, by module, plan only
+    #[serde(skip_serializing_if = "Option::is_none")]
+    change_counts: Option<&'a BTreeMap<String, ChangeCounts>>,
+    warnings: &'a [TerraformWarning],
+    timings: TimingReport,
+}
+
 #[derive(Subcommand, Debug)]
 enum Actions {
     /// Plan the module
-    Plan,
+    Plan {
+        /// Directory where the plan is saved as `<module_id>.tfplan`
+        #[arg(long)]
+        plan_out: Option<PathBuf>,
+        /// Directory where every planned module gets `<module_id>.tfplan` and its
+        /// `terraform show -json` rendering `<module_id>.json`
+        #[arg(long)]
+        plan_out_dir: Option<PathBuf>,
+        /// Exit with status 2 when a plan has changes
+        #[arg(long)]
+        exit_on_diff: bool,
+    },
     /// Apply the module
-    Apply,
+    Apply {
+        /// Apply exactly this saved plan file (produced by `plan --plan-out`)
+        #[arg(long)]
+        plan_in: Option<PathBuf>,
+    },
     /// Destroy the module
     Destroy,
+    /// Update the module state from the real resources (`apply -refresh-only`) without changing them
+    Refresh {
+        /// Refresh every dependency too, not only read their outputs (same as --with-deps)
+        #[arg(long)]
+        refresh_deps: bool,
+    },
+    /// Print the resolved variables of every module of the closure as JSON, without running terraform
+    RenderVars {
+        /// Render every module under this scope instead of a single --module-id
+        #[arg(long)]
+        target_scope: Option<String>,
+    },
+    /// Print the resolved variables of --module-id as JSON, each with where its value came
+    /// from, without running terraform
+    Inspect,
+    /// Check the infrastructure file and every input reference without running terraform
+    Validate {
+        /// Warn about the modules the selected targets never depend on, possibly dead config
+        #[arg(long)]
+        report_orphans: bool,
+    },
+    /// Write the module graph as versioned JSON (nodes and dependency edges)
+    ExportGraph {
+        /// Output file, stdout when omitted
+        #[arg(long)]
+        out: Option<PathBuf>,
+    },
+    /// Print the module dependency graph as Graphviz DOT, clustered by scope
+    Graph {
+        /// Output file, stdout when omitted
+        #[arg(long)]
+        output: Option<PathBuf>,
+        /// Color this module and every module it depends on
+        #[arg(long)]
+        highlight: Option<String>,
+    },
+    /// Print every scope and module id as a tree, with module sources and dependencies
+    List {
+        /// Only list this scope or module id and what is below it
+        #[arg(long)]
+        scope: Option<String>,
+    },
+    /// Print the outputs of --module-id, `key = value` lines or JSON with --format json
+    Output,
+    /// Inspect the terraform state of --module-id
+    State {
+        /// Print the address of every resource the state holds (`terraform state list`)
+        #[arg(long, required = true)]
+        list: bool,
+    },
+    /// Check organizational rules against the resolved graph
+    Assert {
+        /// YAML rule file, can be repeated
+        #[arg(long = "rule", required = true)]
+        rules: Vec<PathBuf>,
+    },
+    /// Compare the resolved module graphs of two infrastructure files
+    Diff {
+        /// Infrastructure file before the change
+        #[arg(long)]
+        base: PathBuf,
+        /// Infrastructure file after the change
+        #[arg(long)]
+        head: PathBuf,
+    },
 }
 
 #[tokio::main]
 async fn main() {
     if let Err(err) = main_wrapper().await {
+        // Errors go to stderr, which may be piped while stdout is not
+        if !std::io::stderr().is_terminal() && std::env::var_os("CLICOLOR_FORCE").is_none() {
+            colored::control::set_override(false);
+        }
         print_error(&err);
         std::process::exit(1);
-    } else {
-        println!(
-            "{}",
-            "✔ Success: module executed successfully".green().bold()
-        );
     }
 }
 
 async fn main_wrapper() -> Result<()> {
     let cli = Cli::parse();
+    // stdout only carries the data then, progress goes to stderr
+    if cli.format == OutputFormat::Json
+        || matches!(cli.action, Actions::State { list: true } | Actions::Output)
+    {
+        progress::use_stderr();
+    }
+    configure_color(cli.no_color);
+
+    if let Actions::Diff { base, head } = &cli.action {
+        return diff_infra_files(
+            base,
+            head,
+            &cli.modules_dir,
+            cli.environment.as_deref(),
+            !cli.no_deep_merge,
+        );
+    }
 
     // Load InfraFile from YAML
-    let infra =
-        InfraFile::from_path(&cli.infra_file).context("while parsing infrastructure YAML file")?;
+    let mut infra = InfraFile::from_path(
+        &cli.infra_file,
+        &cli.modules_dir,
+        cli.environment.as_deref(),
+        !cli.no_deep_merge,
+    )
+    .context("while parsing infrastructure YAML file")?;
+    for spec in &cli.scope_vars {
+        let (scope_id, key, value) = variables::parse_scope_variable(spec)?;
+        infra.set_scope_variable(&scope_id, &key, value)?;
+    }
     //dbg!(&infra);
+    // Workspace names become a directory of the cache dir
+    if let Some(workspace) = &cli.workspace
+        && (workspace.is_empty()
+            || !workspace
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'))
+    {
+        anyhow::bail!(
+            "Invalid workspace '{}': only letters, digits, '-' and '_' are allowed",
+            workspace
+        );
+    }
+    let workspace_dir = match &cli.workspace {
+        Some(workspace) => cli.cache_dir.join(workspace),
+        None => cli.cache_dir.clone(),
+    };
+    let runner = build_runner(&cli)?;
+    if let Actions::State { list: true } = cli.action {
+        let Some(module_id) = &cli.module_id else {
+            anyhow::bail!("state needs --module-id");
+        };
+        let runtime = Runtime::new(runner, &infra, RunOptions::default())?;
+        for address in runtime.state_list(module_id).await? {
+            println!("{}", address);
+        }
+        return Ok(());
+    }
+    if let Actions::Output = cli.action {
+        let Some(module_id) = &cli.module_id else {
+            anyhow::bail!("output needs --module-id");
+        };
+        let options = RunOptions {
+            use_cached_outputs: cli.use_cached_outputs,
+            ..Default::default()
+        };
+        let runtime = Runtime::new(runner, &infra, options)?;
+        let outputs = runtime.module_outputs(module_id).await?;
+        return print_outputs(&outputs, cli.format);
+    }
+    // A failed apply records what it completed for `--resume`
+    let run_state_dir = (!cli.dry_run && matches!(cli.action, Actions::Apply { .. }))
+        .then(|| workspace_dir.clone());
     // Map CLI action to TerraformAction
+    let mut options = RunOptions {
+        from_layer: cli.from_layer,
+        to_layer: cli.to_layer,
+        with_deps: cli.with_deps,
+        plan_only_deps: cli.plan_only_deps,
+        ignore_dep_drift: cli.ignore_dep_drift,
+        serial_global_apply: cli.serial_global_apply,
+        audit_log: cli.audit_log,
+        check_versions: cli.check_versions,
+        check_variables: cli.check_variables,
+        check_inputs: cli.check_inputs,
+        validate_modules: cli.validate_modules,
+        check_fmt: cli.check_fmt,
+        deps_only: cli.deps_only,
+        only: cli.only,
+        explain_layers: cli.explain_layers,
+        collect_errors: cli.collect_errors,
+        load_outputs: cli.load_outputs,
+        force: cli.force,
+        use_cached_outputs: cli.use_cached_outputs,
+        allow_mock_fallback: cli.allow_mock_fallback,
+        tf_targets: cli.tf_targets,
+        keep_going: cli.keep_going,
+        no_auto_approve: cli.no_auto_approve,
+        confirm_deps: cli.confirm_deps,
+        concurrency: cli.concurrency.map(NonZeroUsize::get),
+        global_variables: variables::read_global_variables(&cli.var_files, &cli.vars)?,
+        incremental_state: cli
+            .incremental
+            .then(|| workspace_dir.join(".tfstacks-incremental.json")),
+        infra_digest: run_state_dir.as_ref().and(infra.digest.clone()),
+        run_state_dir,
+        resume: cli.resume,
+        ..Default::default()
+    };
+    if cli.resume && !matches!(cli.action, Actions::Apply { .. }) {
+        anyhow::bail!("--resume only applies to apply");
+    }
+    if cli.incremental && !matches!(cli.action, Actions::Apply { .. }) {
+        anyhow::bail!("--incremental only applies to apply");
+    }
+    if !options.tf_targets.is_empty()
+        && !matches!(
+            cli.action,
+            Actions::Plan { .. } | Actions::Apply { plan_in: None }
+        )
+    {
+        anyhow::bail!("--tf-target only applies to plan and to apply without --plan-in");
+    }
+    if cli.use_cached_outputs && matches!(cli.action, Actions::Destroy) {
+        anyhow::bail!(
+            "--use-cached-outputs does not apply to destroy, which prepares every module"
+        );
+    }
+    let mut exit_on_diff = false;
     let action = match cli.action {
-        Actions::Plan => TerraformAction::Plan,
-        Actions::Apply => TerraformAction::Apply,
+        Actions::Plan {
+            plan_out: Some(_), ..
+        }
+        | Actions::Plan {
+            plan_out_dir: Some(_),
+            ..
+        }
+        | Actions::Apply { plan_in: Some(_) }
+            if cli.dry_run =>
+        {
+            anyhow::bail!("--dry-run cannot save or apply plan files");
+        }
+        Actions::Plan {
+            plan_out,
+            plan_out_dir,
+            exit_on_diff: on_diff,
+        } => {
+            options.plan_out = plan_out;
+            options.plan_out_dir = plan_out_dir;
+            exit_on_diff = on_diff;
+            TerraformAction::Plan
+        }
+        Actions::Apply { plan_in } => {
+            options.plan_in = plan_in;
+            TerraformAction::Apply
+        }
         Actions::Destroy => TerraformAction::Destroy,
+        Actions::Refresh { refresh_deps } => {
+            options.with_deps |= refresh_deps;
+            TerraformAction::Refresh
+        }
+        Actions::RenderVars { target_scope } => {
+            return render_vars(
+                &infra,
+                cli.module_id.as_deref(),
+                target_scope.as_deref(),
+                options.global_variables,
+            )
+            .await;
+        }
+        Actions::Inspect => {
+            let Some(module_id) = cli.module_id else {
+                anyhow::bail!("inspect needs --module-id");
+            };
+            // Cached outputs are read from the module directories, nothing is run
+            let runner: Arc<dyn RunTerraformCommand + Send + Sync> = if cli.use_cached_outputs {
+                let mut runner = TerraformRunner::new(cli.bin_path, cli.cache_dir, cli.modules_dir);
+                runner.workspace = cli.workspace;
+                Arc::new(runner)
+            } else {
+                Arc::new(MockRunner::default())
+            };
+            return inspect(&infra, &module_id, runner, options).await;
+        }
+        Actions::Validate { report_orphans } => {
+            return validate(
+                &infra,
+                cli.module_id.as_deref(),
+                cli.tag.as_deref(),
+                cli.filter_scope.as_deref(),
+                report_orphans,
+            );
+        }
+        Actions::ExportGraph { out } => return export_graph(&infra, out.as_deref()),
+        Actions::Graph { output, highlight } => {
+            return print_dot(&infra, output.as_deref(), highlight.as_deref());
+        }
+        Actions::List { scope } => return list_ids(&infra, scope.as_deref()),
+        Actions::Assert { rules } => return assert_rules(&infra, &rules),
+        Actions::Diff { .. } => unreachable!("diff is handled before loading --infra-file"),
+        Actions::State { .. } => unreachable!("state is handled before the run actions"),
+        Actions::Output => unreachable!("output is handled before the run actions"),
     };
 
-    // Create TerraformRunner (actual or mock)
-    let runner = TerraformRunner::new(cli.bin_path, cli.cache_dir, cli.modules_dir);
+    if cli.format == OutputFormat::Json && (cli.raw_output || cli.no_auto_approve) {
+        anyhow::bail!(
+            "--raw-output and --no-auto-approve would mix terraform output into the JSON of --format json"
+        );
+    }
 
-    // Wrap in Arc to allow sharing across async tasks
-    let runtime = Runtime::new(Arc::new(runner), &infra)?;
-    // Run the target module by module ID
-    runtime.run_module(&cli.module_id, action).await?;
+    let runtime = Runtime::new(runner, &infra, options)?;
+    let result = match cli.module_id {
+        // Run the target module by module ID
+        Some(module_id) => runtime.run_module(&module_id, action).await,
+        None if cli.tag.is_some() || cli.filter_scope.is_some() => {
+            let targets = runtime
+                .graph
+                .select_modules(cli.tag.as_deref(), cli.filter_scope.as_deref())?;
+            runtime.run_modules(&targets, action).await
+        }
+        None => anyhow::bail!(
+            "--module-id, --tag or --filter-scope is required to plan, apply, destroy or refresh"
+        ),
+    };
+    if cli.format == OutputFormat::Json {
+        return print_run_document(&runtime, action, result, exit_on_diff);
+    }
+    let report = result?;
+    print_warnings(&report.warnings);
+    print_timings(&runtime.timings());
+
+    println!(
+        "{}",
+        "✔ Success: module executed successfully".green().bold()
+    );
+    if runtime.incremental.is_some() {
+        print_incremental(&runtime.module_runs());
+    }
+    if matches!(action, TerraformAction::Plan) {
+        print_changes(&report.changes, &report.change_counts);
+        if exit_on_diff && !report.changes.is_empty() {
+            std::process::exit(2);
+        }
+    }
+    Ok(())
+}
+
+/// Create the TerraformRunner (actual or mock), wrapped in Arc to allow sharing across async tasks
+fn build_runner(cli: &Cli) -> Result<Arc<dyn RunTerraformCommand + Send + Sync>> {
+    let runner: Arc<dyn RunTerraformCommand + Send + Sync> = if cli.dry_run {
+        Arc::new(MockRunner {
+            workspace: cli.workspace.clone(),
+            ..Default::default()
+        })
+    } else {
+        let mut runner = TerraformRunner::new(
+            cli.bin_path.clone(),
+            cli.cache_dir.clone(),
+            cli.modules_dir.clone(),
+        );
+        runner.compact_warnings = cli.compact_warnings;
+        runner.parallelism = cli.tf_parallelism;
+        runner.retries = cli.retries;
+        runner.retry_backoff = Duration::from_millis(cli.retry_backoff);
+        runner.timeout = cli.timeout.map(Duration::from_secs);
+        runner.force_copy = cli.force_copy;
+        runner.workspace = cli.workspace.clone();
+        runner.raw_output = cli.raw_output;
+        runner.use_chdir = cli.use_chdir;
+        runner.no_color = !colored::control::SHOULD_COLORIZE.should_colorize();
+        runner.backend_template = cli
+            .backend_template
+            .as_deref()
+            .map(read_backend_template)
+            .transpose()?;
+        Arc::new(runner)
+    };
+    Ok(runner)
+}
+
+/// Print the `--format json` document of the run, failed or not; the error is then
+/// reported as usual
+fn print_run_document(
+    runtime: &Runtime,
+    action: TerraformAction,
+    result: Result<RunReport>,
+    exit_on_diff: bool,
+) -> Result<()> {
+    let (report, error) = match result {
+        Ok(report) => (report, None),
+        Err(err) => {
+            let report = RunReport {
+                warnings: runtime.runner.take_warnings(),
+                ..Default::default()
+            };
+            (report, Some(err))
+        }
+    };
+    let document = RunDocument {
+        action: action.name(),
+        success: error.is_none(),
+        error: error.as_ref().map(|err| format!("{:#}", err)),
+        modules: runtime.module_runs(),
+        changes: matches!(action, TerraformAction::Plan).then_some(report.changes.as_slice()),
+        change_counts: matches!(action, TerraformAction::Plan).then_some(&report.change_counts),
+        warnings: &report.warnings,
+        timings: runtime.timings(),
+    };
+    println!("{}", serde_json::to_string_pretty(&document)?);
+    if let Some(err) = error {
+        return Err(err);
+    }
+    if exit_on_diff && !report.changes.is_empty() {
+        std::process::exit(2);
+    }
+    Ok(())
+}
+
+/// Print how long each module spent in init, its action and output, then each layer
+fn print_timings(timings: &TimingReport) {
+    if timings.modules.is_empty() {
+        return;
+    }
+    let seconds = |ms: Option<u64>| match ms {
+        Some(ms) => format!("{:.1}s", ms as f64 / 1000.0),
+        None => "-".to_string(),
+    };
+    let width = timings
+        .modules
+        .iter()
+        .map(|t| t.id.len())
+        .max()
+        .unwrap_or(0);
+    println!("{}", "Timings:".bold());
+    println!(
+        "  {:<5}  {:<width$}  {:>7}  {:>7}  {:>7}  {:>7}",
+        "layer", "module", "init", "action", "output", "total"
+    );
+    for t in &timings.modules {
+        println!(
+            "  {:<5}  {:<width$}  {:>7}  {:>7}  {:>7}  {:>7}",
+            t.layer,
+            t.id,
+            seconds(t.init_ms),
+            seconds(t.action_ms),
+            seconds(t.output_ms),
+            seconds(Some(t.total_ms()))
+        );
+    }
+    let layers: Vec<String> = timings
+        .layers
+        .iter()
+        .map(|l| format!("layer {} {}", l.layer, seconds(Some(l.duration_ms))))
+        .collect();
+    println!("  {}", layers.join(", "));
+}
+
+/// Print whether the plans found drift, and in which modules with their resource
+/// actions as `+create ~update -delete`, `-/+replace` when there are some
+fn print_changes(changes: &[String], counts: &BTreeMap<String, ChangeCounts>) {
+    if changes.is_empty() {
+        println!("No changes: the infrastructure matches the configuration");
+    } else {
+        println!(
+            "{}",
+            format!("Changes present in {} module(s):", changes.len())
+                .yellow()
+                .bold()
+        );
+        for id in changes {
+            match counts.get(id) {
+                Some(counts) => println!("  - {}  {}", id, format_change_counts(counts)),
+                None => println!("  - {}", id),
+            }
+        }
+    }
+}
+
+/// Outputs as `name = value` lines, complex values as compact JSON, or as one JSON object
+fn print_outputs(
+    outputs: &BTreeMap<String, serde_yaml::Value>,
+    format: OutputFormat,
+) -> Result<()> {
+    match format {
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(outputs)?),
+        OutputFormat::Text => {
+            for (name, value) in outputs {
+                println!("{} = {}", name, env_value(value));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// List the modules `--incremental` applied and the ones it skipped as unchanged
+fn print_incremental(runs: &[ModuleRun]) {
+    let with_status = |status: ModuleStatus| -> Vec<&str> {
+        runs.iter()
+            .filter(|run| run.status == status)
+            .map(|run| run.id.as_str())
+            .collect()
+    };
+    let applied = with_status(ModuleStatus::Succeeded);
+    let unchanged = with_status(ModuleStatus::Unchanged);
+    println!(
+        "Applied {} module(s), {} unchanged module(s) skipped",
+        applied.len(),
+        unchanged.len()
+    );
+    for id in applied {
+        println!("  {} {}", "applied".green(), id);
+    }
+    for id in unchanged {
+        println!("  {} {}", "unchanged".dimmed(), id);
+    }
+}
+
+/// `+3 ~1 -0`, with ` -/+2` for replacements
+fn format_change_counts(counts: &ChangeCounts) -> String {
+    let mut out = format!(
+        "{} {} {}",
+        format!("+{}", counts.create).green(),
+        format!("~{}", counts.update).yellow(),
+        format!("-{}", counts.delete).red()
+    );
+    if counts.replace > 0 {
+        out.push_str(&format!(" {}", format!("-/+{}", counts.replace).magenta()));
+    }
+    out
+}
+
+/// Print the resolved variables of a module with where each value came from
+async fn inspect(
+    infra: &InfraFile,
+    module_id: &str,
+    runner: Arc<dyn RunTerraformCommand + Send + Sync>,
+    options: RunOptions,
+) -> Result<()> {
+    let options = RunOptions {
+        global_variables: options.global_variables,
+        use_cached_outputs: options.use_cached_outputs,
+        ..Default::default()
+    };
+    let runtime = Runtime::new(runner, infra, options)?;
+    let variables = runtime.inspect_module(module_id).await?;
+    println!("{}", serde_json::to_string_pretty(&variables)?);
+    Ok(())
+}
 
+/// Print the resolved variables of the selected modules and their dependencies
+async fn render_vars(
+    infra: &InfraFile,
+    module_id: Option<&str>,
+    target_scope: Option<&str>,
+    global_variables: HashMap<String, serde_yaml::Value>,
+) -> Result<()> {
+    // Dependency outputs come from `mocked_outputs`, nothing is run
+    let options = RunOptions {
+        global_variables,
+        ..Default::default()
+    };
+    let runtime = Runtime::new(Arc::new(MockRunner::default()), infra, options)?;
+    let targets = match (module_id, target_scope) {
+        (Some(id), None) => vec![id.to_string()],
+        (None, Some(scope)) => runtime.graph.select_modules(None, Some(scope))?,
+        _ => anyhow::bail!("render-vars needs exactly one of --module-id or --target-scope"),
+    };
+    let manifest = runtime.render_variables(&targets).await?;
+    println!("{}", serde_json::to_string_pretty(&manifest)?);
+    Ok(())
+}
+
+/// Validate the closure of the selected modules, or of every module when none is selected
+fn validate(
+    infra: &InfraFile,
+    module_id: Option<&str>,
+    tag: Option<&str>,
+    scope: Option<&str>,
+    report_orphans: bool,
+) -> Result<()> {
+    // With every module as a target, nothing could be an orphan
+    if report_orphans && module_id.is_none() && tag.is_none() && scope.is_none() {
+        anyhow::bail!("--report-orphans needs entry targets: --module-id, --tag or --filter-scope");
+    }
+    // Nothing is run and no module directory is prepared
+    let runtime = Runtime::new(
+        Arc::new(MockRunner::default()),
+        infra,
+        RunOptions::default(),
+    )?;
+    let targets = match module_id {
+        Some(id) => vec![id.to_string()],
+        None => runtime.graph.select_modules(tag, scope)?,
+    };
+    let problems = runtime.validate(&targets)?;
+    if report_orphans {
+        for orphan in runtime.graph.orphans(&targets)? {
+            println!(
+                "{} orphan module '{}': no target depends on it",
+                "⚠".yellow().bold(),
+                orphan
+            );
+        }
+    }
+    if problems.is_empty() {
+        println!(
+            "{}",
+            format!(
+                "✔ {} target(s) and their dependencies are valid",
+                targets.len()
+            )
+            .green()
+            .bold()
+        );
+        return Ok(());
+    }
+    for problem in &problems {
+        println!("{} {}", "✘".red().bold(), problem);
+    }
+    anyhow::bail!("{} validation error(s)", problems.len())
+}
+
+/// Write the stable graph export of the whole infrastructure
+fn export_graph(infra: &InfraFile, out: Option<&Path>) -> Result<()> {
+    let graph = ModuleGraph::new(infra).context("While building dependency graph")?;
+    let mut json = serde_json::to_string_pretty(&graph.export())?;
+    json.push('\n');
+    match out {
+        Some(path) => std::fs::write(path, json)
+            .with_context(|| format!("Failed to write graph export {:?}", path))?,
+        None => print!("{}", json),
+    }
+    Ok(())
+}
+
+/// Write the graph as DOT, e.g. for `dot -Tpng`
+fn print_dot(infra: &InfraFile, out: Option<&Path>, highlight: Option<&str>) -> Result<()> {
+    let graph = ModuleGraph::new(infra).context("While building dependency graph")?;
+    let dot = graph.to_dot(highlight)?;
+    match out {
+        Some(path) => {
+            std::fs::write(path, dot).with_context(|| format!("Failed to write {:?}", path))?
+        }
+        None => print!("{}", dot),
+    }
+    Ok(())
+}
+
+fn list_ids(infra: &InfraFile, scope: Option<&str>) -> Result<()> {
+    let graph = ModuleGraph::new(infra).context("While building dependency graph")?;
+    let tree = graph.to_tree(scope);
+    if tree.is_empty() {
+        anyhow::bail!(
+            "No scope or module id matches '{}'",
+            scope.unwrap_or_default()
+        );
+    }
+    print!("{}", tree);
+    Ok(())
+}
+
+/// Evaluate rule files against the graph, failing on any violation
+fn assert_rules(infra: &InfraFile, paths: &[PathBuf]) -> Result<()> {
+    let graph = ModuleGraph::new(infra).context("While building dependency graph")?;
+    let mut rules = Vec::new();
+    for path in paths {
+        rules.extend(RuleFile::from_path(path)?.rules);
+    }
+    let violations = policy::evaluate(&graph, &rules)?;
+    if violations.is_empty() {
+        println!(
+            "{}",
+            format!("✔ {} rule(s) passed", rules.len()).green().bold()
+        );
+        return Ok(());
+    }
+    for violation in &violations {
+        println!("{} {}", "✘".red().bold(), violation);
+    }
+    anyhow::bail!("{} policy violation(s)", violations.len())
+}
+
+/// Print the structural changes between two infrastructure files
+fn diff_infra_files(
+    base: &Path,
+    head: &Path,
+    modules_dir: &Path,
+    environment: Option<&str>,
+    deep_merge: bool,
+) -> Result<()> {
+    let load = |path: &Path| -> Result<ModuleGraph> {
+        let infra = InfraFile::from_path(path, modules_dir, environment, deep_merge)
+            .with_context(|| format!("while parsing infrastructure YAML file {:?}", path))?;
+        ModuleGraph::new(&infra).with_context(|| format!("while building the graph of {:?}", path))
+    };
+    let diff = GraphDiff::new(load(base)?, load(head)?);
+    print!("{}", diff);
     Ok(())
 }
 
+/// Print the terraform warnings collected during a run, grouped after all output
+fn print_warnings(warnings: &[TerraformWarning]) {
+    if warnings.is_empty() {
+        return;
+    }
+    println!(
+        "{}",
+        format!("⚠ {} terraform warning(s):", warnings.len())
+            .yellow()
+            .bold()
+    );
+    for warning in warnings {
+        println!("  {}: {}", warning.module_id.bold(), warning.message);
+    }
+}
+
+/// Turn colors off with `--no-color`, or when the stream progress goes to is piped:
+/// `colored` only checks stdout on its own, while progress goes to stderr under
+/// `--format json`
+fn configure_color(no_color: bool) {
+    let terminal = match progress::on_stderr() {
+        true => std::io::stderr().is_terminal(),
+        false => std::io::stdout().is_terminal(),
+    };
+    // Like `colored`, CLICOLOR_FORCE wins over NO_COLOR
+    let forced = std::env::var_os("CLICOLOR_FORCE").is_some();
+    let colors = forced || (terminal && std::env::var_os("NO_COLOR").is_none());
+    colored::control::set_override(!no_color && colors);
+}
+
 /// Prints an anyhow::Error with color and cause chain (Terraform-style)
 fn print_error(context: &anyhow::Error) {
     eprintln!("{} {}:", "Error".red().bold(), context.to_string().bold());
@@ -117,3 +1061,25 @@ pub fn print_error_cause(msg: &str) {
         eprintln!("    {}", line);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn change_counts_read_as_a_short_summary() {
+        colored::control::set_override(false);
+        let mut counts = ChangeCounts {
+            create: 3,
+            update: 1,
+            delete: 0,
+            replace: 0,
+        };
+        assert_eq!(format_change_counts(&counts), "+3 ~1 -0");
+        counts.replace = 2;
+        assert_eq!(format_change_counts(&counts), "+3 ~1 -0 -/+2");
+        let json = include_bytes!("../tests/fixtures/plan_changes.json");
+        let counts = ChangeCounts::from_plan_json(json).unwrap();
+        assert_eq!(format_change_counts(&counts), "+2 ~1 -1 -/+2");
+    }
+}
@@ -0,0 +1,162 @@
+use crate::parser::ModuleNode;
+use anyhow::{Context, Result, anyhow};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use serde_yaml::Value;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Environment variable holding the key plan manifests are signed with
+pub const SIGNING_KEY_ENV: &str = "TFSTACKS_PLAN_SIGNING_KEY";
+
+const MANIFEST_VERSION: u32 = 1;
+
+/// Reviewable record of a saved plan, written next to it as `<plan>.manifest.json`.
+///
+/// `plan_sha256` is the hex SHA-256 of the plan file bytes. When signed,
+/// `signature` is the hex HMAC-SHA256 of the manifest serialized as compact
+/// JSON without the `signature` field, fields in declaration order and
+/// `inputs` sorted by name.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PlanManifest {
+    pub manifest_version: u32,
+    pub module_id: String,
+    /// `<unix seconds>-<pid>` of the run that made the plan
+    pub run_id: String,
+    /// File name of the plan, relative to the manifest
+    pub plan_file: String,
+    pub plan_sha256: String,
+    /// Resolved inputs the plan was made with, sensitive values redacted
+    pub inputs: BTreeMap<String, Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
+}
+
+impl PlanManifest {
+    /// Build the manifest of a freshly saved plan, signed when a key is given
+    pub fn new(plan_file: &Path, module: &ModuleNode, key: Option<&str>) -> Result<Self> {
+        let secs = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        let mut manifest = Self {
+            manifest_version: MANIFEST_VERSION,
+            module_id: module.id.clone(),
+            run_id: format!("{}-{}", secs, std::process::id()),
+            plan_file: plan_file
+                .file_name()
+                .map(|n| n.to_string_lossy().into_owned())
+                .ok_or_else(|| anyhow!("Invalid plan file path {:?}", plan_file))?,
+            plan_sha256: sha256_file(plan_file)?,
+            inputs: module.redacted_variables().into_iter().collect(),
+            signature: None,
+        };
+        if let Some(key) = key {
+            manifest.signature = Some(manifest.sign(key)?);
+        }
+        Ok(manifest)
+    }
+
+    pub fn path_for(plan_file: &Path) -> PathBuf {
+        let mut name = plan_file.as_os_str().to_owned();
+        name.push(".manifest.json");
+        PathBuf::from(name)
+    }
+
+    pub fn write(&self, plan_file: &Path) -> Result<()> {
+        let path = Self::path_for(plan_file);
+        let json = serde_json::to_string_pretty(self)?;
+        std::fs::write(&path, json)
+            .with_context(|| format!("Failed to write plan manifest {:?}", path))
+    }
+
+    pub fn read(plan_file: &Path) -> Result<Option<Self>> {
+        let path = Self::path_for(plan_file);
+        if !path.is_file() {
+            return Ok(None);
+        }
+        let content = std::fs::read(&path)
+            .with_context(|| format!("Failed to read plan manifest {:?}", path))?;
+        serde_json::from_slice(&content)
+            .map(Some)
+            .with_context(|| format!("Invalid plan manifest {:?}", path))
+    }
+
+    /// Refuse a plan that is not the one the manifest was written for
+    pub fn verify(&self, plan_file: &Path, module_id: &str, key: Option<&str>) -> Result<()> {
+        if self.module_id != module_id {
+            return Err(anyhow!(
+                "plan manifest is for module '{}', not '{}'",
+                self.module_id,
+                module_id
+            ));
+        }
+        let actual = sha256_file(plan_file)?;
+        if actual != self.plan_sha256 {
+            return Err(anyhow!(
+                "plan file {:?} was modified: sha256 is {} but the manifest records {}",
+                plan_file,
+                actual,
+                self.plan_sha256
+            ));
+        }
+        if let Some(key) = key {
+            let signature = self.signature.as_deref().ok_or_else(|| {
+                anyhow!("plan manifest is not signed but {} is set", SIGNING_KEY_ENV)
+            })?;
+            let mut mac = Self::mac(key)?;
+            mac.update(&self.payload()?);
+            let expected = decode_hex(signature)
+                .ok_or_else(|| anyhow!("plan manifest signature is not hex"))?;
+            mac.verify_slice(&expected)
+                .map_err(|_| anyhow!("plan manifest signature does not match"))?;
+        }
+        Ok(())
+    }
+
+    /// Canonical bytes covered by the signature
+    fn payload(&self) -> Result<Vec<u8>> {
+        let unsigned = Self {
+            signature: None,
+            ..self.clone()
+        };
+        Ok(serde_json::to_vec(&unsigned)?)
+    }
+
+    fn sign(&self, key: &str) -> Result<String> {
+        let mut mac = Self::mac(key)?;
+        mac.update(&self.payload()?);
+        Ok(encode_hex(&mac.finalize().into_bytes()))
+    }
+
+    fn mac(key: &str) -> Result<Hmac<Sha256>> {
+        Hmac::<Sha256>::new_from_slice(key.as_bytes()).map_err(|_| anyhow!("Invalid signing key"))
+    }
+}
+
+fn sha256_file(path: &Path) -> Result<String> {
+    let bytes =
+        std::fs::read(path).with_context(|| format!("Failed to read plan file {:?}", path))?;
+    Ok(sha256_hex(&bytes))
+}
+
+/// Hex SHA-256 of the bytes
+pub fn sha256_hex(bytes: &[u8]) -> String {
+    encode_hex(&Sha256::digest(bytes))
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
@@ -1,9 +1,12 @@
-use anyhow::{Context, Result, bail};
+use crate::interpolate::{expand, interpolate_env};
+use crate::manifest::sha256_hex;
+use crate::terraform::json_to_value;
+use anyhow::{Context, Result};
 use serde::{Deserialize, Deserializer};
 use serde_yaml::Value;
 use std::collections::{HashMap, HashSet};
-use std::fs::File;
-use std::path::Path;
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
 use thiserror::Error;
 
 /// Root structure for the whole infrastructure file.
@@ -11,8 +14,14 @@ use thiserror::Error;
 pub struct InfraFile {
     pub nodes: HashMap<String, InfraNode>,
     pub source_defaults: HashMap<String, ModuleDefaults>,
+    /// Declared environment names, any name is accepted when empty
+    pub environments: Vec<String>,
+    /// SHA-256 of the text the file was parsed from, none when deserialized
+    pub digest: Option<String>,
 }
 
+// Nodes are built once per load, boxing modules would not save anything
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug, Clone, Deserialize)]
 pub enum InfraNode {
     Scope(ScopeNode),
@@ -20,18 +29,79 @@ pub enum InfraNode {
 }
 
 /// Input value enum
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum InputValue {
     /// Reference to module output or scope variable
     Ref { path: String }, // "vpc.main_lb" or "tenant.id"
-    /// Reference with fallback default
+    /// Reference with fallback default, which may itself be a reference
     RefWithDefault {
         path: String,
-        default: serde_yaml::Value,
+        default: Box<InputValue>,
     },
     /// Literal value
     Default(serde_yaml::Value),
+    /// Secret resolved at runtime from a secrets backend
+    Secret { uri: String }, // "aws-sm://prod/db/password"
+}
+impl InputValue {
+    /// Replace `each.key` and `each.value` references along the default chain with
+    /// the values of an expanded module instance
+    pub fn substitute_each(&mut self, each: &EachInstance) -> Result<()> {
+        match self {
+            InputValue::Ref { path } | InputValue::RefWithDefault { path, .. }
+                if is_each_ref(path) =>
+            {
+                let value = each.lookup(path)?;
+                *self = InputValue::Default(value);
+            }
+            InputValue::RefWithDefault { default, .. } => default.substitute_each(each)?,
+            InputValue::Ref { .. } | InputValue::Default(_) | InputValue::Secret { .. } => {}
+        }
+        Ok(())
+    }
+
+    /// Paths of the references along the default chain, first one first
+    pub fn ref_paths(&self) -> Vec<&str> {
+        match self {
+            InputValue::Ref { path } => vec![path],
+            InputValue::RefWithDefault { path, default } => {
+                let mut paths = vec![path.as_str()];
+                paths.extend(default.ref_paths());
+                paths
+            }
+            InputValue::Default(_) | InputValue::Secret { .. } => Vec::new(),
+        }
+    }
 }
+
+/// Whether a reference path reads the key or value of an expanded module instance
+pub fn is_each_ref(path: &str) -> bool {
+    path.split('.').next() == Some("each")
+}
+
+/// Name of the input of the same module read by a `self.<input>` reference path
+pub fn self_input(path: &str) -> Option<&str> {
+    let rest = path.strip_prefix("self.")?;
+    let end = rest.find(['.', '[']).unwrap_or(rest.len());
+    Some(&rest[..end]).filter(|input| !input.is_empty())
+}
+
+impl std::fmt::Display for InputValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InputValue::Ref { path } => write!(f, "from {}", path),
+            InputValue::RefWithDefault { path, default } => {
+                write!(f, "from {} (default: {})", path, default)
+            }
+            InputValue::Default(v) => match serde_json::to_string(v) {
+                Ok(json) => f.write_str(&json),
+                Err(_) => write!(f, "{:?}", v),
+            },
+            InputValue::Secret { uri } => write!(f, "secret {}", uri),
+        }
+    }
+}
+
 /// Represents module definitions (concrete Terraform stacks).
 #[derive(Debug, Clone, Deserialize)]
 pub struct ModuleNode {
@@ -40,6 +110,13 @@ pub struct ModuleNode {
     pub id: String,
     #[serde(default, deserialize_with = "deserialize_dependencies")]
     pub dependencies: Vec<Dependency>,
+    /// Modules that must run first, named like `dependencies`, whose outputs cannot
+    /// be referenced
+    #[serde(default, deserialize_with = "deserialize_dependencies")]
+    pub order_after: Vec<Dependency>,
+    /// Outputs each dependency, by name, must produce, checked as soon as they are read
+    #[serde(default)]
+    pub expects: HashMap<String, Vec<String>>,
     #[serde(default)]
     pub variables: HashMap<String, Value>,
     #[serde(default)]
@@ -48,18 +125,112 @@ pub struct ModuleNode {
     pub inputs: HashMap<String, InputValue>,
     #[serde(default)]
     pub scope_ids: HashSet<String>,
+    /// Docker image terraform runs in instead of the local binary
+    #[serde(default)]
+    pub container_image: Option<String>,
+    /// Minimum terraform version, checked with `--check-versions`
+    #[serde(default)]
+    pub min_tf_version: Option<String>,
+    /// Free-form labels selecting modules across scopes with `--tag`
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Role the module fulfills in its scope, referenced as `@role:<role>.<output>`
+    #[serde(default)]
+    pub role: Option<String>,
+    /// Passed to `terraform init` as `-backend-config=<key>=<value>`, with
+    /// `${module_id}` in values replaced by the module id
+    #[serde(default)]
+    pub backend_config: HashMap<String, Value>,
+    /// Environment variables of every terraform command of the module, e.g. `AWS_PROFILE`
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+    /// Variables holding sensitive values, redacted whenever displayed
+    #[serde(skip)]
+    pub sensitive: HashSet<String>,
+    /// Expands the module into that many instances, `<id>[0]`, `<id>[1]`...
+    #[serde(default)]
+    pub count: Option<usize>,
+    /// Expands the module into an instance per list item or mapping entry, `<id>[<key>]`
+    #[serde(default)]
+    pub for_each: Option<Value>,
+    /// Set on the instances of an expanded module when the graph is built
+    #[serde(skip)]
+    pub each: Option<EachInstance>,
+}
+
+/// Instance of a module expanded with `count` or `for_each`
+#[derive(Debug, Clone)]
+pub struct EachInstance {
+    /// Id of the module before expansion
+    pub base_id: String,
+    /// Index with `count`, list item or mapping key with `for_each`
+    pub key: Value,
+    /// Index with `count`, list item or mapping value with `for_each`
+    pub value: Value,
+}
+
+impl EachInstance {
+    /// Value of an `each.key` or `each.value[.<key>...]` reference
+    fn lookup(&self, path: &str) -> Result<Value> {
+        let mut keys = path.split('.').skip(1);
+        let mut value = match keys.next() {
+            Some("key") => &self.key,
+            Some("value") => &self.value,
+            _ => anyhow::bail!(
+                "Invalid reference '{}', expected each.key or each.value",
+                path
+            ),
+        };
+        for key in keys {
+            value = value
+                .get(key)
+                .ok_or_else(|| anyhow::anyhow!("'{}' not found in each.value", path))?;
+        }
+        Ok(value.clone())
+    }
+}
+
+impl ModuleNode {
+    /// Variables with every sensitive value replaced by "***"
+    pub fn redacted_variables(&self) -> HashMap<String, Value> {
+        self.variables
+            .iter()
+            .map(|(k, v)| {
+                if self.sensitive.contains(k) {
+                    (k.clone(), Value::from("***"))
+                } else {
+                    (k.clone(), v.clone())
+                }
+            })
+            .collect()
+    }
 }
 
 fn deserialize_dependencies<'de, D>(deserializer: D) -> Result<Vec<Dependency>, D::Error>
 where
     D: Deserializer<'de>,
 {
-    let raw = <Vec<String>>::deserialize(deserializer)?;
+    /// A dependency written as a plain source name or as `{ name, when }`
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum RawDependency {
+        Name(String),
+        Conditional { name: String, when: Option<String> },
+    }
+
+    let raw = <Vec<RawDependency>>::deserialize(deserializer)?;
     Ok(raw
         .into_iter()
-        .map(|s| Dependency {
-            id: "".to_string(),
-            name: s,
+        .map(|dep| {
+            let (name, when) = match dep {
+                RawDependency::Name(name) => (name, None),
+                RawDependency::Conditional { name, when } => (name, when),
+            };
+            Dependency {
+                id: "".to_string(),
+                name,
+                when,
+            }
         })
         .collect())
 }
@@ -74,12 +245,27 @@ pub struct ScopeNode {
     pub variables: HashMap<String, Value>,
     #[serde(default)]
     pub children: HashMap<String, InfraNode>,
+    /// Variables merged over `variables` when their environment is selected
+    #[serde(default)]
+    pub variables_by_env: HashMap<String, HashMap<String, Value>>,
+    /// Shell command run before any module of the scope
+    #[serde(default)]
+    pub before: Option<String>,
+    /// Shell command run after all modules of the scope completed
+    #[serde(default)]
+    pub after: Option<String>,
+    /// Defaults of every module below the scope, the closest scope winning
+    #[serde(default)]
+    pub defaults: Option<ModuleDefaults>,
 }
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct Dependency {
     pub id: String,
     pub name: String,
+    /// Condition on scope variables, the edge only exists when it is truthy
+    #[serde(default)]
+    pub when: Option<String>,
 }
 /// Defines reusable default settings for a given source
 #[derive(Debug, Clone, Deserialize)]
@@ -92,6 +278,16 @@ pub struct ModuleDefaults {
     pub mocked_outputs: Option<HashMap<String, Value>>,
     #[serde(default)]
     pub inputs: HashMap<String, InputValue>,
+    #[serde(default)]
+    pub container_image: Option<String>,
+    #[serde(default)]
+    pub min_tf_version: Option<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub backend_config: HashMap<String, Value>,
+    #[serde(default)]
+    pub env: HashMap<String, String>,
 }
 
 #[derive(Error, Debug)]
@@ -104,6 +300,72 @@ pub enum InfraError {
 
     #[error("Scope '{0}' contains a 'source' key — scopes cannot define sources.")]
     InvalidScopeSource(String),
+
+    #[error("Invalid YAML structure: {0}")]
+    InvalidModule(#[from] ModuleValidationError),
+
+    #[error("node '{path}': {inner}")]
+    Node {
+        /// Dotted path of the offending node, e.g. "account-1.tenant-a.webapp"
+        path: String,
+        inner: Box<InfraError>,
+    },
+
+    #[error("{inner} at line {line} column {column}")]
+    Located {
+        line: usize,
+        column: usize,
+        inner: Box<InfraError>,
+    },
+}
+
+/// A module node breaking a rule of the schema
+#[derive(Error, Debug)]
+pub enum ModuleValidationError {
+    #[error("Module 'id' must not be set by user; it is auto-generated.")]
+    UserSetId,
+
+    #[error("Module 'source' must be set and non-empty.")]
+    EmptySource,
+
+    #[error("Module 'module' must correspond to a directory in modules_dir: {0:?}")]
+    SourceDirMissing(PathBuf),
+
+    #[error("Module 'source' must be a relative path under modules_dir, without '..': {0:?}")]
+    SourceOutsideModulesDir(String),
+
+    #[error("Module 'variables' must be empty; only orchestrator sets variables.")]
+    VariablesNotEmpty,
+
+    #[error("Module 'scope_ids' must not be set by user; it is auto-populated.")]
+    ScopeIdsSet,
+
+    #[error("Module cannot set both 'count' and 'for_each'.")]
+    CountAndForEach,
+}
+
+impl InfraError {
+    /// The module rule broken, if the error is one, whatever node or position is attached
+    pub fn module_error(&self) -> Option<&ModuleValidationError> {
+        match self {
+            InfraError::InvalidModule(err) => Some(err),
+            InfraError::Node { inner, .. } | InfraError::Located { inner, .. } => {
+                inner.module_error()
+            }
+            _ => None,
+        }
+    }
+
+    /// Attach the node path to an error, keeping the innermost one already attached
+    fn at(path: &str, err: InfraError) -> Self {
+        match err {
+            InfraError::Node { .. } => err,
+            err => InfraError::Node {
+                path: path.to_string(),
+                inner: Box::new(err),
+            },
+        }
+    }
 }
 
 impl<'de> Deserialize<'de> for InputValue {
@@ -116,17 +378,24 @@ impl<'de> Deserialize<'de> for InputValue {
         match val {
             serde_yaml::Value::Mapping(map) => {
                 if let Some(serde_yaml::Value::String(path)) =
-                    map.get(&serde_yaml::Value::from("from"))
+                    map.get(serde_yaml::Value::from("from"))
                 {
-                    let default_val = map.get(&serde_yaml::Value::from("default")).cloned();
+                    let default_val = map.get(serde_yaml::Value::from("default")).cloned();
                     if let Some(default_val) = default_val {
+                        // `default: { from: ... }` chains to another reference
+                        let default: InputValue = serde_yaml::from_value(default_val)
+                            .map_err(serde::de::Error::custom)?;
                         Ok(InputValue::RefWithDefault {
                             path: path.clone(),
-                            default: default_val,
+                            default: Box::new(default),
                         })
                     } else {
                         Ok(InputValue::Ref { path: path.clone() })
                     }
+                } else if let Some(serde_yaml::Value::String(uri)) =
+                    map.get(serde_yaml::Value::from("secret"))
+                {
+                    Ok(InputValue::Secret { uri: uri.clone() })
                 } else {
                     Ok(InputValue::Default(serde_yaml::Value::Mapping(map)))
                 }
@@ -137,29 +406,42 @@ impl<'de> Deserialize<'de> for InputValue {
 }
 
 /// Validate a ModuleNode according to schema rules
-fn validate_module_node(module: &ModuleNode, modules_dir: &Path) -> Result<()> {
+fn validate_module_node(
+    module: &ModuleNode,
+    modules_dir: &Path,
+) -> Result<(), ModuleValidationError> {
     // 1. id should not be set by user
     if !module.id.is_empty() {
-        anyhow::bail!("Module 'id' must not be set by user; it is auto-generated.");
+        return Err(ModuleValidationError::UserSetId);
     }
     // 2. source must be set and correspond to a terraform project dirname
     if module.source.is_empty() {
-        anyhow::bail!("Module 'source' must be set and non-empty.");
+        return Err(ModuleValidationError::EmptySource);
+    }
+    // `networking/vpc` is fine, but the source must stay under modules_dir
+    if !Path::new(&module.source)
+        .components()
+        .all(|c| matches!(c, Component::Normal(_)))
+    {
+        return Err(ModuleValidationError::SourceOutsideModulesDir(
+            module.source.clone(),
+        ));
     }
     let tf_dir = modules_dir.join(&module.source);
     if !tf_dir.is_dir() {
-        anyhow::bail!(
-            "Module 'module' must correspond to a directory in modules_dir: {:?}",
-            tf_dir
-        );
+        return Err(ModuleValidationError::SourceDirMissing(tf_dir));
     }
     // 3. variables must be empty
     if !module.variables.is_empty() {
-        anyhow::bail!("Module 'variables' must be empty; only orchestrator sets variables.");
+        return Err(ModuleValidationError::VariablesNotEmpty);
     }
     // 5. scope_ids must not be set
     if !module.scope_ids.is_empty() {
-        anyhow::bail!("Module 'scope_ids' must not be set by user; it is auto-populated.");
+        return Err(ModuleValidationError::ScopeIdsSet);
+    }
+    // 6. count and for_each are exclusive
+    if module.count.is_some() && module.for_each.is_some() {
+        return Err(ModuleValidationError::CountAndForEach);
     }
 
     Ok(())
@@ -170,27 +452,47 @@ impl<'de> Deserialize<'de> for InfraFile {
     where
         D: Deserializer<'de>,
     {
-        let raw: serde_yaml::Mapping = Deserialize::deserialize(deserializer)?;
+        let mut value = Value::deserialize(deserializer)?;
+        value.apply_merge().map_err(serde::de::Error::custom)?;
+        let Value::Mapping(raw) = value else {
+            return Err(serde::de::Error::custom("expected a mapping"));
+        };
+        // Sources are checked under the default `--modules-dir`
+        InfraFile::from_mapping(raw, Path::new("modules")).map_err(serde::de::Error::custom)
+    }
+}
+
+impl InfraFile {
+    fn from_mapping(raw: serde_yaml::Mapping, modules_dir: &Path) -> Result<Self, InfraError> {
         let mut nodes = HashMap::new();
         let mut source_defaults = HashMap::new();
-        let modules_dir = Path::new("modules"); // Default modules dir
+        let mut environments = Vec::new();
         for (key, value) in raw {
             let key_str = key.as_str().unwrap_or("<invalid>").to_string();
 
             if key_str == "source_default" {
-                let defaults_map = value
-                    .as_mapping()
-                    .ok_or_else(|| serde::de::Error::custom("source_default must be a mapping"))?;
+                let defaults_map = value.as_mapping().ok_or_else(|| {
+                    InfraError::at(
+                        &key_str,
+                        InfraError::InvalidStructure("source_default must be a mapping".into()),
+                    )
+                })?;
 
                 for (src_key, src_val) in defaults_map {
                     let src_str = src_key.as_str().unwrap_or("<invalid>").to_string();
                     let defaults: ModuleDefaults = serde_yaml::from_value(src_val.clone())
-                        .map_err(serde::de::Error::custom)?;
+                        .map_err(|e| InfraError::at(&format!("{key_str}.{src_str}"), e.into()))?;
                     source_defaults.insert(src_str, defaults);
                 }
+            } else if key_str == "environments" {
+                environments = serde_yaml::from_value(value).map_err(|_| {
+                    InfraError::at(
+                        &key_str,
+                        InfraError::InvalidStructure("environments must be a list of names".into()),
+                    )
+                })?;
             } else {
-                let node = parse_infra_node(&value, &key_str, modules_dir)
-                    .map_err(|e| serde::de::Error::custom(e.to_string()))?;
+                let node = parse_infra_node(&value, &key_str, modules_dir)?;
                 nodes.insert(key_str, node);
             }
         }
@@ -198,32 +500,55 @@ impl<'de> Deserialize<'de> for InfraFile {
         Ok(InfraFile {
             nodes,
             source_defaults,
+            environments,
+            digest: None,
         })
     }
 }
 
-/// Parse a node (module or scope)
+/// Parse a node (module or scope); errors name the innermost offending node
 fn parse_infra_node(
     value: &Value,
     path: &str,
     modules_dir: &Path,
+) -> Result<InfraNode, InfraError> {
+    parse_infra_node_at(value, path, modules_dir).map_err(|e| InfraError::at(path, e))
+}
+
+fn parse_infra_node_at(
+    value: &Value,
+    path: &str,
+    modules_dir: &Path,
 ) -> Result<InfraNode, InfraError> {
     let map = value
         .as_mapping()
         .ok_or_else(|| InfraError::InvalidStructure(format!("expected mapping at {path}")))?;
 
-    if map.contains_key(&Value::from("source")) {
+    if map.contains_key(Value::from("source")) && map.contains_key(Value::from("scope")) {
+        Err(InfraError::InvalidScopeSource(path.to_string()))
+    } else if map.contains_key(Value::from("source")) {
         // Module
         let mut module: ModuleNode = serde_yaml::from_value(value.clone())?;
-        validate_module_node(&module, modules_dir)
-            .map_err(|e| InfraError::InvalidStructure(e.to_string()))?;
+        validate_module_node(&module, modules_dir)?;
         module.id = path.to_string();
 
         Ok(InfraNode::Module(module))
-    } else if map.contains_key(&Value::from("scope")) {
+    } else if map.contains_key(Value::from("scope")) {
         // Scope
+        if map.contains_key(Value::from("backend_config")) {
+            return Err(InfraError::InvalidStructure(format!(
+                "Scope '{}' cannot define 'backend_config', set it on modules or in 'defaults'",
+                path
+            )));
+        }
+        if map.contains_key(Value::from("env")) {
+            return Err(InfraError::InvalidStructure(format!(
+                "Scope '{}' cannot define 'env', set it on modules or in 'defaults'",
+                path
+            )));
+        }
         let scope_val = map
-            .get(&Value::from("scope"))
+            .get(Value::from("scope"))
             .and_then(|v| v.as_str())
             .ok_or_else(|| {
                 InfraError::InvalidStructure(format!(
@@ -234,7 +559,7 @@ fn parse_infra_node(
 
         let mut variables = HashMap::new();
         if let Some(vars) = map
-            .get(&Value::from("variables"))
+            .get(Value::from("variables"))
             .and_then(|v| v.as_mapping())
         {
             variables = vars
@@ -243,10 +568,40 @@ fn parse_infra_node(
                 .collect();
         }
 
+        let mut variables_by_env = HashMap::new();
+        if let Some(by_env) = map.get(Value::from("variables_by_env")) {
+            variables_by_env = serde_yaml::from_value(by_env.clone()).map_err(|_| {
+                InfraError::InvalidStructure(format!(
+                    "'variables_by_env' of scope '{}' must map environment names to variables",
+                    path
+                ))
+            })?;
+        }
+
+        let defaults = match map.get(Value::from("defaults")) {
+            Some(defaults) => Some(serde_yaml::from_value(defaults.clone()).map_err(|e| {
+                InfraError::InvalidStructure(format!(
+                    "'defaults' of scope '{}' is invalid: {}",
+                    path, e
+                ))
+            })?),
+            None => None,
+        };
+
+        let hook = |key: &str| {
+            map.get(Value::from(key))
+                .and_then(|v| v.as_str())
+                .map(str::to_string)
+        };
+
         let mut children = HashMap::new();
         for (k, v) in map {
             let key_str = k.as_str().unwrap_or("<invalid>").to_string();
-            if key_str != "scope" && key_str != "variables" && v.is_mapping() {
+            if !matches!(
+                key_str.as_str(),
+                "scope" | "variables" | "variables_by_env" | "defaults"
+            ) && v.is_mapping()
+            {
                 let child = parse_infra_node(v, &format!("{path}.{key_str}"), modules_dir)?;
                 children.insert(key_str, child);
             }
@@ -256,6 +611,10 @@ fn parse_infra_node(
             scope: scope_val.to_string(),
             variables,
             children,
+            variables_by_env,
+            before: hook("before"),
+            after: hook("after"),
+            defaults,
         }))
     } else {
         Err(InfraError::InvalidStructure(format!(
@@ -266,39 +625,339 @@ fn parse_infra_node(
 }
 
 impl InfraFile {
-    /// Load and parse an InfraFile from disk, then apply defaults.
-    pub fn from_path(path: &Path) -> Result<Self> {
-        let file = File::open(path)
-            .with_context(|| format!("Failed to open infra YAML file {:?}", path))?;
-        let mut infra: InfraFile = serde_yaml::from_reader(file)
-            .with_context(|| format!("Failed to parse YAML file {:?}", path))?;
+    /// Load and parse an InfraFile from disk (JSON for `.json` files, YAML otherwise, `-`
+    /// reads YAML from stdin), checking module sources under `modules_dir`; select the
+    /// environment's scope variables, then apply defaults, deep-merging mappings with
+    /// `deep_merge`.
+    pub fn from_path(
+        path: &Path,
+        modules_dir: &Path,
+        environment: Option<&str>,
+        deep_merge: bool,
+    ) -> Result<Self> {
+        if path == Path::new("-") {
+            return Self::from_reader(
+                std::io::stdin().lock(),
+                modules_dir,
+                environment,
+                deep_merge,
+            );
+        }
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to open infra file {:?}", path))?;
+        let is_json = path.extension().is_some_and(|ext| ext == "json");
+        Self::parse(
+            &content,
+            path,
+            modules_dir,
+            is_json,
+            environment,
+            deep_merge,
+        )
+    }
+
+    /// Like `from_path`, reading YAML (or JSON, which YAML parses too) from `reader`
+    pub fn from_reader(
+        mut reader: impl Read,
+        modules_dir: &Path,
+        environment: Option<&str>,
+        deep_merge: bool,
+    ) -> Result<Self> {
+        let mut content = String::new();
+        reader
+            .read_to_string(&mut content)
+            .context("Failed to read the infra file")?;
+        Self::parse(
+            &content,
+            Path::new("<stdin>"),
+            modules_dir,
+            false,
+            environment,
+            deep_merge,
+        )
+    }
+
+    /// Parse the `content` of the infra file at `path`, named in errors
+    fn parse(
+        content: &str,
+        path: &Path,
+        modules_dir: &Path,
+        is_json: bool,
+        environment: Option<&str>,
+        deep_merge: bool,
+    ) -> Result<Self> {
+        let format = if is_json { "JSON" } else { "YAML" };
+        // JSON goes through the same YAML value the node parser works on
+        let value: Value = if is_json {
+            let json: serde_json::Value = serde_json::from_str(content)
+                .with_context(|| format!("Failed to parse JSON file {:?}", path))?;
+            json_to_value(json)
+        } else {
+            let mut value: Value = serde_yaml::from_str(content)
+                .with_context(|| format!("Failed to parse YAML file {:?}", path))?;
+            // Aliases are resolved while parsing, but `<<: *anchor` merge keys are not
+            value
+                .apply_merge()
+                .with_context(|| format!("Failed to merge '<<' keys of YAML file {:?}", path))?;
+            value
+        };
+        let Value::Mapping(raw) = value else {
+            anyhow::bail!(
+                "Failed to parse {format} file {:?}: expected a mapping",
+                path
+            );
+        };
+        let mut infra = InfraFile::from_mapping(raw, modules_dir)
+            .map_err(|err| {
+                // serde_yaml values carry no position, so find the node in the text
+                let location = match &err {
+                    InfraError::Node { path: node, .. } if !is_json => locate_node(content, node),
+                    _ => None,
+                };
+                match location {
+                    Some((line, column)) => InfraError::Located {
+                        line,
+                        column,
+                        inner: Box::new(err),
+                    },
+                    None => err,
+                }
+            })
+            .with_context(|| format!("Failed to parse {format} file {:?}", path))?;
+        infra.digest = Some(sha256_hex(content.as_bytes()));
+
+        infra
+            .interpolate_env()
+            .with_context(|| format!("while expanding environment variables of {:?}", path))?;
+        infra.select_environment(environment)?;
 
         // Apply defaults like `source_default`, inheritance, etc.
-        infra.apply_defaults();
+        infra.apply_defaults(deep_merge);
         infra.add_scope_id_to_childrens();
+        infra.validate_dependencies()?;
 
         Ok(infra)
     }
 
+    /// Expand `${VAR}` references to environment variables in scope variables,
+    /// default variables and `env`; inputs are left as written
+    fn interpolate_env(&mut self) -> Result<()> {
+        fn defaults(defaults: &mut ModuleDefaults) -> Result<()> {
+            for value in defaults.variables.values_mut() {
+                interpolate_env(value)?;
+            }
+            for (key, value) in defaults.env.iter_mut() {
+                *value = expand(value).with_context(|| format!("in env '{}'", key))?;
+            }
+            Ok(())
+        }
+        fn walk(name: &str, node: &mut InfraNode) -> Result<()> {
+            match node {
+                InfraNode::Module(module) => {
+                    for (key, value) in module.env.iter_mut() {
+                        *value = expand(value)
+                            .with_context(|| format!("in env '{}' of module '{}'", key, name))?;
+                    }
+                }
+                InfraNode::Scope(scope) => {
+                    let variables = scope.variables.values_mut().chain(
+                        scope
+                            .variables_by_env
+                            .values_mut()
+                            .flat_map(|v| v.values_mut()),
+                    );
+                    for value in variables {
+                        interpolate_env(value)
+                            .with_context(|| format!("in variables of scope '{}'", name))?;
+                    }
+                    if let Some(scope_defaults) = &mut scope.defaults {
+                        defaults(scope_defaults)
+                            .with_context(|| format!("in defaults of scope '{}'", name))?;
+                    }
+                    for (child_name, child) in scope.children.iter_mut() {
+                        walk(&format!("{}.{}", name, child_name), child)?;
+                    }
+                }
+            }
+            Ok(())
+        }
+        for (source, source_defaults) in self.source_defaults.iter_mut() {
+            defaults(source_defaults)
+                .with_context(|| format!("in source_default of '{}'", source))?;
+        }
+        for (name, child) in self.nodes.iter_mut() {
+            walk(name, child)?;
+        }
+        Ok(())
+    }
+
+    /// Set a variable of the scope with that id, over the one the file gives it
+    pub fn set_scope_variable(&mut self, scope_id: &str, key: &str, value: Value) -> Result<()> {
+        fn find<'a>(node: &'a mut InfraNode, scope_id: &str) -> Option<&'a mut ScopeNode> {
+            let InfraNode::Scope(scope) = node else {
+                return None;
+            };
+            if scope.id == scope_id {
+                return Some(scope);
+            }
+            scope
+                .children
+                .values_mut()
+                .find_map(|child| find(child, scope_id))
+        }
+        let scope = self
+            .nodes
+            .values_mut()
+            .find_map(|node| find(node, scope_id))
+            .ok_or_else(|| {
+                anyhow::anyhow!("No scope '{}' to set variable '{}' on", scope_id, key)
+            })?;
+        scope.variables.insert(key.to_string(), value);
+        Ok(())
+    }
+
+    /// Every module, at any depth
+    fn all_modules(&self) -> Vec<&ModuleNode> {
+        fn collect<'a>(node: &'a InfraNode, out: &mut Vec<&'a ModuleNode>) {
+            match node {
+                InfraNode::Module(m) => out.push(m),
+                InfraNode::Scope(scope) => {
+                    for child in scope.children.values() {
+                        collect(child, out);
+                    }
+                }
+            }
+        }
+        let mut modules = Vec::new();
+        for node in self.nodes.values() {
+            collect(node, &mut modules);
+        }
+        modules
+    }
+
+    /// Fail, listing all of them, when `dependencies` or `order_after` entries name no module of the
+    /// same source in the module's scopes, or `expects` names no dependency. Conditional
+    /// dependencies are only checked when the graph is built, if their condition holds.
+    pub fn validate_dependencies(&self) -> Result<()> {
+        let modules = self.all_modules();
+        let mut unresolved: Vec<String> = Vec::new();
+        for module in &modules {
+            let entries = module.dependencies.iter().chain(&module.order_after);
+            for dependency in entries.filter(|d| d.when.is_none()) {
+                let resolves = modules.iter().any(|m| {
+                    m.source == dependency.name && !m.scope_ids.is_disjoint(&module.scope_ids)
+                });
+                if !resolves {
+                    unresolved.push(format!(
+                        "dependency '{}' of module '{}' matches no module in its scopes",
+                        dependency.name, module.id
+                    ));
+                }
+            }
+            for name in module.expects.keys() {
+                if !module.dependencies.iter().any(|d| &d.name == name) {
+                    unresolved.push(format!(
+                        "expects of module '{}' names '{}', which is not one of its dependencies",
+                        module.id, name
+                    ));
+                }
+            }
+        }
+        if !unresolved.is_empty() {
+            unresolved.sort();
+            anyhow::bail!(
+                "{} unresolved dependencies:\n{}",
+                unresolved.len(),
+                unresolved.join("\n")
+            );
+        }
+        Ok(())
+    }
+
+    /// Merge the `variables_by_env` of the selected environment over scope variables;
+    /// other environments are ignored
+    fn select_environment(&mut self, environment: Option<&str>) -> Result<()> {
+        fn apply_recursive(
+            node: &mut InfraNode,
+            environment: Option<&str>,
+            declared: &[String],
+            seen: &mut HashSet<String>,
+        ) -> Result<()> {
+            let InfraNode::Scope(scope) = node else {
+                return Ok(());
+            };
+            for env in scope.variables_by_env.keys() {
+                if !declared.is_empty() && !declared.contains(env) {
+                    anyhow::bail!(
+                        "Scope '{}' has variables for undeclared environment '{}'",
+                        scope.id,
+                        env
+                    );
+                }
+                seen.insert(env.clone());
+            }
+            if let Some(vars) = environment.and_then(|env| scope.variables_by_env.get(env)) {
+                scope.variables.extend(vars.clone());
+            }
+            for child in scope.children.values_mut() {
+                apply_recursive(child, environment, declared, seen)?;
+            }
+            Ok(())
+        }
+
+        let mut seen = HashSet::new();
+        for node in self.nodes.values_mut() {
+            apply_recursive(node, environment, &self.environments, &mut seen)?;
+        }
+        if let Some(env) = environment {
+            let known = if self.environments.is_empty() {
+                seen.contains(env)
+            } else {
+                self.environments.iter().any(|e| e == env)
+            };
+            if !known {
+                anyhow::bail!("Unknown environment '{}'", env);
+            }
+        }
+        Ok(())
+    }
+
     /// Merge defaults into all modules recursively
-    pub fn apply_defaults(&mut self) {
-        fn apply_recursive(node: &mut InfraNode, defaults: &HashMap<String, ModuleDefaults>) {
+    /// The module wins over the `defaults` of its scopes, closest first, which win
+    /// over the `source_default` of its source; with `deep_merge`, mapping variables
+    /// and literal inputs are merged key by key at every level, not replaced
+    pub fn apply_defaults(&mut self, deep_merge: bool) {
+        fn apply_recursive(
+            node: &mut InfraNode,
+            defaults: &HashMap<String, ModuleDefaults>,
+            scope_defaults: &mut Vec<ModuleDefaults>,
+            deep_merge: bool,
+        ) {
             match node {
                 InfraNode::Module(m) => {
+                    for def in scope_defaults.iter().rev() {
+                        merge_module_defaults(m, def, deep_merge);
+                    }
                     if let Some(def) = defaults.get(&m.source) {
-                        merge_module_defaults(m, def);
+                        merge_module_defaults(m, def, deep_merge);
                     }
                 }
                 InfraNode::Scope(scope) => {
+                    if let Some(def) = &scope.defaults {
+                        scope_defaults.push(def.clone());
+                    }
                     for child in scope.children.values_mut() {
-                        apply_recursive(child, defaults);
+                        apply_recursive(child, defaults, scope_defaults, deep_merge);
+                    }
+                    if scope.defaults.is_some() {
+                        scope_defaults.pop();
                     }
                 }
             }
         }
 
         for node in self.nodes.values_mut() {
-            apply_recursive(node, &self.source_defaults);
+            apply_recursive(node, &self.source_defaults, &mut Vec::new(), deep_merge);
         }
     }
     fn add_scope_id_to_childrens(&mut self) {
@@ -336,27 +995,508 @@ impl InfraFile {
     }
 }
 
+/// 1-based line and column of the key of a dotted node path in block-style YAML,
+/// each key searched within the block of its parent
+fn locate_node(content: &str, path: &str) -> Option<(usize, usize)> {
+    let lines: Vec<&str> = content.lines().collect();
+    let mut from = 0;
+    let mut parent_indent: Option<usize> = None;
+    let mut found = None;
+    for key in path.split('.') {
+        found = None;
+        // Direct children share the indentation of the first line of the block
+        let mut child_indent = None;
+        for (n, line) in lines.iter().enumerate().skip(from) {
+            let trimmed = line.trim_start();
+            if trimmed.is_empty() || trimmed.starts_with('#') {
+                continue;
+            }
+            let indent = line.len() - trimmed.len();
+            if parent_indent.is_some_and(|parent| indent <= parent) {
+                break;
+            }
+            if *child_indent.get_or_insert(indent) != indent {
+                continue;
+            }
+            let line_key = trimmed
+                .split_once(':')
+                .map(|(k, _)| k.trim().trim_matches(['"', '\'']));
+            if line_key == Some(key) {
+                found = Some((n, indent));
+                break;
+            }
+        }
+        let (n, indent) = found?;
+        from = n + 1;
+        parent_indent = Some(indent);
+    }
+    found.map(|(n, indent)| (n + 1, indent + 1))
+}
+
 /// Merge defaults → module (module overrides defaults)
-fn merge_module_defaults(module: &mut ModuleNode, defaults: &ModuleDefaults) {
+fn merge_module_defaults(module: &mut ModuleNode, defaults: &ModuleDefaults, deep_merge: bool) {
     // dependencies
     if module.dependencies.is_empty() && !defaults.dependencies.is_empty() {
         module.dependencies = defaults.dependencies.clone();
     }
 
-    // variables
+    // variables, mappings key by key with `deep_merge`
     for (k, v) in &defaults.variables {
-        module.variables.entry(k.clone()).or_insert(v.clone());
+        match module.variables.get_mut(k) {
+            Some(value) if deep_merge => merge_mappings(value, v),
+            Some(_) => {}
+            None => {
+                module.variables.insert(k.clone(), v.clone());
+            }
+        }
     }
 
-    // inputs
+    // inputs, literal mappings key by key with `deep_merge`
     for (k, v) in &defaults.inputs {
-        module.inputs.entry(k.clone()).or_insert(v.clone());
+        match (module.inputs.get_mut(k), v) {
+            (Some(InputValue::Default(value)), InputValue::Default(default)) if deep_merge => {
+                merge_mappings(value, default)
+            }
+            (Some(_), _) => {}
+            (None, _) => {
+                module.inputs.insert(k.clone(), v.clone());
+            }
+        }
     }
 
     // mocked outputs
     if module.mocked_outputs.is_none() && defaults.mocked_outputs.is_some() {
         module.mocked_outputs = defaults.mocked_outputs.clone();
     }
+
+    // container image
+    if module.container_image.is_none() {
+        module.container_image = defaults.container_image.clone();
+    }
+
+    // terraform version
+    if module.min_tf_version.is_none() {
+        module.min_tf_version = defaults.min_tf_version.clone();
+    }
+
+    // backend config, key by key
+    for (k, v) in &defaults.backend_config {
+        module.backend_config.entry(k.clone()).or_insert(v.clone());
+    }
+
+    // env, key by key
+    for (k, v) in &defaults.env {
+        module.env.entry(k.clone()).or_insert(v.clone());
+    }
+
+    // tags add up
+    for tag in &defaults.tags {
+        if !module.tags.contains(tag) {
+            module.tags.push(tag.clone());
+        }
+    }
 }
 
 //fn resolve_dependencies_ids(infra: InfraFile, module_id: &str, dep_name: &str) -> Option<String> {}
+
+/// Merge the keys of the `default` mapping missing from `value`, recursively; the
+/// value wins at the leaves, and anything but two mappings keeps the value as is
+fn merge_mappings(value: &mut Value, default: &Value) {
+    if let (Value::Mapping(value), Value::Mapping(default)) = (value, default) {
+        for (k, v) in default {
+            match value.get_mut(k) {
+                Some(existing) => merge_mappings(existing, v),
+                None => {
+                    value.insert(k.clone(), v.clone());
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graph::ModuleGraph;
+    use crate::testing::{example, infra, modules_dir, temp_dir, try_infra};
+
+    #[test]
+    fn environment_variables_expand_in_scope_variables_and_env_only() {
+        let graph = ModuleGraph::new(&infra(
+            "
+acc:
+  scope: account
+  variables:
+    region: \"${LIGHTSTACKS_TEST_UNSET:-eu-west-1}\"
+  vpc:
+    source: vpc
+    env:
+      PROFILE: \"${LIGHTSTACKS_TEST_UNSET:-net}-admin\"
+      PRICE: \"$$5\"
+    inputs:
+      price: \"$$5\"
+      token: \"$LIGHTSTACKS_TEST_UNSET\"
+",
+        ))
+        .unwrap();
+        let scope = graph.get_scope_by_id("acc").unwrap();
+        assert_eq!(scope.variables["region"], Value::from("eu-west-1"));
+        let vpc = graph.get_module_by_id("acc.vpc").unwrap();
+        assert_eq!(vpc.env["PROFILE"], "net-admin");
+        assert_eq!(vpc.env["PRICE"], "$5");
+        // Inputs are passed as written
+        for (input, written) in [("price", "$$5"), ("token", "$LIGHTSTACKS_TEST_UNSET")] {
+            assert!(matches!(
+                &vpc.inputs[input],
+                InputValue::Default(Value::String(s)) if s == written
+            ));
+        }
+    }
+
+    #[test]
+    fn undefined_env_variable_fails_the_load() {
+        let err = try_infra(
+            "
+acc:
+  scope: account
+  vpc:
+    source: vpc
+    env:
+      TOKEN: \"$LIGHTSTACKS_TEST_UNSET\"
+",
+        )
+        .unwrap_err();
+        let message = format!("{err:#}");
+        assert!(
+            message.contains("in env 'TOKEN' of module 'acc.vpc'"),
+            "{message}"
+        );
+    }
+
+    #[test]
+    fn env_is_rejected_on_scopes() {
+        let err = try_infra(
+            "
+acc:
+  scope: account
+  env:
+    AWS_PROFILE: admin
+  vpc:
+    source: vpc
+",
+        )
+        .unwrap_err();
+        let message = format!("{err:#}");
+        assert!(
+            message.contains("Scope 'acc' cannot define 'env'"),
+            "{message}"
+        );
+    }
+
+    #[test]
+    fn undefined_scope_variable_fails_the_load() {
+        let err = try_infra(
+            "
+acc:
+  scope: account
+  variables:
+    region: \"$LIGHTSTACKS_TEST_UNSET\"
+  vpc:
+    source: vpc
+",
+        )
+        .unwrap_err();
+        let message = format!("{err:#}");
+        assert!(message.contains("in variables of scope 'acc'"), "{message}");
+        assert!(message.contains("LIGHTSTACKS_TEST_UNSET"), "{message}");
+    }
+
+    #[test]
+    fn json_infra_file_parses_like_its_yaml_twin() {
+        let load = |path: &str| {
+            let infra = InfraFile::from_path(&example(path), modules_dir(), None, true).unwrap();
+            ModuleGraph::new(&infra).unwrap()
+        };
+        let json = load("deployments/infra_example.json");
+        let yaml = load("deployments/infra_example.yaml");
+        assert_eq!(
+            serde_json::to_value(json.export()).unwrap(),
+            serde_json::to_value(yaml.export()).unwrap()
+        );
+        let (json, yaml) = (json.modules(), yaml.modules());
+        assert_eq!(json.len(), yaml.len());
+        for (id, module) in &yaml {
+            let twin = &json[id];
+            assert_eq!(twin.source, module.source, "{id}");
+            assert_eq!(twin.inputs, module.inputs, "{id}");
+            assert_eq!(twin.variables, module.variables, "{id}");
+            assert_eq!(twin.mocked_outputs, module.mocked_outputs, "{id}");
+        }
+    }
+
+    #[test]
+    fn invalid_node_errors_point_at_its_line() {
+        let path = &example("deployments/invalid_module.yaml");
+        let err = InfraFile::from_path(path, modules_dir(), None, true).unwrap_err();
+        let message = format!("{err:#}");
+        assert!(
+            message.contains("node 'account-1.tenant-a.webapp'"),
+            "{message}"
+        );
+        assert!(message.contains("at line 9 column 5"), "{message}");
+        let infra_error = err.downcast_ref::<InfraError>().expect("an InfraError");
+        assert!(matches!(
+            infra_error.module_error(),
+            Some(ModuleValidationError::SourceDirMissing(_))
+        ));
+    }
+
+    #[test]
+    fn yaml_syntax_errors_report_their_line() {
+        let err = try_infra("acc:\n  scope: account\n  vpc: [unclosed\n").unwrap_err();
+        let message = format!("{err:#}");
+        assert!(message.contains("line 4"), "{message}");
+    }
+
+    fn literal(module: &ModuleNode, input: &str) -> Value {
+        match &module.inputs[input] {
+            InputValue::Default(value) => value.clone(),
+            other => panic!("input '{input}' of '{}' is {other:?}", module.id),
+        }
+    }
+
+    fn yaml(value: &str) -> Value {
+        serde_yaml::from_str(value).unwrap()
+    }
+
+    fn scope_defaults(deep_merge: bool) -> ModuleGraph {
+        let path = &example("deployments/scope_defaults.yaml");
+        ModuleGraph::new(&InfraFile::from_path(path, modules_dir(), None, deep_merge).unwrap())
+            .unwrap()
+    }
+
+    #[test]
+    fn scope_defaults_apply_closest_scope_first_and_modules_win() {
+        let graph = scope_defaults(true);
+        let vpc = graph.get_module_by_id("account-1.tenant-a.vpc").unwrap();
+        let compute = graph.get_module_by_id("account-1.compute").unwrap();
+        assert_eq!(literal(&vpc, "region"), yaml("us-east-1"));
+        assert_eq!(literal(&compute, "region"), yaml("ap-south-1"));
+        // Deep merged, one key overridden each
+        assert_eq!(
+            literal(&compute, "labels"),
+            yaml("{team: data, cost_center: '42'}")
+        );
+        assert_eq!(vpc.variables["tags"], yaml("{owner: tenant-a, env: prod}"));
+        assert_eq!(vpc.tags, ["account-1"]);
+        assert_eq!(compute.tags, ["account-1"]);
+    }
+
+    #[test]
+    fn scope_defaults_are_replaced_whole_without_deep_merge() {
+        let graph = scope_defaults(false);
+        let vpc = graph.get_module_by_id("account-1.tenant-a.vpc").unwrap();
+        let compute = graph.get_module_by_id("account-1.compute").unwrap();
+        assert_eq!(literal(&compute, "labels"), yaml("{team: data}"));
+        assert_eq!(vpc.variables["tags"], yaml("{owner: tenant-a}"));
+    }
+
+    #[test]
+    fn every_unresolved_dependency_is_reported_at_once() {
+        let path = &example("deployments/broken_dependencies.yaml");
+        let err = InfraFile::from_path(path, modules_dir(), None, true).unwrap_err();
+        let message = format!("{err:#}");
+        assert!(message.contains("2 unresolved dependencies"), "{message}");
+        for missing in ["cache", "database"] {
+            assert!(
+                message.contains(&format!(
+                    "dependency '{missing}' of module 'account-1.tenant-a.webapp' matches no module in its scopes"
+                )),
+                "{message}"
+            );
+        }
+        assert!(!message.contains("'vpc'"), "{message}");
+    }
+
+    #[test]
+    fn anchored_blocks_parse_like_their_copies() {
+        let infra = InfraFile::from_path(
+            &example("deployments/anchors.yaml"),
+            modules_dir(),
+            None,
+            true,
+        )
+        .unwrap();
+        let graph = ModuleGraph::new(&infra).unwrap();
+        let module = |id: &str| graph.get_module_by_id(id).unwrap();
+        let (compute, webapp) = (module("account-1.compute"), module("account-1.webapp"));
+        assert_eq!(
+            compute.inputs["vpc_id"],
+            InputValue::Ref {
+                path: "vpc.vpc_id".to_string()
+            }
+        );
+        assert_eq!(webapp.inputs, compute.inputs);
+
+        // `<<:` merges the anchored inputs under the module's own
+        let network = module("account-1.network");
+        let mut merged = compute.inputs.clone();
+        merged.insert(
+            "name".to_string(),
+            InputValue::Default(Value::from("worker")),
+        );
+        assert_eq!(network.inputs, merged);
+
+        let (first, second) = (
+            graph.get_scope_by_id("account-1").unwrap(),
+            graph.get_scope_by_id("account-2").unwrap(),
+        );
+        assert_eq!(second.variables, first.variables);
+        assert_eq!(first.variables["region"], Value::from("eu-west-1"));
+    }
+
+    const SOURCE_DEFAULT: &str = "
+source_default:
+  compute:
+    inputs:
+      labels:
+        team: platform
+        cost_center: '42'
+        limits: {cpu: 2, memory: 4}
+        zones: [a, b]
+acc:
+  scope: account
+  compute:
+    source: compute
+    inputs:
+      labels:
+        team: data
+        limits: {cpu: 8}
+        zones: [c]
+";
+
+    #[test]
+    fn source_default_mappings_deep_merge_under_the_module() {
+        let compute = |deep_merge| {
+            let infra =
+                InfraFile::from_reader(SOURCE_DEFAULT.as_bytes(), modules_dir(), None, deep_merge)
+                    .unwrap();
+            ModuleGraph::new(&infra)
+                .unwrap()
+                .get_module_by_id("acc.compute")
+                .unwrap()
+        };
+        // The module wins at the leaves, sequences are replaced whole
+        assert_eq!(
+            literal(&compute(true), "labels"),
+            yaml("{team: data, cost_center: '42', limits: {cpu: 8, memory: 4}, zones: [c]}")
+        );
+        assert_eq!(
+            literal(&compute(false), "labels"),
+            yaml("{team: data, limits: {cpu: 8}, zones: [c]}")
+        );
+    }
+
+    #[test]
+    fn infra_read_from_a_reader_parses_like_its_file() {
+        let path = &example("deployments/infra_example.yaml");
+        let infra = InfraFile::from_path(path, modules_dir(), None, true).unwrap();
+        let content = std::fs::read(path).unwrap();
+        let read = InfraFile::from_reader(content.as_slice(), modules_dir(), None, true).unwrap();
+        // The digest a run state is recorded with comes from the text read once
+        assert_eq!(infra.digest, Some(sha256_hex(&content)));
+        assert_eq!(read.digest, infra.digest);
+        let (from_path, from_reader) = (
+            ModuleGraph::new(&infra).unwrap(),
+            ModuleGraph::new(&read).unwrap(),
+        );
+        assert_eq!(
+            serde_json::to_value(from_reader.export()).unwrap(),
+            serde_json::to_value(from_path.export()).unwrap()
+        );
+    }
+
+    #[test]
+    fn each_module_rule_has_its_own_error() {
+        type IsRule = fn(&ModuleValidationError) -> bool;
+        let cases: [(&str, IsRule); 7] = [
+            ("source: vpc\n    id: acc.vpc", |e| {
+                matches!(e, ModuleValidationError::UserSetId)
+            }),
+            ("source: ''", |e| {
+                matches!(e, ModuleValidationError::EmptySource)
+            }),
+            (
+                "source: nowhere",
+                |e| matches!(e, ModuleValidationError::SourceDirMissing(dir) if dir.ends_with("nowhere")),
+            ),
+            (
+                "source: ../modules/vpc",
+                |e| matches!(e, ModuleValidationError::SourceOutsideModulesDir(s) if s == "../modules/vpc"),
+            ),
+            ("source: vpc\n    variables: {region: eu-west-1}", |e| {
+                matches!(e, ModuleValidationError::VariablesNotEmpty)
+            }),
+            ("source: vpc\n    scope_ids: [acc]", |e| {
+                matches!(e, ModuleValidationError::ScopeIdsSet)
+            }),
+            ("source: vpc\n    count: 2\n    for_each: [a, b]", |e| {
+                matches!(e, ModuleValidationError::CountAndForEach)
+            }),
+        ];
+        for (module, expected) in cases {
+            let yaml = format!("acc:\n  scope: account\n  vpc:\n    {module}\n");
+            let err = try_infra(&yaml).unwrap_err();
+            let infra_error = err
+                .downcast_ref::<InfraError>()
+                .unwrap_or_else(|| panic!("{module}: {err:#}"));
+            let rule = infra_error
+                .module_error()
+                .unwrap_or_else(|| panic!("{module}: {err:#}"));
+            assert!(expected(rule), "{module}: {rule:?}");
+        }
+    }
+
+    #[test]
+    fn expects_must_name_a_dependency() {
+        let err = try_infra(
+            "
+acc:
+  scope: account
+  vpc:
+    source: vpc
+  webapp:
+    source: webapp
+    expects:
+      vpc: [vpc_id]
+",
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "1 unresolved dependencies:\nexpects of module 'acc.webapp' names 'vpc', which is not one of its dependencies"
+        );
+    }
+
+    #[test]
+    fn sources_are_checked_under_the_given_modules_dir() {
+        let dir = temp_dir("modules-dir");
+        std::fs::create_dir_all(dir.join("custom")).unwrap();
+        let yaml = "
+acc:
+  scope: account
+  app:
+    source: custom
+";
+        let infra = InfraFile::from_reader(yaml.as_bytes(), &dir, None, true).unwrap();
+        assert_eq!(infra.all_modules()[0].source, "custom");
+
+        let err =
+            InfraFile::from_reader(yaml.as_bytes(), &dir.join("missing"), None, true).unwrap_err();
+        let err = err.downcast_ref::<InfraError>().unwrap();
+        assert!(
+            matches!(err.module_error(), Some(ModuleValidationError::SourceDirMissing(path)) if *path == dir.join("missing/custom")),
+            "{err}"
+        );
+    }
+}
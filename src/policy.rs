@@ -0,0 +1,251 @@
+use crate::graph::{GraphExport, GraphExportNode, GraphExportScope, ModuleGraph};
+use crate::terraform::env_value;
+use anyhow::{Context, Result, anyhow};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// A file of organizational rules checked against the resolved graph
+#[derive(Debug, Deserialize)]
+pub struct RuleFile {
+    pub rules: Vec<Rule>,
+}
+
+/// Either a built-in rule or an expression rule: every module matching all
+/// `when` terms must satisfy all `require` terms
+#[derive(Debug, Deserialize)]
+pub struct Rule {
+    pub name: String,
+    /// `no-cross-scope-dependency`, `max-dependencies` or `unique-source-per-scope`
+    #[serde(default)]
+    pub builtin: Option<String>,
+    /// Scope type for `no-cross-scope-dependency`
+    #[serde(default)]
+    pub scope: Option<String>,
+    /// Limit for `max-dependencies`
+    #[serde(default)]
+    pub limit: Option<usize>,
+    /// Terms selecting the modules the rule applies to, all of them by default
+    #[serde(default)]
+    pub when: Vec<String>,
+    #[serde(default)]
+    pub require: Vec<String>,
+}
+
+impl RuleFile {
+    pub fn from_path(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read rule file {:?}", path))?;
+        serde_yaml::from_str(&content).with_context(|| format!("Invalid rule file {:?}", path))
+    }
+}
+
+/// A term like `scope.account.environment == prod`, `tags != legacy` or `input.backend exists`
+#[derive(Debug)]
+enum Term {
+    Eq(String, String),
+    Ne(String, String),
+    Exists(String),
+    Missing(String),
+}
+
+impl Term {
+    fn parse(raw: &str) -> Result<Self> {
+        let parts: Vec<&str> = raw.trim().splitn(3, ' ').collect();
+        match parts.as_slice() {
+            [field, "exists"] => Ok(Term::Exists(field.to_string())),
+            [field, "missing"] => Ok(Term::Missing(field.to_string())),
+            [field, "==", value] => Ok(Term::Eq(field.to_string(), value.trim().to_string())),
+            [field, "!=", value] => Ok(Term::Ne(field.to_string(), value.trim().to_string())),
+            _ => Err(anyhow!(
+                "Invalid term '{}': expected '<field> ==|!= <value>' or '<field> exists|missing'",
+                raw
+            )),
+        }
+    }
+}
+
+/// Resolved graph the rules are evaluated against
+struct PolicyContext<'a> {
+    export: GraphExport,
+    graph: &'a ModuleGraph,
+}
+
+impl PolicyContext<'_> {
+    fn scope(&self, id: &str) -> Option<&GraphExportScope> {
+        self.export.scopes.iter().find(|s| s.id == id)
+    }
+
+    /// Id of the scope of the given type enclosing a module
+    fn scope_of_type(&self, node: &GraphExportNode, scope_type: &str) -> Option<String> {
+        node.scopes
+            .iter()
+            .filter_map(|id| self.scope(id))
+            .find(|s| s.scope == scope_type)
+            .map(|s| s.id.clone())
+    }
+
+    /// Values of a module field, empty when the field is missing:
+    /// `id`, `source`, `tags`, `input.<name>`, `scope.<type>` and `scope.<type>.<variable>[.<key>...]`
+    fn field(&self, node: &GraphExportNode, field: &str) -> Result<Vec<String>> {
+        let mut parts = field.splitn(3, '.');
+        let values = match (parts.next(), parts.next(), parts.next()) {
+            (Some("id"), None, None) => vec![node.id.clone()],
+            (Some("source"), None, None) => vec![node.source.clone()],
+            (Some("tags"), None, None) => node.tags.clone(),
+            (Some("input"), Some(name), None) => self
+                .graph
+                .get_module_by_id(&node.id)
+                .and_then(|m| m.inputs.get(name).map(|v| v.to_string()))
+                .into_iter()
+                .collect(),
+            (Some("scope"), Some(scope_type), None) => {
+                self.scope_of_type(node, scope_type).into_iter().collect()
+            }
+            (Some("scope"), Some(scope_type), Some(path)) => self
+                .scope_of_type(node, scope_type)
+                .and_then(|id| self.scope(&id))
+                .and_then(|scope| {
+                    let mut keys = path.split('.');
+                    let mut value = scope.variables.get(keys.next()?)?;
+                    for key in keys {
+                        value = value.get(key)?;
+                    }
+                    Some(env_value(value))
+                })
+                .into_iter()
+                .collect(),
+            _ => return Err(anyhow!("Unknown field '{}'", field)),
+        };
+        Ok(values)
+    }
+
+    fn matches(&self, node: &GraphExportNode, term: &Term) -> Result<bool> {
+        Ok(match term {
+            Term::Eq(field, value) => self.field(node, field)?.contains(value),
+            Term::Ne(field, value) => !self.field(node, field)?.contains(value),
+            Term::Exists(field) => !self.field(node, field)?.is_empty(),
+            Term::Missing(field) => self.field(node, field)?.is_empty(),
+        })
+    }
+}
+
+/// Evaluate every rule against the graph and return one line per violation
+pub fn evaluate(graph: &ModuleGraph, rules: &[Rule]) -> Result<Vec<String>> {
+    let ctx = PolicyContext {
+        export: graph.export(),
+        graph,
+    };
+    let mut violations = Vec::new();
+    for rule in rules {
+        violations
+            .extend(evaluate_rule(&ctx, rule).with_context(|| format!("in rule '{}'", rule.name))?);
+    }
+    Ok(violations)
+}
+
+fn evaluate_rule(ctx: &PolicyContext, rule: &Rule) -> Result<Vec<String>> {
+    let when = rule
+        .when
+        .iter()
+        .map(|t| Term::parse(t))
+        .collect::<Result<Vec<_>>>()?;
+    let require = rule
+        .require
+        .iter()
+        .map(|t| Term::parse(t))
+        .collect::<Result<Vec<_>>>()?;
+    if rule.builtin.is_none() && require.is_empty() {
+        return Err(anyhow!("a rule needs either `builtin` or `require`"));
+    }
+
+    let mut selected = Vec::new();
+    for node in &ctx.export.nodes {
+        if when
+            .iter()
+            .map(|t| ctx.matches(node, t))
+            .collect::<Result<Vec<_>>>()?
+            .into_iter()
+            .all(|m| m)
+        {
+            selected.push(node);
+        }
+    }
+
+    let mut violations = Vec::new();
+    for node in &selected {
+        for (raw, term) in rule.require.iter().zip(&require) {
+            if !ctx.matches(node, term)? {
+                violations.push(format!(
+                    "{}: module '{}' does not satisfy '{}'",
+                    rule.name, node.id, raw
+                ));
+            }
+        }
+    }
+
+    match rule.builtin.as_deref() {
+        None => {}
+        Some("no-cross-scope-dependency") => {
+            let scope_type = rule
+                .scope
+                .as_deref()
+                .ok_or_else(|| anyhow!("no-cross-scope-dependency needs `scope`"))?;
+            let nodes: HashMap<&str, &GraphExportNode> = ctx
+                .export
+                .nodes
+                .iter()
+                .map(|n| (n.id.as_str(), n))
+                .collect();
+            for edge in &ctx.export.edges {
+                let (Some(from), Some(to)) =
+                    (nodes.get(edge.from.as_str()), nodes.get(edge.to.as_str()))
+                else {
+                    continue;
+                };
+                if !selected.iter().any(|n| n.id == to.id) {
+                    continue;
+                }
+                let (from_scope, to_scope) = (
+                    ctx.scope_of_type(from, scope_type),
+                    ctx.scope_of_type(to, scope_type),
+                );
+                if from_scope != to_scope {
+                    violations.push(format!(
+                        "{}: module '{}' depends on '{}' across {} boundaries",
+                        rule.name, to.id, from.id, scope_type
+                    ));
+                }
+            }
+        }
+        Some("max-dependencies") => {
+            let limit = rule
+                .limit
+                .ok_or_else(|| anyhow!("max-dependencies needs `limit`"))?;
+            for node in &selected {
+                let count = ctx.export.edges.iter().filter(|e| e.to == node.id).count();
+                if count > limit {
+                    violations.push(format!(
+                        "{}: module '{}' has {} dependencies, more than {}",
+                        rule.name, node.id, count, limit
+                    ));
+                }
+            }
+        }
+        Some("unique-source-per-scope") => {
+            // Two modules of one source in the same scope make dependency resolution ambiguous
+            let mut seen: HashMap<(Option<&String>, &str), &str> = HashMap::new();
+            for node in &selected {
+                let key = (node.scopes.last(), node.source.as_str());
+                if let Some(other) = seen.insert(key, &node.id) {
+                    violations.push(format!(
+                        "{}: modules '{}' and '{}' share source '{}' in the same scope",
+                        rule.name, other, node.id, node.source
+                    ));
+                }
+            }
+        }
+        Some(other) => return Err(anyhow!("Unknown builtin rule '{}'", other)),
+    }
+    Ok(violations)
+}
@@ -0,0 +1,26 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Set when stdout is reserved for machine-readable output (`--format json`)
+static ON_STDERR: AtomicBool = AtomicBool::new(false);
+
+/// Send progress messages and echoed terraform output to stderr from now on
+pub fn use_stderr() {
+    ON_STDERR.store(true, Ordering::Relaxed);
+}
+
+pub fn on_stderr() -> bool {
+    ON_STDERR.load(Ordering::Relaxed)
+}
+
+/// `println!` for human progress messages, on stderr when stdout carries JSON
+macro_rules! progress {
+    ($($arg:tt)*) => {
+        if $crate::progress::on_stderr() {
+            eprintln!($($arg)*);
+        } else {
+            println!($($arg)*);
+        }
+    };
+}
+
+pub(crate) use progress;
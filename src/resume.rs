@@ -0,0 +1,222 @@
+use crate::manifest::sha256_hex;
+use crate::progress::progress;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+
+/// What an apply recorded of its progress
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct RunStateFile {
+    /// Digest of the infrastructure file the apply ran with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    infra_digest: Option<String>,
+    /// Modules applied so far
+    #[serde(default)]
+    completed: BTreeSet<String>,
+}
+
+/// Longest target id kept readable in a run state file name
+const MAX_NAMED_TARGET: usize = 100;
+
+/// Modules an apply completed, saved in `<cache_dir>/run-<targets>.state` when it
+/// fails so `--resume` can skip them; removed once the apply succeeds. Nothing is
+/// read unless resuming, nor written unless the apply fails.
+#[derive(Debug)]
+pub struct RunState {
+    path: PathBuf,
+    infra_digest: Option<String>,
+    /// Completed by an earlier run, skipped by this one
+    resumed: BTreeSet<String>,
+    completed: BTreeSet<String>,
+}
+
+impl RunState {
+    /// Path of the run state of an apply of these targets: `run-<module_id>.state`
+    /// for a single target, a digest of the sorted ids for several or a long one
+    pub fn path(dir: &Path, targets: &[String]) -> PathBuf {
+        let name = match targets {
+            [target] if target.len() <= MAX_NAMED_TARGET => target.clone(),
+            _ => {
+                let mut targets = targets.to_vec();
+                targets.sort();
+                sha256_hex(targets.join("\n").as_bytes())[..32].to_string()
+            }
+        };
+        dir.join(format!("run-{}.state", name))
+    }
+
+    /// Start recording at `path`; with `resume`, keep the modules a run of the same
+    /// infrastructure file completed, unless it changed since
+    pub fn load(path: &Path, infra_digest: Option<String>, resume: bool) -> Result<Self> {
+        let mut resumed = BTreeSet::new();
+        if resume && path.is_file() {
+            let content = std::fs::read(path)
+                .with_context(|| format!("Failed to read run state {:?}", path))?;
+            let recorded: RunStateFile = serde_json::from_slice(&content)
+                .with_context(|| format!("Invalid run state {:?}", path))?;
+            if recorded.infra_digest == infra_digest {
+                resumed = recorded.completed;
+            } else {
+                progress!(
+                    "Warning: the infrastructure file changed since {:?} was recorded, nothing is resumed",
+                    path
+                );
+            }
+        } else if resume {
+            progress!("No run state at {:?}, nothing is resumed", path);
+        }
+        Ok(Self {
+            path: path.to_path_buf(),
+            infra_digest,
+            completed: resumed.clone(),
+            resumed,
+        })
+    }
+
+    /// Whether an earlier run completed the module
+    pub fn is_resumed(&self, id: &str) -> bool {
+        self.resumed.contains(id)
+    }
+
+    pub fn record_completed(&mut self, id: &str) {
+        self.completed.insert(id.to_string());
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let state = RunStateFile {
+            infra_digest: self.infra_digest.clone(),
+            completed: self.completed.clone(),
+        };
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create run state dir {:?}", parent))?;
+        }
+        std::fs::write(&self.path, serde_json::to_string_pretty(&state)?)
+            .with_context(|| format!("Failed to write run state {:?}", self.path))
+    }
+
+    /// Forget the run state once the apply succeeded
+    pub fn remove(&self) -> Result<()> {
+        match std::fs::remove_file(&self.path) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+                Err(err).with_context(|| format!("Failed to remove run state {:?}", self.path))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::{RunOptions, Runtime};
+    use crate::scripted::{Command, ScriptedRunner};
+    use crate::terraform::TerraformAction;
+    use crate::testing::{infra, temp_dir};
+    use std::sync::Arc;
+
+    const LAYERS: &str = "
+acc:
+  scope: account
+  vpc:
+    source: vpc
+  compute:
+    source: compute
+    dependencies: [vpc]
+  webapp:
+    source: webapp
+    dependencies: [compute]
+";
+
+    fn options(dir: &Path, resume: bool, digest: &str) -> RunOptions {
+        RunOptions {
+            with_deps: true,
+            run_state_dir: Some(dir.to_path_buf()),
+            resume,
+            infra_digest: Some(digest.to_string()),
+            ..Default::default()
+        }
+    }
+
+    async fn apply(runner: &Arc<ScriptedRunner>, options: RunOptions) -> Result<()> {
+        let runtime = Runtime::new(runner.clone(), &infra(LAYERS), options)?;
+        runtime
+            .run_module("acc.webapp", TerraformAction::Apply)
+            .await
+            .map(|_| ())
+    }
+
+    #[tokio::test]
+    async fn resumed_apply_skips_the_modules_the_failed_one_completed() {
+        let dir = temp_dir("resume");
+        let failing = Arc::new(
+            ScriptedRunner::builder()
+                .fail("acc.webapp", Command::Apply, "boom")
+                .build(),
+        );
+        assert!(apply(&failing, options(&dir, false, "d1")).await.is_err());
+        let path = RunState::path(&dir, &["acc.webapp".to_string()]);
+        assert!(path.is_file());
+
+        let resumed = Arc::new(ScriptedRunner::default());
+        apply(&resumed, options(&dir, true, "d1")).await.unwrap();
+        assert_eq!(resumed.finished_on(Command::Apply), ["acc.webapp"]);
+        // Completed modules only have their outputs read, without init
+        assert_eq!(resumed.calls("acc.vpc", Command::Init), 0);
+        assert_eq!(resumed.calls("acc.vpc", Command::Output), 1);
+        assert_eq!(resumed.calls("acc.compute", Command::Output), 1);
+        assert!(!path.exists(), "a successful apply removes its run state");
+    }
+
+    #[tokio::test]
+    async fn run_state_of_another_infra_file_is_not_resumed() {
+        let dir = temp_dir("resume-digest");
+        let failing = Arc::new(
+            ScriptedRunner::builder()
+                .fail("acc.webapp", Command::Apply, "boom")
+                .build(),
+        );
+        assert!(apply(&failing, options(&dir, false, "d1")).await.is_err());
+
+        let rerun = Arc::new(ScriptedRunner::default());
+        apply(&rerun, options(&dir, true, "d2")).await.unwrap();
+        assert_eq!(
+            rerun.finished_on(Command::Apply),
+            ["acc.vpc", "acc.compute", "acc.webapp"]
+        );
+    }
+
+    #[tokio::test]
+    async fn unwritable_run_state_keeps_the_apply_error() {
+        let dir = temp_dir("resume-unwritable");
+        // A file where the run state dir should be makes saving fail
+        let blocked = dir.join("blocked");
+        std::fs::write(&blocked, "").unwrap();
+        let failing = Arc::new(
+            ScriptedRunner::builder()
+                .fail("acc.webapp", Command::Apply, "boom")
+                .build(),
+        );
+        let err = apply(&failing, options(&blocked, false, "d1"))
+            .await
+            .unwrap_err();
+        assert!(format!("{err:#}").contains("boom"), "{err:#}");
+    }
+
+    #[test]
+    fn run_state_names_stay_short() {
+        let dir = Path::new("cache");
+        assert_eq!(
+            RunState::path(dir, &["acc.webapp".to_string()]),
+            dir.join("run-acc.webapp.state")
+        );
+        let many: Vec<String> = (0..500).map(|i| format!("acc.module-{i}")).collect();
+        let mut reversed = many.clone();
+        reversed.reverse();
+        let path = RunState::path(dir, &many);
+        assert_eq!(path, RunState::path(dir, &reversed));
+        assert!(path.file_name().unwrap().len() < 64);
+        assert_ne!(path, RunState::path(dir, &many[1..]));
+    }
+}
@@ -1,207 +1,3674 @@
-use crate::graph::ModuleGraph;
-use crate::parser::{InfraFile, InputValue, ModuleNode};
-use crate::terraform::{RunTerraformCommand, TerraformAction};
+use crate::audit::{AuditEntry, AuditLog, redact_outputs};
+use crate::graph::{ModuleGraph, nearest_scope_id};
+use crate::incremental::{IncrementalState, fingerprint};
+use crate::manifest::{PlanManifest, SIGNING_KEY_ENV};
+use crate::parser::{Dependency, InfraFile, InputValue, ModuleNode, self_input};
+use crate::progress::progress;
+use crate::resume::RunState;
+use crate::secrets::SecretStore;
+use crate::terraform::{
+    ChangeCounts, PlanResult, RunTerraformCommand, TerraformAction, TerraformWarning, env_value,
+    json_to_value, module_path_component,
+};
 use anyhow::{Context, Result, anyhow};
 use futures::future::join_all;
+use futures::stream::{self, FuturesUnordered, StreamExt};
+use serde::Serialize;
 use serde_yaml::Value;
-use std::collections::HashMap;
-use std::sync::Arc;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use thiserror::Error;
+use tokio::sync::{Semaphore, watch};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 enum PathSegment {
     Key(String),
     Index(usize),
 }
+
+/// A module an interrupt kept from starting while it waited for a slot, not a failure
+#[derive(Debug, Error)]
+#[error("interrupted before module '{0}' started")]
+struct NotStarted(String);
+
+/// Scope ids attached to each scheduled layer
+type ScopesPerLayer = Vec<Vec<String>>;
+
+/// Layer number (1-based) and what each of its modules does
+type ScheduledLayer = (usize, Vec<(String, Step)>);
+
+/// What a run does with a scheduled module
+#[derive(Debug, Clone, Copy)]
+enum Step {
+    /// Only read the outputs of an already applied module
+    Outputs,
+    /// Init and read outputs without changing anything
+    Prepare,
+    /// Init, run the action, then read outputs
+    Run(TerraformAction),
+}
+/// Options controlling how a run is executed
+#[derive(Debug, Clone, Default)]
+pub struct RunOptions {
+    /// Directory where the target's plan is saved as `<module_id>.tfplan`
+    pub plan_out: Option<PathBuf>,
+    /// Directory where every planned module gets `<module_id>.tfplan` and its
+    /// `terraform show -json` rendering `<module_id>.json`
+    pub plan_out_dir: Option<PathBuf>,
+    /// Saved plan file applied to the target instead of planning again
+    pub plan_in: Option<PathBuf>,
+    /// First layer to run (1-based); earlier layers only have their outputs read
+    pub from_layer: Option<usize>,
+    /// Last layer to run (1-based)
+    pub to_layer: Option<usize>,
+    /// Run the action on every dependency too, not only read their outputs
+    pub with_deps: bool,
+    /// List every input of a module that does not resolve, not only the first
+    pub collect_errors: bool,
+    /// Plan every dependency instead of reading its outputs only, for an apply of the
+    /// target; a dependency with changes fails the run
+    pub plan_only_deps: bool,
+    /// Under `plan_only_deps`, let dependency changes through
+    pub ignore_dep_drift: bool,
+    /// Run every action one at a time (implies `with_deps`), initializing up front and
+    /// concurrently the modules none of whose dependencies has to run first
+    pub serial_global_apply: bool,
+    /// JSONL file receiving one audit entry per apply/destroy
+    pub audit_log: Option<PathBuf>,
+    /// Check terraform version constraints of every module before running
+    pub check_versions: bool,
+    /// Check injected variables against the variables each module declares
+    pub check_variables: bool,
+    /// Like `check_variables`, but undeclared inputs are only warned about
+    pub check_inputs: bool,
+    /// Init and `terraform validate` every module the action runs on before running
+    pub validate_modules: bool,
+    /// Also reject modules `terraform fmt -check -recursive` fails on
+    pub check_fmt: bool,
+    /// Run the action on every dependency but skip the target (implies `with_deps`)
+    pub deps_only: bool,
+    /// Print why each module landed in its layer before running
+    pub explain_layers: bool,
+    /// JSON file of `{ module_id: terraform output -json }` preseeding dependency outputs
+    pub load_outputs: Option<PathBuf>,
+    /// Run dependencies even when their outputs were preloaded
+    pub force: bool,
+    /// Run only the target, with the outputs its dependencies cached in an earlier run
+    pub only: bool,
+    /// Read the outputs a dependency has cached in an earlier invocation instead of
+    /// initializing it again
+    pub use_cached_outputs: bool,
+    /// Use the `mocked_outputs` of a dependency that has no outputs yet
+    pub allow_mock_fallback: bool,
+    /// Resource addresses the target's plan or apply is limited to (`-target`)
+    pub tf_targets: Vec<String>,
+    /// Let every module of a layer finish when one fails, instead of cancelling the others
+    pub keep_going: bool,
+    /// Let terraform ask for approval of the target's apply or destroy
+    pub no_auto_approve: bool,
+    /// Ask for approval of the dependencies' apply or destroy too
+    pub confirm_deps: bool,
+    /// State file of incremental applies: a module is only applied again when its
+    /// source, its variables or the outputs of one of its dependencies changed
+    pub incremental_state: Option<PathBuf>,
+    /// Directory of the run state of an apply, recording the modules it completed
+    /// until it succeeds
+    pub run_state_dir: Option<PathBuf>,
+    /// Skip the modules the last failed apply of the same targets completed, only
+    /// reading their outputs
+    pub resume: bool,
+    /// Digest of the infrastructure file, a run state recorded with another one is
+    /// not resumed
+    pub infra_digest: Option<String>,
+    /// Most modules running at once, the number of CPUs by default
+    pub concurrency: Option<usize>,
+    /// Variables from `--var-file` and `--var`, given to every module that does
+    /// not set them itself
+    pub global_variables: HashMap<String, Value>,
+}
+
+/// Where the action stands on a module
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ModuleStatus {
+    Pending,
+    Running,
+    Succeeded,
+    Failed,
+    /// Stopped, or never started, because another module of its layer failed
+    Cancelled,
+    /// Not applied by `--incremental`: unchanged since its last apply
+    Unchanged,
+}
+
+/// A module the action runs on, as reported by `--format json`
+#[derive(Debug, Clone, Serialize)]
+pub struct ModuleRun {
+    pub id: String,
+    /// 1-based execution layer
+    pub layer: usize,
+    pub status: ModuleStatus,
+    /// Time the action took, once finished
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duration_ms: Option<u64>,
+    /// Resolved inputs the action ran with, sensitive values redacted; empty while pending
+    pub inputs: BTreeMap<String, Value>,
+}
+
+/// Wall-clock time a module spent in each terraform step, summed over the run
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ModuleTiming {
+    pub id: String,
+    /// 1-based execution layer
+    pub layer: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub init_ms: Option<u64>,
+    /// plan, apply, destroy or refresh
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub action_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub output_ms: Option<u64>,
+}
+
+impl ModuleTiming {
+    pub fn total_ms(&self) -> u64 {
+        [self.init_ms, self.action_ms, self.output_ms]
+            .into_iter()
+            .flatten()
+            .sum()
+    }
+}
+
+/// Wall-clock time a layer took to run, scope hooks excluded
+#[derive(Debug, Clone, Serialize)]
+pub struct LayerTiming {
+    /// 1-based execution layer
+    pub layer: usize,
+    pub duration_ms: u64,
+}
+
+/// Time spent by every module that ran a terraform step, and by every layer
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct TimingReport {
+    /// In execution order
+    pub modules: Vec<ModuleTiming>,
+    /// In layer order
+    pub layers: Vec<LayerTiming>,
+}
+
+/// Summary of a finished run
+#[derive(Debug, Default)]
+pub struct RunReport {
+    /// Terraform warnings of every module, in the order they were reported
+    pub warnings: Vec<TerraformWarning>,
+    /// Modules whose plan has changes to apply, sorted
+    pub changes: Vec<String>,
+    /// Resource actions of the plans with changes, by module, when they could be counted
+    pub change_counts: BTreeMap<String, ChangeCounts>,
+}
+
 #[derive(Debug)]
 pub struct Runtime {
     pub runner: Arc<dyn RunTerraformCommand + Send + Sync>,
     pub graph: ModuleGraph,
+    pub options: RunOptions,
+    pub secrets: Arc<SecretStore>,
+    pub audit: Option<AuditLog>,
+    pub incremental: Option<IncrementalState>,
+    /// Run state of the current apply, with `run_state_dir`
+    pub run_state: Mutex<Option<RunState>>,
+    /// Permits of the modules allowed to run at once
+    pub slots: Arc<Semaphore>,
+    /// Modules planned so far whose plan has changes
+    pub planned_changes: Mutex<BTreeMap<String, Option<ChangeCounts>>>,
+    /// Modules the action runs on, by id
+    pub runs: Mutex<BTreeMap<String, ModuleRun>>,
+    /// Step timings of every scheduled module, by id
+    pub timings: Mutex<BTreeMap<String, ModuleTiming>>,
+    /// Time spent running each layer, by layer number
+    pub layer_timings: Mutex<BTreeMap<usize, u64>>,
+    /// Ctrl-C presses received while running: the first stops scheduling modules,
+    /// the second kills the running ones
+    pub interrupts: watch::Sender<u32>,
 }
 
 impl Runtime {
     pub fn new(
         runner: Arc<dyn RunTerraformCommand + Send + Sync>,
         infra: &InfraFile,
+        options: RunOptions,
     ) -> Result<Self> {
         let graph = ModuleGraph::new(infra).context("While building dependency graph")?;
-        Ok(Self { runner, graph })
+        let audit = options.audit_log.clone().map(AuditLog::new);
+        let incremental = options
+            .incremental_state
+            .as_deref()
+            .map(IncrementalState::load)
+            .transpose()?;
+        let concurrency = options.concurrency.unwrap_or_else(default_concurrency);
+        Ok(Self {
+            runner,
+            graph,
+            options,
+            secrets: Arc::new(SecretStore::new()),
+            audit,
+            incremental,
+            run_state: Mutex::new(None),
+            slots: Arc::new(Semaphore::new(concurrency)),
+            planned_changes: Mutex::new(BTreeMap::new()),
+            runs: Mutex::new(BTreeMap::new()),
+            timings: Mutex::new(BTreeMap::new()),
+            layer_timings: Mutex::new(BTreeMap::new()),
+            interrupts: watch::Sender::new(0),
+        })
     }
 
     /// Execute a target module and all its dependencies in correct graph order
-    pub async fn run_module(&self, module_id: &str, action: TerraformAction) -> Result<()> {
-        let (layers, target) = self.graph.execution_layers(module_id)?;
-        let mut outputs_map: HashMap<String, HashMap<String, Value>> = HashMap::new();
+    pub async fn run_module(&self, module_id: &str, action: TerraformAction) -> Result<RunReport> {
+        self.run_modules(&[module_id.to_string()], action).await
+    }
 
-        for layer in layers {
-            // Run all modules in this layer in parallel
-            let futures = layer.into_iter().map(|id| {
-                let runner = Arc::clone(&self.runner);
-                let graph = self.graph.clone();
-                let mut module = graph.get_module_by_id(&id).unwrap();
-                let outputs_map = outputs_map.clone();
-                async move {
-                    inject_inputs(&mut module, &outputs_map, &graph)?;
-                    runner.init(&module).await?;
-                    let outputs = runner.output(&module).await?;
-                    Ok::<(String, HashMap<String, Value>), anyhow::Error>((id, outputs))
+    /// Execute several targets and the union of all their dependencies in graph order
+    pub async fn run_modules(
+        &self,
+        targets: &[String],
+        action: TerraformAction,
+    ) -> Result<RunReport> {
+        let layers = if self.options.only {
+            if targets.len() != 1 {
+                return Err(anyhow!("--only runs a single target"));
+            }
+            vec![targets.to_vec()]
+        } else {
+            self.graph.closure_layers(targets)?
+        };
+        if self.options.explain_layers {
+            self.print_layer_explanation(&layers)?;
+        }
+        let target_set: HashSet<&str> = targets.iter().map(String::as_str).collect();
+        if let Some(plan_in) = &self.options.plan_in {
+            if self.options.deps_only {
+                return Err(anyhow!(
+                    "A saved plan only applies to the target, which --deps-only skips"
+                ));
+            }
+            if targets.len() > 1 {
+                return Err(anyhow!("A saved plan only applies to a single target"));
+            }
+            if self.incremental.is_some() {
+                return Err(anyhow!(
+                    "A saved plan is always applied, drop --incremental"
+                ));
+            }
+            // Reject a missing saved plan before spending time on dependencies
+            if !plan_in.is_file() {
+                return Err(anyhow!("Saved plan file {:?} does not exist", plan_in));
+            }
+        }
+        let last_layer = layers.len();
+        let from_layer = self.options.from_layer.unwrap_or(1);
+        let to_layer = self.options.to_layer.unwrap_or(last_layer);
+        if from_layer < 1 || from_layer > to_layer || to_layer > last_layer {
+            return Err(anyhow!(
+                "Invalid layer range {}..{}: '{}' has layers 1..{}",
+                from_layer,
+                to_layer,
+                targets.join(", "),
+                last_layer
+            ));
+        }
+        if self.options.plan_only_deps && !matches!(action, TerraformAction::Apply) {
+            return Err(anyhow!(
+                "--plan-only-deps only runs with apply, not {}",
+                action.name()
+            ));
+        }
+        // Dependencies only get their outputs read unless asked to run the action too
+        let dep_step = if self.options.plan_only_deps {
+            Step::Run(TerraformAction::Plan)
+        } else if self.options.with_deps
+            || self.options.serial_global_apply
+            || self.options.deps_only
+        {
+            Step::Run(action)
+        } else {
+            Step::Prepare
+        };
+        let mut depended_on: HashSet<String> = HashSet::new();
+        for id in layers.iter().flatten() {
+            let module = self.module(id)?;
+            let entries = module.dependencies.into_iter().chain(module.order_after);
+            depended_on.extend(entries.map(|d| d.id));
+        }
+        let mut preloaded = match &self.options.load_outputs {
+            Some(path) => load_outputs(path)?,
+            None => HashMap::new(),
+        };
+        if self.options.only {
+            preloaded.extend(self.only_dependency_outputs(&targets[0]).await?);
+        }
+        if self.options.resume && !matches!(action, TerraformAction::Apply) {
+            return Err(anyhow!("--resume only applies to apply"));
+        }
+        let run_state = match &self.options.run_state_dir {
+            Some(dir) if matches!(action, TerraformAction::Apply) => Some(RunState::load(
+                &RunState::path(dir, targets),
+                self.options.infra_digest.clone(),
+                self.options.resume,
+            )?),
+            _ => None,
+        };
+        let mut scheduled: Vec<ScheduledLayer> = Vec::new();
+        let mut skipped: Vec<String> = Vec::new();
+        for (layer_no, layer) in (1..).zip(layers) {
+            if layer_no > to_layer {
+                break;
+            }
+            // Layers before the range must already be applied, only read their outputs
+            let outputs_only = layer_no < from_layer;
+            if outputs_only {
+                progress!("Layer {}: reading outputs only", layer_no);
+            }
+            let mut entries = Vec::new();
+            for id in layer {
+                // Preloaded dependencies are satisfied, neither init nor output is needed
+                if !self.options.force
+                    && !target_set.contains(id.as_str())
+                    && preloaded.contains_key(&id)
+                {
+                    progress!("Layer {}: '{}' outputs preloaded, skipped", layer_no, id);
+                    continue;
                 }
-            });
+                // Only applied modules are recorded, whatever their role in this run
+                let resumed = run_state.as_ref().is_some_and(|s| s.is_resumed(&id));
+                let step = if outputs_only {
+                    Step::Outputs
+                } else if resumed {
+                    progress!(
+                        "Layer {}: '{}' applied by the failed run, reading outputs only (--resume)",
+                        layer_no,
+                        id
+                    );
+                    Step::Outputs
+                } else if !target_set.contains(id.as_str()) {
+                    dep_step
+                } else if !self.options.deps_only {
+                    Step::Run(action)
+                } else if depended_on.contains(&id) {
+                    // Under --deps-only a target still runs when another target needs it
+                    dep_step
+                } else {
+                    skipped.push(id);
+                    continue;
+                };
+                entries.push((id, step));
+            }
+            if !entries.is_empty() {
+                scheduled.push((layer_no, entries));
+            }
+        }
 
-            let results = join_all(futures).await;
-            for res in results {
-                let (id, outputs) = res?;
-                outputs_map.insert(id, outputs);
+        self.runs.lock().unwrap().extend(
+            scheduled
+                .iter()
+                .flat_map(|(layer_no, layer)| layer.iter().map(move |entry| (*layer_no, entry)))
+                .filter(|(_, (_, step))| matches!(step, Step::Run(_)))
+                .map(|(layer, (id, _))| {
+                    let run = ModuleRun {
+                        id: id.clone(),
+                        layer,
+                        status: ModuleStatus::Pending,
+                        duration_ms: None,
+                        inputs: BTreeMap::new(),
+                    };
+                    (id.clone(), run)
+                }),
+        );
+        self.timings.lock().unwrap().extend(
+            scheduled
+                .iter()
+                .flat_map(|(layer_no, layer)| layer.iter().map(move |(id, _)| (*layer_no, id)))
+                .map(|(layer, id)| {
+                    let timing = ModuleTiming {
+                        id: id.clone(),
+                        layer,
+                        ..Default::default()
+                    };
+                    (id.clone(), timing)
+                }),
+        );
+        if self.options.check_versions {
+            self.check_versions(&scheduled).await?;
+        }
+        if self.options.check_variables || self.options.check_inputs {
+            self.check_variables(&scheduled, self.options.check_variables)
+                .await?;
+        }
+        if self.options.validate_modules {
+            self.validate_modules(&scheduled).await?;
+        }
+        *self.run_state.lock().unwrap() = run_state;
+        let (enter, leave) = self.scope_spans(&scheduled)?;
+        let listener = self.listen_for_interrupts();
+        let result = if matches!(action, TerraformAction::Destroy) {
+            self.run_destroy(&scheduled, &target_set, preloaded).await
+        } else if self.options.serial_global_apply {
+            self.run_serially(&scheduled, &target_set, &enter, &leave, preloaded)
+                .await
+        } else {
+            self.run_layers(&scheduled, &target_set, &enter, &leave, preloaded)
+                .await
+                .map(|_| ())
+        };
+        listener.abort();
+        // Keep what was applied before a failure so it is not applied again; failing
+        // to keep it must not hide how the run ended
+        if let Some(Err(err)) = self.incremental.as_ref().map(IncrementalState::save) {
+            progress!("Warning: {:#}", err);
+        }
+        if let Some(state) = self.run_state.lock().unwrap().take() {
+            let kept = match &result {
+                Ok(()) => state.remove(),
+                Err(_) => state.save(),
+            };
+            if let Err(err) = kept {
+                progress!("Warning: {:#}", err);
             }
         }
+        result?;
 
-        // Finally, run the target module
-        let mut target_module = self
-            .graph
-            .get_module_by_id(&module_id)
-            .ok_or_else(|| anyhow!("Target module not found: {}", target))?;
-        inject_inputs(&mut target_module, &outputs_map, &self.graph)?;
-        self.runner.init(&target_module).await?;
-        let _outputs = self.runner.output(&target_module).await?;
-        self.runner.apply(&target_module).await?;
+        let ran: Vec<&str> = scheduled
+            .iter()
+            .flat_map(|(_, layer)| layer)
+            .filter(|(_, step)| matches!(step, Step::Run(_)))
+            .map(|(id, _)| id.as_str())
+            .collect();
+        if self.options.deps_only {
+            progress!(
+                "Ran {} on {} dependencies: {}",
+                action.name(),
+                ran.len(),
+                ran.join(", ")
+            );
+            progress!("Target '{}' skipped (--deps-only)", skipped.join("', '"));
+        } else if to_layer < last_layer {
+            let not_run: Vec<&str> = targets
+                .iter()
+                .map(String::as_str)
+                .filter(|id| !ran.contains(id))
+                .collect();
+            progress!(
+                "Stopped after layer {}, target '{}' was not run",
+                to_layer,
+                not_run.join("', '")
+            );
+        }
+        let planned = std::mem::take(&mut *self.planned_changes.lock().unwrap());
+        Ok(RunReport {
+            warnings: self.runner.take_warnings(),
+            changes: planned.keys().cloned().collect(),
+            change_counts: planned
+                .into_iter()
+                .filter_map(|(id, counts)| Some((id, counts?)))
+                .collect(),
+        })
+    }
+
+    /// Run the layers one after the other, the modules of a layer in parallel
+    async fn run_layers(
+        &self,
+        scheduled: &[ScheduledLayer],
+        targets: &HashSet<&str>,
+        enter: &[Vec<String>],
+        leave: &[Vec<String>],
+        mut outputs_map: HashMap<String, HashMap<String, Value>>,
+    ) -> Result<HashMap<String, HashMap<String, Value>>> {
+        for (pos, (layer_no, layer)) in scheduled.iter().enumerate() {
+            self.check_interrupted(*layer_no)?;
+            self.run_scope_hooks(&enter[pos], true).await?;
+            let started = Instant::now();
+            let futures = layer.iter().map(|(id, step)| {
+                let run =
+                    self.execute_module(id, *step, &outputs_map, targets.contains(id.as_str()));
+                (id.as_str(), run)
+            });
+            let results = self.join_layer(futures).await;
+            self.record_layer_timing(*layer_no, started);
+            outputs_map.extend(results?);
+            self.run_scope_hooks(&leave[pos], false).await?;
+        }
+        Ok(outputs_map)
+    }
+
+    /// Destroy in reverse dependency order: first prepare every module layer by layer
+    /// so all variables resolve, then destroy the last layer first, so a module is
+    /// always destroyed before the modules it depends on
+    async fn run_destroy(
+        &self,
+        scheduled: &[ScheduledLayer],
+        targets: &HashSet<&str>,
+        outputs_map: HashMap<String, HashMap<String, Value>>,
+    ) -> Result<()> {
+        let prepare: Vec<ScheduledLayer> = scheduled
+            .iter()
+            .map(|(layer_no, layer)| {
+                let layer = layer
+                    .iter()
+                    .map(|(id, step)| match step {
+                        Step::Run(_) => (id.clone(), Step::Prepare),
+                        step => (id.clone(), *step),
+                    })
+                    .collect();
+                (*layer_no, layer)
+            })
+            .collect();
+        let no_hooks = vec![Vec::new(); prepare.len()];
+        let outputs_map = self
+            .run_layers(&prepare, targets, &no_hooks, &no_hooks, outputs_map)
+            .await?;
+
+        // Scope hooks surround the destroys, in the reversed order
+        let reversed: Vec<ScheduledLayer> = scheduled
+            .iter()
+            .rev()
+            .filter_map(|(layer_no, layer)| {
+                let layer: Vec<(String, Step)> = layer
+                    .iter()
+                    .filter(|(_, step)| matches!(step, Step::Run(_)))
+                    .cloned()
+                    .collect();
+                (!layer.is_empty()).then_some((*layer_no, layer))
+            })
+            .collect();
+        let (enter, leave) = self.scope_spans(&reversed)?;
+        for (pos, (layer_no, layer)) in reversed.iter().enumerate() {
+            self.check_interrupted(*layer_no)?;
+            self.run_scope_hooks(&enter[pos], true).await?;
+            progress!("Layer {}: destroying", layer_no);
+            let started = Instant::now();
+            let result = if self.options.serial_global_apply {
+                let mut result = Ok(());
+                for (id, _) in layer {
+                    result = self
+                        .destroy_module(id, &outputs_map, targets.contains(id.as_str()))
+                        .await;
+                    if result.is_err() {
+                        break;
+                    }
+                }
+                result
+            } else {
+                let futures = layer.iter().map(|(id, _)| {
+                    let run = self.destroy_module(id, &outputs_map, targets.contains(id.as_str()));
+                    (id.as_str(), run)
+                });
+                self.join_layer(futures).await.map(|_| ())
+            };
+            self.record_layer_timing(*layer_no, started);
+            result?;
+            self.run_scope_hooks(&leave[pos], false).await?;
+        }
         Ok(())
     }
-}
 
-/// Inject resolved inputs into a Terraform module before execution
-fn inject_inputs(
-    module: &mut ModuleNode,
-    outputs_map: &HashMap<String, HashMap<String, Value>>,
-    graph: &ModuleGraph,
-) -> Result<()> {
-    for (key, val) in &module.inputs {
-        let resolved = match val {
-            InputValue::Default(v) => v.clone(),
-            InputValue::Ref { path } => resolve_ref(path, module, outputs_map, graph)?
-                .ok_or_else(|| anyhow!("Reference '{}' not found", path))?,
-            InputValue::RefWithDefault { path, default } => {
-                resolve_ref(path, module, outputs_map, graph)?.unwrap_or(default.clone())
+    /// Await the modules of a layer. The first failure cancels the modules still
+    /// running, unless `--keep-going` lets every module finish first.
+    async fn join_layer<'a, T, F>(
+        &self,
+        modules: impl IntoIterator<Item = (&'a str, F)>,
+    ) -> Result<Vec<T>>
+    where
+        F: Future<Output = Result<T>>,
+    {
+        let mut unfinished: BTreeSet<&str> = BTreeSet::new();
+        let mut pending: FuturesUnordered<_> = modules
+            .into_iter()
+            .map(|(id, run)| {
+                unfinished.insert(id);
+                async move { (id, run.await) }
+            })
+            .collect();
+        let mut done = Vec::new();
+        let mut failures: Vec<(&str, anyhow::Error)> = Vec::new();
+        let mut not_started: Vec<&str> = Vec::new();
+        loop {
+            let next = tokio::select! {
+                next = pending.next() => next,
+                () = self.killed() => {
+                    // Dropping the futures kills their terraform commands
+                    drop(pending);
+                    self.cancel_runs(&unfinished);
+                    let cancelled: Vec<&str> = unfinished.into_iter().collect();
+                    return Err(anyhow!("Interrupted twice, cancelled '{}'", cancelled.join("', '")));
+                }
+            };
+            let Some((id, result)) = next else {
+                break;
+            };
+            unfinished.remove(id);
+            match result {
+                Ok(value) => done.push(value),
+                Err(err) if err.is::<NotStarted>() => not_started.push(id),
+                Err(err) if self.options.keep_going => failures.push((id, err)),
+                Err(err) => {
+                    // Dropping the futures kills their terraform commands
+                    drop(pending);
+                    if unfinished.is_empty() {
+                        return Err(err.context(format!("module '{}' failed", id)));
+                    }
+                    self.cancel_runs(&unfinished);
+                    let cancelled: Vec<&str> = unfinished.into_iter().collect();
+                    return Err(err.context(format!(
+                        "module '{}' failed, cancelled '{}'",
+                        id,
+                        cancelled.join("', '")
+                    )));
+                }
+            }
+        }
+        failures.sort_by_key(|(id, _)| *id);
+        let mut failures = failures.into_iter();
+        let Some((id, err)) = failures.next() else {
+            if !not_started.is_empty() {
+                not_started.sort();
+                return Err(anyhow!(
+                    "Interrupted, '{}' were not started",
+                    not_started.join("', '")
+                ));
             }
+            return Ok(done);
         };
-        module.variables.insert(key.clone(), resolved);
+        let others: Vec<&str> = failures.map(|(id, _)| id).collect();
+        Err(match others.is_empty() {
+            true => err.context(format!("module '{}' failed", id)),
+            false => err.context(format!(
+                "module '{}' failed, and so did '{}'",
+                id,
+                others.join("', '")
+            )),
+        })
     }
-    Ok(())
-}
 
-/// Resolve a Terraform-style reference like "vpc.subnets[0]" or "region.id"
-fn resolve_ref(
-    path: &str,
-    module: &ModuleNode,
-    outputs_map: &HashMap<String, HashMap<String, Value>>,
-    graph: &ModuleGraph,
-) -> Result<Option<Value>> {
-    let mut parts = path.splitn(2, '.');
-    let first = parts.next().unwrap();
-    let rest = parts.next().unwrap_or("");
-    // 1️⃣ Dependency reference (vpc.subnets[0])
-    if let Some(dep) = module.dependencies.iter().find(|dep| dep.name == first) {
-        let dep_outputs = outputs_map
-            .get(&dep.id)
-            .ok_or_else(|| anyhow!("Outputs missing for '{}'", dep.id))?;
+    fn cancel_runs(&self, ids: &BTreeSet<&str>) {
+        let mut runs = self.runs.lock().unwrap();
+        for id in ids {
+            if let Some(run) = runs.get_mut(*id) {
+                run.status = ModuleStatus::Cancelled;
+            }
+        }
+    }
 
-        let yaml = Value::Mapping(
-            dep_outputs
-                .iter()
-                .map(|(k, v)| (Value::String(k.clone()), v.clone()))
-                .collect(),
-        );
+    /// Count Ctrl-C presses in `interrupts` until aborted. Terraform gets the signal
+    /// too and stops gracefully, saving its state.
+    fn listen_for_interrupts(&self) -> tokio::task::JoinHandle<()> {
+        let interrupts = self.interrupts.clone();
+        tokio::spawn(async move {
+            while tokio::signal::ctrl_c().await.is_ok() {
+                interrupts.send_modify(|count| *count += 1);
+                if *interrupts.borrow() == 1 {
+                    progress!(
+                        "Interrupted: waiting for the running modules to finish, none is started anymore; interrupt again to kill them"
+                    );
+                } else {
+                    progress!("Interrupted again: killing the running terraform commands");
+                }
+            }
+        })
+    }
 
-        let segments = parse_path(rest);
-        let resolved = get_value_from_path(&yaml, &segments)
-            .ok_or_else(|| anyhow!("Cannot resolve '{}'", path))?;
-        return Ok(Some(resolved));
+    /// Refuse to start a layer once interrupted
+    fn check_interrupted(&self, layer_no: usize) -> Result<()> {
+        if *self.interrupts.borrow() > 0 {
+            return Err(anyhow!(
+                "Interrupted, layer {} and the ones after it were not started",
+                layer_no
+            ));
+        }
+        Ok(())
     }
 
-    // 2️⃣ Scope variable (from ancestor scopes)
-    if let Some(val) = find_scope_variable(module, first, rest, graph) {
-        return Ok(Some(val));
+    /// Resolve once interrupted twice
+    async fn killed(&self) {
+        let mut interrupts = self.interrupts.subscribe();
+        if interrupts.wait_for(|count| *count >= 2).await.is_err() {
+            std::future::pending::<()>().await;
+        }
     }
 
-    Ok(None)
-}
+    /// Print every layer with, for each module, the dependencies from the previous
+    /// layer that kept it from running earlier
+    fn print_layer_explanation(&self, layers: &[Vec<String>]) -> Result<()> {
+        for (layer_no, layer) in (1..).zip(self.graph.explain_layers(layers)?) {
+            progress!("Layer {}:", layer_no);
+            for (id, forced_by) in layer {
+                if forced_by.is_empty() {
+                    progress!("  {} (no dependency in an earlier layer)", id);
+                } else {
+                    progress!("  {} <- waits for {}", id, forced_by.join(", "));
+                }
+            }
+        }
+        Ok(())
+    }
 
-/// Lookup a scope variable by traversing parent scopes upward
-fn find_scope_variable(
-    module: &ModuleNode,
-    scope_type: &str,
-    rest: &str,
-    graph: &ModuleGraph,
-) -> Option<Value> {
-    if let Some(scope) = module.scope_ids.iter().find_map(|id| {
-        graph.get_scope_by_id(id).and_then(|scope| {
-            if scope.name == scope_type {
-                Some(scope)
-            } else {
-                None
+    /// Init every scheduled module concurrently, then run the actions strictly
+    /// one at a time in topological order
+    async fn run_serially(
+        &self,
+        scheduled: &[ScheduledLayer],
+        targets: &HashSet<&str>,
+        enter: &[Vec<String>],
+        leave: &[Vec<String>],
+        mut outputs_map: HashMap<String, HashMap<String, Value>>,
+    ) -> Result<()> {
+        // Modules are initialized up front, so enter all scopes first
+        self.run_scope_hooks(&enter.concat(), true).await?;
+        let concurrency = self.slots.available_permits();
+        // Modules whose dependencies already have outputs are initialized concurrently,
+        // with their inputs; the others once their dependencies ran
+        let mut prepared: HashMap<String, ModuleNode> = HashMap::new();
+        let mut cached: HashSet<&str> = HashSet::new();
+        for (id, step) in scheduled.iter().flat_map(|(_, layer)| layer) {
+            let mut module = self.module(id)?;
+            if let Some(outputs) = self.cached_outputs(&module, *step).await? {
+                outputs_map.insert(id.clone(), outputs);
+                cached.insert(id);
+            } else if !matches!(step, Step::Outputs)
+                && module
+                    .dependencies
+                    .iter()
+                    .all(|dep| outputs_map.contains_key(&dep.id))
+            {
+                self.inject_variables(&mut module, &outputs_map).await?;
+                prepared.insert(id.clone(), module);
             }
-        })
-    }) {
-        let yaml = Value::Mapping(
-            scope
+        }
+        let inits: Vec<Result<()>> = stream::iter(prepared.values().map(|m| self.timed_init(m)))
+            .buffer_unordered(concurrency)
+            .collect()
+            .await;
+        inits.into_iter().collect::<Result<Vec<()>>>()?;
+
+        for (pos, (layer_no, layer)) in scheduled.iter().enumerate() {
+            let started = Instant::now();
+            for (id, step) in layer {
+                if cached.contains(id.as_str()) {
+                    continue;
+                }
+                let module = match prepared.remove(id) {
+                    Some(module) => module,
+                    None if matches!(step, Step::Outputs) => self.module(id)?,
+                    None => {
+                        self.check_interrupted(*layer_no)?;
+                        let mut module = self.module(id)?;
+                        self.inject_variables(&mut module, &outputs_map).await?;
+                        self.timed_init(&module).await?;
+                        module
+                    }
+                };
+                if let Step::Run(action) = step {
+                    self.check_interrupted(*layer_no)?;
+                    let run = self.run_step(&module, *action, targets.contains(id.as_str()));
+                    tokio::select! {
+                        result = run => result?,
+                        () = self.killed() => {
+                            self.cancel_runs(&BTreeSet::from([id.as_str()]));
+                            return Err(anyhow!("Interrupted twice, cancelled '{}'", id));
+                        }
+                    }
+                }
+                let outputs = self.read_outputs(&module, *step).await?;
+                outputs_map.insert(id.clone(), outputs);
+            }
+            self.record_layer_timing(*layer_no, started);
+            self.run_scope_hooks(&leave[pos], false).await?;
+        }
+        Ok(())
+    }
+
+    /// Preflight: every scheduled module's version constraints, all failures reported at once
+    async fn check_versions(&self, scheduled: &[ScheduledLayer]) -> Result<()> {
+        let modules = scheduled
+            .iter()
+            .flat_map(|(_, layer)| layer)
+            .map(|(id, _)| self.module(id))
+            .collect::<Result<Vec<_>>>()?;
+        let results = join_all(modules.iter().map(|m| self.runner.check_version(m))).await;
+        let failures: Vec<String> = results
+            .into_iter()
+            .filter_map(|r| r.err().map(|e| format!("{:#}", e)))
+            .collect();
+        if !failures.is_empty() {
+            return Err(anyhow!(
+                "terraform version check failed:\n{}",
+                failures.join("\n")
+            ));
+        }
+        Ok(())
+    }
+
+    /// Preflight: init and validate every module the action runs on, concurrently,
+    /// so a broken configuration fails the run before anything is applied
+    async fn validate_modules(&self, scheduled: &[ScheduledLayer]) -> Result<()> {
+        let modules = scheduled
+            .iter()
+            .flat_map(|(_, layer)| layer)
+            .filter(|(_, step)| matches!(step, Step::Run(_)))
+            .map(|(id, _)| self.module(id))
+            .collect::<Result<Vec<_>>>()?;
+        let concurrency = self.slots.available_permits();
+        let results: Vec<(String, Result<()>)> = stream::iter(modules.iter().map(|m| async {
+            (
+                m.id.clone(),
+                self.runner.validate(m, self.options.check_fmt).await,
+            )
+        }))
+        .buffer_unordered(concurrency)
+        .collect()
+        .await;
+        let mut failures: Vec<String> = results
+            .into_iter()
+            .filter_map(|(id, r)| r.err().map(|e| format!("module '{}': {:#}", id, e)))
+            .collect();
+        if !failures.is_empty() {
+            failures.sort();
+            return Err(anyhow!(
+                "module validation failed:\n{}",
+                failures.join("\n")
+            ));
+        }
+        Ok(())
+    }
+
+    /// Preflight: every module the action runs on gets all its required variables
+    /// and no undeclared one, which is only a warning unless `strict`. Input names are
+    /// static, so this runs before any output exists.
+    async fn check_variables(&self, scheduled: &[ScheduledLayer], strict: bool) -> Result<()> {
+        let mut failures = Vec::new();
+        for (id, _) in scheduled
+            .iter()
+            .flat_map(|(_, layer)| layer)
+            .filter(|(_, step)| matches!(step, Step::Run(_)))
+        {
+            let module = self.module(id)?;
+            let Some(declared) = self.runner.declared_variables(&module).await? else {
+                continue;
+            };
+            let passed: HashSet<&String> = module
+                .inputs
+                .keys()
+                .chain(module.variables.keys())
+                .collect();
+            let mut unknown: Vec<&str> = passed
+                .iter()
+                .filter(|name| !declared.contains_key(name.as_str()))
+                .map(|name| name.as_str())
+                .collect();
+            unknown.sort();
+            let missing: Vec<&str> = declared
+                .iter()
+                .filter(|(name, required)| {
+                    **required
+                        && !passed.contains(name)
+                        && !self.options.global_variables.contains_key(name.as_str())
+                })
+                .map(|(name, _)| name.as_str())
+                .collect();
+            if !missing.is_empty() {
+                failures.push(format!(
+                    "module '{}' misses required variables: {}",
+                    id,
+                    missing.join(", ")
+                ));
+            }
+            if !unknown.is_empty() && strict {
+                failures.push(format!(
+                    "module '{}' is given undeclared variables: {}",
+                    id,
+                    unknown.join(", ")
+                ));
+            } else if !unknown.is_empty() {
+                progress!(
+                    "Warning: module '{}' is given undeclared variables, ignored by terraform: {}",
+                    id,
+                    unknown.join(", ")
+                );
+            }
+        }
+        if !failures.is_empty() {
+            return Err(anyhow!("variable check failed:\n{}", failures.join("\n")));
+        }
+        Ok(())
+    }
+
+    /// For each scheduled layer, the scopes entered before it (outermost first)
+    /// and the scopes left after it (innermost first)
+    fn scope_spans(
+        &self,
+        scheduled: &[ScheduledLayer],
+    ) -> Result<(ScopesPerLayer, ScopesPerLayer)> {
+        let mut spans: HashMap<String, (usize, usize)> = HashMap::new();
+        for (pos, (_, layer)) in scheduled.iter().enumerate() {
+            for (id, _) in layer {
+                for scope_id in self.module(id)?.scope_ids {
+                    spans
+                        .entry(scope_id)
+                        .and_modify(|(_, last)| *last = pos)
+                        .or_insert((pos, pos));
+                }
+            }
+        }
+        let mut enter = vec![Vec::new(); scheduled.len()];
+        let mut leave = vec![Vec::new(); scheduled.len()];
+        for (scope_id, (first, last)) in spans {
+            enter[first].push(scope_id.clone());
+            leave[last].push(scope_id);
+        }
+        // Nested scope ids extend their parent's id, so length orders by depth
+        for scopes in &mut enter {
+            scopes.sort_by_key(|id| (id.len(), id.clone()));
+        }
+        for scopes in &mut leave {
+            scopes.sort_by_key(|id| std::cmp::Reverse((id.len(), id.clone())));
+        }
+        Ok((enter, leave))
+    }
+
+    /// Run the `before` (or `after`) hooks of the given scopes, in order
+    async fn run_scope_hooks(&self, scope_ids: &[String], before: bool) -> Result<()> {
+        for scope_id in scope_ids {
+            let Some(scope) = self.graph.get_scope_by_id(scope_id) else {
+                continue;
+            };
+            let (hook, command) = if before {
+                ("before", scope.before.as_ref())
+            } else {
+                ("after", scope.after.as_ref())
+            };
+            let Some(command) = command else {
+                continue;
+            };
+            let mut envs: HashMap<String, String> = scope
                 .variables
                 .iter()
-                .map(|(k, v)| (Value::String(k.clone()), v.clone()))
-                .collect(),
-        );
-        let segments = parse_path(rest);
-        return get_value_from_path(&yaml, &segments);
+                .map(|(k, v)| (format!("TFSTACKS_VAR_{}", k), env_value(v)))
+                .collect();
+            envs.insert("TFSTACKS_SCOPE_ID".to_string(), scope_id.clone());
+            envs.insert("TFSTACKS_SCOPE".to_string(), scope.name.clone());
+            self.runner
+                .run_hook(scope_id, command, &envs)
+                .await
+                .with_context(|| format!("{} hook of scope '{}' failed", hook, scope_id))?;
+        }
+        Ok(())
     }
-    None
-}
 
-/// Parses a path like "subnets[0].id" into Key/Index segments
-fn parse_path(path: &str) -> Vec<PathSegment> {
-    let mut segs = Vec::new();
-    for part in path.split('.') {
-        let mut rem = part;
-        while let Some(i) = rem.find('[') {
-            if i > 0 {
-                segs.push(PathSegment::Key(rem[..i].to_string()));
+    /// Run one module according to its step and return its outputs
+    async fn execute_module(
+        &self,
+        id: &str,
+        step: Step,
+        outputs_map: &HashMap<String, HashMap<String, Value>>,
+        is_target: bool,
+    ) -> Result<(String, HashMap<String, Value>)> {
+        let _slot = self.slots.acquire().await?;
+        if *self.interrupts.borrow() > 0 {
+            return Err(NotStarted(id.to_string()).into());
+        }
+        let mut module = self.module(id)?;
+        if let Some(outputs) = self.cached_outputs(&module, step).await? {
+            return Ok((id.to_string(), outputs));
+        }
+        if !matches!(step, Step::Outputs) {
+            self.inject_variables(&mut module, outputs_map).await?;
+            self.timed_init(&module).await?;
+        }
+        if let Step::Run(action) = step {
+            self.run_step(&module, action, is_target).await?;
+        }
+        let outputs = self.read_outputs(&module, step).await?;
+        Ok((id.to_string(), outputs))
+    }
+
+    /// Destroy an already prepared module
+    async fn destroy_module(
+        &self,
+        id: &str,
+        outputs_map: &HashMap<String, HashMap<String, Value>>,
+        is_target: bool,
+    ) -> Result<()> {
+        let _slot = self.slots.acquire().await?;
+        if *self.interrupts.borrow() > 0 {
+            return Err(NotStarted(id.to_string()).into());
+        }
+        let mut module = self.module(id)?;
+        inject_inputs(
+            &mut module,
+            outputs_map,
+            &self.graph,
+            Some(self.secrets.as_ref()),
+            self.options.collect_errors,
+        )
+        .await?;
+        self.add_global_variables(&mut module);
+        self.run_step(&module, TerraformAction::Destroy, is_target)
+            .await
+    }
+
+    /// Read the module outputs, recording them for `--incremental`. Under
+    /// `--allow-mock-fallback`, a module whose action is not run falls back to its
+    /// `mocked_outputs` when it has no outputs yet or they cannot be read.
+    async fn read_outputs(
+        &self,
+        module: &ModuleNode,
+        step: Step,
+    ) -> Result<HashMap<String, Value>> {
+        let started = Instant::now();
+        let read = self.runner.output(module).await;
+        self.record_timing(&module.id, started, |t| &mut t.output_ms);
+        let fallback = module
+            .mocked_outputs
+            .as_ref()
+            .filter(|_| self.options.allow_mock_fallback && !matches!(step, Step::Run(_)));
+        let outputs = match (read, fallback) {
+            (Ok(outputs), Some(mocked)) if outputs.is_empty() => {
+                progress!(
+                    "Warning: '{}' has no outputs yet, using its mocked_outputs (--allow-mock-fallback)",
+                    module.id
+                );
+                return Ok(mocked.clone());
             }
-            let j = rem[i + 1..].find(']').expect("Unmatched bracket") + i + 1;
-            let idx = rem[i + 1..j].parse::<usize>().expect("Invalid index");
-            segs.push(PathSegment::Index(idx));
-            rem = &rem[j + 1..];
+            (Err(err), Some(mocked)) => {
+                progress!(
+                    "Warning: outputs of '{}' could not be read ({:#}), using its mocked_outputs (--allow-mock-fallback)",
+                    module.id,
+                    err
+                );
+                return Ok(mocked.clone());
+            }
+            (read, _) => read?,
+        };
+        if let Some(state) = &self.incremental {
+            state.record_outputs(&module.id, &outputs)?;
         }
-        if !rem.is_empty() {
-            segs.push(PathSegment::Key(rem.to_string()));
+        Ok(outputs)
+    }
+
+    /// Modules the action runs on, in execution order
+    pub fn module_runs(&self) -> Vec<ModuleRun> {
+        let mut runs: Vec<ModuleRun> = self.runs.lock().unwrap().values().cloned().collect();
+        runs.sort_by(|a, b| (a.layer, &a.id).cmp(&(b.layer, &b.id)));
+        runs
+    }
+
+    /// Step timings of the modules that ran a terraform step and of every layer
+    pub fn timings(&self) -> TimingReport {
+        let mut modules: Vec<ModuleTiming> = self
+            .timings
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|t| t.init_ms.or(t.action_ms).or(t.output_ms).is_some())
+            .cloned()
+            .collect();
+        modules.sort_by(|a, b| (a.layer, &a.id).cmp(&(b.layer, &b.id)));
+        let layers = self
+            .layer_timings
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(&layer, &duration_ms)| LayerTiming { layer, duration_ms })
+            .collect();
+        TimingReport { modules, layers }
+    }
+
+    /// Add the time since `started` to a step of the module's timing
+    fn record_timing(
+        &self,
+        id: &str,
+        started: Instant,
+        step: fn(&mut ModuleTiming) -> &mut Option<u64>,
+    ) {
+        if let Some(timing) = self.timings.lock().unwrap().get_mut(id) {
+            let elapsed = started.elapsed().as_millis() as u64;
+            *step(timing).get_or_insert(0) += elapsed;
         }
     }
 
-    segs
-}
+    /// Add the time since `started` to the layer's timing
+    fn record_layer_timing(&self, layer_no: usize, started: Instant) {
+        let elapsed = started.elapsed().as_millis() as u64;
+        *self
+            .layer_timings
+            .lock()
+            .unwrap()
+            .entry(layer_no)
+            .or_insert(0) += elapsed;
+    }
 
-fn get_value_from_path(root: &Value, path: &[PathSegment]) -> Option<Value> {
-    let mut cur = root;
-    for seg in path {
-        match seg {
-            PathSegment::Key(k) => {
-                if let Value::Mapping(m) = cur {
-                    cur = m.get(Value::String(k.clone()))?;
-                } else {
-                    return None;
+    /// Init the module, timing it
+    async fn timed_init(&self, module: &ModuleNode) -> Result<()> {
+        let started = Instant::now();
+        let result = self.runner.init(module).await;
+        self.record_timing(&module.id, started, |t| &mut t.init_ms);
+        result
+    }
+
+    fn set_status(&self, module: &ModuleNode, status: ModuleStatus, started: Instant) {
+        if let Some(run) = self.runs.lock().unwrap().get_mut(&module.id) {
+            run.status = status;
+            if status == ModuleStatus::Running {
+                run.inputs = module.redacted_variables().into_iter().collect();
+            } else {
+                run.duration_ms = Some(started.elapsed().as_millis() as u64);
+            }
+        }
+    }
+
+    /// Run the action, tracking the module's status
+    async fn run_step(
+        &self,
+        module: &ModuleNode,
+        action: TerraformAction,
+        is_target: bool,
+    ) -> Result<()> {
+        let started = Instant::now();
+        self.set_status(module, ModuleStatus::Running, started);
+        let result = self.run_step_inner(module, action, is_target).await;
+        self.record_timing(&module.id, started, |t| &mut t.action_ms);
+        let status = match &result {
+            Ok(status) => *status,
+            Err(_) => ModuleStatus::Failed,
+        };
+        if result.is_ok()
+            && matches!(action, TerraformAction::Apply)
+            && let Some(state) = self.run_state.lock().unwrap().as_mut()
+        {
+            state.record_completed(&module.id);
+        }
+        self.set_status(module, status, started);
+        result.map(|_| ())
+    }
+
+    /// Run the action, except an apply `--incremental` finds unchanged since the
+    /// last one while no dependency's outputs changed during this run
+    async fn run_step_inner(
+        &self,
+        module: &ModuleNode,
+        action: TerraformAction,
+        is_target: bool,
+    ) -> Result<ModuleStatus> {
+        let Some(state) = self
+            .incremental
+            .as_ref()
+            .filter(|_| matches!(action, TerraformAction::Apply))
+        else {
+            self.run_action(module, action, is_target).await?;
+            return Ok(ModuleStatus::Succeeded);
+        };
+        let fingerprint = fingerprint(
+            self.runner.source_digest(module).await?.as_deref(),
+            &module.variables,
+        )?;
+        let dirty = state.dirty_among(module.dependencies.iter().map(|d| d.id.as_str()));
+        if dirty.is_empty() && state.is_unchanged(&module.id, &fingerprint) {
+            progress!(
+                "'{}' unchanged since its last apply, skipped (--incremental)",
+                module.id
+            );
+            return Ok(ModuleStatus::Unchanged);
+        }
+        if !dirty.is_empty() {
+            progress!(
+                "'{}' applied again: outputs of '{}' changed",
+                module.id,
+                dirty.join("', '")
+            );
+        }
+        self.run_action(module, action, is_target).await?;
+        state.record_applied(&module.id, fingerprint);
+        Ok(ModuleStatus::Succeeded)
+    }
+
+    /// Run the action, recording an audit entry for changes when enabled
+    async fn run_action(
+        &self,
+        module: &ModuleNode,
+        action: TerraformAction,
+        is_target: bool,
+    ) -> Result<()> {
+        let Some(audit) = &self.audit else {
+            return self.dispatch_action(module, action, is_target).await;
+        };
+        if matches!(action, TerraformAction::Plan) {
+            return self.dispatch_action(module, action, is_target).await;
+        }
+        let mut entry = AuditEntry::new(&module.id, action.name(), module.redacted_variables());
+        entry.outputs_before = self.runner.output(module).await.ok().map(redact_outputs);
+        let result = self.dispatch_action(module, action, is_target).await;
+        match &result {
+            Ok(()) => {
+                entry.outputs_after = self.runner.output(module).await.ok().map(redact_outputs)
+            }
+            Err(err) => entry.error = Some(format!("{:#}", err)),
+        }
+        audit
+            .append(&entry)
+            .await
+            .with_context(|| format!("while auditing '{}'", module.id))?;
+        result
+    }
+
+    /// Dispatch the terraform action; saved plan files only apply to the target
+    async fn dispatch_action(
+        &self,
+        module: &ModuleNode,
+        action: TerraformAction,
+        is_target: bool,
+    ) -> Result<()> {
+        match action {
+            TerraformAction::Plan => {
+                let file_name = module_path_component(&module.id)?;
+                let plan_file = self
+                    .options
+                    .plan_out
+                    .as_ref()
+                    .filter(|_| is_target)
+                    .or(self.options.plan_out_dir.as_ref())
+                    .map(|dir| dir.join(format!("{}.tfplan", file_name)));
+                let resources = self.resource_targets(is_target);
+                if let PlanResult::Changes(counts) = self
+                    .runner
+                    .plan(module, plan_file.as_deref(), resources)
+                    .await?
+                {
+                    self.planned_changes
+                        .lock()
+                        .unwrap()
+                        .insert(module.id.clone(), counts);
+                    if !is_target && self.options.plan_only_deps && !self.options.ignore_dep_drift {
+                        return Err(anyhow!(
+                            "dependency '{}' has changes to apply, which --plan-only-deps does not apply: apply it first, or pass --ignore-dep-drift",
+                            module.id
+                        ));
+                    }
+                }
+                if let Some(plan_file) = plan_file {
+                    if !plan_file.is_file() {
+                        return Err(anyhow!("terraform did not write plan file {:?}", plan_file));
+                    }
+                    PlanManifest::new(&plan_file, module, signing_key().as_deref())?
+                        .write(&plan_file)?;
+                    if let Some(dir) = &self.options.plan_out_dir {
+                        let json = self.runner.show_plan_json(module, &plan_file).await?;
+                        let path = dir.join(format!("{}.json", file_name));
+                        tokio::fs::write(&path, json)
+                            .await
+                            .with_context(|| format!("Failed to write plan summary {:?}", path))?;
+                    }
+                }
+                Ok(())
+            }
+            TerraformAction::Destroy => {
+                self.runner
+                    .destroy(module, self.auto_approve(is_target))
+                    .await
+            }
+            TerraformAction::Refresh => self.runner.refresh(module).await,
+            TerraformAction::Apply => {
+                let plan_file = self.options.plan_in.as_deref().filter(|_| is_target);
+                if let Some(plan_file) = plan_file {
+                    verify_plan_manifest(plan_file, &module.id)?;
                 }
+                self.runner
+                    .apply(
+                        module,
+                        plan_file,
+                        self.resource_targets(is_target),
+                        self.auto_approve(is_target),
+                    )
+                    .await
             }
-            PathSegment::Index(i) => match cur {
-                Value::Sequence(seq) => cur = seq.get(*i)?,
-                _ => return None,
-            },
         }
+    }
 
-        // Final recursive unwrap for "value" keys after all segments
-        loop {
-            if let Value::Mapping(m) = cur
-                && let Some(v) = m.get(Value::String("value".into()))
-            {
-                cur = v;
+    /// Resolve the variables of every module in the closure of `targets` without
+    /// running terraform; dependency outputs come from the runner's `output` only.
+    /// Sensitive values are redacted and secrets are never fetched.
+    pub async fn render_variables(
+        &self,
+        targets: &[String],
+    ) -> Result<BTreeMap<String, BTreeMap<String, Value>>> {
+        let layers = self.graph.closure_layers(targets)?;
+        let mut outputs_map: HashMap<String, HashMap<String, Value>> = HashMap::new();
+        let mut manifest = BTreeMap::new();
+        for id in layers.iter().flatten() {
+            let mut module = self.module(id)?;
+            inject_inputs(
+                &mut module,
+                &outputs_map,
+                &self.graph,
+                None,
+                self.options.collect_errors,
+            )
+            .await
+            .with_context(|| format!("while rendering variables of '{}'", id))?;
+            self.add_global_variables(&mut module);
+            outputs_map.insert(id.clone(), self.runner.output(&module).await?);
+            manifest.insert(
+                id.clone(),
+                module.redacted_variables().into_iter().collect(),
+            );
+        }
+        Ok(manifest)
+    }
+
+    /// Init `target` and list the resource addresses of its state; no input is
+    /// resolved and no dependency is touched
+    pub async fn state_list(&self, target: &str) -> Result<Vec<String>> {
+        let module = self.module(target)?;
+        self.timed_init(&module).await?;
+        self.runner.state_list(&module).await
+    }
+
+    /// Outputs of `target` unwrapped from terraform's `{value, sensitive, type}`, the
+    /// sensitive ones as "***". Read from the cache under `--use-cached-outputs`, else
+    /// from terraform after an init; outputs need no input, so no dependency is touched.
+    pub async fn module_outputs(&self, target: &str) -> Result<BTreeMap<String, Value>> {
+        let module = self.module(target)?;
+        let outputs = match self.cached_outputs(&module, Step::Outputs).await? {
+            Some(outputs) => outputs,
+            None => {
+                self.timed_init(&module).await?;
+                self.runner.output(&module).await?
+            }
+        };
+        let outputs = redact_outputs(outputs);
+        Ok(outputs
+            .iter()
+            .filter_map(|(name, output)| {
+                let root = Value::Mapping(serde_yaml::Mapping::from_iter([(
+                    Value::from(name.as_str()),
+                    output.clone(),
+                )]));
+                let value =
+                    get_value_from_path(&root, &[PathSegment::Key(name.clone())], true).ok()?;
+                Some((name.clone(), value))
+            })
+            .collect())
+    }
+
+    /// Resolve the variables of `target` like `render_variables`, each with where its
+    /// value came from. Under `--use-cached-outputs`, dependency outputs come from
+    /// their cache, or else their `mocked_outputs`.
+    pub async fn inspect_module(
+        &self,
+        target: &str,
+    ) -> Result<BTreeMap<String, InspectedVariable>> {
+        let layers = self.graph.closure_layers(&[target.to_string()])?;
+        let mut outputs_map: HashMap<String, HashMap<String, Value>> = HashMap::new();
+        for id in layers.iter().flatten() {
+            let mut module = self.module(id)?;
+            let defaults = module.variables.clone();
+            let mut variables = inject_inputs(
+                &mut module,
+                &outputs_map,
+                &self.graph,
+                None,
+                self.options.collect_errors,
+            )
+            .await
+            .with_context(|| format!("while inspecting variables of '{}'", id))?;
+            if id != target {
+                let cached = match self.options.use_cached_outputs {
+                    true => self.runner.cached_outputs(&module).await?,
+                    false => None,
+                };
+                let outputs = match cached {
+                    Some(outputs) => outputs,
+                    None if self.options.use_cached_outputs => {
+                        module.mocked_outputs.clone().unwrap_or_default()
+                    }
+                    None => self.runner.output(&module).await?,
+                };
+                outputs_map.insert(id.clone(), outputs);
                 continue;
             }
-            break;
+            // Inputs win over default variables, which win over global ones
+            let others = defaults
+                .into_iter()
+                .map(|(key, value)| (key, value, VariableOrigin::Default))
+                .chain(
+                    self.options
+                        .global_variables
+                        .iter()
+                        .map(|(key, value)| (key.clone(), value.clone(), VariableOrigin::Global)),
+                );
+            for (key, value, origin) in others {
+                variables.entry(key).or_insert(InspectedVariable {
+                    value,
+                    origin,
+                    unresolved: Vec::new(),
+                });
+            }
+            for (key, variable) in variables.iter_mut() {
+                if module.sensitive.contains(key) {
+                    variable.value = Value::from("***");
+                }
+            }
+            return Ok(variables);
+        }
+        Err(anyhow!("Module not found: {}", target))
+    }
+
+    /// Check that every input of the closure of `targets` can resolve, without
+    /// running terraform. Dependency outputs are only known from `mocked_outputs`.
+    /// Returns one line per problem.
+    pub fn validate(&self, targets: &[String]) -> Result<Vec<String>> {
+        let layers = self.graph.closure_layers(targets)?;
+        let mut ids: Vec<&String> = layers.iter().flatten().collect();
+        ids.sort();
+        let mut problems = Vec::new();
+        for id in ids {
+            let module = self.module(id)?;
+            let mut expects: Vec<(&String, &Vec<String>)> = module.expects.iter().collect();
+            expects.sort();
+            for (name, expected) in expects {
+                let Some(dep) = module.dependencies.iter().find(|d| &d.name == name) else {
+                    continue;
+                };
+                for output in expected {
+                    let segments = [PathSegment::Key(output.clone())];
+                    if let Err(reason) = self.check_mocked_outputs(&dep.id, &segments) {
+                        problems.push(format!(
+                            "module '{}' expects output '{}' of '{}': {}",
+                            id, output, name, reason
+                        ));
+                    }
+                }
+            }
+            let mut inputs: Vec<(&String, &InputValue)> = module.inputs.iter().collect();
+            inputs.sort_by_key(|(key, _)| *key);
+            for (key, input) in inputs {
+                // A chain ending in a literal or a secret always resolves
+                let mut current = input;
+                let mut failed = Vec::new();
+                loop {
+                    let (path, next) = match current {
+                        InputValue::Ref { path } => (path, None),
+                        InputValue::RefWithDefault { path, default } => (path, Some(default)),
+                        InputValue::Default(_) | InputValue::Secret { .. } => {
+                            failed.clear();
+                            break;
+                        }
+                    };
+                    match self.check_ref(path, &module) {
+                        Ok(()) => {
+                            failed.clear();
+                            break;
+                        }
+                        Err(reason) => failed.push(format!("'{}' ({})", path, reason)),
+                    }
+                    match next {
+                        Some(default) => current = default,
+                        None => break,
+                    }
+                }
+                if !failed.is_empty() {
+                    problems.push(format!(
+                        "module '{}' input '{}' cannot resolve: {}",
+                        id,
+                        key,
+                        failed.join(", ")
+                    ));
+                }
+            }
+        }
+        Ok(problems)
+    }
+
+    /// Statically check a reference: a dependency (and its mocked output when
+    /// known), a variable of an enclosing scope or another input of the module
+    fn check_ref(&self, path: &str, module: &ModuleNode) -> std::result::Result<(), String> {
+        let first = path.split('.').next().unwrap_or(path);
+        let segments = parse_path(path, first.len()).map_err(|e| e.to_string())?;
+        // Another input, its own references are checked on their own
+        if let Some(input) = self_input(path) {
+            return match module.inputs.contains_key(input) {
+                true => Ok(()),
+                false => Err(format!("the module has no input '{}'", input)),
+            };
+        }
+        if let Some(dep) = module.dependencies.iter().find(|dep| dep.name == first) {
+            let dep_module = self.module(&dep.id).ok();
+            // Same extra hop as `resolve_ref`, to a dependency of the dependency
+            if let [PathSegment::Key(name), rest @ ..] = segments.as_slice()
+                && !dep_module
+                    .as_ref()
+                    .and_then(|m| m.mocked_outputs.as_ref())
+                    .is_some_and(|mocked| mocked.contains_key(name))
+                && let Some(hop) = dep_module
+                    .as_ref()
+                    .and_then(|m| m.dependencies.iter().find(|d| &d.name == name))
+            {
+                return self.check_mocked_outputs(&hop.id, rest);
+            }
+            return self.check_mocked_outputs(&dep.id, &segments);
+        }
+        match find_scope_variable(path, module, first, &segments, &self.graph) {
+            Ok(Some(_)) => return Ok(()),
+            Err(err) => return Err(format!("{:#}", err)),
+            Ok(None) => {}
+        }
+        if module.order_after.iter().any(|dep| dep.name == first) {
+            return Err(format!(
+                "'{}' is in order_after, which only orders runs: add it to dependencies to read its outputs",
+                first
+            ));
+        }
+        Err("no dependency or enclosing scope variable matches".to_string())
+    }
+
+    /// A path into the outputs of a module passes unless its mocked outputs lack it
+    fn check_mocked_outputs(
+        &self,
+        id: &str,
+        segments: &[PathSegment],
+    ) -> std::result::Result<(), String> {
+        let Some(mocked) = self.module(id).ok().and_then(|m| m.mocked_outputs) else {
+            return Ok(());
+        };
+        let outputs = Value::Mapping(
+            mocked
+                .into_iter()
+                .map(|(k, v)| (Value::String(k), v))
+                .collect(),
+        );
+        match get_value_from_path(&outputs, segments, true) {
+            Ok(_) => Ok(()),
+            Err(err) => Err(format!("not in the mocked outputs of '{}': {}", id, err)),
         }
     }
-    Some(cur.clone())
+
+    /// Outputs of the target's dependencies cached by an earlier run, for `--only`,
+    /// and of their own dependencies when cached, for references reading through them
+    async fn only_dependency_outputs(
+        &self,
+        target: &str,
+    ) -> Result<HashMap<String, HashMap<String, Value>>> {
+        let module = self.module(target)?;
+        let mut outputs = HashMap::new();
+        let mut missing = Vec::new();
+        for dep in &module.dependencies {
+            let dep_module = self.module(&dep.id)?;
+            let fallback = dep_module
+                .mocked_outputs
+                .as_ref()
+                .filter(|_| self.options.allow_mock_fallback);
+            match (self.runner.cached_outputs(&dep_module).await?, fallback) {
+                (Some(cached), _) => {
+                    outputs.insert(dep.id.clone(), cached);
+                }
+                (None, Some(mocked)) => {
+                    progress!(
+                        "Warning: '{}' has no cached outputs, using its mocked_outputs (--allow-mock-fallback)",
+                        dep.id
+                    );
+                    outputs.insert(dep.id.clone(), mocked.clone());
+                }
+                (None, None) => missing.push(dep.id.as_str()),
+            }
+            for hop in &dep_module.dependencies {
+                if !outputs.contains_key(&hop.id)
+                    && let Some(cached) = self.runner.cached_outputs(&self.module(&hop.id)?).await?
+                {
+                    outputs.insert(hop.id.clone(), cached);
+                }
+            }
+        }
+        if !missing.is_empty() {
+            missing.sort();
+            return Err(anyhow!(
+                "--only needs the cached outputs of every dependency of '{}', there are none for '{}': run '{}' once without --only first",
+                target,
+                missing.join("', '"),
+                target
+            ));
+        }
+        progress!(
+            "'{}' runs alone (--only), dependency outputs read from the cache",
+            target
+        );
+        Ok(outputs)
+    }
+
+    /// Outputs cached by an earlier invocation, under `--use-cached-outputs` and
+    /// for a module whose action is not run
+    async fn cached_outputs(
+        &self,
+        module: &ModuleNode,
+        step: Step,
+    ) -> Result<Option<HashMap<String, Value>>> {
+        if !self.options.use_cached_outputs || matches!(step, Step::Run(_)) {
+            return Ok(None);
+        }
+        let cached = self.runner.cached_outputs(module).await?;
+        if cached.is_some() {
+            progress!(
+                "'{}' outputs read from the cache, not initialized",
+                module.id
+            );
+        }
+        Ok(cached)
+    }
+
+    /// Whether an apply or destroy of the module is approved without asking;
+    /// dependencies are only asked about under `--confirm-deps`
+    fn auto_approve(&self, is_target: bool) -> bool {
+        !self.options.no_auto_approve || (!is_target && !self.options.confirm_deps)
+    }
+
+    /// `--tf-target` resource addresses, which only apply to the target
+    fn resource_targets(&self, is_target: bool) -> &[String] {
+        match is_target {
+            true => &self.options.tf_targets,
+            false => &[],
+        }
+    }
+
+    /// Resolve the inputs of a module before its init, then add the global variables
+    async fn inject_variables(
+        &self,
+        module: &mut ModuleNode,
+        outputs_map: &HashMap<String, HashMap<String, Value>>,
+    ) -> Result<()> {
+        inject_inputs(
+            module,
+            outputs_map,
+            &self.graph,
+            Some(self.secrets.as_ref()),
+            self.options.collect_errors,
+        )
+        .await?;
+        self.add_global_variables(module);
+        Ok(())
+    }
+
+    /// Give the module every global variable it does not set itself
+    fn add_global_variables(&self, module: &mut ModuleNode) {
+        for (key, value) in &self.options.global_variables {
+            module
+                .variables
+                .entry(key.clone())
+                .or_insert_with(|| value.clone());
+        }
+    }
+
+    fn module(&self, id: &str) -> Result<ModuleNode> {
+        self.graph
+            .get_module_by_id(id)
+            .ok_or_else(|| anyhow!("Module '{}' is scheduled but not in the graph", id))
+    }
+}
+
+/// Check a saved plan against its manifest before applying it; a manifest is
+/// required whenever a signing key is set
+fn verify_plan_manifest(plan_file: &Path, module_id: &str) -> Result<()> {
+    let key = signing_key();
+    match PlanManifest::read(plan_file)? {
+        Some(manifest) => manifest.verify(plan_file, module_id, key.as_deref()),
+        None if key.is_some() => Err(anyhow!(
+            "no manifest {:?} for saved plan but {} is set",
+            PlanManifest::path_for(plan_file),
+            SIGNING_KEY_ENV
+        )),
+        None => Ok(()),
+    }
+    .with_context(|| format!("Refusing to apply saved plan {:?}", plan_file))
+}
+
+fn default_concurrency() -> usize {
+    std::thread::available_parallelism().map_or(4, |n| n.get())
+}
+
+fn signing_key() -> Option<String> {
+    std::env::var(SIGNING_KEY_ENV)
+        .ok()
+        .filter(|k| !k.is_empty())
+}
+
+/// Read a `{ module_id: { output: { value, ... } } }` file, as produced by
+/// `terraform output -json` for each module
+fn load_outputs(path: &Path) -> Result<HashMap<String, HashMap<String, Value>>> {
+    let content =
+        std::fs::read(path).with_context(|| format!("Failed to read outputs file {:?}", path))?;
+    let raw: HashMap<String, HashMap<String, serde_json::Value>> = serde_json::from_slice(&content)
+        .with_context(|| format!("Invalid outputs file {:?}", path))?;
+    Ok(raw
+        .into_iter()
+        .map(|(id, outputs)| {
+            let outputs = outputs
+                .into_iter()
+                .map(|(k, v)| (k, json_to_value(v)))
+                .collect();
+            (id, outputs)
+        })
+        .collect())
+}
+
+/// Where the value of a module variable came from, as printed by `inspect`
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "origin", rename_all = "snake_case")]
+pub enum VariableOrigin {
+    /// Literal value of the input
+    Literal,
+    /// Output of a dependency
+    Dependency {
+        module_id: String,
+        path: String,
+        /// Terraform marked the output sensitive
+        #[serde(skip_serializing_if = "std::ops::Not::not")]
+        sensitive: bool,
+    },
+    /// Variable of an enclosing scope
+    ScopeVariable { scope_id: String, path: String },
+    /// Another input of the same module, read with `self.<input>`
+    SelfInput { input: String, path: String },
+    /// Secret reference, never fetched by `inspect`
+    Secret { uri: String },
+    /// `variables` of a source or scope default
+    Default,
+    /// `--var` or `--var-file`
+    Global,
+}
+
+/// A resolved module variable and where its value came from
+#[derive(Debug, Clone, Serialize)]
+pub struct InspectedVariable {
+    pub value: Value,
+    #[serde(flatten)]
+    pub origin: VariableOrigin,
+    /// References tried before, that did not resolve
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub unresolved: Vec<String>,
+}
+
+/// Inject resolved inputs into a Terraform module before execution, returning
+/// where each value came from. The first input that does not resolve fails, or
+/// with `collect_errors` every one of them is listed.
+async fn inject_inputs(
+    module: &mut ModuleNode,
+    outputs_map: &HashMap<String, HashMap<String, Value>>,
+    graph: &ModuleGraph,
+    secrets: Option<&SecretStore>,
+    collect_errors: bool,
+) -> Result<BTreeMap<String, InspectedVariable>> {
+    check_expected_outputs(module, outputs_map)?;
+    let inputs = module.inputs.clone();
+    let mut origins = BTreeMap::new();
+    let mut errors: Vec<String> = Vec::new();
+    for key in input_order(module)? {
+        let input = resolve_input(
+            &key,
+            &inputs[&key],
+            module,
+            &origins,
+            outputs_map,
+            graph,
+            secrets,
+        );
+        let (resolved, origin, failed) = match input.await {
+            Ok(resolved) => resolved,
+            Err(err) if collect_errors => {
+                errors.push(format!("input '{}': {:#}", key, err));
+                continue;
+            }
+            Err(err) => return Err(err),
+        };
+        // An input read from a sensitive output or a secret input stays redacted
+        match &origin {
+            VariableOrigin::Dependency {
+                sensitive: true, ..
+            } => {
+                module.sensitive.insert(key.clone());
+            }
+            VariableOrigin::SelfInput { input, .. } if module.sensitive.contains(input) => {
+                module.sensitive.insert(key.clone());
+            }
+            _ => {}
+        }
+        module.variables.insert(key.clone(), resolved.clone());
+        let inspected = InspectedVariable {
+            value: resolved,
+            origin,
+            unresolved: failed,
+        };
+        origins.insert(key.clone(), inspected);
+    }
+    if !errors.is_empty() {
+        return Err(anyhow!(
+            "{} input(s) of module '{}' did not resolve:\n{}",
+            errors.len(),
+            module.id,
+            errors.join("\n")
+        ));
+    }
+    Ok(origins)
+}
+
+/// Fail, listing every one of them, when a dependency lacks an output the module
+/// `expects` of it. A dependency whose outputs were not read, or dropped by its
+/// condition, is left to input resolution.
+fn check_expected_outputs(
+    module: &ModuleNode,
+    outputs_map: &HashMap<String, HashMap<String, Value>>,
+) -> Result<()> {
+    let mut missing = Vec::new();
+    let mut expects: Vec<(&String, &Vec<String>)> = module.expects.iter().collect();
+    expects.sort();
+    for (name, expected) in expects {
+        let Some(dep) = module.dependencies.iter().find(|d| &d.name == name) else {
+            continue;
+        };
+        let Some(dep_outputs) = outputs_map.get(&dep.id) else {
+            continue;
+        };
+        for output in expected.iter().filter(|o| !dep_outputs.contains_key(*o)) {
+            missing.push(format!(
+                "dependency '{}' ('{}') produced no output named '{}'",
+                dep.name, dep.id, output
+            ));
+        }
+    }
+    if !missing.is_empty() {
+        return Err(anyhow!(
+            "module '{}' is missing {} expected output(s) of its dependencies:\n{}",
+            module.id,
+            missing.len(),
+            missing.join("\n")
+        ));
+    }
+    Ok(())
+}
+
+/// Resolve one input, following its default chain until a value resolves; also
+/// returns the references tried before that did not
+async fn resolve_input(
+    key: &str,
+    input: &InputValue,
+    module: &mut ModuleNode,
+    origins: &BTreeMap<String, InspectedVariable>,
+    outputs_map: &HashMap<String, HashMap<String, Value>>,
+    graph: &ModuleGraph,
+    secrets: Option<&SecretStore>,
+) -> Result<(Value, VariableOrigin, Vec<String>)> {
+    let mut current = input;
+    let mut failed: Vec<String> = Vec::new();
+    loop {
+        match current {
+            InputValue::Default(v) => return Ok((v.clone(), VariableOrigin::Literal, failed)),
+            InputValue::Ref { path } => {
+                match resolve_ref(path, module, origins, outputs_map, graph) {
+                    Ok(Some((value, origin))) => return Ok((value, origin, failed)),
+                    Ok(None) if failed.is_empty() => {
+                        return Err(anyhow!(
+                            "Reference '{}' not found: no dependency or enclosing scope variable matches",
+                            path
+                        ));
+                    }
+                    Err(err) if failed.is_empty() => return Err(err),
+                    Ok(None) => failed.push(format!("'{}' (not found)", path)),
+                    Err(err) => failed.push(format!("'{}' ({})", path, err)),
+                }
+                return Err(anyhow!(
+                    "No reference of input '{}' resolved: {}",
+                    key,
+                    failed.join(", ")
+                ));
+            }
+            InputValue::RefWithDefault { path, default } => {
+                match resolve_ref(path, module, origins, outputs_map, graph) {
+                    Ok(Some((value, origin))) => return Ok((value, origin, failed)),
+                    Ok(None) => failed.push(format!("'{}' (not found)", path)),
+                    Err(err) => failed.push(format!("'{}' ({})", path, err)),
+                }
+                current = default;
+            }
+            InputValue::Secret { uri } => {
+                // Without a store (rendering only) secrets are never fetched
+                let secret = match secrets {
+                    Some(secrets) => secrets.resolve(uri).await.with_context(|| {
+                        format!("while resolving input '{}' of '{}'", key, module.id)
+                    })?,
+                    None => "***".to_string(),
+                };
+                module.sensitive.insert(key.to_string());
+                let origin = VariableOrigin::Secret { uri: uri.clone() };
+                return Ok((Value::String(secret), origin, failed));
+            }
+        }
+    }
+}
+
+/// Inputs of a module ordered so that an input comes after the inputs it reads
+/// with `self.<input>`; inputs reading each other in a cycle are an error
+fn input_order(module: &ModuleNode) -> Result<Vec<String>> {
+    fn visit(
+        key: &str,
+        module: &ModuleNode,
+        stack: &mut Vec<String>,
+        order: &mut Vec<String>,
+    ) -> Result<()> {
+        if order.iter().any(|k| k == key) {
+            return Ok(());
+        }
+        if let Some(at) = stack.iter().position(|k| k == key) {
+            let mut cycle = stack[at..].to_vec();
+            cycle.push(key.to_string());
+            return Err(anyhow!(
+                "inputs of module '{}' reference each other in a cycle: {}",
+                module.id,
+                cycle.join(" -> ")
+            ));
+        }
+        stack.push(key.to_string());
+        for path in module.inputs[key].ref_paths() {
+            if let Some(input) = self_input(path)
+                && module.inputs.contains_key(input)
+            {
+                visit(input, module, stack, order)?;
+            }
+        }
+        stack.pop();
+        order.push(key.to_string());
+        Ok(())
+    }
+
+    let mut keys: Vec<&String> = module.inputs.keys().collect();
+    keys.sort();
+    let mut order = Vec::with_capacity(keys.len());
+    for key in keys {
+        visit(key, module, &mut Vec::new(), &mut order)?;
+    }
+    Ok(order)
+}
+
+/// Resolve a Terraform-style reference like "vpc.subnets[0]", "region.id" or
+/// "self.env", with where the value came from
+fn resolve_ref(
+    path: &str,
+    module: &ModuleNode,
+    resolved: &BTreeMap<String, InspectedVariable>,
+    outputs_map: &HashMap<String, HashMap<String, Value>>,
+    graph: &ModuleGraph,
+) -> Result<Option<(Value, VariableOrigin)>> {
+    let first = path.split('.').next().unwrap_or(path);
+    let segments = parse_path(path, first.len())?;
+    // Input of the same module (self.env), resolved before this one
+    if let Some(input) = self_input(path) {
+        let Some(variable) = resolved.get(input) else {
+            return Ok(None);
+        };
+        let value = get_value_from_path(&variable.value, &segments[1..], false)
+            .with_context(|| format!("Cannot resolve '{}'", path))?;
+        let origin = VariableOrigin::SelfInput {
+            input: input.to_string(),
+            path: path.to_string(),
+        };
+        return Ok(Some((value, origin)));
+    }
+    // 1️⃣ Dependency reference (vpc.subnets[0])
+    if let Some(dep) = module.dependencies.iter().find(|dep| dep.name == first) {
+        let dep_outputs = recorded_outputs(dep, outputs_map)?;
+        // 2️⃣ Output of a dependency of the dependency (vpc.network.cidr), when the
+        // dependency has no output of that name
+        if let [PathSegment::Key(name), rest @ ..] = segments.as_slice()
+            && !dep_outputs.contains_key(name)
+            && let Some(hop) = graph
+                .get_module_by_id(&dep.id)
+                .and_then(|m| m.dependencies.into_iter().find(|d| &d.name == name))
+        {
+            let hop_outputs = recorded_outputs(&hop, outputs_map)?;
+            let value = resolve_output(path, &hop, hop_outputs, rest)?;
+            let origin = dependency_origin(&hop, path, hop_outputs, rest);
+            return Ok(Some((value, origin)));
+        }
+        let value = resolve_output(path, dep, dep_outputs, &segments)?;
+        let origin = dependency_origin(dep, path, dep_outputs, &segments);
+        return Ok(Some((value, origin)));
+    }
+
+    // 3️⃣ Scope variable (from ancestor scopes)
+    if let Some((value, scope_id)) = find_scope_variable(path, module, first, &segments, graph)? {
+        let origin = VariableOrigin::ScopeVariable {
+            scope_id,
+            path: path.to_string(),
+        };
+        return Ok(Some((value, origin)));
+    }
+
+    Ok(None)
+}
+
+/// Origin of a value read at `segments` in the outputs of a dependency, sensitive
+/// when terraform marked the output read (or one of them) as such
+fn dependency_origin(
+    dep: &Dependency,
+    path: &str,
+    dep_outputs: &HashMap<String, Value>,
+    segments: &[PathSegment],
+) -> VariableOrigin {
+    let is_sensitive =
+        |output: &Value| output.get("sensitive").and_then(Value::as_bool) == Some(true);
+    let sensitive = match segments.first() {
+        Some(PathSegment::Key(output)) => dep_outputs.get(output).is_some_and(is_sensitive),
+        // The whole outputs map
+        None => dep_outputs.values().any(is_sensitive),
+        Some(PathSegment::Index(_)) => false,
+    };
+    VariableOrigin::Dependency {
+        module_id: dep.id.clone(),
+        path: path.to_string(),
+        sensitive,
+    }
+}
+
+fn recorded_outputs<'a>(
+    dep: &Dependency,
+    outputs_map: &'a HashMap<String, HashMap<String, Value>>,
+) -> Result<&'a HashMap<String, Value>> {
+    outputs_map.get(&dep.id).ok_or_else(|| {
+        anyhow!(
+            "dependency '{}' ('{}') has not run: no outputs were recorded for it",
+            dep.name,
+            dep.id
+        )
+    })
+}
+
+/// Value at `segments` in the outputs of a dependency
+fn resolve_output(
+    path: &str,
+    dep: &Dependency,
+    dep_outputs: &HashMap<String, Value>,
+    segments: &[PathSegment],
+) -> Result<Value> {
+    if let Some(PathSegment::Key(output)) = segments.first()
+        && !dep_outputs.contains_key(output)
+    {
+        let mut available: Vec<&str> = dep_outputs.keys().map(String::as_str).collect();
+        available.sort();
+        let available = if available.is_empty() {
+            "it has no outputs".to_string()
+        } else {
+            format!("available outputs: {}", available.join(", "))
+        };
+        return Err(anyhow!(
+            "dependency '{}' ('{}') produced no output named '{}' ({})",
+            dep.name,
+            dep.id,
+            output,
+            available
+        ));
+    }
+
+    let yaml = Value::Mapping(
+        dep_outputs
+            .iter()
+            .map(|(k, v)| (Value::String(k.clone()), v.clone()))
+            .collect(),
+    );
+
+    get_value_from_path(&yaml, segments, true).with_context(|| format!("Cannot resolve '{}'", path))
+}
+
+/// Lookup a scope variable of an enclosing scope, named by its id (`account-1`, matching
+/// the last segment of the scope id) or else by its scope type, the innermost scope
+/// winning; returns the value and the id of the scope, or None when no scope matches
+fn find_scope_variable(
+    path: &str,
+    module: &ModuleNode,
+    scope_name: &str,
+    segments: &[PathSegment],
+    graph: &ModuleGraph,
+) -> Result<Option<(Value, String)>> {
+    let is_type = |id: &str| {
+        graph
+            .get_scope_by_id(id)
+            .is_some_and(|scope| scope.name == scope_name)
+    };
+    let Some((scope_id, scope)) = nearest_scope_id(module, scope_name, is_type)
+        .and_then(|id| Some((id, graph.get_scope_by_id(id)?)))
+    else {
+        return Ok(None);
+    };
+    let yaml = Value::Mapping(
+        scope
+            .variables
+            .iter()
+            .map(|(k, v)| (Value::String(k.clone()), v.clone()))
+            .collect(),
+    );
+    let value = get_value_from_path(&yaml, segments, false).with_context(|| {
+        format!(
+            "Cannot resolve '{}' in the variables of scope '{}'",
+            path, scope_id
+        )
+    })?;
+    Ok(Some((value, scope_id.clone())))
+}
+
+/// Parse `path` from byte `start` on, like "subnets[0].id", into Key/Index segments.
+/// Errors quote the whole path and the 1-based column of the problem.
+fn parse_path(path: &str, start: usize) -> Result<Vec<PathSegment>> {
+    let invalid = |at: usize, problem: String| {
+        anyhow!("Invalid path '{}': {} at column {}", path, problem, at + 1)
+    };
+    let mut segs = Vec::new();
+    let mut key = String::new();
+    // A '.' still waiting for the segment it separates
+    let mut dot: Option<usize> = None;
+    let mut chars = path.char_indices().skip_while(|(i, _)| *i < start);
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '.' | '[' if !key.is_empty() => {
+                segs.push(PathSegment::Key(std::mem::take(&mut key)));
+            }
+            '.' | '[' if dot.is_some() => {
+                return Err(invalid(i, "empty segment".to_string()));
+            }
+            _ => {}
+        }
+        dot = None;
+        match c {
+            '.' => dot = Some(i),
+            '[' => {
+                let mut index = String::new();
+                loop {
+                    match chars.next() {
+                        Some((_, ']')) => break,
+                        Some((_, c)) => index.push(c),
+                        None => return Err(invalid(i, "unmatched '['".to_string())),
+                    }
+                }
+                let index = index
+                    .parse::<usize>()
+                    .map_err(|_| invalid(i + 1, format!("index '{}' is not a number", index)))?;
+                segs.push(PathSegment::Index(index));
+            }
+            ']' => return Err(invalid(i, "unmatched ']'".to_string())),
+            c => key.push(c),
+        }
+    }
+    if let Some(at) = dot {
+        return Err(invalid(at + 1, "empty segment".to_string()));
+    }
+    if !key.is_empty() {
+        segs.push(PathSegment::Key(key));
+    }
+    Ok(segs)
+}
+
+/// Value at `path` in `root`. When `root` maps output names to terraform outputs,
+/// the output read by the first segment is unwrapped from its `{"value": ...}`;
+/// nothing deeper is, so outputs holding a `value` key of their own read as they are.
+/// Errors name the first key missing, index out of range or segment read from a
+/// value of the wrong type, with the path read so far.
+fn get_value_from_path(root: &Value, path: &[PathSegment], outputs: bool) -> Result<Value> {
+    let mut cur = root;
+    let mut read = String::new();
+    for (pos, seg) in path.iter().enumerate() {
+        let at = match read.is_empty() {
+            true => "the top level".to_string(),
+            false => format!("'{}'", read),
+        };
+        match (seg, cur) {
+            (PathSegment::Key(k), Value::Mapping(m)) => {
+                cur = m
+                    .get(k.as_str())
+                    .ok_or_else(|| anyhow!("no key '{}' at {}", k, at))?;
+            }
+            (PathSegment::Index(i), Value::Sequence(seq)) => {
+                cur = seq.get(*i).ok_or_else(|| {
+                    anyhow!("index {} out of range at {} (len {})", i, at, seq.len())
+                })?;
+            }
+            (PathSegment::Key(k), _) => {
+                return Err(anyhow!(
+                    "expected mapping at {} to read '{}' but found {}",
+                    at,
+                    k,
+                    value_kind(cur)
+                ));
+            }
+            (PathSegment::Index(i), _) => {
+                return Err(anyhow!(
+                    "expected sequence at {} to read [{}] but found {}",
+                    at,
+                    i,
+                    value_kind(cur)
+                ));
+            }
+        }
+        match seg {
+            PathSegment::Key(k) if read.is_empty() => read.push_str(k),
+            PathSegment::Key(k) => read.push_str(&format!(".{}", k)),
+            PathSegment::Index(i) => read.push_str(&format!("[{}]", i)),
+        }
+
+        if outputs
+            && pos == 0
+            && let Value::Mapping(m) = cur
+            && let Some(v) = m.get("value")
+        {
+            cur = v;
+        }
+    }
+    Ok(cur.clone())
+}
+
+fn value_kind(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "bool",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Sequence(_) => "sequence",
+        Value::Mapping(_) => "mapping",
+        Value::Tagged(_) => "tagged value",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scripted::{Command, ScriptedRunner};
+    use crate::testing::infra;
+    use std::time::Duration;
+
+    /// vpc <- compute <- webapp
+    const CHAIN: &str = "
+acc:
+  scope: account
+  vpc:
+    source: vpc
+  compute:
+    source: compute
+    dependencies: [vpc]
+  webapp:
+    source: webapp
+    dependencies: [compute]
+";
+
+    fn runtime(yaml: &str, runner: &Arc<ScriptedRunner>, options: RunOptions) -> Runtime {
+        Runtime::new(runner.clone(), &infra(yaml), options).unwrap()
+    }
+
+    #[tokio::test]
+    async fn each_action_runs_its_own_command_on_the_target() {
+        let cases = [
+            (TerraformAction::Plan, Command::Plan),
+            (TerraformAction::Apply, Command::Apply),
+            (TerraformAction::Destroy, Command::Destroy),
+            (TerraformAction::Refresh, Command::Refresh),
+        ];
+        for (action, command) in cases {
+            let runner = Arc::new(ScriptedRunner::default());
+            let runtime = runtime(CHAIN, &runner, RunOptions::default());
+            runtime.run_module("acc.compute", action).await.unwrap();
+            // Only the target changes, with the command of the action alone
+            let changes = [
+                Command::Plan,
+                Command::Apply,
+                Command::Destroy,
+                Command::Refresh,
+            ];
+            for change in changes {
+                let expected = usize::from(change == command);
+                assert_eq!(runner.calls("acc.compute", change), expected, "{action:?}");
+                assert_eq!(runner.calls("acc.vpc", change), 0, "{action:?}");
+            }
+            assert_eq!(runner.calls("acc.compute", Command::Init), 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn refresh_deps_refreshes_the_dependencies_first() {
+        let runner = Arc::new(ScriptedRunner::default());
+        let options = RunOptions {
+            with_deps: true,
+            ..Default::default()
+        };
+        runtime(CHAIN, &runner, options)
+            .run_module("acc.compute", TerraformAction::Refresh)
+            .await
+            .unwrap();
+        assert_eq!(
+            runner.finished_on(Command::Refresh),
+            ["acc.vpc", "acc.compute"]
+        );
+        assert!(runner.finished_on(Command::Apply).is_empty());
+    }
+
+    #[tokio::test]
+    async fn mocked_outputs_stand_in_for_unreadable_dependency_outputs() {
+        let yaml = "
+acc:
+  scope: account
+  vpc:
+    source: vpc
+    mocked_outputs:
+      vpc_id: vpc-mocked
+  compute:
+    source: compute
+    dependencies: [vpc]
+    inputs:
+      vpc_id:
+        from: vpc.vpc_id
+";
+        let failing = || {
+            Arc::new(
+                ScriptedRunner::builder()
+                    .fail("acc.vpc", Command::Output, "no state")
+                    .build(),
+            )
+        };
+        let runner = failing();
+        let err = runtime(yaml, &runner, RunOptions::default())
+            .run_module("acc.compute", TerraformAction::Apply)
+            .await
+            .unwrap_err();
+        assert!(format!("{err:#}").contains("no state"), "{err:#}");
+
+        let options = || RunOptions {
+            allow_mock_fallback: true,
+            ..Default::default()
+        };
+        let runner = failing();
+        runtime(yaml, &runner, options())
+            .run_module("acc.compute", TerraformAction::Apply)
+            .await
+            .unwrap();
+        let variables = runner.variables("acc.compute").unwrap();
+        assert_eq!(variables["vpc_id"], Value::from("vpc-mocked"));
+
+        // No outputs yet falls back too
+        let runner = Arc::new(
+            ScriptedRunner::builder()
+                .outputs("acc.vpc", Vec::<(String, Value)>::new())
+                .build(),
+        );
+        runtime(yaml, &runner, options())
+            .run_module("acc.compute", TerraformAction::Apply)
+            .await
+            .unwrap();
+        let variables = runner.variables("acc.compute").unwrap();
+        assert_eq!(variables["vpc_id"], Value::from("vpc-mocked"));
+    }
+
+    #[tokio::test]
+    async fn tf_targets_only_limit_the_target() {
+        let runner = Arc::new(ScriptedRunner::default());
+        let options = RunOptions {
+            with_deps: true,
+            tf_targets: vec!["null_resource.b".to_string(), "null_resource.a".to_string()],
+            ..Default::default()
+        };
+        runtime(CHAIN, &runner, options)
+            .run_module("acc.compute", TerraformAction::Apply)
+            .await
+            .unwrap();
+        assert_eq!(
+            runner.resource_targets("acc.compute").unwrap(),
+            ["null_resource.b", "null_resource.a"]
+        );
+        assert_eq!(
+            runner.resource_targets("acc.vpc").unwrap(),
+            Vec::<String>::new()
+        );
+    }
+
+    #[test]
+    fn no_auto_approve_asks_for_the_target_and_deps_only_with_confirm_deps() {
+        let runner = Arc::new(ScriptedRunner::default());
+        let approves = |no_auto_approve, confirm_deps| {
+            let options = RunOptions {
+                no_auto_approve,
+                confirm_deps,
+                ..Default::default()
+            };
+            let runtime = runtime(CHAIN, &runner, options);
+            (runtime.auto_approve(true), runtime.auto_approve(false))
+        };
+        assert_eq!(approves(false, false), (true, true));
+        assert_eq!(approves(true, false), (false, true));
+        assert_eq!(approves(true, true), (false, false));
+    }
+
+    #[tokio::test]
+    async fn modules_are_validated_before_anything_is_applied() {
+        let options = || RunOptions {
+            with_deps: true,
+            validate_modules: true,
+            ..Default::default()
+        };
+        let runner = Arc::new(ScriptedRunner::default());
+        runtime(CHAIN, &runner, options())
+            .run_module("acc.webapp", TerraformAction::Apply)
+            .await
+            .unwrap();
+        let finished = runner.finished();
+        let position = |command| finished.iter().position(|(_, c)| *c == command);
+        let last_validate = finished
+            .iter()
+            .rposition(|(_, c)| *c == Command::Validate)
+            .unwrap();
+        assert!(
+            last_validate < position(Command::Apply).unwrap(),
+            "{finished:?}"
+        );
+        assert_eq!(runner.finished_on(Command::Validate).len(), 3);
+
+        let runner = Arc::new(
+            ScriptedRunner::builder()
+                .fail("acc.webapp", Command::Validate, "invalid")
+                .build(),
+        );
+        let err = runtime(CHAIN, &runner, options())
+            .run_module("acc.webapp", TerraformAction::Apply)
+            .await
+            .unwrap_err();
+        assert!(
+            format!("{err:#}").contains("module 'acc.webapp'"),
+            "{err:#}"
+        );
+        assert!(runner.finished_on(Command::Apply).is_empty());
+    }
+
+    #[tokio::test]
+    async fn timings_report_every_module_and_layer_that_ran() {
+        let runner = Arc::new(
+            ScriptedRunner::builder()
+                .delay("acc.compute", Duration::from_millis(30))
+                .build(),
+        );
+        let options = RunOptions {
+            with_deps: true,
+            ..Default::default()
+        };
+        let runtime = runtime(CHAIN, &runner, options);
+        runtime
+            .run_module("acc.webapp", TerraformAction::Apply)
+            .await
+            .unwrap();
+        let report = runtime.timings();
+        let modules: Vec<(&str, usize)> = report
+            .modules
+            .iter()
+            .map(|t| (t.id.as_str(), t.layer))
+            .collect();
+        assert_eq!(
+            modules,
+            [("acc.vpc", 1), ("acc.compute", 2), ("acc.webapp", 3)]
+        );
+        let compute = &report.modules[1];
+        // init, apply and output each waited for the delay
+        for step in [compute.init_ms, compute.action_ms, compute.output_ms] {
+            assert!(step.unwrap() >= 30, "{compute:?}");
+        }
+        assert!(compute.total_ms() >= 90, "{compute:?}");
+        let layers: Vec<usize> = report.layers.iter().map(|l| l.layer).collect();
+        assert_eq!(layers, [1, 2, 3]);
+        assert!(report.layers[1].duration_ms >= compute.init_ms.unwrap());
+    }
+
+    #[tokio::test]
+    async fn only_runs_the_target_on_cached_dependency_outputs() {
+        let yaml = "
+acc:
+  scope: account
+  vpc:
+    source: vpc
+  compute:
+    source: compute
+    dependencies: [vpc]
+    inputs:
+      vpc_id:
+        from: vpc.vpc_id
+";
+        let options = || RunOptions {
+            only: true,
+            ..Default::default()
+        };
+        let runner = Arc::new(
+            ScriptedRunner::builder()
+                .cached("acc.vpc", [("vpc_id", Value::from("vpc-cached"))])
+                .build(),
+        );
+        runtime(yaml, &runner, options())
+            .run_module("acc.compute", TerraformAction::Apply)
+            .await
+            .unwrap();
+        assert!(
+            runner.all_calls().keys().all(|(id, _)| id == "acc.compute"),
+            "{:?}",
+            runner.all_calls()
+        );
+        assert_eq!(runner.calls("acc.compute", Command::Init), 1);
+        let variables = runner.variables("acc.compute").unwrap();
+        assert_eq!(variables["vpc_id"], Value::from("vpc-cached"));
+
+        let runner = Arc::new(ScriptedRunner::default());
+        let err = runtime(yaml, &runner, options())
+            .run_module("acc.compute", TerraformAction::Apply)
+            .await
+            .unwrap_err();
+        assert!(
+            err.to_string().contains(
+                "there are none for 'acc.vpc': run 'acc.compute' once without --only first"
+            ),
+            "{err}"
+        );
+        assert!(runner.all_calls().is_empty());
+    }
+
+    #[tokio::test]
+    async fn destroy_runs_in_the_reverse_order_of_apply() {
+        let options = || RunOptions {
+            with_deps: true,
+            ..Default::default()
+        };
+        let applied = Arc::new(ScriptedRunner::default());
+        runtime(CHAIN, &applied, options())
+            .run_module("acc.webapp", TerraformAction::Apply)
+            .await
+            .unwrap();
+        let apply_order = applied.finished_on(Command::Apply);
+        assert_eq!(apply_order, ["acc.vpc", "acc.compute", "acc.webapp"]);
+
+        let destroyed = Arc::new(ScriptedRunner::default());
+        runtime(CHAIN, &destroyed, options())
+            .run_module("acc.webapp", TerraformAction::Destroy)
+            .await
+            .unwrap();
+        let mut reversed = apply_order;
+        reversed.reverse();
+        assert_eq!(destroyed.finished_on(Command::Destroy), reversed);
+    }
+
+    /// Four independent modules, a single layer
+    const WIDE: &str = "
+acc:
+  scope: account
+  vpc:
+    source: vpc
+  compute:
+    source: compute
+  network:
+    source: network
+  webapp:
+    source: webapp
+";
+
+    fn wide_targets() -> Vec<String> {
+        ["vpc", "compute", "network", "webapp"]
+            .map(|name| format!("acc.{name}"))
+            .to_vec()
+    }
+
+    fn slow(ids: &[String], delay: Duration) -> Arc<ScriptedRunner> {
+        let builder = ids
+            .iter()
+            .fold(ScriptedRunner::builder(), |b, id| b.delay(id, delay));
+        Arc::new(builder.build())
+    }
+
+    #[tokio::test]
+    async fn concurrency_one_runs_a_wide_layer_one_module_at_a_time() {
+        let targets = wide_targets();
+        let runner = slow(&targets, Duration::from_millis(10));
+        let options = RunOptions {
+            concurrency: Some(1),
+            ..Default::default()
+        };
+        runtime(WIDE, &runner, options)
+            .run_modules(&targets, TerraformAction::Apply)
+            .await
+            .unwrap();
+        assert_eq!(runner.finished_on(Command::Apply).len(), 4);
+        assert_eq!(runner.peak_running(), 1);
+    }
+
+    #[tokio::test]
+    async fn concurrency_caps_the_modules_running_at_once() {
+        let targets = wide_targets();
+        let runner = slow(&targets, Duration::from_millis(20));
+        let options = RunOptions {
+            concurrency: Some(2),
+            ..Default::default()
+        };
+        runtime(WIDE, &runner, options)
+            .run_modules(&targets, TerraformAction::Apply)
+            .await
+            .unwrap();
+        assert_eq!(runner.peak_running(), 2);
+    }
+
+    /// Layer 1: vpc and compute, layer 2: webapp needing both
+    const LAYERED: &str = "
+acc:
+  scope: account
+  vpc:
+    source: vpc
+  compute:
+    source: compute
+  webapp:
+    source: webapp
+    dependencies: [vpc, compute]
+";
+
+    fn statuses(runtime: &Runtime) -> BTreeMap<String, ModuleStatus> {
+        let runs = runtime.module_runs().into_iter();
+        runs.map(|run| (run.id, run.status)).collect()
+    }
+
+    /// vpc fails its apply at once while compute is still running
+    fn failing_next_to_slow() -> Arc<ScriptedRunner> {
+        Arc::new(
+            ScriptedRunner::builder()
+                .fail("acc.vpc", Command::Apply, "boom")
+                .delay("acc.compute", Duration::from_secs(2))
+                .build(),
+        )
+    }
+
+    #[tokio::test]
+    async fn failing_module_cancels_its_layer_and_stops_the_run() {
+        let runner = failing_next_to_slow();
+        let options = RunOptions {
+            with_deps: true,
+            concurrency: Some(2),
+            ..Default::default()
+        };
+        let runtime = runtime(LAYERED, &runner, options);
+        let started = Instant::now();
+        let err = runtime
+            .run_module("acc.webapp", TerraformAction::Apply)
+            .await
+            .unwrap_err();
+        assert!(format!("{err:#}").contains("boom"), "{err:#}");
+        assert!(
+            started.elapsed() < Duration::from_secs(2),
+            "compute was waited for"
+        );
+        assert_eq!(
+            statuses(&runtime),
+            BTreeMap::from([
+                ("acc.compute".to_string(), ModuleStatus::Cancelled),
+                ("acc.vpc".to_string(), ModuleStatus::Failed),
+                ("acc.webapp".to_string(), ModuleStatus::Pending),
+            ])
+        );
+        // Only the failed apply returned
+        assert_eq!(runner.finished_on(Command::Apply), ["acc.vpc"]);
+        assert!(runner.all_calls().keys().all(|(id, _)| id != "acc.webapp"));
+    }
+
+    #[tokio::test]
+    async fn keep_going_lets_the_layer_finish_but_not_the_next_one() {
+        let runner = Arc::new(
+            ScriptedRunner::builder()
+                .fail("acc.vpc", Command::Apply, "boom")
+                .delay("acc.compute", Duration::from_millis(20))
+                .build(),
+        );
+        let options = RunOptions {
+            with_deps: true,
+            keep_going: true,
+            concurrency: Some(2),
+            ..Default::default()
+        };
+        let runtime = runtime(LAYERED, &runner, options);
+        assert!(
+            runtime
+                .run_module("acc.webapp", TerraformAction::Apply)
+                .await
+                .is_err()
+        );
+        let statuses = statuses(&runtime);
+        assert_eq!(statuses["acc.compute"], ModuleStatus::Succeeded);
+        assert_eq!(statuses["acc.vpc"], ModuleStatus::Failed);
+        assert_eq!(statuses["acc.webapp"], ModuleStatus::Pending);
+        assert_eq!(
+            runner.finished_on(Command::Apply),
+            ["acc.vpc", "acc.compute"]
+        );
+    }
+
+    fn path_error(path: &str) -> String {
+        let first = path.split(['.', '[']).next().unwrap();
+        parse_path(path, first.len()).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn paths_parse_into_keys_and_indices() {
+        let segments = parse_path("vpc.subnets[0].id", 3).unwrap();
+        assert_eq!(
+            segments,
+            [
+                PathSegment::Key("subnets".to_string()),
+                PathSegment::Index(0),
+                PathSegment::Key("id".to_string()),
+            ]
+        );
+        assert_eq!(
+            parse_path("vpc.matrix[1][2]", 3).unwrap(),
+            [
+                PathSegment::Key("matrix".to_string()),
+                PathSegment::Index(1),
+                PathSegment::Index(2),
+            ]
+        );
+    }
+
+    #[test]
+    fn malformed_paths_report_the_column() {
+        assert_eq!(
+            path_error("vpc.subnets[0"),
+            "Invalid path 'vpc.subnets[0': unmatched '[' at column 12"
+        );
+        assert_eq!(
+            path_error("vpc.subnets[abc].id"),
+            "Invalid path 'vpc.subnets[abc].id': index 'abc' is not a number at column 13"
+        );
+        assert_eq!(
+            path_error("vpc.subnets]"),
+            "Invalid path 'vpc.subnets]': unmatched ']' at column 12"
+        );
+    }
+
+    #[test]
+    fn empty_segments_are_rejected() {
+        assert_eq!(
+            path_error("vpc..id"),
+            "Invalid path 'vpc..id': empty segment at column 5"
+        );
+        assert_eq!(
+            path_error("vpc.subnets."),
+            "Invalid path 'vpc.subnets.': empty segment at column 13"
+        );
+        assert_eq!(
+            path_error("vpc.[0]"),
+            "Invalid path 'vpc.[0]': empty segment at column 5"
+        );
+        assert_eq!(
+            path_error("vpc.subnets[]"),
+            "Invalid path 'vpc.subnets[]': index '' is not a number at column 13"
+        );
+    }
+
+    fn graph(yaml: &str) -> ModuleGraph {
+        ModuleGraph::new(&infra(yaml)).unwrap()
+    }
+
+    /// Resolve `path` for the module as its inputs are, over the recorded outputs
+    fn resolve(
+        graph: &ModuleGraph,
+        id: &str,
+        path: &str,
+        outputs: &[(&str, &[(&str, Value)])],
+    ) -> Result<Option<Value>> {
+        let module = graph.get_module_by_id(id).unwrap();
+        let outputs_map = outputs
+            .iter()
+            .map(|(id, outputs)| {
+                let outputs = outputs.iter().map(|(k, v)| (k.to_string(), v.clone()));
+                (id.to_string(), outputs.collect())
+            })
+            .collect();
+        let resolved = resolve_ref(path, &module, &BTreeMap::new(), &outputs_map, graph)?;
+        Ok(resolved.map(|(value, _)| value))
+    }
+
+    #[test]
+    fn dependency_that_has_not_run_is_told_apart() {
+        let graph = graph(CHAIN);
+        let err = resolve(&graph, "acc.compute", "vpc.vpc_id", &[]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "dependency 'vpc' ('acc.vpc') has not run: no outputs were recorded for it"
+        );
+    }
+
+    #[test]
+    fn missing_output_lists_the_available_ones() {
+        let graph = graph(CHAIN);
+        let err = resolve(&graph, "acc.compute", "vpc.vpc_id", &[("acc.vpc", &[])]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "dependency 'vpc' ('acc.vpc') produced no output named 'vpc_id' (it has no outputs)"
+        );
+
+        let outputs: &[(&str, Value)] = &[("subnets", Value::Null), ("cidr", Value::Null)];
+        let err =
+            resolve(&graph, "acc.compute", "vpc.vpc_id", &[("acc.vpc", outputs)]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "dependency 'vpc' ('acc.vpc') produced no output named 'vpc_id' (available outputs: cidr, subnets)"
+        );
+
+        let outputs: &[(&str, Value)] = &[("vpc_id", Value::from("vpc-1"))];
+        let value = resolve(&graph, "acc.compute", "vpc.vpc_id", &[("acc.vpc", outputs)]).unwrap();
+        assert_eq!(value, Some(Value::from("vpc-1")));
+    }
+
+    /// Two nested scopes of type `account`
+    const NESTED: &str = "
+org-root:
+  scope: account
+  variables:
+    region: eu-west-1
+    network: {cidr: 10.0.0.0/8}
+  member-1:
+    scope: account
+    variables:
+      region: us-east-1
+    webapp:
+      source: webapp
+";
+
+    #[test]
+    fn scope_variables_resolve_by_id_then_innermost_type() {
+        let graph = graph(NESTED);
+        let webapp = "org-root.member-1.webapp";
+        let resolve = |path| resolve(&graph, webapp, path, &[]).unwrap();
+        assert_eq!(resolve("account.region"), Some(Value::from("us-east-1")));
+        assert_eq!(resolve("member-1.region"), Some(Value::from("us-east-1")));
+        assert_eq!(resolve("org-root.region"), Some(Value::from("eu-west-1")));
+        assert_eq!(
+            resolve("org-root.network.cidr"),
+            Some(Value::from("10.0.0.0/8"))
+        );
+        assert_eq!(resolve("tenant.region"), None);
+    }
+
+    #[test]
+    fn scope_variable_missing_in_the_named_scope_is_an_error() {
+        // The innermost `account` wins even when only the outer one has the variable
+        let err = resolve(
+            &graph(NESTED),
+            "org-root.member-1.webapp",
+            "account.network",
+            &[],
+        )
+        .unwrap_err();
+        assert!(
+            format!("{err:#}").contains("in the variables of scope 'org-root.member-1'"),
+            "{err:#}"
+        );
+    }
+
+    #[test]
+    fn references_hop_through_a_dependency_of_the_dependency() {
+        let graph = graph(CHAIN);
+        let outputs: &[(&str, &[(&str, Value)])] = &[
+            ("acc.vpc", &[("cidr", Value::from("10.0.0.0/16"))]),
+            ("acc.compute", &[("instance", Value::from("i-1"))]),
+        ];
+        let resolve = |path| resolve(&graph, "acc.webapp", path, outputs);
+        assert_eq!(
+            resolve("compute.vpc.cidr").unwrap(),
+            Some(Value::from("10.0.0.0/16"))
+        );
+        assert_eq!(
+            resolve("compute.instance").unwrap(),
+            Some(Value::from("i-1"))
+        );
+        // Only a dependency of the dependency is reachable
+        let err = resolve("compute.network.cidr").unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("produced no output named 'network'"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn own_output_of_the_dependency_wins_over_a_hop() {
+        let graph = graph(CHAIN);
+        let outputs: &[(&str, &[(&str, Value)])] = &[
+            ("acc.vpc", &[("cidr", Value::from("from-vpc"))]),
+            ("acc.compute", &[("vpc", Value::from("own"))]),
+        ];
+        assert_eq!(
+            resolve(&graph, "acc.webapp", "compute.vpc", outputs).unwrap(),
+            Some(Value::from("own"))
+        );
+    }
+
+    #[tokio::test]
+    async fn plan_out_dir_keeps_a_plan_and_its_json_per_planned_module() {
+        let dir = crate::testing::temp_dir("plan-out-dir");
+        let runner = Arc::new(ScriptedRunner::default());
+        let options = RunOptions {
+            with_deps: true,
+            plan_out_dir: Some(dir.clone()),
+            ..Default::default()
+        };
+        runtime(CHAIN, &runner, options)
+            .run_module("acc.compute", TerraformAction::Plan)
+            .await
+            .unwrap();
+        for id in ["acc.vpc", "acc.compute"] {
+            assert!(dir.join(format!("{id}.tfplan")).is_file(), "{id}");
+            let json = std::fs::read_to_string(dir.join(format!("{id}.json"))).unwrap();
+            assert_eq!(json, "{}", "{id}");
+        }
+        // webapp is not part of the run
+        assert!(!dir.join("acc.webapp.json").exists());
+    }
+
+    #[tokio::test]
+    async fn inspect_labels_where_each_variable_came_from() {
+        let yaml = "
+acc:
+  scope: account
+  variables:
+    region: eu-west-3
+  vpc:
+    source: vpc
+    mocked_outputs:
+      vpc_id: vpc-mocked
+  compute:
+    source: compute
+    dependencies: [vpc]
+    inputs:
+      vpc_id:
+        from: vpc.vpc_id
+      region:
+        from: account.region
+      size: small
+";
+        let runner = Arc::new(ScriptedRunner::default());
+        let options = RunOptions {
+            global_variables: HashMap::from([("owner".to_string(), Value::from("platform"))]),
+            ..Default::default()
+        };
+        let inspected = runtime(yaml, &runner, options)
+            .inspect_module("acc.compute")
+            .await
+            .unwrap();
+        let json = serde_json::to_value(&inspected).unwrap();
+        assert_eq!(
+            json["vpc_id"],
+            serde_json::json!({
+                "value": "vpc-mocked",
+                "origin": "dependency",
+                "module_id": "acc.vpc",
+                "path": "vpc.vpc_id",
+            })
+        );
+        assert_eq!(
+            json["region"],
+            serde_json::json!({
+                "value": "eu-west-3",
+                "origin": "scope_variable",
+                "scope_id": "acc",
+                "path": "account.region",
+            })
+        );
+        assert_eq!(json["size"]["origin"], "literal");
+        assert_eq!(json["owner"]["origin"], "global");
+        // Nothing is changed, dependencies only have their outputs read
+        assert!(runner.finished_on(Command::Apply).is_empty());
+        assert!(runner.finished_on(Command::Plan).is_empty());
+    }
+
+    #[tokio::test]
+    async fn inputs_read_other_inputs_of_the_module_through_self() {
+        let yaml = "
+acc:
+  scope: account
+  vpc:
+    source: vpc
+    mocked_outputs:
+      vpc_id: vpc-1
+  compute:
+    source: compute
+    dependencies: [vpc]
+    inputs:
+      app:
+        from: self.name
+      name:
+        from: self.env
+      env: prod
+      network:
+        from: vpc.vpc_id
+      tags:
+        team: platform
+      team:
+        from: self.tags.team
+      vpc:
+        from: self.network
+";
+        let runner = Arc::new(ScriptedRunner::default());
+        runtime(yaml, &runner, RunOptions::default())
+            .run_module("acc.compute", TerraformAction::Apply)
+            .await
+            .unwrap();
+        let variables = runner.variables("acc.compute").unwrap();
+        assert_eq!(variables["app"], Value::from("prod"));
+        assert_eq!(variables["name"], Value::from("prod"));
+        assert_eq!(variables["team"], Value::from("platform"));
+        assert_eq!(variables["vpc"], Value::from("vpc-1"));
+    }
+
+    #[tokio::test]
+    async fn inputs_reading_each_other_in_a_cycle_are_an_error() {
+        let yaml = "
+acc:
+  scope: account
+  vpc:
+    source: vpc
+    inputs:
+      a:
+        from: self.b
+      b:
+        from: self.a
+";
+        let runner = Arc::new(ScriptedRunner::default());
+        let err = runtime(yaml, &runner, RunOptions::default())
+            .run_module("acc.vpc", TerraformAction::Apply)
+            .await
+            .unwrap_err();
+        assert!(
+            format!("{err:#}").contains(
+                "inputs of module 'acc.vpc' reference each other in a cycle: a -> b -> a"
+            ),
+            "{err:#}"
+        );
+        assert!(runner.finished_on(Command::Apply).is_empty());
+    }
+
+    #[tokio::test]
+    async fn filtered_scope_runs_its_modules_and_their_shared_dependency() {
+        let yaml = "
+acc:
+  scope: account
+  vpc:
+    source: vpc
+  other:
+    source: network
+  tenant-a:
+    scope: tenant
+    webapp:
+      source: webapp
+      dependencies: [vpc]
+    compute:
+      source: compute
+      dependencies: [vpc]
+";
+        let runner = Arc::new(ScriptedRunner::default());
+        let options = RunOptions {
+            with_deps: true,
+            ..Default::default()
+        };
+        let runtime = runtime(yaml, &runner, options);
+        let targets = runtime
+            .graph
+            .select_modules(None, Some("acc.tenant-a"))
+            .unwrap();
+        assert_eq!(targets, ["acc.tenant-a.compute", "acc.tenant-a.webapp"]);
+        runtime
+            .run_modules(&targets, TerraformAction::Apply)
+            .await
+            .unwrap();
+        let mut applied = runner.finished_on(Command::Apply);
+        // The shared dependency runs once, before both
+        assert_eq!(applied.remove(0), "acc.vpc");
+        applied.sort();
+        assert_eq!(applied, targets);
+        assert_eq!(runner.calls("acc.other", Command::Init), 0);
+    }
+
+    #[tokio::test]
+    async fn sensitive_outputs_are_masked_but_reach_terraform() {
+        let yaml = "
+acc:
+  scope: account
+  vpc:
+    source: vpc
+  compute:
+    source: compute
+    dependencies: [vpc]
+    inputs:
+      password:
+        from: vpc.password
+      vpc_id:
+        from: vpc.vpc_id
+";
+        let output = |value: &str, sensitive: bool| {
+            serde_yaml::from_str::<Value>(&format!(
+                "{{value: {value}, sensitive: {sensitive}, type: string}}"
+            ))
+            .unwrap()
+        };
+        let outputs = [
+            ("password", output("s3cr3t", true)),
+            ("vpc_id", output("vpc-1", false)),
+        ];
+        let runner = Arc::new(
+            ScriptedRunner::builder()
+                .outputs("acc.vpc", outputs)
+                .build(),
+        );
+        let runtime = runtime(yaml, &runner, RunOptions::default());
+        runtime
+            .run_module("acc.compute", TerraformAction::Apply)
+            .await
+            .unwrap();
+
+        let variables = runner.variables("acc.compute").unwrap();
+        assert_eq!(variables["password"], Value::from("s3cr3t"));
+        let compute = runtime
+            .module_runs()
+            .into_iter()
+            .find(|run| run.id == "acc.compute")
+            .unwrap();
+        assert_eq!(compute.inputs["password"], Value::from("***"));
+        assert_eq!(compute.inputs["vpc_id"], Value::from("vpc-1"));
+
+        let inspected = runtime.inspect_module("acc.compute").await.unwrap();
+        assert_eq!(inspected["password"].value, Value::from("***"));
+        assert!(matches!(
+            inspected["password"].origin,
+            VariableOrigin::Dependency {
+                sensitive: true,
+                ..
+            }
+        ));
+        assert_eq!(inspected["vpc_id"].value, Value::from("vpc-1"));
+    }
+
+    /// Interrupt the runtime as Ctrl-C would, `times` times, once `after` elapsed
+    fn interrupt(runtime: &Runtime, times: u32, after: Duration) {
+        let interrupts = runtime.interrupts.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(after).await;
+            for _ in 0..times {
+                interrupts.send_modify(|count| *count += 1);
+            }
+        });
+    }
+
+    #[tokio::test]
+    async fn interrupt_lets_running_modules_finish_and_starts_no_other() {
+        let runner = Arc::new(
+            ScriptedRunner::builder()
+                .delay("acc.vpc", Duration::from_millis(300))
+                .build(),
+        );
+        let options = RunOptions {
+            with_deps: true,
+            ..Default::default()
+        };
+        let runtime = runtime(CHAIN, &runner, options);
+        interrupt(&runtime, 1, Duration::from_millis(50));
+        let err = runtime
+            .run_module("acc.webapp", TerraformAction::Apply)
+            .await
+            .unwrap_err();
+        assert!(format!("{err:#}").contains("were not started"), "{err:#}");
+        // vpc was running, so it finished its apply; the later layers never started
+        assert_eq!(runner.finished_on(Command::Apply), ["acc.vpc"]);
+        assert!(
+            runner.all_calls().keys().all(|(id, _)| id == "acc.vpc"),
+            "{:?}",
+            runner.all_calls()
+        );
+        assert_eq!(statuses(&runtime)["acc.vpc"], ModuleStatus::Succeeded);
+    }
+
+    #[tokio::test]
+    async fn second_interrupt_cancels_the_running_modules() {
+        let runner = Arc::new(
+            ScriptedRunner::builder()
+                .delay("acc.vpc", Duration::from_secs(30))
+                .build(),
+        );
+        let options = RunOptions {
+            with_deps: true,
+            ..Default::default()
+        };
+        let runtime = runtime(CHAIN, &runner, options);
+        interrupt(&runtime, 2, Duration::from_millis(50));
+        let err = tokio::time::timeout(
+            Duration::from_secs(5),
+            runtime.run_module("acc.webapp", TerraformAction::Apply),
+        )
+        .await
+        .expect("the running module was waited for")
+        .unwrap_err();
+        assert!(
+            format!("{err:#}").contains("Interrupted twice, cancelled 'acc.vpc'"),
+            "{err:#}"
+        );
+        assert!(runner.finished().is_empty(), "{:?}", runner.finished());
+        assert_eq!(statuses(&runtime)["acc.vpc"], ModuleStatus::Cancelled);
+        assert_eq!(runner.calls("acc.compute", Command::Init), 0);
+    }
+
+    #[tokio::test]
+    async fn module_outputs_are_unwrapped_and_redacted() {
+        let terraform_output = |yaml: &str| serde_yaml::from_str::<Value>(yaml).unwrap();
+        let runner = Arc::new(
+            ScriptedRunner::builder()
+                .outputs(
+                    "acc.vpc",
+                    [
+                        (
+                            "vpc_id",
+                            terraform_output("{value: vpc-1, type: string, sensitive: false}"),
+                        ),
+                        (
+                            "token",
+                            terraform_output("{value: s3cr3t, type: string, sensitive: true}"),
+                        ),
+                    ],
+                )
+                .cached(
+                    "acc.compute",
+                    [("instance_id", terraform_output("{value: i-cached}"))],
+                )
+                .build(),
+        );
+        let options = RunOptions {
+            use_cached_outputs: true,
+            ..Default::default()
+        };
+        let runtime = runtime(CHAIN, &runner, options);
+
+        let outputs = runtime.module_outputs("acc.vpc").await.unwrap();
+        assert_eq!(
+            outputs,
+            BTreeMap::from([
+                ("token".to_string(), Value::from("***")),
+                ("vpc_id".to_string(), Value::from("vpc-1")),
+            ])
+        );
+        assert_eq!(runner.calls("acc.vpc", Command::Output), 1);
+
+        let outputs = runtime.module_outputs("acc.compute").await.unwrap();
+        assert_eq!(outputs["instance_id"], Value::from("i-cached"));
+        assert_eq!(runner.calls("acc.compute", Command::Init), 0);
+        assert_eq!(runner.calls("acc.compute", Command::Output), 0);
+    }
+
+    #[tokio::test]
+    async fn plan_only_deps_plans_the_dependencies_and_applies_the_target() {
+        let options = || RunOptions {
+            plan_only_deps: true,
+            ..Default::default()
+        };
+        let runner = Arc::new(ScriptedRunner::default());
+        runtime(CHAIN, &runner, options())
+            .run_module("acc.webapp", TerraformAction::Apply)
+            .await
+            .unwrap();
+        assert_eq!(
+            runner.finished_on(Command::Plan),
+            ["acc.vpc", "acc.compute"]
+        );
+        assert_eq!(runner.finished_on(Command::Apply), ["acc.webapp"]);
+
+        let err = runtime(CHAIN, &runner, options())
+            .run_module("acc.webapp", TerraformAction::Plan)
+            .await
+            .unwrap_err();
+        assert!(
+            format!("{err:#}").contains("only runs with apply"),
+            "{err:#}"
+        );
+    }
+
+    #[tokio::test]
+    async fn plan_only_deps_stops_on_dependency_drift_unless_ignored() {
+        let drifted = || {
+            Arc::new(
+                ScriptedRunner::builder()
+                    .plan_changes("acc.compute", None)
+                    .build(),
+            )
+        };
+        let runner = drifted();
+        let options = RunOptions {
+            plan_only_deps: true,
+            ..Default::default()
+        };
+        let err = runtime(CHAIN, &runner, options)
+            .run_module("acc.webapp", TerraformAction::Apply)
+            .await
+            .unwrap_err();
+        assert!(
+            format!("{err:#}").contains("dependency 'acc.compute' has changes to apply"),
+            "{err:#}"
+        );
+        assert!(runner.finished_on(Command::Apply).is_empty());
+
+        let runner = drifted();
+        let options = RunOptions {
+            plan_only_deps: true,
+            ignore_dep_drift: true,
+            ..Default::default()
+        };
+        runtime(CHAIN, &runner, options)
+            .run_module("acc.webapp", TerraformAction::Apply)
+            .await
+            .unwrap();
+        assert_eq!(runner.finished_on(Command::Apply), ["acc.webapp"]);
+    }
+
+    #[tokio::test]
+    async fn order_after_runs_first_but_its_outputs_are_not_readable() {
+        let yaml = "
+acc:
+  scope: account
+  vpc:
+    source: vpc
+    mocked_outputs:
+      vpc_id: vpc-1
+  webapp:
+    source: webapp
+    order_after: [vpc]
+";
+        let runner = Arc::new(ScriptedRunner::default());
+        let options = || RunOptions {
+            with_deps: true,
+            ..Default::default()
+        };
+        runtime(yaml, &runner, options())
+            .run_module("acc.webapp", TerraformAction::Apply)
+            .await
+            .unwrap();
+        assert_eq!(
+            runner.finished_on(Command::Apply),
+            ["acc.vpc", "acc.webapp"]
+        );
+
+        let reading = format!("{yaml}    inputs:\n      vpc_id:\n        from: vpc.vpc_id\n");
+        let runner = Arc::new(ScriptedRunner::default());
+        let err = runtime(&reading, &runner, options())
+            .run_module("acc.webapp", TerraformAction::Apply)
+            .await
+            .unwrap_err();
+        assert!(format!("{err:#}").contains("vpc.vpc_id"), "{err:#}");
+        assert!(
+            runner
+                .finished_on(Command::Apply)
+                .iter()
+                .all(|id| id != "acc.webapp")
+        );
+    }
+
+    #[tokio::test]
+    async fn collect_errors_lists_every_unresolved_input() {
+        let yaml = "
+acc:
+  scope: account
+  vpc:
+    source: vpc
+    mocked_outputs:
+      vpc_id: vpc-1
+      subnets: [subnet-1]
+  webapp:
+    source: webapp
+    dependencies: [vpc]
+    inputs:
+      a_missing_dependency:
+        from: ghost.id
+      b_missing_key:
+        from: vpc.nope
+      c_out_of_range:
+        from: vpc.subnets[5]
+";
+        let run = |collect_errors: bool| async move {
+            let runner = Arc::new(ScriptedRunner::default());
+            let options = RunOptions {
+                collect_errors,
+                ..Default::default()
+            };
+            let err = runtime(yaml, &runner, options)
+                .run_module("acc.webapp", TerraformAction::Apply)
+                .await
+                .unwrap_err();
+            format!("{err:#}")
+        };
+        let collected = run(true).await;
+        assert!(
+            collected.contains("3 input(s) of module 'acc.webapp' did not resolve"),
+            "{collected}"
+        );
+        for line in [
+            "input 'a_missing_dependency': Reference 'ghost.id' not found",
+            "input 'b_missing_key': dependency 'vpc' ('acc.vpc') produced no output named 'nope'",
+            "input 'c_out_of_range': Cannot resolve 'vpc.subnets[5]': index 5 out of range",
+        ] {
+            assert!(collected.contains(line), "{collected}");
+        }
+        let first = run(false).await;
+        assert!(first.contains("ghost.id"), "{first}");
+        assert!(!first.contains("vpc.nope"), "{first}");
+    }
+
+    fn read_path(root: &str, path: &str, outputs: bool) -> Result<Value> {
+        let root: Value = serde_yaml::from_str(root).unwrap();
+        get_value_from_path(&root, &parse_path(path, 0).unwrap(), outputs)
+    }
+
+    #[test]
+    fn unreadable_paths_say_what_they_found() {
+        let outputs = "{subnets: {value: [a, b, c]}, name: {value: web}}";
+        let err = |path: &str| read_path(outputs, path, true).unwrap_err().to_string();
+        assert_eq!(
+            read_path(outputs, "subnets[2]", true).unwrap(),
+            Value::from("c")
+        );
+        assert_eq!(
+            err("subnets[5]"),
+            "index 5 out of range at 'subnets' (len 3)"
+        );
+        assert_eq!(
+            err("name.id"),
+            "expected mapping at 'name' to read 'id' but found string"
+        );
+        assert_eq!(
+            err("subnets.id"),
+            "expected mapping at 'subnets' to read 'id' but found sequence"
+        );
+        assert_eq!(
+            err("name[0]"),
+            "expected sequence at 'name' to read [0] but found string"
+        );
+        assert_eq!(err("ghost"), "no key 'ghost' at the top level");
+    }
+
+    #[test]
+    fn only_the_terraform_output_wrapper_is_unwrapped() {
+        let outputs = "{config: {value: {value: 1, enabled: true}, sensitive: false}}";
+        let config: Value = serde_yaml::from_str("{value: 1, enabled: true}").unwrap();
+        assert_eq!(read_path(outputs, "config", true).unwrap(), config);
+        assert_eq!(
+            read_path(outputs, "config.value", true).unwrap(),
+            Value::from(1)
+        );
+        assert_eq!(
+            read_path(outputs, "config.enabled", true).unwrap(),
+            Value::from(true)
+        );
+        // Scope variables have no wrapper to remove
+        let variables = "{config: {value: 1}}";
+        assert_eq!(
+            read_path(variables, "config", false).unwrap(),
+            serde_yaml::from_str::<Value>("{value: 1}").unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn dependency_output_holding_a_value_key_is_passed_whole() {
+        let yaml = "
+acc:
+  scope: account
+  vpc:
+    source: vpc
+  webapp:
+    source: webapp
+    dependencies: [vpc]
+    inputs:
+      config:
+        from: vpc.config
+";
+        let output = serde_yaml::from_str("{value: {value: blob, format: json}, type: object}");
+        let runner = Arc::new(
+            ScriptedRunner::builder()
+                .outputs("acc.vpc", [("config", output.unwrap())])
+                .build(),
+        );
+        runtime(yaml, &runner, RunOptions::default())
+            .run_module("acc.webapp", TerraformAction::Apply)
+            .await
+            .unwrap();
+        assert_eq!(
+            runner.variables("acc.webapp").unwrap()["config"],
+            serde_yaml::from_str::<Value>("{value: blob, format: json}").unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn missing_expected_output_fails_before_the_consumer_runs() {
+        let yaml = "
+acc:
+  scope: account
+  vpc:
+    source: vpc
+    mocked_outputs:
+      vpc_id: vpc-1
+  webapp:
+    source: webapp
+    dependencies: [vpc]
+    expects:
+      vpc: [vpc_id, subnets]
+";
+        let runner = Arc::new(ScriptedRunner::default());
+        let err = runtime(yaml, &runner, RunOptions::default())
+            .run_module("acc.webapp", TerraformAction::Apply)
+            .await
+            .unwrap_err();
+        let message = format!("{err:#}");
+        assert!(
+            message.contains(
+                "module 'acc.webapp' is missing 1 expected output(s) of its dependencies"
+            ),
+            "{message}"
+        );
+        assert!(
+            message.contains("dependency 'vpc' ('acc.vpc') produced no output named 'subnets'"),
+            "{message}"
+        );
+        assert!(!message.contains("'vpc_id'"), "{message}");
+        assert_eq!(runner.calls("acc.webapp", Command::Init), 0);
+    }
+
+    #[tokio::test]
+    async fn scope_variable_override_reaches_the_modules_of_the_scope() {
+        let yaml = "
+org:
+  scope: org
+  acc:
+    scope: account
+    variables:
+      region: eu-west-1
+    webapp:
+      source: webapp
+      inputs:
+        region:
+          from: account.region
+";
+        let mut infra = infra(yaml);
+        infra
+            .set_scope_variable("org.acc", "region", Value::from("us-east-1"))
+            .unwrap();
+        let err = infra
+            .set_scope_variable("org.ghost", "region", Value::from("us-east-1"))
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "No scope 'org.ghost' to set variable 'region' on"
+        );
+
+        let runner = Arc::new(ScriptedRunner::default());
+        Runtime::new(runner.clone(), &infra, RunOptions::default())
+            .unwrap()
+            .run_module("org.acc.webapp", TerraformAction::Apply)
+            .await
+            .unwrap();
+        assert_eq!(
+            runner.variables("org.acc.webapp").unwrap()["region"],
+            Value::from("us-east-1")
+        );
+    }
+
+    #[tokio::test]
+    async fn serial_apply_inits_each_module_with_its_inputs() {
+        let yaml = "
+acc:
+  scope: account
+  vpc:
+    source: vpc
+    mocked_outputs:
+      vpc_id: vpc-1
+  network:
+    source: network
+  compute:
+    source: compute
+    dependencies: [vpc]
+    inputs:
+      vpc_id:
+        from: vpc.vpc_id
+  webapp:
+    source: webapp
+    dependencies: [compute, network]
+";
+        let runner = Arc::new(ScriptedRunner::default());
+        let options = RunOptions {
+            serial_global_apply: true,
+            concurrency: Some(2),
+            ..Default::default()
+        };
+        runtime(yaml, &runner, options)
+            .run_module("acc.webapp", TerraformAction::Apply)
+            .await
+            .unwrap();
+        // Like in layers, compute is initialized once vpc ran, with the vpc outputs
+        assert_eq!(
+            runner.init_variables("acc.compute").unwrap()["vpc_id"],
+            Value::from("vpc-1")
+        );
+        let finished = runner.finished();
+        let at = |id: &str, command| {
+            finished
+                .iter()
+                .position(|c| *c == (id.to_string(), command))
+        };
+        assert!(at("acc.vpc", Command::Apply) < at("acc.compute", Command::Init));
+        // Modules with nothing to wait for are initialized before any apply
+        let first_apply = at("acc.vpc", Command::Apply);
+        assert!(at("acc.network", Command::Init) < first_apply);
+        assert_eq!(
+            runner.finished_on(Command::Apply),
+            ["acc.network", "acc.vpc", "acc.compute", "acc.webapp"]
+        );
+    }
 }
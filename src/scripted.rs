@@ -0,0 +1,471 @@
+use crate::parser::ModuleNode;
+use crate::terraform::{ChangeCounts, PlanResult, RunTerraformCommand};
+use anyhow::Result;
+use async_trait::async_trait;
+use serde_yaml::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use thiserror::Error;
+
+/// Terraform command of a [`ScriptedRunner`], counted on every call and failed on demand
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Command {
+    Init,
+    Output,
+    Plan,
+    Apply,
+    Destroy,
+    Refresh,
+    Validate,
+}
+
+/// Failure a [`ScriptedRunner`] was told to inject, downcastable from the run error
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+#[error("Scripted {command:?} of '{module_id}' failed: {message}")]
+pub struct ScriptedFailure {
+    pub module_id: String,
+    pub command: Command,
+    pub message: String,
+}
+
+#[derive(Debug)]
+struct Failure {
+    message: String,
+    /// Calls that fail before the command succeeds, every call when `None`
+    times: Option<usize>,
+}
+
+/// Runner that runs nothing and answers as scripted per module: outputs, plan
+/// changes, failures of a command and how long a command takes. Every call is
+/// counted, to check in tests what a run did. Build it with [`ScriptedRunner::builder`].
+#[derive(Debug, Default)]
+pub struct ScriptedRunner {
+    outputs: HashMap<String, HashMap<String, Value>>,
+    cached: HashMap<String, HashMap<String, Value>>,
+    plans: HashMap<String, PlanResult>,
+    failures: HashMap<(String, Command), Failure>,
+    delays: HashMap<String, Duration>,
+    calls: Mutex<BTreeMap<(String, Command), usize>>,
+    /// Calls that returned, in the order they did
+    finished: Mutex<Vec<(String, Command)>>,
+    /// Variables of the last plan, apply, destroy or refresh of each module
+    variables: Mutex<HashMap<String, HashMap<String, Value>>>,
+    /// Variables of the last init of each module
+    init_variables: Mutex<HashMap<String, HashMap<String, Value>>>,
+    /// `-target` resources of the last plan or apply of each module
+    targets: Mutex<HashMap<String, Vec<String>>>,
+    running: AtomicUsize,
+    peak: AtomicUsize,
+}
+
+/// Counts a call as running until it returns or is dropped, when its module is cancelled
+struct Running<'a>(&'a AtomicUsize);
+
+impl Drop for Running<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Builder of a [`ScriptedRunner`]
+#[derive(Debug, Default)]
+pub struct ScriptedRunnerBuilder {
+    runner: ScriptedRunner,
+}
+
+impl ScriptedRunnerBuilder {
+    /// Outputs `output` returns for the module, in place of its `mocked_outputs`
+    pub fn outputs<K: Into<String>>(
+        mut self,
+        module_id: &str,
+        outputs: impl IntoIterator<Item = (K, Value)>,
+    ) -> Self {
+        self.runner.outputs.insert(
+            module_id.to_string(),
+            outputs.into_iter().map(|(k, v)| (k.into(), v)).collect(),
+        );
+        self
+    }
+
+    /// Outputs `cached_outputs` returns for the module, as if an earlier run saved them
+    pub fn cached<K: Into<String>>(
+        mut self,
+        module_id: &str,
+        outputs: impl IntoIterator<Item = (K, Value)>,
+    ) -> Self {
+        self.runner.cached.insert(
+            module_id.to_string(),
+            outputs.into_iter().map(|(k, v)| (k.into(), v)).collect(),
+        );
+        self
+    }
+
+    /// Make the plan of the module report changes, counted or not
+    pub fn plan_changes(mut self, module_id: &str, counts: Option<ChangeCounts>) -> Self {
+        self.runner
+            .plans
+            .insert(module_id.to_string(), PlanResult::Changes(counts));
+        self
+    }
+
+    /// Fail every call of the command on the module
+    pub fn fail(mut self, module_id: &str, command: Command, message: &str) -> Self {
+        self.runner.failures.insert(
+            (module_id.to_string(), command),
+            Failure {
+                message: message.to_string(),
+                times: None,
+            },
+        );
+        self
+    }
+
+    /// Fail the first `times` calls of the command on the module, then succeed
+    pub fn fail_times(
+        mut self,
+        module_id: &str,
+        command: Command,
+        times: usize,
+        message: &str,
+    ) -> Self {
+        self.runner.failures.insert(
+            (module_id.to_string(), command),
+            Failure {
+                message: message.to_string(),
+                times: Some(times),
+            },
+        );
+        self
+    }
+
+    /// Wait before answering any command on the module, to keep it running
+    /// while another one fails or the run is interrupted
+    pub fn delay(mut self, module_id: &str, delay: Duration) -> Self {
+        self.runner.delays.insert(module_id.to_string(), delay);
+        self
+    }
+
+    pub fn build(self) -> ScriptedRunner {
+        self.runner
+    }
+}
+
+impl ScriptedRunner {
+    pub fn builder() -> ScriptedRunnerBuilder {
+        ScriptedRunnerBuilder::default()
+    }
+
+    /// How many times the command was called on the module
+    pub fn calls(&self, module_id: &str, command: Command) -> usize {
+        let calls = self.calls.lock().unwrap();
+        calls
+            .get(&(module_id.to_string(), command))
+            .copied()
+            .unwrap_or(0)
+    }
+
+    /// Every call made so far, by module and command
+    pub fn all_calls(&self) -> BTreeMap<(String, Command), usize> {
+        self.calls.lock().unwrap().clone()
+    }
+
+    /// Calls that returned, successful or not, in the order they did; a call
+    /// cancelled while running is counted by `calls` but missing here
+    pub fn finished(&self) -> Vec<(String, Command)> {
+        self.finished.lock().unwrap().clone()
+    }
+
+    /// Modules the command returned on, in order
+    pub fn finished_on(&self, command: Command) -> Vec<String> {
+        self.finished()
+            .into_iter()
+            .filter(|(_, c)| *c == command)
+            .map(|(id, _)| id)
+            .collect()
+    }
+
+    /// Variables the last plan, apply, destroy or refresh of the module ran with
+    pub fn variables(&self, module_id: &str) -> Option<HashMap<String, Value>> {
+        self.variables.lock().unwrap().get(module_id).cloned()
+    }
+
+    /// Variables the last init of the module ran with
+    pub fn init_variables(&self, module_id: &str) -> Option<HashMap<String, Value>> {
+        self.init_variables.lock().unwrap().get(module_id).cloned()
+    }
+
+    /// Resources the last plan or apply of the module was limited to
+    pub fn resource_targets(&self, module_id: &str) -> Option<Vec<String>> {
+        self.targets.lock().unwrap().get(module_id).cloned()
+    }
+
+    /// Most calls running at once so far
+    pub fn peak_running(&self) -> usize {
+        self.peak.load(Ordering::SeqCst)
+    }
+
+    /// Count the call, wait the delay of the module and fail it if scripted to
+    async fn call(&self, module: &ModuleNode, command: Command) -> Result<()> {
+        let key = (module.id.clone(), command);
+        let count = {
+            let mut calls = self.calls.lock().unwrap();
+            let count = calls.entry(key.clone()).or_insert(0);
+            *count += 1;
+            *count
+        };
+        let running = self.running.fetch_add(1, Ordering::SeqCst) + 1;
+        self.peak.fetch_max(running, Ordering::SeqCst);
+        let _running = Running(&self.running);
+        if let Some(delay) = self.delays.get(&module.id) {
+            tokio::time::sleep(*delay).await;
+        }
+        self.finished.lock().unwrap().push(key.clone());
+        match self.failures.get(&key) {
+            Some(failure) if failure.times.is_none_or(|times| count <= times) => {
+                Err(ScriptedFailure {
+                    module_id: module.id.clone(),
+                    command,
+                    message: failure.message.clone(),
+                }
+                .into())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Record the variables of a command changing the module, then call it
+    async fn change(&self, module: &ModuleNode, command: Command) -> Result<()> {
+        self.variables
+            .lock()
+            .unwrap()
+            .insert(module.id.clone(), module.variables.clone());
+        self.call(module, command).await
+    }
+
+    fn record_targets(&self, module: &ModuleNode, resources: &[String]) {
+        self.targets
+            .lock()
+            .unwrap()
+            .insert(module.id.clone(), resources.to_vec());
+    }
+}
+
+#[async_trait]
+impl RunTerraformCommand for ScriptedRunner {
+    async fn init(&self, module: &ModuleNode) -> Result<()> {
+        self.init_variables
+            .lock()
+            .unwrap()
+            .insert(module.id.clone(), module.variables.clone());
+        self.call(module, Command::Init).await
+    }
+
+    /// The scripted outputs of the module, else its `mocked_outputs`
+    async fn output(&self, module: &ModuleNode) -> Result<HashMap<String, Value>> {
+        self.call(module, Command::Output).await?;
+        Ok(match self.outputs.get(&module.id) {
+            Some(outputs) => outputs.clone(),
+            None => module.mocked_outputs.clone().unwrap_or_default(),
+        })
+    }
+
+    /// Writes the plan file, if any, like terraform
+    async fn plan(
+        &self,
+        module: &ModuleNode,
+        plan_file: Option<&Path>,
+        resources: &[String],
+    ) -> Result<PlanResult> {
+        self.record_targets(module, resources);
+        self.change(module, Command::Plan).await?;
+        if let Some(plan_file) = plan_file {
+            if let Some(parent) = plan_file.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::write(plan_file, format!("scripted plan of {}\n", module.id))?;
+        }
+        Ok(self
+            .plans
+            .get(&module.id)
+            .copied()
+            .unwrap_or(PlanResult::NoChanges))
+    }
+
+    async fn apply(
+        &self,
+        module: &ModuleNode,
+        _plan_file: Option<&Path>,
+        resources: &[String],
+        _auto_approve: bool,
+    ) -> Result<()> {
+        self.record_targets(module, resources);
+        self.change(module, Command::Apply).await
+    }
+
+    async fn destroy(&self, module: &ModuleNode, _auto_approve: bool) -> Result<()> {
+        self.change(module, Command::Destroy).await
+    }
+
+    async fn refresh(&self, module: &ModuleNode) -> Result<()> {
+        self.change(module, Command::Refresh).await
+    }
+
+    async fn show_plan_json(&self, _module: &ModuleNode, _plan_file: &Path) -> Result<Vec<u8>> {
+        Ok(b"{}".to_vec())
+    }
+
+    async fn state_list(&self, _module: &ModuleNode) -> Result<Vec<String>> {
+        Ok(Vec::new())
+    }
+
+    async fn check_version(&self, _module: &ModuleNode) -> Result<()> {
+        Ok(())
+    }
+
+    async fn validate(&self, module: &ModuleNode, _check_fmt: bool) -> Result<()> {
+        self.init(module).await?;
+        self.call(module, Command::Validate).await
+    }
+
+    async fn declared_variables(
+        &self,
+        _module: &ModuleNode,
+    ) -> Result<Option<BTreeMap<String, bool>>> {
+        Ok(None)
+    }
+
+    async fn source_digest(&self, _module: &ModuleNode) -> Result<Option<String>> {
+        Ok(None)
+    }
+
+    /// The scripted cached outputs of the module, not counted as a call
+    async fn cached_outputs(&self, module: &ModuleNode) -> Result<Option<HashMap<String, Value>>> {
+        Ok(self.cached.get(&module.id).cloned())
+    }
+
+    async fn run_hook(
+        &self,
+        _scope_id: &str,
+        _command: &str,
+        _envs: &HashMap<String, String>,
+    ) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graph::ModuleGraph;
+    use crate::runtime::{RunOptions, Runtime};
+    use crate::terraform::TerraformAction;
+    use crate::testing::infra;
+    use std::sync::Arc;
+
+    const CHAIN: &str = "
+acc:
+  scope: account
+  vpc:
+    source: vpc
+  compute:
+    source: compute
+    dependencies: [vpc]
+    inputs:
+      vpc_id:
+        from: vpc.vpc_id
+";
+
+    fn with_deps() -> RunOptions {
+        RunOptions {
+            with_deps: true,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn injected_apply_failure_fails_the_run() {
+        let runner = Arc::new(
+            ScriptedRunner::builder()
+                .outputs("acc.vpc", [("vpc_id", Value::from("vpc-1"))])
+                .fail("acc.compute", Command::Apply, "quota exceeded")
+                .build(),
+        );
+        let runtime = Runtime::new(runner.clone(), &infra(CHAIN), with_deps()).unwrap();
+        let err = runtime
+            .run_module("acc.compute", TerraformAction::Apply)
+            .await
+            .unwrap_err();
+
+        let failure = err
+            .chain()
+            .find_map(|e| e.downcast_ref::<ScriptedFailure>())
+            .expect("scripted failure in the chain");
+        assert_eq!(failure.module_id, "acc.compute");
+        assert_eq!(failure.command, Command::Apply);
+        assert_eq!(failure.message, "quota exceeded");
+        assert_eq!(runner.calls("acc.vpc", Command::Apply), 1);
+        assert_eq!(runner.calls("acc.compute", Command::Apply), 1);
+        // A failed apply reads no outputs
+        assert_eq!(runner.calls("acc.compute", Command::Output), 0);
+    }
+
+    #[tokio::test]
+    async fn scripted_outputs_reach_dependents() {
+        let runner = Arc::new(
+            ScriptedRunner::builder()
+                .outputs("acc.vpc", [("vpc_id", Value::from("vpc-9"))])
+                .build(),
+        );
+        let runtime = Runtime::new(runner.clone(), &infra(CHAIN), with_deps()).unwrap();
+        runtime
+            .run_module("acc.compute", TerraformAction::Apply)
+            .await
+            .unwrap();
+
+        let variables = runner.variables("acc.compute").unwrap();
+        assert_eq!(variables["vpc_id"], Value::from("vpc-9"));
+        assert_eq!(
+            runner.finished_on(Command::Apply),
+            ["acc.vpc", "acc.compute"]
+        );
+    }
+
+    #[tokio::test]
+    async fn fail_times_fails_then_succeeds() {
+        let graph = ModuleGraph::new(&infra(CHAIN)).unwrap();
+        let vpc = graph.get_module_by_id("acc.vpc").unwrap();
+        let runner = ScriptedRunner::builder()
+            .fail_times("acc.vpc", Command::Init, 2, "flaky")
+            .build();
+
+        assert!(runner.init(&vpc).await.is_err());
+        assert!(runner.init(&vpc).await.is_err());
+        assert!(runner.init(&vpc).await.is_ok());
+        assert!(runner.init(&vpc).await.is_ok());
+        assert_eq!(runner.calls("acc.vpc", Command::Init), 4);
+        assert_eq!(runner.calls("acc.vpc", Command::Apply), 0);
+    }
+
+    #[tokio::test]
+    async fn plan_changes_are_reported() {
+        let counts = ChangeCounts {
+            create: 2,
+            ..Default::default()
+        };
+        let runner = Arc::new(
+            ScriptedRunner::builder()
+                .plan_changes("acc.vpc", Some(counts))
+                .build(),
+        );
+        let runtime = Runtime::new(runner, &infra(CHAIN), RunOptions::default()).unwrap();
+        let report = runtime
+            .run_module("acc.vpc", TerraformAction::Plan)
+            .await
+            .unwrap();
+        assert_eq!(report.changes, ["acc.vpc"]);
+        assert_eq!(report.change_counts["acc.vpc"], counts);
+    }
+}
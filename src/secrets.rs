@@ -0,0 +1,169 @@
+use anyhow::{Context, Result, anyhow};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::fs;
+use tokio::process::Command;
+
+/// Backend resolving secret references of a single URI scheme
+#[async_trait::async_trait]
+pub trait SecretResolver: std::fmt::Debug + Send + Sync {
+    /// Resolve the part of the reference after `<scheme>://`
+    async fn resolve(&self, key: &str) -> Result<String>;
+}
+
+/// Registry of secret backends keyed by URI scheme
+#[derive(Debug, Clone)]
+pub struct SecretStore {
+    resolvers: HashMap<String, Arc<dyn SecretResolver>>,
+}
+
+impl SecretStore {
+    /// Store with the built-in `env`, `file`, `aws-sm` and `vault` backends
+    pub fn new() -> Self {
+        let mut store = Self {
+            resolvers: HashMap::new(),
+        };
+        store.register("env", Arc::new(EnvSecret));
+        store.register("file", Arc::new(FileSecret));
+        store.register("aws-sm", Arc::new(AwsSecretsManager));
+        store.register("vault", Arc::new(VaultSecret));
+        store
+    }
+
+    /// Register (or replace) the backend used for `scheme://` references
+    pub fn register(&mut self, scheme: &str, resolver: Arc<dyn SecretResolver>) {
+        self.resolvers.insert(scheme.to_string(), resolver);
+    }
+
+    /// Resolve a reference like "aws-sm://prod/db/password".
+    /// Errors never include the secret value.
+    pub async fn resolve(&self, uri: &str) -> Result<String> {
+        let (scheme, key) = uri.split_once("://").ok_or_else(|| {
+            anyhow!(
+                "Invalid secret reference '{}': expected <scheme>://<key>",
+                uri
+            )
+        })?;
+        let resolver = self.resolvers.get(scheme).ok_or_else(|| {
+            let mut known: Vec<_> = self.resolvers.keys().cloned().collect();
+            known.sort();
+            anyhow!(
+                "Unknown secret scheme '{}' in '{}' (known schemes: {})",
+                scheme,
+                uri,
+                known.join(", ")
+            )
+        })?;
+        resolver
+            .resolve(key)
+            .await
+            .with_context(|| format!("Failed to resolve secret '{}'", uri))
+    }
+}
+
+impl Default for SecretStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// `env://NAME` — read from the orchestrator's environment
+#[derive(Debug)]
+pub struct EnvSecret;
+
+#[async_trait::async_trait]
+impl SecretResolver for EnvSecret {
+    async fn resolve(&self, key: &str) -> Result<String> {
+        std::env::var(key).map_err(|_| anyhow!("environment variable '{}' is not set", key))
+    }
+}
+
+/// `file://path` — read a file, trailing newline stripped
+#[derive(Debug)]
+pub struct FileSecret;
+
+#[async_trait::async_trait]
+impl SecretResolver for FileSecret {
+    async fn resolve(&self, key: &str) -> Result<String> {
+        let content = fs::read_to_string(key)
+            .await
+            .with_context(|| format!("Failed to read secret file {:?}", key))?;
+        Ok(content.trim_end_matches(['\r', '\n']).to_string())
+    }
+}
+
+/// `aws-sm://<secret-id>[#json_key]` — AWS Secrets Manager through the aws CLI
+#[derive(Debug)]
+pub struct AwsSecretsManager;
+
+#[async_trait::async_trait]
+impl SecretResolver for AwsSecretsManager {
+    async fn resolve(&self, key: &str) -> Result<String> {
+        let (secret_id, json_key) = match key.split_once('#') {
+            Some((id, field)) => (id, Some(field)),
+            None => (key, None),
+        };
+        let value = run_secret_cmd(
+            "aws",
+            &[
+                "secretsmanager",
+                "get-secret-value",
+                "--secret-id",
+                secret_id,
+                "--query",
+                "SecretString",
+                "--output",
+                "text",
+            ],
+        )
+        .await?;
+        match json_key {
+            Some(field) => {
+                let json: serde_json::Value = serde_json::from_str(&value)
+                    .map_err(|_| anyhow!("secret '{}' is not a JSON object", secret_id))?;
+                match json.get(field) {
+                    Some(serde_json::Value::String(s)) => Ok(s.clone()),
+                    Some(other) => Ok(other.to_string()),
+                    None => Err(anyhow!("secret '{}' has no key '{}'", secret_id, field)),
+                }
+            }
+            None => Ok(value),
+        }
+    }
+}
+
+/// `vault://<path>#<field>` — HashiCorp Vault KV through the vault CLI
+#[derive(Debug)]
+pub struct VaultSecret;
+
+#[async_trait::async_trait]
+impl SecretResolver for VaultSecret {
+    async fn resolve(&self, key: &str) -> Result<String> {
+        let (path, field) = key
+            .split_once('#')
+            .ok_or_else(|| anyhow!("vault reference must be vault://<path>#<field>"))?;
+        let field_arg = format!("-field={}", field);
+        run_secret_cmd("vault", &["kv", "get", &field_arg, path]).await
+    }
+}
+
+/// Run a secrets CLI and return its trimmed stdout.
+/// Only stderr is surfaced on failure, stdout may hold the secret.
+async fn run_secret_cmd(bin: &str, args: &[&str]) -> Result<String> {
+    let output = Command::new(bin)
+        .args(args)
+        .output()
+        .await
+        .with_context(|| format!("Failed to run {}", bin))?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "{} failed with status {:?}\nStderr: {}",
+            bin,
+            output.status,
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .trim_end_matches(['\r', '\n'])
+        .to_string())
+}
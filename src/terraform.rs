@@ -1,8 +1,19 @@
+use crate::manifest::sha256_hex;
 use crate::parser::ModuleNode;
+use crate::progress::progress;
+use crate::variables::read_declared_variables;
+use crate::version::{Version, VersionConstraint, read_required_versions};
 use anyhow::{Context, Result};
+use colored::{Color, Colorize};
+use serde::Serialize;
 use serde_yaml::Value;
-use std::collections::HashMap;
-use std::path::{Path, PathBuf};
+use std::collections::{BTreeMap, HashMap};
+use std::num::NonZeroUsize;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
 use tokio::process::Command;
 use tokio::{fs, io};
 
@@ -12,23 +23,298 @@ pub enum TerraformAction {
     Plan,
     Apply,
     Destroy,
+    Refresh,
 }
 
-/// Terraform command outputs
+impl TerraformAction {
+    pub fn name(&self) -> &'static str {
+        match self {
+            TerraformAction::Plan => "plan",
+            TerraformAction::Apply => "apply",
+            TerraformAction::Destroy => "destroy",
+            TerraformAction::Refresh => "refresh",
+        }
+    }
+}
+
+/// Outcome of a successful `terraform plan -detailed-exitcode`; any other exit code
+/// is the `Err` of `RunTerraformCommand::plan`, so `?` propagates it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlanResult {
+    /// Exit code 0
+    NoChanges,
+    /// Exit code 2: the plan succeeded and has changes to apply, counted when the
+    /// plan could be read back
+    Changes(Option<ChangeCounts>),
+}
+
+/// Resource actions of a plan, from the `resource_changes` of `terraform show -json`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct ChangeCounts {
+    pub create: usize,
+    pub update: usize,
+    pub delete: usize,
+    /// Destroyed and created again, in either order
+    pub replace: usize,
+}
+
+impl ChangeCounts {
+    /// Count the actions of the `resource_changes` of a plan JSON document;
+    /// `no-op` and `read` changes are not counted
+    pub fn from_plan_json(json: &[u8]) -> Result<Self> {
+        #[derive(serde::Deserialize)]
+        struct Plan {
+            #[serde(default)]
+            resource_changes: Vec<ResourceChange>,
+        }
+        #[derive(serde::Deserialize)]
+        struct ResourceChange {
+            change: Change,
+        }
+        #[derive(serde::Deserialize)]
+        struct Change {
+            actions: Vec<String>,
+        }
+
+        let plan: Plan = serde_json::from_slice(json).context("Failed to parse plan JSON")?;
+        let mut counts = ChangeCounts::default();
+        for resource in plan.resource_changes {
+            let actions: Vec<&str> = resource.change.actions.iter().map(String::as_str).collect();
+            match actions.as_slice() {
+                ["create"] => counts.create += 1,
+                ["update"] => counts.update += 1,
+                ["delete"] => counts.delete += 1,
+                ["delete", "create"] | ["create", "delete"] => counts.replace += 1,
+                _ => {}
+            }
+        }
+        Ok(counts)
+    }
+}
+
+/// Output of a terraform command that succeeded; a failure is a `TerraformCommandError`
+/// carrying its status and stderr
 pub struct TerraformOutput {
-    status: std::process::ExitStatus,
     stdout: Vec<u8>,
-    stderr: Vec<u8>,
 }
-#[derive(Debug)]
-pub struct MockRunner;
+/// A terraform command that exited with a failure status
+#[derive(Error, Debug)]
+#[error("Terraform command {args:?} failed with status {status:?}\nStderr: {stderr}")]
+pub struct TerraformCommandError {
+    pub args: Vec<String>,
+    pub status: std::process::ExitStatus,
+    pub stderr: String,
+}
+
+/// Warning terraform reported while running a module
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TerraformWarning {
+    pub module_id: String,
+    pub message: String,
+}
+
+#[derive(Debug, Default)]
+pub struct MockRunner {
+    /// Workspace interpolated into backend config, see `TerraformRunner::workspace`
+    pub workspace: Option<String>,
+    /// Resource addresses `state_list` returns for every module
+    pub state: Vec<String>,
+}
 
 /// Trait for running Terraform commands
 #[async_trait::async_trait]
 pub trait RunTerraformCommand: std::fmt::Debug {
     async fn init(&self, module: &ModuleNode) -> Result<()>;
     async fn output(&self, module: &ModuleNode) -> Result<HashMap<String, Value>>;
-    async fn apply(&self, module: &ModuleNode) -> Result<()>;
+    /// Plan the module, saving the plan to `plan_file` when given; a non-empty
+    /// `resources` limits the plan to these addresses (`-target`)
+    async fn plan(
+        &self,
+        module: &ModuleNode,
+        plan_file: Option<&Path>,
+        resources: &[String],
+    ) -> Result<PlanResult>;
+    /// Apply the module, or exactly the saved `plan_file` when given; a non-empty
+    /// `resources` limits the apply to these addresses (`-target`). Without
+    /// `auto_approve`, terraform asks for approval on the terminal.
+    async fn apply(
+        &self,
+        module: &ModuleNode,
+        plan_file: Option<&Path>,
+        resources: &[String],
+        auto_approve: bool,
+    ) -> Result<()>;
+    /// Destroy every resource of the module, asking for approval without `auto_approve`
+    async fn destroy(&self, module: &ModuleNode, auto_approve: bool) -> Result<()>;
+    /// Update the module state from the real resources without changing them
+    async fn refresh(&self, module: &ModuleNode) -> Result<()>;
+    /// `terraform show -json` rendering of a saved plan
+    async fn show_plan_json(&self, module: &ModuleNode, plan_file: &Path) -> Result<Vec<u8>>;
+    /// Addresses of the resources in the module state (`terraform state list`)
+    async fn state_list(&self, module: &ModuleNode) -> Result<Vec<String>>;
+    /// Fail when the terraform in use does not satisfy the module's version constraints
+    async fn check_version(&self, module: &ModuleNode) -> Result<()>;
+    /// Init the module and run `terraform validate`, with `terraform fmt -check
+    /// -recursive` first when `check_fmt` is set
+    async fn validate(&self, module: &ModuleNode, check_fmt: bool) -> Result<()>;
+    /// Variables the module declares, mapped to whether they are required;
+    /// `None` when the runner cannot know
+    async fn declared_variables(
+        &self,
+        module: &ModuleNode,
+    ) -> Result<Option<BTreeMap<String, bool>>>;
+    /// Digest of the module's source files, `None` when the runner cannot know
+    async fn source_digest(&self, module: &ModuleNode) -> Result<Option<String>>;
+    /// Outputs saved by the last `output` of the module, `None` when there are none
+    async fn cached_outputs(&self, module: &ModuleNode) -> Result<Option<HashMap<String, Value>>>;
+    /// Run a scope lifecycle hook command
+    async fn run_hook(
+        &self,
+        scope_id: &str,
+        command: &str,
+        envs: &HashMap<String, String>,
+    ) -> Result<()>;
+    /// Drain the warnings collected since the last call
+    fn take_warnings(&self) -> Vec<TerraformWarning> {
+        Vec::new()
+    }
+}
+
+/// Encode a value for an environment variable: strings raw, numbers and bools
+/// unquoted, sequences and mappings as compact JSON (what terraform parses for
+/// list, map and object variables), YAML tags dropped
+pub fn env_value(v: &Value) -> String {
+    match v {
+        Value::String(s) => s.clone(),
+        Value::Bool(b) => b.to_string(),
+        Value::Number(n) => n.to_string(),
+        Value::Tagged(tagged) => env_value(&tagged.value),
+        _ => serde_json::to_string(v).unwrap_or_else(|_| "null".to_string()),
+    }
+}
+
+/// Redacted variables of the module, sorted by name for stable mock output
+fn sorted_variables(module: &ModuleNode) -> BTreeMap<String, Value> {
+    module.redacted_variables().into_iter().collect()
+}
+
+/// `-target=<address>` flags, in the given order
+fn target_args(resources: &[String]) -> Vec<String> {
+    resources
+        .iter()
+        .map(|address| format!("-target={}", address))
+        .collect()
+}
+
+/// `value` with `${module_id}` and `${workspace}` (`default` without one) replaced
+fn backend_placeholders(value: &str, module: &ModuleNode, workspace: Option<&str>) -> String {
+    value
+        .replace("${module_id}", &module.id)
+        .replace("${workspace}", workspace.unwrap_or("default"))
+}
+
+/// `-backend-config=<key>=<value>` init flags of the module, sorted by key, with
+/// the placeholders of `backend_placeholders` replaced in values
+fn backend_config_args(module: &ModuleNode, workspace: Option<&str>) -> Vec<String> {
+    let mut config: Vec<(&String, &Value)> = module.backend_config.iter().collect();
+    config.sort_by_key(|(k, _)| *k);
+    config
+        .into_iter()
+        .map(|(k, v)| {
+            let value = backend_placeholders(&env_value(v), module, workspace);
+            format!("-backend-config={}={}", k, value)
+        })
+        .collect()
+}
+
+/// Parse a `--backend-template` file, a terraform JSON configuration object
+pub fn read_backend_template(path: &Path) -> Result<serde_json::Value> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read backend template {:?}", path))?;
+    let template: serde_json::Value = serde_json::from_str(&content)
+        .with_context(|| format!("Failed to parse backend template {:?}", path))?;
+    if !template.is_object() {
+        anyhow::bail!("Backend template {:?} must be a JSON object", path);
+    }
+    Ok(template)
+}
+
+/// The template with the placeholders of `backend_placeholders` replaced in every string
+fn render_backend_template(
+    template: &serde_json::Value,
+    module: &ModuleNode,
+    workspace: Option<&str>,
+) -> serde_json::Value {
+    match template {
+        serde_json::Value::String(s) => {
+            serde_json::Value::String(backend_placeholders(s, module, workspace))
+        }
+        serde_json::Value::Array(items) => serde_json::Value::Array(
+            items
+                .iter()
+                .map(|item| render_backend_template(item, module, workspace))
+                .collect(),
+        ),
+        serde_json::Value::Object(map) => serde_json::Value::Object(
+            map.iter()
+                .map(|(k, v)| (k.clone(), render_backend_template(v, module, workspace)))
+                .collect(),
+        ),
+        other => other.clone(),
+    }
+}
+
+/// Why terraform cannot be given the value through `TF_VAR_`, if it cannot
+fn tf_var_problem(v: &Value) -> Option<String> {
+    match v {
+        Value::Number(n) if n.as_f64().is_some_and(|f| !f.is_finite()) => {
+            Some(format!("{} is not a finite number", n))
+        }
+        Value::Sequence(items) => items.iter().find_map(tf_var_problem),
+        Value::Mapping(map) => map.iter().find_map(|(k, v)| match k {
+            Value::String(_) | Value::Number(_) | Value::Bool(_) => tf_var_problem(v),
+            _ => Some(format!("mapping key {:?} is not a scalar", k)),
+        }),
+        Value::Tagged(tagged) => tf_var_problem(&tagged.value),
+        _ => None,
+    }
+}
+
+/// Convert terraform JSON to a YAML value without losing numeric precision:
+/// numbers a YAML number cannot hold exactly (integers beyond 64 bits, decimals
+/// that do not round-trip through f64) are kept as their exact decimal string
+pub fn json_to_value(v: serde_json::Value) -> Value {
+    match v {
+        serde_json::Value::Null => Value::Null,
+        serde_json::Value::Bool(b) => Value::Bool(b),
+        serde_json::Value::Number(n) => {
+            if let Some(u) = n.as_u64() {
+                Value::from(u)
+            } else if let Some(i) = n.as_i64() {
+                Value::from(i)
+            } else {
+                let exact = n.to_string();
+                match n.as_f64() {
+                    Some(f)
+                        if serde_json::Number::from_f64(f)
+                            .is_some_and(|r| r.to_string() == exact) =>
+                    {
+                        Value::from(f)
+                    }
+                    _ => Value::String(exact),
+                }
+            }
+        }
+        serde_json::Value::String(s) => Value::String(s),
+        serde_json::Value::Array(items) => {
+            Value::Sequence(items.into_iter().map(json_to_value).collect())
+        }
+        serde_json::Value::Object(map) => Value::Mapping(
+            map.into_iter()
+                .map(|(k, v)| (Value::String(k), json_to_value(v)))
+                .collect(),
+        ),
+    }
 }
 
 /// Mock runner for testing
@@ -36,7 +322,14 @@ pub trait RunTerraformCommand: std::fmt::Debug {
 #[async_trait::async_trait]
 impl RunTerraformCommand for MockRunner {
     async fn init(&self, module: &ModuleNode) -> Result<()> {
-        println!("[mock] terraform init '{}'", module.id);
+        progress!("[mock] terraform init '{}'", module.id);
+        let backend_config = backend_config_args(module, self.workspace.as_deref());
+        if !backend_config.is_empty() {
+            progress!("[mock] backend config: {:?}", backend_config);
+        }
+        if let Some(workspace) = &self.workspace {
+            progress!("[mock] terraform workspace select '{}'", workspace);
+        }
         Ok(())
     }
 
@@ -44,18 +337,336 @@ impl RunTerraformCommand for MockRunner {
         Ok(module.mocked_outputs.clone().unwrap_or_default())
     }
 
-    async fn apply(&self, module: &ModuleNode) -> Result<()> {
-        println!("[mock] terraform apply '{}'", module.id);
+    async fn plan(
+        &self,
+        module: &ModuleNode,
+        plan_file: Option<&Path>,
+        resources: &[String],
+    ) -> Result<PlanResult> {
+        match plan_file {
+            Some(plan_file) => {
+                progress!("[mock] terraform plan '{}' -> {:?}", module.id, plan_file)
+            }
+            None => progress!("[mock] terraform plan '{}'", module.id),
+        }
+        if !resources.is_empty() {
+            progress!("[mock] targets: {:?}", target_args(resources));
+        }
+        progress!("[mock] variables: {:?}", sorted_variables(module));
+        Ok(PlanResult::NoChanges)
+    }
+
+    async fn apply(
+        &self,
+        module: &ModuleNode,
+        plan_file: Option<&Path>,
+        resources: &[String],
+        auto_approve: bool,
+    ) -> Result<()> {
+        match plan_file {
+            Some(plan_file) => progress!("[mock] terraform apply '{}' {:?}", module.id, plan_file),
+            None => progress!("[mock] terraform apply '{}'", module.id),
+        }
+        if !resources.is_empty() {
+            progress!("[mock] targets: {:?}", target_args(resources));
+        }
+        if !auto_approve && plan_file.is_none() {
+            progress!("[mock] approval asked, answered yes");
+        }
+        progress!("[mock] variables: {:?}", sorted_variables(module));
+        Ok(())
+    }
+
+    async fn destroy(&self, module: &ModuleNode, auto_approve: bool) -> Result<()> {
+        progress!("[mock] terraform destroy '{}'", module.id);
+        if !auto_approve {
+            progress!("[mock] approval asked, answered yes");
+        }
+        progress!("[mock] variables: {:?}", sorted_variables(module));
+        Ok(())
+    }
+
+    async fn refresh(&self, module: &ModuleNode) -> Result<()> {
+        progress!("[mock] terraform refresh '{}'", module.id);
+        progress!("[mock] variables: {:?}", sorted_variables(module));
+        Ok(())
+    }
+
+    async fn show_plan_json(&self, module: &ModuleNode, plan_file: &Path) -> Result<Vec<u8>> {
+        progress!(
+            "[mock] terraform show -json '{}' {:?}",
+            module.id,
+            plan_file
+        );
+        Ok(b"{}".to_vec())
+    }
+
+    async fn state_list(&self, module: &ModuleNode) -> Result<Vec<String>> {
+        progress!("[mock] terraform state list '{}'", module.id);
+        Ok(self.state.clone())
+    }
+
+    async fn validate(&self, module: &ModuleNode, check_fmt: bool) -> Result<()> {
+        if check_fmt {
+            progress!("[mock] terraform fmt -check -recursive '{}'", module.id);
+        }
+        self.init(module).await?;
+        progress!("[mock] terraform validate '{}'", module.id);
+        Ok(())
+    }
+
+    async fn check_version(&self, _module: &ModuleNode) -> Result<()> {
+        Ok(())
+    }
+
+    async fn declared_variables(
+        &self,
+        _module: &ModuleNode,
+    ) -> Result<Option<BTreeMap<String, bool>>> {
+        Ok(None)
+    }
+
+    async fn source_digest(&self, _module: &ModuleNode) -> Result<Option<String>> {
+        Ok(None)
+    }
+
+    /// The mocked outputs stand in for the cache, as for `output`
+    async fn cached_outputs(&self, module: &ModuleNode) -> Result<Option<HashMap<String, Value>>> {
+        self.output(module).await.map(Some)
+    }
+
+    async fn run_hook(
+        &self,
+        scope_id: &str,
+        command: &str,
+        _envs: &HashMap<String, String>,
+    ) -> Result<()> {
+        progress!("[mock] hook '{}': {}", scope_id, command);
         Ok(())
     }
 }
 
+/// Extract warning summaries from terraform's human readable output, both the
+/// boxed `│ Warning: ...` diagnostics and the `-compact-warnings` list
+fn scan_warnings(output: &str) -> Vec<String> {
+    let mut warnings = Vec::new();
+    let mut in_compact_list = false;
+    for line in output.lines() {
+        let line = strip_ansi(line);
+        let boxed = line.trim_start_matches(['│', '╷', '╵']).trim();
+        if let Some(summary) = boxed.strip_prefix("Warning: ") {
+            warnings.push(summary.trim().to_string());
+        } else if boxed == "Warnings:" {
+            in_compact_list = true;
+        } else if in_compact_list {
+            if let Some(summary) = line.strip_prefix("- ") {
+                warnings.push(summary.trim().to_string());
+            } else if line.starts_with("To see the full warning notes") {
+                in_compact_list = false;
+            }
+        }
+    }
+    warnings.dedup();
+    warnings
+}
+
+/// Remove terminal color sequences (`ESC [ ... m`)
+fn strip_ansi(line: &str) -> String {
+    let mut out = String::with_capacity(line.len());
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        if c == '\u{1b}' {
+            for c in chars.by_ref() {
+                if c.is_ascii_alphabetic() {
+                    break;
+                }
+            }
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+/// Forward every line of a child stream to our stdout/stderr and return the whole text
+async fn tee_lines<R: AsyncRead + Unpin>(
+    reader: R,
+    to_stderr: bool,
+    prefix: &str,
+) -> io::Result<String> {
+    let mut lines = BufReader::new(reader).lines();
+    let mut text = String::new();
+    while let Some(line) = lines.next_line().await? {
+        if to_stderr || crate::progress::on_stderr() {
+            eprintln!("{} {}", prefix, line);
+        } else {
+            println!("{} {}", prefix, line);
+        }
+        text.push_str(&line);
+        text.push('\n');
+    }
+    Ok(text)
+}
+
+/// `[module_id]` prefix of echoed terraform output, in a color picked from the id
+/// so concurrent modules are told apart
+fn output_prefix(module_id: &str) -> String {
+    const COLORS: [Color; 6] = [
+        Color::Cyan,
+        Color::Magenta,
+        Color::Blue,
+        Color::Yellow,
+        Color::Green,
+        Color::BrightCyan,
+    ];
+    let pick = module_id.bytes().map(usize::from).sum::<usize>() % COLORS.len();
+    format!("[{}]", module_id).color(COLORS[pick]).to_string()
+}
+
+/// Copy the module sources into its dir, then record their digest
+async fn copy_module_sources(
+    src_dir: &Path,
+    dir: &Path,
+    hash_file: &Path,
+    digest: &str,
+) -> Result<()> {
+    async fn copy_dir(src: &Path, dst: &Path) -> io::Result<()> {
+        let mut stack = vec![(src.to_path_buf(), dst.to_path_buf())];
+        while let Some((src_dir, dst_dir)) = stack.pop() {
+            fs::create_dir_all(&dst_dir).await?;
+            let mut entries = fs::read_dir(&src_dir).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                let path = entry.path();
+                let dst_path = dst_dir.join(entry.file_name());
+                if path.is_dir() {
+                    stack.push((path, dst_path));
+                } else {
+                    fs::copy(&path, &dst_path).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    copy_dir(src_dir, dir).await.with_context(|| {
+        format!(
+            "Failed to copy module files from {:?} to {:?}",
+            src_dir, dir
+        )
+    })?;
+    fs::write(hash_file, digest)
+        .await
+        .with_context(|| format!("Failed to write {:?}", hash_file))
+}
+
+/// Digest of the relative paths and contents of every file under `dir`
+fn digest_dir(dir: &Path) -> Result<String> {
+    let mut payload = Vec::new();
+    for entry in walkdir::WalkDir::new(dir).sort_by_file_name() {
+        let entry = entry.with_context(|| format!("Failed to read {:?}", dir))?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let relative = entry.path().strip_prefix(dir).unwrap_or(entry.path());
+        payload.extend(relative.to_string_lossy().as_bytes());
+        payload.push(0);
+        payload.extend(sha256_hex(&std::fs::read(entry.path())?).as_bytes());
+    }
+    Ok(sha256_hex(&payload))
+}
+
+/// `base * 2^(attempt - 1)`, randomly shortened by up to half so concurrent
+/// modules do not retry in lockstep
+fn jittered_backoff(base: Duration, attempt: u32) -> Duration {
+    let delay = base.saturating_mul(2u32.saturating_pow(attempt - 1));
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.subsec_nanos());
+    delay.mul_f64(1.0 - f64::from(nanos % 1000) / 2000.0)
+}
+
+/// File of the per-module cache dir holding the digest of the copied sources
+const SOURCE_HASH_FILE: &str = ".tfstacks_hash";
+
+/// File of the per-module cache dir holding the last `terraform output -json`
+const OUTPUTS_CACHE_FILE: &str = "outputs.json";
+
+/// Plan saved in the module dir when no plan file is kept, to count its changes
+const CHANGES_PLAN_FILE: &str = ".tfstacks-changes.tfplan";
+
+/// Override terraform merges over the module's backend, rendered from `--backend-template`
+const BACKEND_OVERRIDE_FILE: &str = "backend_override.tf.json";
+
+/// A module id used as a file or directory name, rejected when it is not a single
+/// plain path component, so a crafted scope key cannot escape the directory
+pub fn module_path_component(id: &str) -> Result<&str> {
+    let mut components = Path::new(id).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(name)), None) if name == id && !id.contains('\\') => Ok(id),
+        _ => anyhow::bail!(
+            "Module id '{}' cannot be used as a directory name: it must not contain '/' or '\\' nor be '.' or '..'",
+            id
+        ),
+    }
+}
+
+/// Whether terraform failed because the module has no state to read: its backend
+/// was never initialized or no state was written yet
+fn is_missing_state(stderr: &str) -> bool {
+    let stderr = stderr.to_lowercase();
+    ["initialization required", "no state file was found"]
+        .iter()
+        .any(|marker| stderr.contains(marker))
+}
+
+/// Resource addresses printed by `terraform state list`, one per line
+fn parse_state_list(stdout: &[u8]) -> Vec<String> {
+    String::from_utf8_lossy(stdout)
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Parse `terraform output -json`
+fn parse_outputs(json: &[u8]) -> Result<HashMap<String, Value>> {
+    let value: HashMap<String, serde_json::Value> =
+        serde_json::from_slice(json).context("Failed to parse terraform output")?;
+    Ok(value
+        .into_iter()
+        .map(|(k, v)| (k, json_to_value(v)))
+        .collect())
+}
+
+/// Arguments of a terraform command run in `dir` through the global `-chdir` option,
+/// which must come before the subcommand
+fn chdir_args(dir: &Path, args: &[&str]) -> Vec<String> {
+    std::iter::once(format!("-chdir={}", dir.display()))
+        .chain(args.iter().map(|arg| arg.to_string()))
+        .collect()
+}
+
 /// Real Terraform runner
 #[derive(Debug)]
 pub struct TerraformRunner {
-    pub bin_path: PathBuf,    // terraform binary
-    pub cache_dir: PathBuf,   // per-module terraform state
-    pub modules_dir: PathBuf, // terraform modules source
+    pub bin_path: PathBuf,                             // terraform binary
+    pub cache_dir: PathBuf,                            // per-module terraform state
+    pub modules_dir: PathBuf,                          // terraform modules source
+    pub compact_warnings: bool,                        // pass -compact-warnings to plan/apply
+    pub parallelism: Option<NonZeroUsize>,             // terraform -parallelism of plan/apply
+    pub retries: u32,                                  // extra attempts of a failed init/plan/apply
+    pub retry_backoff: Duration,                       // first retry delay, doubled after
+    pub timeout: Option<Duration>,                     // kill terraform commands running longer
+    pub force_copy: bool,                              // copy module sources even when unchanged
+    pub workspace: Option<String>,                     // terraform workspace selected after init
+    pub raw_output: bool,                              // inherit terraform's stdout/stderr
+    pub backend_template: Option<serde_json::Value>,   // rendered to backend_override.tf.json
+    pub use_chdir: bool,                               // pass -chdir=<dir> instead of the cwd
+    pub no_color: bool,                                // pass -no-color to plan/apply/destroy
+    prompt: tokio::sync::Mutex<()>,                    // held while terraform asks for approval
+    versions: Mutex<HashMap<Option<String>, Version>>, // detected version per container image
+    warnings: Mutex<Vec<TerraformWarning>>,            // collected from every command
 }
 
 impl TerraformRunner {
@@ -64,126 +675,467 @@ impl TerraformRunner {
             bin_path,
             cache_dir,
             modules_dir,
+            compact_warnings: false,
+            parallelism: None,
+            retries: 0,
+            retry_backoff: Duration::from_secs(1),
+            timeout: None,
+            force_copy: false,
+            workspace: None,
+            raw_output: false,
+            backend_template: None,
+            use_chdir: false,
+            no_color: false,
+            prompt: tokio::sync::Mutex::new(()),
+            versions: Mutex::new(HashMap::new()),
+            warnings: Mutex::new(Vec::new()),
         }
     }
 
-    /// Get per-module terraform working directory
-    fn module_dir(&self, module: &ModuleNode) -> PathBuf {
-        self.cache_dir.join(&module.id)
+    /// Changes of a saved plan; a plan that cannot be read back is still a plan with
+    /// changes, only not counted
+    async fn change_counts(&self, module: &ModuleNode, plan_file: &Path) -> Option<ChangeCounts> {
+        let counts = match self.show_plan_json(module, plan_file).await {
+            Ok(json) => ChangeCounts::from_plan_json(&json),
+            Err(err) => Err(err),
+        };
+        match counts {
+            Ok(counts) => Some(counts),
+            Err(err) => {
+                progress!(
+                    "Warning: could not count the planned changes of '{}': {:#}",
+                    module.id,
+                    err
+                );
+                None
+            }
+        }
     }
 
-    /// Convert module variables to TF_VAR_* environment variables
-    fn tf_var_env(vars: &HashMap<String, Value>) -> HashMap<String, String> {
-        vars.iter()
-            .map(|(k, v)| {
-                let json_value = match v {
-                    Value::String(s) => s.clone(),
-                    _ => serde_json::to_string(v).unwrap_or_else(|_| "null".to_string()),
-                };
-                (format!("TF_VAR_{}", k), json_value)
-            })
-            .collect()
+    /// Remember the warnings found in a command's output
+    fn record_warnings(&self, module: &ModuleNode, output: &str) {
+        let found = scan_warnings(output);
+        if found.is_empty() {
+            return;
+        }
+        self.warnings
+            .lock()
+            .unwrap()
+            .extend(found.into_iter().map(|message| TerraformWarning {
+                module_id: module.id.clone(),
+                message,
+            }));
+    }
+
+    /// `plan`/`apply`/`destroy` arguments, with `-compact-warnings` and `-no-color` when enabled
+    fn change_args(&self, args: &[&str]) -> Vec<String> {
+        let mut out: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+        if self.no_color {
+            out.insert(1, "-no-color".to_string());
+        }
+        if self.compact_warnings {
+            out.insert(1, "-compact-warnings".to_string());
+        }
+        if let Some(parallelism) = self.parallelism {
+            out.insert(1, format!("-parallelism={}", parallelism));
+        }
+        out
+    }
+
+    /// Detect the terraform version used for a module (local binary or its image), once
+    async fn terraform_version(&self, module: &ModuleNode) -> Result<Version> {
+        let key = module.container_image.clone();
+        if let Some(version) = self.versions.lock().unwrap().get(&key) {
+            return Ok(*version);
+        }
+        let dir = self.source_dir(module);
+        let resp = self
+            .run_terraform_cmd(module, &dir, Some(&["version", "-json"]), None)
+            .await?;
+        let json: serde_json::Value =
+            serde_json::from_slice(&resp.stdout).context("Failed to parse terraform version")?;
+        let raw = json
+            .get("terraform_version")
+            .and_then(|v| v.as_str())
+            .context("terraform version -json has no terraform_version")?;
+        let version = Version::parse(raw)
+            .with_context(|| format!("Unrecognized terraform version '{}'", raw))?;
+        self.versions.lock().unwrap().insert(key, version);
+        Ok(version)
     }
 
-    /// Ensure terraform directory exists and copy module sources
+    /// Sources of a module, possibly nested like `modules/networking/vpc`
+    fn source_dir(&self, module: &ModuleNode) -> PathBuf {
+        self.modules_dir.join(&module.source)
+    }
+
+    /// Get per-module terraform working directory, under the workspace's own
+    /// directory when one is selected
+    fn module_dir(&self, module: &ModuleNode) -> Result<PathBuf> {
+        let id = module_path_component(&module.id)?;
+        Ok(match &self.workspace {
+            Some(workspace) => self.cache_dir.join(workspace).join(id),
+            None => self.cache_dir.join(id),
+        })
+    }
+
+    /// Convert module variables to TF_VAR_* environment variables, see `env_value`.
+    /// A null variable is left unset so its default applies.
+    fn tf_var_env(module: &ModuleNode) -> Result<HashMap<String, String>> {
+        let mut envs = HashMap::new();
+        for (k, v) in &module.variables {
+            if v.is_null() {
+                continue;
+            }
+            if let Some(problem) = tf_var_problem(v) {
+                anyhow::bail!(
+                    "Variable '{}' of module '{}' cannot be passed to terraform: {}",
+                    k,
+                    module.id,
+                    problem
+                );
+            }
+            envs.insert(format!("TF_VAR_{}", k), env_value(v));
+        }
+        Ok(envs)
+    }
+
+    /// Ensure terraform directory exists and copy module sources, unless the sources
+    /// are unchanged since the last copy
     pub async fn ensure_module_dir(&self, module: &ModuleNode) -> Result<PathBuf> {
-        let dir = self.module_dir(module);
+        let dir = self.module_dir(module)?;
         fs::create_dir_all(&dir)
             .await
             .with_context(|| format!("Failed to create terraform dir: {:?}", dir))?;
 
-        let src_dir = self.modules_dir.join(&module.source);
-
-        async fn copy_dir(src: &Path, dst: &Path) -> io::Result<()> {
-            let mut stack = vec![(src.to_path_buf(), dst.to_path_buf())];
-            while let Some((src_dir, dst_dir)) = stack.pop() {
-                fs::create_dir_all(&dst_dir).await?;
-                let mut entries = fs::read_dir(&src_dir).await?;
-                while let Some(entry) = entries.next_entry().await? {
-                    let path = entry.path();
-                    let dst_path = dst_dir.join(entry.file_name());
-                    if path.is_dir() {
-                        stack.push((path, dst_path));
-                    } else {
-                        fs::copy(&path, &dst_path).await?;
-                    }
-                }
+        let src_dir = self.source_dir(module);
+        let digest = digest_dir(&src_dir)?;
+        let hash_file = dir.join(SOURCE_HASH_FILE);
+        if self.force_copy
+            || fs::read_to_string(&hash_file).await.ok().as_deref() != Some(digest.as_str())
+        {
+            copy_module_sources(&src_dir, &dir, &hash_file, &digest).await?;
+        }
+        self.write_backend_override(module, &src_dir, &dir).await?;
+        Ok(dir)
+    }
+
+    /// Render `backend_template` into the module dir when it changed, removing an
+    /// override an earlier run rendered when there is no template anymore
+    async fn write_backend_override(
+        &self,
+        module: &ModuleNode,
+        src_dir: &Path,
+        dir: &Path,
+    ) -> Result<()> {
+        let path = dir.join(BACKEND_OVERRIDE_FILE);
+        let Some(template) = &self.backend_template else {
+            if !src_dir.join(BACKEND_OVERRIDE_FILE).exists() && path.exists() {
+                fs::remove_file(&path)
+                    .await
+                    .with_context(|| format!("Failed to remove {:?}", path))?;
             }
-            Ok(())
+            return Ok(());
+        };
+        let rendered = render_backend_template(template, module, self.workspace.as_deref());
+        let content = serde_json::to_string_pretty(&rendered)?;
+        // Rewriting an unchanged file would only bump its mtime on every command
+        if fs::read_to_string(&path).await.ok().as_deref() == Some(content.as_str()) {
+            return Ok(());
         }
+        fs::write(&path, content)
+            .await
+            .with_context(|| format!("Failed to write {:?}", path))
+    }
 
-        copy_dir(&src_dir, &dir).await.with_context(|| {
-            format!(
-                "Failed to copy module files from {:?} to {:?}",
-                src_dir, dir
-            )
-        })?;
+    /// Build the terraform command for a module, either with the local binary
+    /// or inside the module's `container_image`
+    fn terraform_command(
+        &self,
+        module: &ModuleNode,
+        dir: &Path,
+        args: &[&str],
+        envs: &HashMap<String, String>,
+    ) -> Result<Command> {
+        // The module's own `env` goes to every command, `envs` winning
+        let envs: HashMap<String, String> = module
+            .env
+            .iter()
+            .chain(envs)
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+        let envs = &envs;
+        let Some(image) = &module.container_image else {
+            progress!("Running {:?} with {:?} in {:?}", &self.bin_path, args, dir);
+            let mut cmd = Command::new(&self.bin_path);
+            match self.use_chdir {
+                true => cmd.args(chdir_args(dir, args)),
+                false => cmd.args(args).current_dir(dir),
+            };
+            cmd.envs(envs);
+            return Ok(cmd);
+        };
 
-        Ok(dir)
+        progress!(
+            "Running terraform in {} with {:?} in {:?}",
+            image,
+            args,
+            dir
+        );
+        // Paths inside the container are the same as on the host, so mounts must be absolute
+        let workdir = std::path::absolute(dir)
+            .with_context(|| format!("Invalid terraform dir: {:?}", dir))?;
+        let mut mounts = vec![workdir.clone()];
+        let mut env_names: Vec<&str> = envs.keys().map(String::as_str).collect();
+        if let Some(plugin_cache) = std::env::var_os("TF_PLUGIN_CACHE_DIR") {
+            mounts.push(PathBuf::from(plugin_cache));
+            env_names.push("TF_PLUGIN_CACHE_DIR");
+        }
+        // Plan files given as `-out=<path>` or positional paths live outside the workdir
+        for arg in args {
+            let path = Path::new(arg.split_once('=').map_or(*arg, |(_, v)| v));
+            if path.is_absolute()
+                && let Some(parent) = path.parent()
+            {
+                mounts.push(parent.to_path_buf());
+            }
+        }
+        mounts.sort();
+        mounts.dedup();
+        env_names.sort();
+
+        let mut cmd = Command::new("docker");
+        cmd.args(["run", "--rm", "-i"]);
+        for mount in &mounts {
+            let mount = mount.to_string_lossy();
+            cmd.arg("-v").arg(format!("{mount}:{mount}"));
+        }
+        cmd.arg("-w").arg(&workdir);
+        // Only pass variable names, values are inherited from the docker client env
+        for name in env_names {
+            cmd.arg("-e").arg(name);
+        }
+        cmd.args(["--entrypoint", "terraform", image]);
+        match self.use_chdir {
+            true => cmd.args(chdir_args(&workdir, args)),
+            false => cmd.args(args),
+        };
+        cmd.envs(envs);
+        Ok(cmd)
+    }
+
+    fn timed_out(&self, module: &ModuleNode, args: &[&str]) -> anyhow::Error {
+        anyhow::anyhow!(
+            "Terraform command {:?} of module '{}' timed out after {:?} and was killed",
+            args,
+            module.id,
+            self.timeout.unwrap_or_default()
+        )
+    }
+
+    /// Run `attempt` again while it fails, up to `retries` times for `init`, `plan` and
+    /// `apply`, waiting a jittered exponential backoff between attempts
+    async fn with_retries<T, F, Fut>(
+        &self,
+        module: &ModuleNode,
+        args: &[&str],
+        attempt: F,
+    ) -> Result<T>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let retryable = matches!(args.first(), Some(&("init" | "plan" | "apply")));
+        let mut failures = 0;
+        loop {
+            match attempt().await {
+                Err(err) if retryable && failures < self.retries => {
+                    failures += 1;
+                    let delay = jittered_backoff(self.retry_backoff, failures);
+                    progress!(
+                        "'{}': terraform {} failed (attempt {}/{}), retrying in {:?}: {:#}",
+                        module.id,
+                        args[0],
+                        failures,
+                        self.retries + 1,
+                        delay,
+                        err
+                    );
+                    tokio::time::sleep(delay).await;
+                }
+                result => return result,
+            }
+        }
     }
 
     /// Run terraform CLI command asynchronously in a specific directory
     /// `args` is optional (default empty)
     pub async fn run_terraform_cmd(
         &self,
+        module: &ModuleNode,
         dir: &Path,
         args: Option<&[&str]>,
         envs: Option<&HashMap<String, String>>,
     ) -> Result<TerraformOutput> {
         let args = args.unwrap_or(&[]);
-        println!("Running {:?} with {:?} in {:?}", &self.bin_path, args, dir);
+        self.with_retries(module, args, || {
+            self.run_terraform_cmd_once(module, dir, args, envs)
+        })
+        .await
+    }
+
+    async fn run_terraform_cmd_once(
+        &self,
+        module: &ModuleNode,
+        dir: &Path,
+        args: &[&str],
+        envs: Option<&HashMap<String, String>>,
+    ) -> Result<TerraformOutput> {
         let local_envs = HashMap::new();
         let envs = envs.unwrap_or(&local_envs);
-        let output = Command::new(&self.bin_path)
-            .args(args)
-            .current_dir(dir)
-            .envs(envs)
-            .output()
-            .await
-            .with_context(|| format!("Failed to run terraform command {:?}", args))?;
+        // Dropping the command on timeout kills it
+        let output = self
+            .terraform_command(module, dir, args, envs)?
+            .kill_on_drop(true)
+            .output();
+        let output = match self.timeout {
+            Some(timeout) => tokio::time::timeout(timeout, output)
+                .await
+                .map_err(|_| self.timed_out(module, args))?,
+            None => output.await,
+        }
+        .with_context(|| format!("Failed to run terraform command {:?}", args))?;
 
         if !output.status.success() {
-            anyhow::bail!(
-                "Terraform command {:?} failed with status {:?}\nStderr: {}",
-                args,
-                output.status,
-                String::from_utf8_lossy(&output.stderr)
-            );
+            return Err(TerraformCommandError {
+                args: args.iter().map(|a| a.to_string()).collect(),
+                status: output.status,
+                stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+            }
+            .into());
         }
+        self.record_warnings(module, &String::from_utf8_lossy(&output.stdout));
+        self.record_warnings(module, &String::from_utf8_lossy(&output.stderr));
 
         Ok(TerraformOutput {
-            status: output.status,
             stdout: output.stdout,
-            stderr: output.stderr,
         })
     }
 
-    /// Run terraform CLI command asynchronously interactively
+    /// Run terraform CLI command asynchronously interactively, echoing its output
+    /// while collecting warnings
     /// `args` and `envs` are optional (defaults: empty args, empty envs)
     pub async fn run_terraform_cmd_interactively(
         &self,
+        module: &ModuleNode,
         dir: &Path,
         args: Option<&[&str]>,
         envs: Option<&HashMap<String, String>>,
     ) -> Result<()> {
         let args = args.unwrap_or(&[]);
+        self.run_terraform_cmd_interactively_with_codes(module, dir, args, envs, &[])
+            .await
+            .map(|_| ())
+    }
+
+    /// Like `run_terraform_cmd_interactively`, also accepting the `extra_codes` exit
+    /// codes as success; returns the exit code
+    async fn run_terraform_cmd_interactively_with_codes(
+        &self,
+        module: &ModuleNode,
+        dir: &Path,
+        args: &[&str],
+        envs: Option<&HashMap<String, String>>,
+        extra_codes: &[i32],
+    ) -> Result<i32> {
+        self.with_retries(module, args, || {
+            self.run_terraform_cmd_interactively_once(module, dir, args, envs, extra_codes, false)
+        })
+        .await
+    }
+
+    /// Run an apply or destroy, with `-auto-approve` in `args` or else letting
+    /// terraform ask for approval on the terminal: one prompt at a time, output not
+    /// prefixed, and a refusal is not retried
+    async fn run_change(
+        &self,
+        module: &ModuleNode,
+        dir: &Path,
+        args: &[&str],
+        envs: &HashMap<String, String>,
+        auto_approve: bool,
+    ) -> Result<()> {
+        if auto_approve {
+            return self
+                .run_terraform_cmd_interactively(module, dir, Some(args), Some(envs))
+                .await;
+        }
+        let _prompt = self.prompt.lock().await;
+        self.run_terraform_cmd_interactively_once(module, dir, args, Some(envs), &[], true)
+            .await
+            .map(|_| ())
+    }
+
+    async fn run_terraform_cmd_interactively_once(
+        &self,
+        module: &ModuleNode,
+        dir: &Path,
+        args: &[&str],
+        envs: Option<&HashMap<String, String>>,
+        extra_codes: &[i32],
+        prompt: bool,
+    ) -> Result<i32> {
         let local_envs = HashMap::new();
         let envs = envs.unwrap_or(&local_envs);
-        println!("Running {:?} with {:?} in {:?}", &self.bin_path, args, dir);
         //dbg!(envs);
 
-        let status = Command::new(&self.bin_path)
-            .args(args)
-            .current_dir(dir)
-            .envs(envs)
+        // Raw output leaves the terminal to terraform, for its approval prompt;
+        // warnings are then not collected
+        let output = || match self.raw_output || prompt {
+            true => std::process::Stdio::inherit(),
+            false => std::process::Stdio::piped(),
+        };
+        // Dropping the command when its layer is cancelled kills it
+        let mut child = self
+            .terraform_command(module, dir, args, envs)?
             .stdin(std::process::Stdio::inherit())
-            .stdout(std::process::Stdio::inherit())
-            .stderr(std::process::Stdio::inherit())
-            .status()
-            .await
+            .stdout(output())
+            .stderr(output())
+            .kill_on_drop(true)
+            .spawn()
             .with_context(|| format!("Failed to run terraform command {:?}", args))?;
+        let stdout = child.stdout.take();
+        let stderr = child.stderr.take();
+        let prefix = output_prefix(&module.id);
+        let run = async {
+            let output = match (stdout, stderr) {
+                (Some(stdout), Some(stderr)) => tokio::try_join!(
+                    tee_lines(stdout, false, &prefix),
+                    tee_lines(stderr, true, &prefix)
+                )
+                .with_context(|| format!("Failed to read terraform output {:?}", args))?,
+                _ => (String::new(), String::new()),
+            };
+            let status = child
+                .wait()
+                .await
+                .with_context(|| format!("Failed to run terraform command {:?}", args))?;
+            anyhow::Ok((output, status))
+        };
+        let result = match self.timeout {
+            Some(timeout) => tokio::time::timeout(timeout, run).await.ok(),
+            None => Some(run.await),
+        };
+        let Some(result) = result else {
+            child.kill().await.ok();
+            return Err(self.timed_out(module, args));
+        };
+        let ((stdout, stderr), status) = result?;
+        self.record_warnings(module, &stdout);
+        self.record_warnings(module, &stderr);
 
-        if !status.success() {
+        let code = status.code();
+        if !status.success() && !code.is_some_and(|code| extra_codes.contains(&code)) {
             anyhow::bail!(
                 "Terraform command {:?} failed with status {:?}",
                 args,
@@ -191,7 +1143,7 @@ impl TerraformRunner {
             );
         }
 
-        Ok(())
+        Ok(code.unwrap_or_default())
     }
 }
 
@@ -199,26 +1151,1150 @@ impl TerraformRunner {
 impl RunTerraformCommand for TerraformRunner {
     async fn init(&self, module: &ModuleNode) -> Result<()> {
         let dir = self.ensure_module_dir(module).await?;
-        self.run_terraform_cmd(&dir, Some(&["init", "-input=false"]), None)
+        let mut args = vec!["init".to_string(), "-input=false".to_string()];
+        args.extend(backend_config_args(module, self.workspace.as_deref()));
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        self.run_terraform_cmd(module, &dir, Some(&args), None)
             .await?;
+        if let Some(workspace) = &self.workspace {
+            // The workspace does not exist in the backend on the first run
+            let selected = self
+                .run_terraform_cmd(
+                    module,
+                    &dir,
+                    Some(&["workspace", "select", workspace]),
+                    None,
+                )
+                .await;
+            if selected.is_err() {
+                self.run_terraform_cmd(module, &dir, Some(&["workspace", "new", workspace]), None)
+                    .await
+                    .with_context(|| format!("Failed to select workspace '{}'", workspace))?;
+            }
+        }
         Ok(())
     }
 
     async fn output(&self, module: &ModuleNode) -> Result<HashMap<String, Value>> {
-        let dir = self.module_dir(module);
+        let dir = self.module_dir(module)?;
+        let resp = self
+            .run_terraform_cmd(module, &dir, Some(&["output", "-json"]), None)
+            .await
+            .map_err(|err| match err.downcast_ref::<TerraformCommandError>() {
+                Some(failed) if is_missing_state(&failed.stderr) => anyhow::anyhow!(
+                    "'{}' has no terraform state yet, apply it before reading its outputs: {}",
+                    module.id,
+                    failed.stderr.trim()
+                ),
+                _ => err,
+            })?;
+        // A module without outputs prints `{}`, or nothing at all
+        let json: &[u8] = if resp.stdout.trim_ascii().is_empty() {
+            b"{}"
+        } else {
+            &resp.stdout
+        };
+        let outputs = parse_outputs(json)?;
+        // Saved for `--use-cached-outputs` of later invocations
+        let cache_file = dir.join(OUTPUTS_CACHE_FILE);
+        fs::write(&cache_file, json)
+            .await
+            .with_context(|| format!("Failed to write {:?}", cache_file))?;
+        Ok(outputs)
+    }
+
+    async fn plan(
+        &self,
+        module: &ModuleNode,
+        plan_file: Option<&Path>,
+        resources: &[String],
+    ) -> Result<PlanResult> {
+        let dir = self.module_dir(module)?;
+        let envs = TerraformRunner::tf_var_env(module)?;
+        let mut args = self.change_args(&["plan", "-input=false", "-detailed-exitcode"]);
+        args.extend(target_args(resources));
+        // Without a plan file to keep, the plan goes to the module dir to count its changes
+        let plan_file = plan_file
+            .unwrap_or(&dir.join(CHANGES_PLAN_FILE))
+            .to_path_buf();
+        // terraform runs inside the module cache dir, so the plan path must be absolute
+        let plan_file = std::path::absolute(&plan_file)
+            .with_context(|| format!("Invalid plan file path {:?}", plan_file))?;
+        if let Some(parent) = plan_file.parent() {
+            fs::create_dir_all(parent)
+                .await
+                .with_context(|| format!("Failed to create plan output dir: {:?}", parent))?;
+        }
+        args.push(format!("-out={}", plan_file.display()));
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        // -detailed-exitcode exits with 2 when the plan has changes
+        let code = self
+            .run_terraform_cmd_interactively_with_codes(module, &dir, &args, Some(&envs), &[2])
+            .await?;
+        Ok(match code {
+            2 => PlanResult::Changes(self.change_counts(module, &plan_file).await),
+            _ => PlanResult::NoChanges,
+        })
+    }
+
+    async fn apply(
+        &self,
+        module: &ModuleNode,
+        plan_file: Option<&Path>,
+        resources: &[String],
+        auto_approve: bool,
+    ) -> Result<()> {
+        let dir = self.module_dir(module)?;
+        let envs = TerraformRunner::tf_var_env(module)?;
+        match plan_file {
+            Some(plan_file) => {
+                let plan_file = std::path::absolute(plan_file)
+                    .with_context(|| format!("Invalid plan file path {:?}", plan_file))?;
+                let plan_arg = plan_file.to_string_lossy();
+                let args = self.change_args(&["apply", "-input=false", &plan_arg]);
+                let args: Vec<&str> = args.iter().map(String::as_str).collect();
+                self.run_terraform_cmd_interactively(module, &dir, Some(&args), Some(&envs))
+                .await
+                .with_context(|| {
+                    format!(
+                        "Failed to apply saved plan {:?}; it may be stale or invalid, run plan again to produce a fresh one",
+                        plan_file
+                    )
+                })?;
+            }
+            None => {
+                let mut args = self.change_args(&["apply"]);
+                if auto_approve {
+                    args.push("-auto-approve".to_string());
+                }
+                args.extend(target_args(resources));
+                let args: Vec<&str> = args.iter().map(String::as_str).collect();
+                self.run_change(module, &dir, &args, &envs, auto_approve)
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn destroy(&self, module: &ModuleNode, auto_approve: bool) -> Result<()> {
+        let dir = self.module_dir(module)?;
+        let envs = TerraformRunner::tf_var_env(module)?;
+        let mut args = self.change_args(&["destroy"]);
+        if auto_approve {
+            args.push("-auto-approve".to_string());
+        }
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        self.run_change(module, &dir, &args, &envs, auto_approve)
+            .await?;
+        // The outputs of destroyed resources must not be reused
+        let cache_file = dir.join(OUTPUTS_CACHE_FILE);
+        if cache_file.exists() {
+            fs::remove_file(&cache_file)
+                .await
+                .with_context(|| format!("Failed to remove {:?}", cache_file))?;
+        }
+        Ok(())
+    }
+
+    async fn refresh(&self, module: &ModuleNode) -> Result<()> {
+        let dir = self.module_dir(module)?;
+        let envs = TerraformRunner::tf_var_env(module)?;
+        let args = self.change_args(&["apply", "-refresh-only", "-auto-approve"]);
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        self.run_terraform_cmd_interactively(module, &dir, Some(&args), Some(&envs))
+            .await?;
+        Ok(())
+    }
+
+    async fn show_plan_json(&self, module: &ModuleNode, plan_file: &Path) -> Result<Vec<u8>> {
+        let dir = self.module_dir(module)?;
+        let plan_file = std::path::absolute(plan_file)
+            .with_context(|| format!("Invalid plan file path {:?}", plan_file))?;
+        let plan_arg = plan_file.to_string_lossy();
+        let resp = self
+            .run_terraform_cmd(module, &dir, Some(&["show", "-json", &plan_arg]), None)
+            .await?;
+        Ok(resp.stdout)
+    }
+
+    async fn state_list(&self, module: &ModuleNode) -> Result<Vec<String>> {
+        let dir = self.module_dir(module)?;
         let resp = self
-            .run_terraform_cmd(&dir, Some(&["output", "-json"]), None)
+            .run_terraform_cmd(module, &dir, Some(&["state", "list"]), None)
             .await?;
-        let value: HashMap<String, Value> =
-            serde_json::from_slice(&resp.stdout).context("Failed to parse terraform output")?;
-        Ok(value)
+        Ok(parse_state_list(&resp.stdout))
     }
 
-    async fn apply(&self, module: &ModuleNode) -> Result<()> {
-        let dir = self.module_dir(module);
-        let envs = TerraformRunner::tf_var_env(&module.variables);
-        self.run_terraform_cmd_interactively(&dir, Some(&["apply", "-auto-approve"]), Some(&envs))
+    async fn validate(&self, module: &ModuleNode, check_fmt: bool) -> Result<()> {
+        if check_fmt {
+            // In the sources, the module dir also holds the modules init downloaded
+            let dir = self.source_dir(module);
+            let args = ["fmt", "-check", "-recursive"];
+            let output = self
+                .terraform_command(module, &dir, &args, &HashMap::new())?
+                .kill_on_drop(true)
+                .output()
+                .await
+                .with_context(|| format!("Failed to run terraform command {:?}", args))?;
+            if !output.status.success() {
+                // The unformatted files are listed on stdout, syntax errors on stderr
+                let mut message = format!("terraform fmt -check failed in {:?}", dir);
+                let files = String::from_utf8_lossy(&output.stdout);
+                if !files.trim().is_empty() {
+                    let files: Vec<&str> = files.lines().collect();
+                    message.push_str(&format!(", unformatted: {}", files.join(", ")));
+                }
+                let stderr = String::from_utf8_lossy(&output.stderr);
+                if !stderr.trim().is_empty() {
+                    message.push_str(&format!("\n{}", stderr.trim_end()));
+                }
+                anyhow::bail!(message);
+            }
+        }
+        self.init(module).await?;
+        let dir = self.module_dir(module)?;
+        self.run_terraform_cmd(module, &dir, Some(&["validate", "-no-color"]), None)
             .await?;
         Ok(())
     }
+
+    async fn check_version(&self, module: &ModuleNode) -> Result<()> {
+        let mut constraints = read_required_versions(&self.source_dir(module))?;
+        if let Some(min) = &module.min_tf_version {
+            constraints.push(VersionConstraint::parse(&format!(">= {}", min))?);
+        }
+        if constraints.is_empty() {
+            return Ok(());
+        }
+        let version = self.terraform_version(module).await?;
+        let unmet: Vec<String> = constraints
+            .iter()
+            .filter(|c| !c.matches(version))
+            .map(|c| format!("'{}'", c))
+            .collect();
+        if !unmet.is_empty() {
+            anyhow::bail!(
+                "module '{}' requires terraform {} but {} is used",
+                module.id,
+                unmet.join(" and "),
+                version
+            );
+        }
+        Ok(())
+    }
+
+    async fn declared_variables(
+        &self,
+        module: &ModuleNode,
+    ) -> Result<Option<BTreeMap<String, bool>>> {
+        read_declared_variables(&self.source_dir(module)).map(Some)
+    }
+
+    async fn source_digest(&self, module: &ModuleNode) -> Result<Option<String>> {
+        digest_dir(&self.source_dir(module)).map(Some)
+    }
+
+    async fn cached_outputs(&self, module: &ModuleNode) -> Result<Option<HashMap<String, Value>>> {
+        let dir = self.module_dir(module)?;
+        let cache_file = dir.join(OUTPUTS_CACHE_FILE);
+        let Ok(cached_at) = fs::metadata(&cache_file).await.and_then(|m| m.modified()) else {
+            return Ok(None);
+        };
+        // A local state written after the outputs were saved was changed by terraform
+        // outside of lightstacks; remote states cannot be checked
+        let state_changed = fs::metadata(dir.join("terraform.tfstate"))
+            .await
+            .and_then(|m| m.modified())
+            .is_ok_and(|modified| modified > cached_at);
+        if state_changed {
+            return Ok(None);
+        }
+        let content = fs::read(&cache_file)
+            .await
+            .with_context(|| format!("Failed to read {:?}", cache_file))?;
+        parse_outputs(&content)
+            .with_context(|| format!("Invalid cached outputs {:?}", cache_file))
+            .map(Some)
+    }
+
+    async fn run_hook(
+        &self,
+        scope_id: &str,
+        command: &str,
+        envs: &HashMap<String, String>,
+    ) -> Result<()> {
+        progress!("Running hook of scope '{}': {}", scope_id, command);
+        let status = Command::new("sh")
+            .args(["-c", command])
+            .envs(envs)
+            .stdin(std::process::Stdio::inherit())
+            .stdout(if crate::progress::on_stderr() {
+                std::io::stderr().into()
+            } else {
+                std::process::Stdio::inherit()
+            })
+            .stderr(std::process::Stdio::inherit())
+            .status()
+            .await
+            .with_context(|| format!("Failed to run hook {:?}", command))?;
+        if !status.success() {
+            anyhow::bail!("Hook {:?} failed with status {:?}", command, status);
+        }
+        Ok(())
+    }
+
+    fn take_warnings(&self) -> Vec<TerraformWarning> {
+        std::mem::take(&mut *self.warnings.lock().unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graph::ModuleGraph;
+    use crate::parser::InfraFile;
+    use crate::testing::{infra, modules_dir, temp_dir};
+
+    fn module(yaml: &str, id: &str) -> ModuleNode {
+        let graph = ModuleGraph::new(&infra(yaml)).unwrap();
+        graph.get_module_by_id(id).unwrap()
+    }
+
+    const VPC: &str = "
+acc:
+  scope: account
+  vpc:
+    source: vpc
+";
+
+    #[tokio::test]
+    async fn backend_override_is_rendered_only_when_it_changes() {
+        let dir = temp_dir("backend-override");
+        let (src, out) = (dir.join("src"), dir.join("out"));
+        std::fs::create_dir_all(&src).unwrap();
+        std::fs::create_dir_all(&out).unwrap();
+        let mut runner = TerraformRunner::new("terraform".into(), dir.clone(), src.clone());
+        runner.workspace = Some("prod".to_string());
+        runner.backend_template = Some(serde_json::json!({
+            "terraform": {"backend": {"s3": {"key": "${workspace}/${module_id}.tfstate"}}}
+        }));
+        let vpc = module(VPC, "acc.vpc");
+        let path = out.join(BACKEND_OVERRIDE_FILE);
+
+        runner
+            .write_backend_override(&vpc, &src, &out)
+            .await
+            .unwrap();
+        let written: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(
+            written["terraform"]["backend"]["s3"]["key"],
+            "prod/acc.vpc.tfstate"
+        );
+
+        // An unchanged rendering leaves the file alone
+        let mtime = std::fs::metadata(&path).unwrap().modified().unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+        runner
+            .write_backend_override(&vpc, &src, &out)
+            .await
+            .unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().modified().unwrap(), mtime);
+
+        // Without a template anymore the stale override goes
+        runner.backend_template = None;
+        runner
+            .write_backend_override(&vpc, &src, &out)
+            .await
+            .unwrap();
+        assert!(!path.exists());
+    }
+
+    fn yaml(value: &str) -> Value {
+        serde_yaml::from_str(value).unwrap()
+    }
+
+    #[test]
+    fn env_values_match_what_terraform_parses() {
+        let cases = [
+            ("plain", "plain"),
+            ("'with \"quotes\" and spaces'", "with \"quotes\" and spaces"),
+            ("'42'", "42"),
+            ("true", "true"),
+            ("false", "false"),
+            ("42", "42"),
+            ("-3.5", "-3.5"),
+            ("18446744073709551615", "18446744073709551615"),
+            ("[a, 1, true, null]", r#"["a",1,true,null]"#),
+            (
+                "{region: eu-west-1, sizes: [1, 2]}",
+                r#"{"region":"eu-west-1","sizes":[1,2]}"#,
+            ),
+            (
+                "{outer: {inner: {deep: x}}}",
+                r#"{"outer":{"inner":{"deep":"x"}}}"#,
+            ),
+            ("null", "null"),
+            ("!secret hidden", "hidden"),
+        ];
+        for (value, expected) in cases {
+            assert_eq!(env_value(&yaml(value)), expected, "{value}");
+        }
+    }
+
+    #[test]
+    fn output_numbers_keep_every_digit_in_env_values() {
+        let json = |s: &str| json_to_value(serde_json::from_str(s).unwrap());
+        let cases = [
+            (
+                "123456789012345678901234567890",
+                "123456789012345678901234567890",
+            ),
+            ("0.1", "0.1"),
+            ("1.10000000000000000001", "1.10000000000000000001"),
+            (
+                "[1, 123456789012345678901234567890]",
+                r#"[1,"123456789012345678901234567890"]"#,
+            ),
+        ];
+        for (value, expected) in cases {
+            assert_eq!(env_value(&json(value)), expected, "{value}");
+        }
+    }
+
+    #[test]
+    fn tf_var_env_skips_null_and_rejects_what_terraform_cannot_parse() {
+        let mut vpc = module(VPC, "acc.vpc");
+        vpc.variables = HashMap::from([
+            ("name".to_string(), yaml("main")),
+            ("count".to_string(), yaml("3")),
+            ("unset".to_string(), yaml("null")),
+        ]);
+        let envs = TerraformRunner::tf_var_env(&vpc).unwrap();
+        assert_eq!(
+            envs,
+            HashMap::from([
+                ("TF_VAR_name".to_string(), "main".to_string()),
+                ("TF_VAR_count".to_string(), "3".to_string()),
+            ])
+        );
+
+        vpc.variables = HashMap::from([("bad".to_string(), yaml("{[1]: x}"))]);
+        assert!(TerraformRunner::tf_var_env(&vpc).is_err());
+        vpc.variables = HashMap::from([("inf".to_string(), yaml(".inf"))]);
+        assert!(TerraformRunner::tf_var_env(&vpc).is_err());
+    }
+
+    #[tokio::test]
+    async fn large_output_numbers_reach_dependents_exactly() {
+        use crate::runtime::{RunOptions, Runtime};
+        use crate::scripted::ScriptedRunner;
+        use std::sync::Arc;
+
+        let outputs = parse_outputs(
+            br#"{
+                "vpc_id": {"value": 1234567890123456789012, "type": "number"},
+                "ratio": {"value": 0.30000000000000000001, "type": "number"}
+            }"#,
+        )
+        .unwrap();
+        let runner = Arc::new(
+            ScriptedRunner::builder()
+                .outputs("acc.vpc", outputs)
+                .build(),
+        );
+        let chain = "
+acc:
+  scope: account
+  vpc:
+    source: vpc
+  compute:
+    source: compute
+    dependencies: [vpc]
+    inputs:
+      vpc_id:
+        from: vpc.vpc_id
+      ratio:
+        from: vpc.ratio
+";
+        let runtime = Runtime::new(runner.clone(), &infra(chain), RunOptions::default()).unwrap();
+        runtime
+            .run_module("acc.compute", TerraformAction::Apply)
+            .await
+            .unwrap();
+
+        let mut compute = module(chain, "acc.compute");
+        compute.variables = runner.variables("acc.compute").unwrap();
+        let envs = TerraformRunner::tf_var_env(&compute).unwrap();
+        assert_eq!(envs["TF_VAR_vpc_id"], "1234567890123456789012");
+        assert_eq!(envs["TF_VAR_ratio"], "0.30000000000000000001");
+    }
+
+    #[test]
+    fn backend_config_becomes_sorted_init_flags() {
+        let yaml = "
+source_default:
+  vpc:
+    backend_config:
+      region: eu-west-1
+acc:
+  scope: account
+  vpc:
+    source: vpc
+    backend_config:
+      bucket: states
+      key: \"${workspace}/${module_id}.tfstate\"
+      encrypt: true
+";
+        let vpc = module(yaml, "acc.vpc");
+        assert_eq!(
+            backend_config_args(&vpc, Some("prod")),
+            [
+                "-backend-config=bucket=states",
+                "-backend-config=encrypt=true",
+                "-backend-config=key=prod/acc.vpc.tfstate",
+                "-backend-config=region=eu-west-1",
+            ]
+        );
+        assert_eq!(
+            backend_config_args(&vpc, None)[2],
+            "-backend-config=key=default/acc.vpc.tfstate"
+        );
+        assert!(backend_config_args(&module(VPC, "acc.vpc"), None).is_empty());
+    }
+
+    #[test]
+    fn backend_config_is_rejected_on_scopes() {
+        let err = crate::testing::try_infra(
+            "
+acc:
+  scope: account
+  backend_config:
+    bucket: states
+  vpc:
+    source: vpc
+",
+        )
+        .unwrap_err();
+        let message = format!("{err:#}");
+        assert!(
+            message.contains("Scope 'acc' cannot define 'backend_config'"),
+            "{message}"
+        );
+    }
+
+    #[tokio::test]
+    async fn global_variables_reach_every_module_as_tf_vars() {
+        use crate::runtime::{RunOptions, Runtime};
+        use crate::scripted::ScriptedRunner;
+        use std::sync::Arc;
+
+        let yaml = "
+acc:
+  scope: account
+  vpc:
+    source: vpc
+    inputs:
+      region: eu-west-3
+";
+        let runner = Arc::new(ScriptedRunner::default());
+        let options = RunOptions {
+            global_variables: HashMap::from([
+                ("region".to_string(), Value::from("us-east-1")),
+                ("owner".to_string(), Value::from("platform")),
+            ]),
+            ..Default::default()
+        };
+        let runtime = Runtime::new(runner.clone(), &infra(yaml), options).unwrap();
+        runtime
+            .run_module("acc.vpc", TerraformAction::Apply)
+            .await
+            .unwrap();
+
+        let mut vpc = module(yaml, "acc.vpc");
+        vpc.variables = runner.variables("acc.vpc").unwrap();
+        let envs = TerraformRunner::tf_var_env(&vpc).unwrap();
+        assert_eq!(envs["TF_VAR_owner"], "platform");
+        // The module's own value wins
+        assert_eq!(envs["TF_VAR_region"], "eu-west-3");
+    }
+
+    #[tokio::test]
+    async fn cached_outputs_spare_the_dependency_its_init() {
+        use crate::runtime::{RunOptions, Runtime};
+        use std::sync::Arc;
+
+        let chain = "
+acc:
+  scope: account
+  vpc:
+    source: vpc
+  compute:
+    source: compute
+    dependencies: [vpc]
+    inputs:
+      vpc_id:
+        from: vpc.vpc_id
+";
+        let (_, _, dir) = fake_terraform(
+            "cached-outputs",
+            r#"echo "$(basename "$PWD") $1 $TF_VAR_vpc_id" >> ../calls.log
+[ "$1" = output ] && echo '{}'
+exit 0"#,
+        );
+        let modules = modules_dir().to_path_buf();
+        let runner = TerraformRunner::new(dir.join("terraform"), dir.clone(), modules);
+        let cache_file = dir.join("acc.vpc").join(OUTPUTS_CACHE_FILE);
+        std::fs::write(&cache_file, r#"{"vpc_id": {"value": "vpc-cached"}}"#).unwrap();
+        let vpc = module(chain, "acc.vpc");
+        assert_eq!(
+            runner.cached_outputs(&vpc).await.unwrap().unwrap()["vpc_id"]["value"],
+            "vpc-cached"
+        );
+
+        let options = RunOptions {
+            use_cached_outputs: true,
+            ..Default::default()
+        };
+        let runtime = Runtime::new(Arc::new(runner), &infra(chain), options).unwrap();
+        runtime
+            .run_module("acc.compute", TerraformAction::Apply)
+            .await
+            .unwrap();
+        let log = std::fs::read_to_string(dir.join("calls.log")).unwrap();
+        assert!(!log.contains("acc.vpc"), "{log}");
+        assert!(log.contains("acc.compute init"), "{log}");
+        assert!(log.contains("acc.compute apply vpc-cached"), "{log}");
+
+        // A local state written after the cache was changed outside of lightstacks
+        std::thread::sleep(Duration::from_millis(20));
+        std::fs::write(dir.join("acc.vpc").join("terraform.tfstate"), "{}").unwrap();
+        let runner = TerraformRunner::new(dir.join("terraform"), dir.clone(), dir.clone());
+        assert!(runner.cached_outputs(&vpc).await.unwrap().is_none());
+    }
+
+    /// Runner whose terraform is the shell `script`, run in `<dir>/acc.vpc`
+    fn fake_terraform(name: &str, script: &str) -> (TerraformRunner, ModuleNode, PathBuf) {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = temp_dir(name);
+        let bin = dir.join("terraform");
+        std::fs::write(&bin, format!("#!/bin/sh\n{script}\n")).unwrap();
+        std::fs::set_permissions(&bin, std::fs::Permissions::from_mode(0o755)).unwrap();
+        std::fs::create_dir_all(dir.join("acc.vpc")).unwrap();
+        let runner = TerraformRunner::new(bin, dir.clone(), dir.clone());
+        (runner, module(VPC, "acc.vpc"), dir)
+    }
+
+    #[test]
+    fn plan_json_actions_are_counted() {
+        let json = include_bytes!("../tests/fixtures/plan_changes.json");
+        assert_eq!(
+            ChangeCounts::from_plan_json(json).unwrap(),
+            ChangeCounts {
+                create: 2,
+                update: 1,
+                delete: 1,
+                replace: 2,
+            }
+        );
+        assert_eq!(
+            ChangeCounts::from_plan_json(b"{}").unwrap(),
+            ChangeCounts::default()
+        );
+        assert!(ChangeCounts::from_plan_json(b"not json").is_err());
+    }
+
+    #[tokio::test]
+    async fn plan_exit_codes_map_to_results() {
+        let (runner, vpc, _) = fake_terraform("plan-0", "exit 0");
+        assert_eq!(
+            runner.plan(&vpc, None, &[]).await.unwrap(),
+            PlanResult::NoChanges
+        );
+
+        let fixture = concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/tests/fixtures/plan_changes.json"
+        );
+        let script = format!("[ \"$1\" = show ] && exec cat '{fixture}'\nexit 2");
+        let (runner, vpc, _) = fake_terraform("plan-2", &script);
+        let Ok(PlanResult::Changes(Some(counts))) = runner.plan(&vpc, None, &[]).await else {
+            panic!("exit code 2 is a plan with changes");
+        };
+        assert_eq!(counts.create, 2);
+
+        let (runner, vpc, _) = fake_terraform("plan-1", "exit 1");
+        let err = runner.plan(&vpc, None, &[]).await.unwrap_err();
+        assert!(err.to_string().contains("failed with status"), "{err}");
+    }
+
+    /// Fails the first `failures` runs, counting every run in `<dir>/attempts`
+    fn flaky_terraform(name: &str, failures: usize) -> (TerraformRunner, ModuleNode, PathBuf) {
+        let script =
+            format!("echo x >> attempts\n[ $(wc -l < attempts) -gt {failures} ] || exit 1\nexit 0");
+        fake_terraform(name, &script)
+    }
+
+    fn attempts(dir: &Path) -> usize {
+        std::fs::read_to_string(dir.join("acc.vpc").join("attempts"))
+            .unwrap()
+            .lines()
+            .count()
+    }
+
+    #[tokio::test]
+    async fn failing_commands_are_retried_until_they_succeed() {
+        let (mut runner, vpc, dir) = flaky_terraform("retry-ok", 2);
+        runner.retries = 3;
+        runner.retry_backoff = Duration::from_millis(1);
+        assert_eq!(
+            runner.plan(&vpc, None, &[]).await.unwrap(),
+            PlanResult::NoChanges
+        );
+        assert_eq!(attempts(&dir), 3);
+    }
+
+    #[tokio::test]
+    async fn retries_stop_after_the_limit() {
+        let (mut runner, vpc, dir) = flaky_terraform("retry-exhausted", 5);
+        runner.retries = 2;
+        runner.retry_backoff = Duration::from_millis(1);
+        assert!(runner.plan(&vpc, None, &[]).await.is_err());
+        assert_eq!(attempts(&dir), 3);
+    }
+
+    #[tokio::test]
+    async fn only_init_plan_and_apply_are_retried() {
+        let (mut runner, vpc, dir) = flaky_terraform("retry-state", 1);
+        runner.retries = 3;
+        runner.retry_backoff = Duration::from_millis(1);
+        assert!(runner.state_list(&vpc).await.is_err());
+        assert_eq!(attempts(&dir), 1);
+    }
+
+    #[tokio::test]
+    async fn hung_commands_are_killed_at_the_timeout() {
+        let (mut runner, vpc, _) = fake_terraform("timeout", "exec sleep 10");
+        runner.timeout = Some(Duration::from_millis(100));
+        let started = std::time::Instant::now();
+        // Both the interactive plan and the captured state list time out
+        let err = runner.plan(&vpc, None, &[]).await.unwrap_err();
+        assert!(
+            err.to_string().contains("of module 'acc.vpc' timed out"),
+            "{err}"
+        );
+        let err = runner.state_list(&vpc).await.unwrap_err();
+        assert!(
+            err.to_string().contains("of module 'acc.vpc' timed out"),
+            "{err}"
+        );
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn workspaces_get_their_own_module_dirs() {
+        let (_, vpc, dir) = fake_terraform(
+            "workspaces",
+            r#"echo "$PWD $@" >> "$(dirname "$0")/calls.log"
+[ "$1 $2" = "workspace select" ] && exit 1
+exit 0"#,
+        );
+        let cache = dir.join("cache");
+        let modules = modules_dir().to_path_buf();
+        for workspace in ["dev", "prod"] {
+            let mut runner =
+                TerraformRunner::new(dir.join("terraform"), cache.clone(), modules.clone());
+            runner.workspace = Some(workspace.to_string());
+            runner.init(&vpc).await.unwrap();
+        }
+        let (dev, prod) = (cache.join("dev/acc.vpc"), cache.join("prod/acc.vpc"));
+        assert!(dev.join("main.tf").is_file());
+        assert!(prod.join("main.tf").is_file());
+        let log = std::fs::read_to_string(dir.join("calls.log")).unwrap();
+        let expected: Vec<String> = [(&dev, "dev"), (&prod, "prod")]
+            .into_iter()
+            .flat_map(|(dir, workspace)| {
+                [
+                    format!("{} init -input=false", dir.display()),
+                    format!("{} workspace select {workspace}", dir.display()),
+                    format!("{} workspace new {workspace}", dir.display()),
+                ]
+            })
+            .collect();
+        assert_eq!(log.lines().collect::<Vec<_>>(), expected);
+    }
+
+    #[tokio::test]
+    async fn resource_targets_become_target_flags_in_order() {
+        let (runner, vpc, dir) = fake_terraform("targets", r#"echo "$@" >> ../args.log"#);
+        let resources = [
+            "null_resource.b".to_string(),
+            "module.a.null_resource.c".to_string(),
+        ];
+        runner.apply(&vpc, None, &resources, true).await.unwrap();
+        assert_eq!(
+            std::fs::read_to_string(dir.join("args.log")).unwrap(),
+            "apply -auto-approve -target=null_resource.b -target=module.a.null_resource.c\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn auto_approve_flag_is_only_passed_when_approved() {
+        let (runner, vpc, dir) = fake_terraform("auto-approve", r#"echo "$@" >> ../args.log"#);
+        runner.apply(&vpc, None, &[], false).await.unwrap();
+        runner.destroy(&vpc, false).await.unwrap();
+        runner.apply(&vpc, None, &[], true).await.unwrap();
+        runner.destroy(&vpc, true).await.unwrap();
+        assert_eq!(
+            std::fs::read_to_string(dir.join("args.log")).unwrap(),
+            "apply\ndestroy\napply -auto-approve\ndestroy -auto-approve\n"
+        );
+    }
+
+    #[test]
+    fn module_dirs_stay_within_the_cache_dir() {
+        let cache = temp_dir("module-dirs");
+        let runner = TerraformRunner::new("terraform".into(), cache.clone(), cache.clone());
+        let nested = "
+account-1:
+  scope: account
+  tenant-a:
+    scope: tenant
+    webapp:
+      source: webapp
+";
+        let webapp = module(nested, "account-1.tenant-a.webapp");
+        assert_eq!(
+            runner.module_dir(&webapp).unwrap(),
+            cache.join("account-1.tenant-a.webapp")
+        );
+
+        let crafted = "
+'../../etc':
+  scope: account
+  vpc:
+    source: vpc
+";
+        let escaping = module(crafted, "../../etc.vpc");
+        let err = runner.module_dir(&escaping).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("cannot be used as a directory name"),
+            "{err}"
+        );
+        for id in ["..", ".", "a/b", "a\\b", "/etc"] {
+            assert!(module_path_component(id).is_err(), "{id}");
+        }
+    }
+
+    #[tokio::test]
+    async fn init_writes_the_backend_override_keyed_by_module_id() {
+        let (_, _, dir) = fake_terraform("backend-template", "exit 0");
+        let template = dir.join("backend.json");
+        std::fs::write(
+            &template,
+            r#"{"terraform": {"backend": {"s3": {"bucket": "state", "key": "${workspace}/${module_id}.tfstate"}}}}"#,
+        )
+        .unwrap();
+        let modules = modules_dir().to_path_buf();
+        let cache = dir.join("cache");
+        let mut runner = TerraformRunner::new(dir.join("terraform"), cache.clone(), modules);
+        runner.backend_template = Some(read_backend_template(&template).unwrap());
+        let yaml = "
+acc:
+  scope: account
+  vpc:
+    source: vpc
+  compute:
+    source: compute
+";
+        for id in ["acc.vpc", "acc.compute"] {
+            runner.init(&module(yaml, id)).await.unwrap();
+            let path = cache.join(id).join(BACKEND_OVERRIDE_FILE);
+            let written: serde_json::Value =
+                serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+            assert_eq!(
+                written,
+                serde_json::json!({"terraform": {"backend": {"s3": {
+                    "bucket": "state",
+                    "key": format!("default/{id}.tfstate"),
+                }}}})
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn tf_parallelism_is_passed_to_changes_only_when_set() {
+        let (mut runner, vpc, dir) = fake_terraform("parallelism", r#"echo "$@" >> ../args.log"#);
+        runner.apply(&vpc, None, &[], true).await.unwrap();
+        runner.parallelism = NonZeroUsize::new(4);
+        runner.apply(&vpc, None, &[], true).await.unwrap();
+        runner.destroy(&vpc, true).await.unwrap();
+        assert_eq!(
+            std::fs::read_to_string(dir.join("args.log")).unwrap(),
+            "apply -auto-approve\n\
+             apply -parallelism=4 -auto-approve\n\
+             destroy -parallelism=4 -auto-approve\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn check_inputs_only_warns_about_undeclared_inputs() {
+        use crate::runtime::{RunOptions, Runtime};
+        use std::sync::Arc;
+
+        let (_, _, dir) = fake_terraform("check-inputs", "exit 0");
+        let modules = dir.join("modules");
+        std::fs::create_dir_all(modules.join("vpc")).unwrap();
+        std::fs::write(
+            modules.join("vpc").join("variables.tf"),
+            "variable \"region\" {\n  type = string\n}\n",
+        )
+        .unwrap();
+        let yaml = "
+acc:
+  scope: account
+  vpc:
+    source: vpc
+    inputs:
+      region: eu-west-3
+      bogus: 1
+";
+        let plan = |check_inputs, check_variables| {
+            let runner = TerraformRunner::new(dir.join("terraform"), dir.clone(), modules.clone());
+            let options = RunOptions {
+                check_inputs,
+                check_variables,
+                ..Default::default()
+            };
+            let runtime = Runtime::new(Arc::new(runner), &infra(yaml), options).unwrap();
+            async move { runtime.run_module("acc.vpc", TerraformAction::Plan).await }
+        };
+        plan(true, false).await.unwrap();
+        let err = plan(false, true).await.unwrap_err();
+        assert!(
+            format!("{err:#}").contains("module 'acc.vpc' is given undeclared variables: bogus"),
+            "{err:#}"
+        );
+    }
+
+    #[tokio::test]
+    async fn outputs_of_a_module_without_any_are_empty() {
+        for (name, script) in [
+            ("outputs-braces", "echo '{}'"),
+            ("outputs-nothing", "exit 0"),
+        ] {
+            let (runner, vpc, dir) = fake_terraform(name, script);
+            assert!(runner.output(&vpc).await.unwrap().is_empty(), "{name}");
+            let cached = std::fs::read(dir.join("acc.vpc").join(OUTPUTS_CACHE_FILE)).unwrap();
+            assert_eq!(cached.trim_ascii(), b"{}", "{name}");
+        }
+    }
+
+    #[tokio::test]
+    async fn outputs_of_a_module_without_state_are_a_clear_error() {
+        let (runner, vpc, _) = fake_terraform(
+            "outputs-no-state",
+            "echo 'Error: Backend initialization required, please run \"terraform init\"' >&2\nexit 1",
+        );
+        let err = runner.output(&vpc).await.unwrap_err();
+        assert!(
+            err.to_string().starts_with(
+                "'acc.vpc' has no terraform state yet, apply it before reading its outputs"
+            ),
+            "{err}"
+        );
+
+        let (runner, vpc, _) = fake_terraform("outputs-broken", "echo 'Error: boom' >&2\nexit 1");
+        let err = runner.output(&vpc).await.unwrap_err();
+        assert!(
+            err.downcast_ref::<TerraformCommandError>().is_some(),
+            "{err}"
+        );
+    }
+
+    #[tokio::test]
+    async fn state_list_returns_the_listed_addresses() {
+        let (runner, vpc, _) = fake_terraform(
+            "state-list",
+            r#"[ "$1 $2" = "state list" ] && printf 'null_resource.a\n\n  module.x.aws_s3_bucket.b  \n'
+exit 0"#,
+        );
+        assert_eq!(
+            runner.state_list(&vpc).await.unwrap(),
+            ["null_resource.a", "module.x.aws_s3_bucket.b"]
+        );
+    }
+
+    #[tokio::test]
+    async fn mock_state_list_is_configurable() {
+        use crate::runtime::{RunOptions, Runtime};
+        use std::sync::Arc;
+
+        let runner = MockRunner {
+            state: vec!["null_resource.dummy".to_string()],
+            ..Default::default()
+        };
+        let runtime = Runtime::new(Arc::new(runner), &infra(VPC), RunOptions::default()).unwrap();
+        assert_eq!(
+            runtime.state_list("acc.vpc").await.unwrap(),
+            ["null_resource.dummy"]
+        );
+    }
+
+    #[tokio::test]
+    async fn module_env_reaches_its_own_commands_only() {
+        let (runner, _, dir) = fake_terraform(
+            "module-env",
+            r#"echo "$(basename "$PWD") ${AWS_PROFILE:-none} ${AWS_REGION:-none}" >> ../env.log"#,
+        );
+        std::fs::create_dir_all(dir.join("acc.network")).unwrap();
+        let yaml = "
+acc:
+  scope: account
+  defaults:
+    env:
+      AWS_REGION: eu-west-1
+  vpc:
+    source: vpc
+    env:
+      AWS_PROFILE: net-admin
+  network:
+    source: network
+";
+        for id in ["acc.vpc", "acc.network"] {
+            runner.plan(&module(yaml, id), None, &[]).await.unwrap();
+        }
+        assert_eq!(
+            std::fs::read_to_string(dir.join("env.log")).unwrap(),
+            "acc.vpc net-admin eu-west-1\nacc.network none eu-west-1\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn nested_sources_are_copied_with_their_subdirectories() {
+        let yaml = "
+acc:
+  scope: account
+  dns:
+    source: networking/dns
+  lb:
+    source: networking/lb
+";
+        let dir = temp_dir("nested-source");
+        let modules = dir.join("modules");
+        for source in ["networking/dns", "networking/lb"] {
+            std::fs::create_dir_all(modules.join(source)).unwrap();
+        }
+        let sources = modules.join("networking/dns");
+        std::fs::create_dir_all(sources.join("records")).unwrap();
+        std::fs::write(sources.join("main.tf"), "# dns").unwrap();
+        std::fs::write(sources.join("records/a.json"), "{}").unwrap();
+        // Sources are validated in the modules dir the runner copies them from
+        let infra = InfraFile::from_reader(yaml.as_bytes(), &modules, None, true).unwrap();
+        let dns = ModuleGraph::new(&infra)
+            .unwrap()
+            .get_module_by_id("acc.dns")
+            .unwrap();
+        assert_eq!(dns.source, "networking/dns");
+        std::fs::remove_dir(modules.join("networking/lb")).unwrap();
+        let err = InfraFile::from_reader(yaml.as_bytes(), &modules, None, true).unwrap_err();
+        assert!(format!("{err:#}").contains("networking/lb"), "{err:#}");
+
+        let cache = dir.join("cache");
+        let runner = TerraformRunner::new(dir.join("terraform"), cache.clone(), modules);
+
+        let module_dir = runner.ensure_module_dir(&dns).await.unwrap();
+        assert_eq!(module_dir, cache.join("acc.dns"));
+        assert_eq!(
+            std::fs::read_to_string(module_dir.join("main.tf")).unwrap(),
+            "# dns"
+        );
+        assert_eq!(
+            std::fs::read_to_string(module_dir.join("records/a.json")).unwrap(),
+            "{}"
+        );
+    }
+
+    #[test]
+    fn use_chdir_puts_the_module_dir_before_the_subcommand() {
+        let mut runner = TerraformRunner::new("terraform".into(), "cache".into(), "modules".into());
+        let mut vpc = module(VPC, "acc.vpc");
+        let dir = Path::new("/cache/acc.vpc");
+        let args_of = |cmd: &Command| -> Vec<String> {
+            let cmd = cmd.as_std();
+            cmd.get_args()
+                .map(|a| a.to_string_lossy().into_owned())
+                .collect()
+        };
+        let envs = HashMap::new();
+
+        let cmd = runner
+            .terraform_command(&vpc, dir, &["plan", "-input=false"], &envs)
+            .unwrap();
+        assert_eq!(args_of(&cmd), ["plan", "-input=false"]);
+        assert_eq!(cmd.as_std().get_current_dir(), Some(dir));
+
+        runner.use_chdir = true;
+        let cmd = runner
+            .terraform_command(&vpc, dir, &["plan", "-input=false"], &envs)
+            .unwrap();
+        assert_eq!(
+            args_of(&cmd),
+            ["-chdir=/cache/acc.vpc", "plan", "-input=false"]
+        );
+        assert_eq!(cmd.as_std().get_current_dir(), None);
+
+        vpc.container_image = Some("hashicorp/terraform:1.9".to_string());
+        let cmd = runner
+            .terraform_command(&vpc, dir, &["plan"], &envs)
+            .unwrap();
+        let args = args_of(&cmd);
+        assert_eq!(
+            args[args.len() - 3..],
+            ["hashicorp/terraform:1.9", "-chdir=/cache/acc.vpc", "plan"]
+        );
+    }
+
+    #[tokio::test]
+    async fn use_chdir_runs_terraform_with_the_chdir_option() {
+        let (_, vpc, dir) =
+            fake_terraform("use-chdir", r#"echo "$@" >> "$(dirname "$0")/args.log""#);
+        let modules = modules_dir().to_path_buf();
+        let mut runner = TerraformRunner::new(dir.join("terraform"), dir.clone(), modules);
+        runner.use_chdir = true;
+        runner.init(&vpc).await.unwrap();
+        let log = std::fs::read_to_string(dir.join("args.log")).unwrap();
+        let first = log.lines().next().unwrap();
+        assert_eq!(
+            first.split(' ').take(2).collect::<Vec<_>>(),
+            [
+                format!("-chdir={}", dir.join("acc.vpc").display()).as_str(),
+                "init"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn refresh_applies_refresh_only() {
+        let (runner, vpc, dir) = fake_terraform("refresh", r#"echo "$@" >> ../args.log"#);
+        runner.refresh(&vpc).await.unwrap();
+        assert_eq!(
+            std::fs::read_to_string(dir.join("args.log")).unwrap(),
+            "apply -refresh-only -auto-approve\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn backend_override_of_the_source_is_kept() {
+        let dir = temp_dir("backend-override-source");
+        std::fs::write(dir.join(BACKEND_OVERRIDE_FILE), "{}").unwrap();
+        let runner = TerraformRunner::new("terraform".into(), dir.clone(), dir.clone());
+        let vpc = module(VPC, "acc.vpc");
+        runner
+            .write_backend_override(&vpc, &dir, &dir)
+            .await
+            .unwrap();
+        assert!(dir.join(BACKEND_OVERRIDE_FILE).exists());
+    }
 }
@@ -0,0 +1,37 @@
+//! Helpers shared by the unit tests
+use crate::parser::InfraFile;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Modules of `examples/`: `vpc`, `compute`, `network`, `webapp` and `networking/dns`
+pub fn modules_dir() -> &'static Path {
+    Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/examples/modules"))
+}
+
+/// A fixture of `examples/`, like `deployments/infra_example.yaml`
+pub fn example(path: &str) -> PathBuf {
+    Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/examples")).join(path)
+}
+
+/// Parse an infra file given inline, deep-merging defaults
+pub fn infra(yaml: &str) -> InfraFile {
+    try_infra(yaml).unwrap_or_else(|err| panic!("{err:#}"))
+}
+
+pub fn try_infra(yaml: &str) -> anyhow::Result<InfraFile> {
+    InfraFile::from_reader(yaml.as_bytes(), modules_dir(), None, true)
+}
+
+/// Fresh empty directory under the system temp dir
+pub fn temp_dir(name: &str) -> PathBuf {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    let dir = std::env::temp_dir().join(format!(
+        "lightstacks-{}-{}-{}",
+        name,
+        std::process::id(),
+        NEXT.fetch_add(1, Ordering::SeqCst)
+    ));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).expect("temp dir");
+    dir
+}
@@ -0,0 +1,261 @@
+use anyhow::{Context, Result, anyhow};
+use serde_yaml::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+
+/// Variables given to every module: the YAML or JSON mappings of `files`, later
+/// files winning, then the `KEY=VALUE` strings of `vars`
+pub fn read_global_variables(files: &[PathBuf], vars: &[String]) -> Result<HashMap<String, Value>> {
+    let mut globals = HashMap::new();
+    for file in files {
+        let content =
+            std::fs::read_to_string(file).with_context(|| format!("Failed to read {:?}", file))?;
+        let parsed: HashMap<String, Value> = serde_yaml::from_str(&content)
+            .with_context(|| format!("Failed to parse {:?} as a mapping of variables", file))?;
+        globals.extend(parsed);
+    }
+    for var in vars {
+        let (key, value) = var
+            .split_once('=')
+            .ok_or_else(|| anyhow!("Invalid --var '{}', expected KEY=VALUE", var))?;
+        globals.insert(key.to_string(), Value::String(value.to_string()));
+    }
+    Ok(globals)
+}
+
+/// Scope id, variable name and value of a `SCOPE_ID:KEY=VALUE` string; the value
+/// is a string, as with `--var`
+pub fn parse_scope_variable(spec: &str) -> Result<(String, String, Value)> {
+    let invalid = || {
+        anyhow!(
+            "Invalid --scope-var '{}', expected SCOPE_ID:KEY=VALUE",
+            spec
+        )
+    };
+    let (scope_id, var) = spec.split_once(':').ok_or_else(invalid)?;
+    let (key, value) = var.split_once('=').ok_or_else(invalid)?;
+    if scope_id.is_empty() || key.is_empty() {
+        return Err(invalid());
+    }
+    Ok((
+        scope_id.to_string(),
+        key.to_string(),
+        Value::String(value.to_string()),
+    ))
+}
+
+/// Variables declared by the `.tf` and `.tf.json` files of a module, mapped to
+/// whether they are required (declared without a `default`)
+pub fn read_declared_variables(dir: &Path) -> Result<BTreeMap<String, bool>> {
+    let mut declared = BTreeMap::new();
+    let entries = std::fs::read_dir(dir).with_context(|| format!("Failed to read {:?}", dir))?;
+    for entry in entries {
+        let path = entry?.path();
+        let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+        if !name.ends_with(".tf") && !name.ends_with(".tf.json") {
+            continue;
+        }
+        let content =
+            std::fs::read_to_string(&path).with_context(|| format!("Failed to read {:?}", path))?;
+        if name.ends_with(".tf.json") {
+            let json: serde_json::Value = serde_json::from_str(&content)
+                .with_context(|| format!("Failed to parse {:?}", path))?;
+            if let Some(vars) = json.get("variable").and_then(|v| v.as_object()) {
+                for (var, body) in vars {
+                    declared.insert(var.clone(), body.get("default").is_none());
+                }
+            }
+        } else {
+            declared.extend(scan_hcl_variables(&content));
+        }
+    }
+    Ok(declared)
+}
+
+/// Find `variable "<name>" { ... }` blocks and whether they set a top-level `default`.
+/// Only braces outside of quoted strings are counted, which covers usual declarations.
+fn scan_hcl_variables(content: &str) -> Vec<(String, bool)> {
+    let mut found = Vec::new();
+    // Name of the open variable block, whether it has a default, and the brace depth
+    let mut current: Option<(String, bool)> = None;
+    let mut depth = 0usize;
+    for line in content.lines() {
+        let trimmed = line.trim();
+        if current.is_none() {
+            let Some(rest) = trimmed
+                .strip_prefix("variable")
+                .filter(|rest| rest.starts_with([' ', '\t']))
+            else {
+                continue;
+            };
+            // Block labels may be quoted or bare: `variable "x" {` or `variable x {`
+            let rest = rest.trim_start();
+            let (name, after) = match rest.strip_prefix('"') {
+                Some(quoted) => match quoted.split_once('"') {
+                    Some(split) => split,
+                    None => continue,
+                },
+                None => rest.split_at(
+                    rest.find(|c: char| !(c.is_alphanumeric() || c == '_' || c == '-'))
+                        .unwrap_or(rest.len()),
+                ),
+            };
+            if name.is_empty() {
+                continue;
+            }
+            // One-line blocks: `variable "x" { default = 1 }`
+            let inline_default = after
+                .split_once('{')
+                .and_then(|(_, body)| body.trim_start().strip_prefix("default"))
+                .is_some_and(|rest| rest.trim_start().starts_with('='));
+            current = Some((name.to_string(), inline_default));
+            depth = 0;
+        } else if depth == 1
+            && let Some(rest) = trimmed.strip_prefix("default")
+            && rest.trim_start().starts_with('=')
+            && let Some((_, has_default)) = current.as_mut()
+        {
+            *has_default = true;
+        }
+
+        let mut in_string = false;
+        let mut escaped = false;
+        for c in trimmed.chars() {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' if in_string => escaped = true,
+                '"' => in_string = !in_string,
+                '#' if !in_string => break,
+                '{' if !in_string => depth += 1,
+                '}' if !in_string => {
+                    depth = depth.saturating_sub(1);
+                    if depth == 0
+                        && let Some((name, has_default)) = current.take()
+                    {
+                        found.push((name, !has_default));
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::temp_dir;
+
+    #[test]
+    fn later_files_then_vars_win() {
+        let dir = temp_dir("var-files");
+        let (first, second) = (dir.join("first.yaml"), dir.join("second.json"));
+        std::fs::write(&first, "region: eu-west-1\ncount: 2\nowner: a\n").unwrap();
+        std::fs::write(&second, r#"{"owner": "b", "tags": {"env": "prod"}}"#).unwrap();
+        let vars = ["region=us-east-1".to_string(), "expr=a=b".to_string()];
+        let globals = read_global_variables(&[first, second], &vars).unwrap();
+        assert_eq!(
+            globals,
+            HashMap::from([
+                ("region".to_string(), Value::from("us-east-1")),
+                ("count".to_string(), Value::from(2)),
+                ("owner".to_string(), Value::from("b")),
+                (
+                    "tags".to_string(),
+                    serde_yaml::from_str("{env: prod}").unwrap()
+                ),
+                ("expr".to_string(), Value::from("a=b")),
+            ])
+        );
+    }
+
+    #[test]
+    fn malformed_vars_and_files_are_errors() {
+        let err = read_global_variables(&[], &["region".to_string()]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid --var 'region', expected KEY=VALUE"
+        );
+        let dir = temp_dir("var-files-bad");
+        let list = dir.join("list.yaml");
+        std::fs::write(&list, "[a, b]").unwrap();
+        let err = read_global_variables(&[list], &[]).unwrap_err();
+        assert!(
+            err.to_string().contains("as a mapping of variables"),
+            "{err}"
+        );
+        assert!(read_global_variables(&[dir.join("missing.yaml")], &[]).is_err());
+    }
+
+    #[test]
+    fn declared_variables_are_read_with_whether_they_are_required() {
+        let dir = temp_dir("declared-variables");
+        std::fs::write(
+            dir.join("variables.tf"),
+            r#"
+variable "region" {
+  type = string
+}
+
+variable sizes {
+  type = object({ default = string })
+  # default = "not this one"
+  validation {
+    condition     = var.sizes.default != "}"
+    error_message = "default = must not be a brace"
+  }
+}
+
+variable "owner" { default = "platform" }
+
+variable "tags" {
+  type    = map(string)
+  default = {}
+}
+"#,
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("extra.tf.json"),
+            r#"{"variable": {"zone": {}, "count": {"default": 1}}}"#,
+        )
+        .unwrap();
+        std::fs::write(dir.join("README.md"), r#"variable "ignored" {}"#).unwrap();
+        let declared = read_declared_variables(&dir).unwrap();
+        let expected = [
+            ("count", false),
+            ("owner", false),
+            ("region", true),
+            ("sizes", true),
+            ("tags", false),
+            ("zone", true),
+        ];
+        assert_eq!(
+            declared,
+            expected
+                .into_iter()
+                .map(|(name, required)| (name.to_string(), required))
+                .collect()
+        );
+    }
+
+    #[test]
+    fn scope_variables_split_at_the_first_colon_and_equals() {
+        assert_eq!(
+            parse_scope_variable("org.acc:endpoint=http://host:8080/?a=b").unwrap(),
+            (
+                "org.acc".to_string(),
+                "endpoint".to_string(),
+                Value::from("http://host:8080/?a=b")
+            )
+        );
+        for spec in ["acc", "acc:region", ":region=x", "acc:=x"] {
+            let err = parse_scope_variable(spec).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                format!("Invalid --scope-var '{spec}', expected SCOPE_ID:KEY=VALUE")
+            );
+        }
+    }
+}
@@ -0,0 +1,135 @@
+use anyhow::{Context, Result, anyhow};
+use std::fmt;
+use std::path::Path;
+
+/// A terraform release version, pre-release suffixes ignored
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Version(pub u64, pub u64, pub u64);
+
+impl Version {
+    /// Parse "1.9.8", "v1.9", "1.10.0-beta1"; returns the version and how many
+    /// segments were given (needed by `~>`)
+    fn parse_partial(s: &str) -> Option<(Version, usize)> {
+        let s = s.trim().trim_start_matches('v');
+        let s = s.split(['-', '+']).next()?;
+        let parts = s
+            .split('.')
+            .map(|p| p.parse::<u64>().ok())
+            .collect::<Option<Vec<_>>>()?;
+        if parts.is_empty() || parts.len() > 3 {
+            return None;
+        }
+        let get = |i: usize| parts.get(i).copied().unwrap_or(0);
+        Some((Version(get(0), get(1), get(2)), parts.len()))
+    }
+
+    pub fn parse(s: &str) -> Option<Version> {
+        Self::parse_partial(s).map(|(v, _)| v)
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.0, self.1, self.2)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Op {
+    Eq,
+    Ne,
+    Gt,
+    Ge,
+    Lt,
+    Le,
+    Pessimistic,
+}
+
+/// A terraform version constraint like ">= 1.3, ~> 1.5, != 1.5.1"
+#[derive(Debug, Clone)]
+pub struct VersionConstraint {
+    raw: String,
+    terms: Vec<(Op, Version, usize)>,
+}
+
+impl VersionConstraint {
+    pub fn parse(raw: &str) -> Result<Self> {
+        let mut terms = Vec::new();
+        for term in raw.split(',') {
+            let term = term.trim();
+            let (op, rest) = [
+                ("~>", Op::Pessimistic),
+                (">=", Op::Ge),
+                ("<=", Op::Le),
+                ("!=", Op::Ne),
+                (">", Op::Gt),
+                ("<", Op::Lt),
+                ("=", Op::Eq),
+            ]
+            .iter()
+            .find_map(|(prefix, op)| term.strip_prefix(prefix).map(|rest| (*op, rest)))
+            .unwrap_or((Op::Eq, term));
+            let (version, precision) = Version::parse_partial(rest)
+                .ok_or_else(|| anyhow!("Invalid version constraint '{}'", raw))?;
+            terms.push((op, version, precision));
+        }
+        Ok(Self {
+            raw: raw.to_string(),
+            terms,
+        })
+    }
+
+    pub fn matches(&self, v: Version) -> bool {
+        self.terms.iter().all(|&(op, c, precision)| match op {
+            Op::Eq => v == c,
+            Op::Ne => v != c,
+            Op::Gt => v > c,
+            Op::Ge => v >= c,
+            Op::Lt => v < c,
+            Op::Le => v <= c,
+            // "~> 1.5" allows 1.x >= 1.5, "~> 1.5.2" allows 1.5.x >= 1.5.2
+            Op::Pessimistic => {
+                let upper = match precision {
+                    1 | 2 => Version(c.0 + 1, 0, 0),
+                    _ => Version(c.0, c.1 + 1, 0),
+                };
+                v >= c && v < upper
+            }
+        })
+    }
+}
+
+impl fmt::Display for VersionConstraint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.raw)
+    }
+}
+
+/// Collect the `required_version` constraints declared by the `.tf` files of a module
+pub fn read_required_versions(dir: &Path) -> Result<Vec<VersionConstraint>> {
+    let mut constraints = Vec::new();
+    let entries = std::fs::read_dir(dir).with_context(|| format!("Failed to read {:?}", dir))?;
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension().and_then(|e| e.to_str()) != Some("tf") {
+            continue;
+        }
+        let content =
+            std::fs::read_to_string(&path).with_context(|| format!("Failed to read {:?}", path))?;
+        for line in content.lines() {
+            let Some(rest) = line.trim().strip_prefix("required_version") else {
+                continue;
+            };
+            if let Some(value) = rest.trim().strip_prefix('=') {
+                // Keep only the quoted string, dropping trailing comments
+                let value = value.trim().trim_start_matches('"');
+                let value = value.split('"').next().unwrap_or(value);
+                constraints.push(
+                    VersionConstraint::parse(value)
+                        .with_context(|| format!("in required_version of {:?}", path))?,
+                );
+            }
+        }
+    }
+    Ok(constraints)
+}
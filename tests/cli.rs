@@ -0,0 +1,547 @@
+//! End-to-end runs of the binary against the fixtures of `examples/`
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+fn examples() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("examples")
+}
+
+/// Fresh empty directory under the system temp dir
+fn temp_dir(name: &str) -> PathBuf {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    let dir = std::env::temp_dir().join(format!(
+        "lightstacks-cli-{}-{}-{}",
+        name,
+        std::process::id(),
+        NEXT.fetch_add(1, Ordering::SeqCst)
+    ));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).expect("temp dir");
+    dir
+}
+
+/// Run the binary in `examples/`
+fn lightstacks(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_lightstacks"))
+        .args(args)
+        .current_dir(examples())
+        .env_remove("TFSTACKS_CACHE_DIR")
+        .env_remove("TFSTACKS_TF_BIN")
+        .output()
+        .expect("run lightstacks")
+}
+
+/// stdout and stderr of a run, which must have succeeded
+fn succeeded(output: &Output) -> String {
+    let text = format!(
+        "{}{}",
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert!(output.status.success(), "{text}");
+    text
+}
+
+/// A terraform that only records that it ran
+fn fake_terraform(dir: &Path) -> (PathBuf, PathBuf) {
+    use std::os::unix::fs::PermissionsExt;
+    let log = dir.join("terraform.log");
+    let bin = dir.join("terraform");
+    std::fs::write(
+        &bin,
+        format!("#!/bin/sh\necho \"$@\" >> '{}'\n", log.display()),
+    )
+    .unwrap();
+    std::fs::set_permissions(&bin, std::fs::Permissions::from_mode(0o755)).unwrap();
+    (bin, log)
+}
+
+#[test]
+fn dry_run_runs_nothing_and_resolves_mocked_outputs() {
+    let dir = temp_dir("dry-run");
+    let (bin, log) = fake_terraform(&dir);
+    let cache = dir.join("cache");
+    let common = [
+        "--infra-file",
+        "deployments/infra_example.yaml",
+        "--module-id",
+        "account-1.tenant-c.webapp",
+        "--with-deps",
+        "--dry-run",
+        "--bin-path",
+        bin.to_str().unwrap(),
+        "--cache-dir",
+        cache.to_str().unwrap(),
+    ];
+
+    let applied = succeeded(&lightstacks(&[&common[..], &["apply"]].concat()));
+    for id in [
+        "account-1.vpc",
+        "account-1.compute",
+        "account-1.tenant-c.webapp",
+    ] {
+        assert!(
+            applied.contains(&format!("[mock] terraform apply '{id}'")),
+            "{applied}"
+        );
+    }
+    // The webapp gets the mocked outputs of vpc and compute
+    assert!(applied.contains(r#""lb": String("alb-111")"#), "{applied}");
+    assert!(
+        applied.contains(r#""subnet": String("subnet-222")"#),
+        "{applied}"
+    );
+
+    let planned = succeeded(&lightstacks(&[&common[..], &["plan"]].concat()));
+    assert!(
+        planned.contains("No changes: the infrastructure matches the configuration"),
+        "{planned}"
+    );
+
+    assert!(
+        !log.exists(),
+        "terraform ran: {:?}",
+        std::fs::read_to_string(&log)
+    );
+    assert!(!cache.exists(), "a dry run wrote the cache dir");
+}
+
+/// The `--format json` document of a dry run of `action` on `module_id`
+fn json_run(infra: &str, module_id: &str, action: &str) -> (bool, serde_json::Value) {
+    let output = lightstacks(&[
+        "--infra-file",
+        infra,
+        "--module-id",
+        module_id,
+        "--with-deps",
+        "--dry-run",
+        "--format",
+        "json",
+        action,
+    ]);
+    let document = serde_json::from_slice(&output.stdout).unwrap_or_else(|err| {
+        panic!("{err}: {}", String::from_utf8_lossy(&output.stdout));
+    });
+    (output.status.success(), document)
+}
+
+fn statuses(document: &serde_json::Value) -> Vec<(String, String, u64)> {
+    let modules = document["modules"].as_array().expect("modules");
+    modules
+        .iter()
+        .map(|m| {
+            let field = |name: &str| m[name].as_str().unwrap().to_string();
+            (field("id"), field("status"), m["layer"].as_u64().unwrap())
+        })
+        .collect()
+}
+
+#[test]
+fn json_format_reports_every_module_of_the_run() {
+    let (success, document) = json_run(
+        "deployments/infra_example.yaml",
+        "account-1.compute",
+        "apply",
+    );
+    assert!(success);
+    assert_eq!(document["action"], "apply");
+    assert_eq!(document["success"], true);
+    assert_eq!(
+        statuses(&document),
+        [
+            ("account-1.vpc".to_string(), "succeeded".to_string(), 1),
+            ("account-1.compute".to_string(), "succeeded".to_string(), 2),
+        ]
+    );
+    // Inputs are resolved from the mocked outputs of vpc
+    let compute = &document["modules"][1];
+    assert_eq!(compute["inputs"]["vpc_cidr"], "172.30.0.0/16");
+    assert!(compute["duration_ms"].is_u64());
+    let timed: Vec<&str> = document["timings"]["modules"]
+        .as_array()
+        .expect("timings")
+        .iter()
+        .map(|t| t["id"].as_str().unwrap())
+        .collect();
+    assert_eq!(timed, ["account-1.vpc", "account-1.compute"]);
+    assert_eq!(document["timings"]["layers"].as_array().unwrap().len(), 2);
+}
+
+#[test]
+fn json_format_reports_a_failed_run() {
+    let (success, document) = json_run("deployments/expects.yaml", "account-1.webapp", "apply");
+    assert!(!success);
+    assert_eq!(document["success"], false);
+    let error = document["error"].as_str().expect("error");
+    assert!(error.contains("no output named 'main_lb'"), "{error}");
+    assert_eq!(statuses(&document)[0].1, "succeeded");
+}
+
+#[test]
+fn list_shows_every_module_of_a_nested_fixture() {
+    let listed = succeeded(&lightstacks(&[
+        "--infra-file",
+        "deployments/infra_example.yaml",
+        "list",
+    ]));
+    for account in ["account-1", "account-2"] {
+        assert!(listed.contains(&format!("{account} (account)")), "{listed}");
+        for module in ["vpc [vpc]", "compute [compute]", "network [network]"] {
+            assert!(listed.contains(&format!("{account}.{module}")), "{listed}");
+        }
+        assert!(
+            listed.contains(&format!("{account}.tenant-c.webapp [webapp]")),
+            "{listed}"
+        );
+    }
+}
+
+#[test]
+fn terraform_lines_are_prefixed_with_the_module_id() {
+    use std::os::unix::fs::PermissionsExt;
+    let dir = temp_dir("prefix");
+    let bin = dir.join("terraform");
+    std::fs::write(
+        &bin,
+        "#!/bin/sh\ncase \"$1\" in\n  output) echo '{}' ;;\n  *) echo \"$1 one\"; echo \"$1 two\" >&2 ;;\nesac\n",
+    )
+    .unwrap();
+    std::fs::set_permissions(&bin, std::fs::Permissions::from_mode(0o755)).unwrap();
+    let run = |raw: bool| {
+        let cache = dir.join(if raw { "raw" } else { "prefixed" });
+        let mut args = vec![
+            "--infra-file",
+            "deployments/infra_example.yaml",
+            "--module-id",
+            "account-1.vpc",
+            "--bin-path",
+            bin.to_str().unwrap(),
+            "--cache-dir",
+            cache.to_str().unwrap(),
+        ];
+        if raw {
+            args.push("--raw-output");
+        }
+        args.push("apply");
+        let output = Command::new(env!("CARGO_BIN_EXE_lightstacks"))
+            .args(&args)
+            .current_dir(examples())
+            .env("NO_COLOR", "1")
+            .output()
+            .expect("run lightstacks");
+        succeeded(&output)
+    };
+
+    let prefixed = run(false);
+    for line in ["apply one", "apply two"] {
+        assert!(
+            prefixed.contains(&format!("[account-1.vpc] {line}\n")),
+            "{prefixed}"
+        );
+    }
+    let raw = run(true);
+    assert!(raw.contains("\napply one\n"), "{raw}");
+    assert!(!raw.contains("[account-1.vpc] apply"), "{raw}");
+}
+
+#[test]
+fn tf_target_needs_a_single_module_id() {
+    let output = lightstacks(&[
+        "--infra-file",
+        "deployments/infra_example.yaml",
+        "--tag",
+        "network",
+        "--tf-target",
+        "null_resource.dummy",
+        "--dry-run",
+        "apply",
+    ]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("--tf-target"), "{stderr}");
+}
+
+#[test]
+fn tf_parallelism_must_be_at_least_one() {
+    let output = lightstacks(&[
+        "--infra-file",
+        "deployments/infra_example.yaml",
+        "--module-id",
+        "account-1.vpc",
+        "--tf-parallelism",
+        "0",
+        "--dry-run",
+        "apply",
+    ]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("--tf-parallelism"), "{stderr}");
+}
+
+#[test]
+fn infra_file_dash_is_read_from_stdin() {
+    use std::io::Write;
+    use std::process::Stdio;
+    let mut child = Command::new(env!("CARGO_BIN_EXE_lightstacks"))
+        .args(["--infra-file", "-", "list"])
+        .current_dir(examples())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("run lightstacks");
+    let yaml = std::fs::read(examples().join("deployments/infra_example.yaml")).unwrap();
+    child.stdin.take().unwrap().write_all(&yaml).unwrap();
+    let piped = succeeded(&child.wait_with_output().unwrap());
+    let listed = succeeded(&lightstacks(&[
+        "--infra-file",
+        "deployments/infra_example.yaml",
+        "list",
+    ]));
+    assert_eq!(piped, listed);
+}
+
+#[test]
+fn validate_reports_orphans_of_the_targets() {
+    let validated = succeeded(&lightstacks(&[
+        "--infra-file",
+        "deployments/infra_example.yaml",
+        "--module-id",
+        "account-1.compute",
+        "validate",
+        "--report-orphans",
+    ]));
+    assert!(
+        validated.contains("orphan module 'account-1.network': no target depends on it"),
+        "{validated}"
+    );
+    assert!(
+        !validated.contains("orphan module 'account-1.vpc'"),
+        "{validated}"
+    );
+    assert!(
+        !validated.contains("orphan module 'account-1.compute'"),
+        "{validated}"
+    );
+}
+
+#[test]
+fn output_prints_the_mocked_outputs_of_the_module() {
+    let output = |format: &str| {
+        let output = lightstacks(&[
+            "--infra-file",
+            "deployments/infra_example.yaml",
+            "--module-id",
+            "account-1.vpc",
+            "--dry-run",
+            "--format",
+            format,
+            "output",
+        ]);
+        succeeded(&output);
+        String::from_utf8(output.stdout).unwrap()
+    };
+
+    let json: serde_json::Value = serde_json::from_str(&output("json")).unwrap();
+    assert_eq!(json["main_lb"], "alb-111");
+    assert_eq!(
+        json["public_subnets"],
+        serde_json::json!(["subnet-111", "subnet-222"])
+    );
+    assert_eq!(json["vpc"]["ipam"]["cidr"], "172.30.0.0/16");
+
+    let text = output("text");
+    assert!(text.contains("main_lb = alb-111\n"), "{text}");
+    assert!(
+        text.contains(r#"public_subnets = ["subnet-111","subnet-222"]"#),
+        "{text}"
+    );
+}
+
+#[test]
+fn piped_or_no_color_output_has_no_ansi_codes() {
+    let run = |force_color: bool, flags: &[&str]| {
+        let mut args = vec![
+            "--infra-file",
+            "deployments/infra_example.yaml",
+            "--module-id",
+            "account-1.ghost",
+        ];
+        args.extend(flags);
+        args.push("apply");
+        let mut command = Command::new(env!("CARGO_BIN_EXE_lightstacks"));
+        command
+            .args(&args)
+            .current_dir(examples())
+            .env_remove("NO_COLOR")
+            .env_remove("CLICOLOR_FORCE");
+        if force_color {
+            command.env("CLICOLOR_FORCE", "1");
+        }
+        let output = command.output().expect("run lightstacks");
+        assert!(!output.status.success());
+        format!(
+            "{}{}",
+            String::from_utf8_lossy(&output.stdout),
+            String::from_utf8_lossy(&output.stderr)
+        )
+    };
+
+    let piped = run(false, &[]);
+    assert!(piped.contains("Error"), "{piped}");
+    assert!(!piped.contains('\x1b'), "{piped:?}");
+    // Forcing color is what brings the codes back, unless --no-color is passed
+    assert!(run(true, &[]).contains("\x1b["));
+    let no_color = run(true, &["--no-color"]);
+    assert!(!no_color.contains('\x1b'), "{no_color:?}");
+}
+
+#[test]
+fn json_format_keeps_colors_off_stdout() {
+    use std::os::unix::fs::PermissionsExt;
+    let dir = temp_dir("json-colors");
+    let bin = dir.join("terraform");
+    std::fs::write(
+        &bin,
+        "#!/bin/sh\ncase \"$1\" in\n  output) echo '{}' ;;\n  *) echo \"$1 done\" ;;\nesac\n",
+    )
+    .unwrap();
+    std::fs::set_permissions(&bin, std::fs::Permissions::from_mode(0o755)).unwrap();
+    let cache = dir.join("cache");
+    let output = Command::new(env!("CARGO_BIN_EXE_lightstacks"))
+        .args([
+            "--infra-file",
+            "deployments/infra_example.yaml",
+            "--module-id",
+            "account-1.vpc",
+            "--format",
+            "json",
+            "--bin-path",
+            bin.to_str().unwrap(),
+            "--cache-dir",
+            cache.to_str().unwrap(),
+            "apply",
+        ])
+        .current_dir(examples())
+        .env("CLICOLOR_FORCE", "1")
+        .output()
+        .expect("run lightstacks");
+    succeeded(&output);
+    // Progress on stderr is colored as forced, the JSON on stdout never is
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("\x1b["), "{stderr}");
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(!stdout.contains('\x1b'), "{stdout:?}");
+    serde_json::from_str::<serde_json::Value>(&stdout).unwrap();
+}
+
+#[test]
+fn sources_of_an_infra_file_from_stdin_are_checked_under_modules_dir() {
+    use std::io::Write;
+    use std::process::Stdio;
+    // Run away from examples/, so that no ./modules is there to fall back on
+    let dir = temp_dir("stdin-modules-dir");
+    let list = |modules_dir: &Path| {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_lightstacks"))
+            .args(["--infra-file", "-", "--modules-dir"])
+            .arg(modules_dir)
+            .arg("list")
+            .current_dir(&dir)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .expect("run lightstacks");
+        let yaml = std::fs::read(examples().join("deployments/infra_example.yaml")).unwrap();
+        child.stdin.take().unwrap().write_all(&yaml).unwrap();
+        child.wait_with_output().unwrap()
+    };
+    let listed = succeeded(&list(&examples().join("modules")));
+    assert!(listed.contains("account-1.vpc"), "{listed}");
+
+    let missing = list(Path::new("modules"));
+    assert!(!missing.status.success());
+    let stderr = String::from_utf8_lossy(&missing.stderr);
+    assert!(stderr.contains("directory in modules_dir"), "{stderr}");
+}
+
+#[test]
+fn apply_from_stdin_resumes_after_a_failure() {
+    use std::io::Write;
+    use std::os::unix::fs::PermissionsExt;
+    use std::process::Stdio;
+    let dir = temp_dir("resume-stdin");
+    let (log, fixed) = (dir.join("terraform.log"), dir.join("fixed"));
+    let bin = dir.join("terraform");
+    std::fs::write(
+        &bin,
+        format!(
+            "#!/bin/sh\necho \"$(basename \"$PWD\") $1\" >> '{}'\ncase \"$1\" in\n  output) echo '{{}}' ;;\n  apply) [ \"$(basename \"$PWD\")\" != acc.compute ] || [ -f '{}' ] ;;\nesac\n",
+            log.display(),
+            fixed.display()
+        ),
+    )
+    .unwrap();
+    std::fs::set_permissions(&bin, std::fs::Permissions::from_mode(0o755)).unwrap();
+    let yaml = "
+acc:
+  scope: account
+  vpc:
+    source: vpc
+  compute:
+    source: compute
+    dependencies: [vpc]
+";
+    let apply = |resume: bool| {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_lightstacks"));
+        command
+            .args([
+                "--infra-file",
+                "-",
+                "--module-id",
+                "acc.compute",
+                "--with-deps",
+            ])
+            .arg("--bin-path")
+            .arg(&bin)
+            .arg("--cache-dir")
+            .arg(dir.join("cache"));
+        if resume {
+            command.arg("--resume");
+        }
+        let mut child = command
+            .arg("apply")
+            .current_dir(examples())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .expect("run lightstacks");
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(yaml.as_bytes())
+            .unwrap();
+        child.wait_with_output().unwrap()
+    };
+    let applies = || -> Vec<String> {
+        let log = std::fs::read_to_string(&log).unwrap_or_default();
+        log.lines()
+            .filter(|line| line.ends_with(" apply"))
+            .map(String::from)
+            .collect()
+    };
+
+    assert!(!apply(false).status.success());
+    assert_eq!(applies(), ["acc.vpc apply", "acc.compute apply"]);
+    assert!(dir.join("cache/run-acc.compute.state").is_file());
+
+    std::fs::write(&fixed, "").unwrap();
+    std::fs::remove_file(&log).unwrap();
+    succeeded(&apply(true));
+    // The run state matched the infra file read from stdin, so vpc was skipped
+    assert_eq!(applies(), ["acc.compute apply"]);
+    assert!(!dir.join("cache/run-acc.compute.state").exists());
+}